@@ -1,26 +1,26 @@
+use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 /// Build script for copying the `rules.lua` script to the target directory.
 ///
 /// This script ensures that the `rules.lua` file, which contains custom spam detection rules
-/// written in Lua, is copied from the project root to the `target/debug` directory during the
-/// build process. This makes the script accessible to the `spam-bot-mvp` application at runtime.
+/// written in Lua, is copied from the project root to the output directory of the current
+/// build profile (e.g. `target/debug` or `target/release`) during the build process. This
+/// makes the script accessible to the `spam-bot-mvp` application at runtime when it is run
+/// from that directory; otherwise point `RULES_LUA_PATH` at the script.
 ///
-/// The script uses the `std::fs` module for file operations and `std::path::Path` for handling
-/// file paths. If the copy operation fails, the script will panic with an error message.
+/// The profile directory is derived from `OUT_DIR` (`<target>/<profile>/build/<crate>/out`),
+/// so custom target directories (`CARGO_TARGET_DIR`) are respected as well.
 ///
 /// # Panics
-/// * Panics if the `rules.lua` file cannot be copied to `target/debug/rules.lua`. This could
+/// * Panics if the `rules.lua` file cannot be copied to the profile directory. This could
 ///   happen if:
 ///   - The source file (`rules.lua`) does not exist in the project root.
-///   - The `target/debug` directory cannot be written to due to permissions issues.
+///   - The profile directory cannot be written to due to permissions issues.
 ///   - Other I/O errors occur during the copy operation.
 ///
 /// # Notes
 /// * This script assumes that `rules.lua` exists in the project root directory.
-/// * The destination path is hard-coded to `target/debug/rules.lua`, which means the script
-///   only works correctly in debug builds. For release builds, the path should be adjusted
-///   to `target/release/rules.lua` to match the build profile.
 ///
 /// # Example
 /// To use this build script, ensure a `rules.lua` file exists in the project root:
@@ -33,9 +33,16 @@ use std::path::Path;
 ///     return 0
 /// end
 /// ```
-/// Running `cargo build` will copy this file to `target/debug/rules.lua`.
+/// Running `cargo build` will copy this file to `target/debug/rules.lua`, and
+/// `cargo build --release` to `target/release/rules.lua`.
 fn main() {
+    println!("cargo:rerun-if-changed=rules.lua");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    let profile_dir = out_dir
+        .ancestors()
+        .nth(3)
+        .expect("OUT_DIR is nested inside the profile directory");
     let src = Path::new("rules.lua");
-    let dst = Path::new("target/debug/rules.lua");
-    fs::copy(src, dst).expect("Failed to copy rules.lua");
-}
\ No newline at end of file
+    let dst = profile_dir.join("rules.lua");
+    fs::copy(src, &dst).unwrap_or_else(|e| panic!("Failed to copy rules.lua to {}: {}", dst.display(), e));
+}
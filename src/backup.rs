@@ -0,0 +1,217 @@
+//! A module for rotating database snapshots, configurable from within Telegram.
+//!
+//! The schedule (interval in hours and number of snapshots to keep) is stored in the
+//! `settings` table so it survives restarts. A background task takes a `VACUUM INTO`
+//! snapshot named `rules-<unix timestamp>.db` into the backup directory whenever the
+//! newest one is older than the interval, then prunes the oldest snapshots beyond
+//! the retention count.
+
+use crate::rules::{self, RuleManager};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Setting key for the backup interval in hours.
+const INTERVAL_KEY: &str = "backup_interval_hours";
+/// Setting key for the number of snapshots to keep.
+const RETENTION_KEY: &str = "backup_retention";
+
+/// How often snapshots are taken and how many are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupSchedule {
+    /// Hours between snapshots.
+    pub interval_hours: u64,
+    /// Number of snapshots to keep.
+    pub retention: usize,
+}
+
+/// Parses a backup schedule of the form `<interval hours> <snapshots to keep>`.
+///
+/// # Arguments
+/// * `spec` - The command argument, e.g. `24 7`.
+///
+/// # Returns
+/// * `Result<BackupSchedule, String>` - The schedule, or a human-readable error message.
+pub fn parse_backup_schedule(spec: &str) -> Result<BackupSchedule, String> {
+    let usage = "Usage: /setbackupschedule <interval hours> <snapshots to keep>".to_string();
+    let parts: Vec<&str> = spec.split_whitespace().collect();
+    if parts.len() != 2 {
+        return Err(usage);
+    }
+    match (parts[0].parse::<u64>(), parts[1].parse::<usize>()) {
+        (Ok(interval_hours), Ok(retention)) if interval_hours > 0 && retention > 0 => Ok(BackupSchedule {
+            interval_hours,
+            retention,
+        }),
+        _ => Err(usage),
+    }
+}
+
+/// Loads the stored backup schedule.
+///
+/// # Returns
+/// * `Option<BackupSchedule>` - The schedule, or `None` if backups were never configured.
+pub fn get_backup_schedule(rule_manager: &RuleManager) -> Option<BackupSchedule> {
+    Some(BackupSchedule {
+        interval_hours: rule_manager.get_setting(INTERVAL_KEY)?.parse().ok()?,
+        retention: rule_manager.get_setting(RETENTION_KEY)?.parse().ok()?,
+    })
+}
+
+/// Stores the backup schedule.
+///
+/// # Returns
+/// * `rules::Result<()>` - A `Result` indicating success or a `RuleManagerError`.
+pub fn set_backup_schedule(rule_manager: &RuleManager, schedule: BackupSchedule) -> rules::Result<()> {
+    rule_manager.set_setting(INTERVAL_KEY, &schedule.interval_hours.to_string())?;
+    rule_manager.set_setting(RETENTION_KEY, &schedule.retention.to_string())
+}
+
+/// Extracts the timestamp from a snapshot file name, if it is one.
+fn snapshot_timestamp(name: &str) -> Option<u64> {
+    name.strip_prefix("rules-")?.strip_suffix(".db")?.parse().ok()
+}
+
+/// Lists the snapshots in a backup directory, oldest first.
+///
+/// # Arguments
+/// * `dir` - The backup directory.
+///
+/// # Returns
+/// * `Vec<String>` - The snapshot file names; empty if the directory does not exist.
+pub fn list_backups(dir: &Path) -> Vec<String> {
+    let mut snapshots: Vec<(u64, String)> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter_map(|name| snapshot_timestamp(&name).map(|ts| (ts, name)))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    snapshots.sort();
+    snapshots.into_iter().map(|(_, name)| name).collect()
+}
+
+/// Takes a snapshot and prunes the oldest ones beyond the retention count.
+///
+/// # Arguments
+/// * `rule_manager` - The `RuleManager` whose database is backed up.
+/// * `dir` - The backup directory, created if missing.
+/// * `now_unix` - The current time, used to name the snapshot.
+/// * `retention` - The number of snapshots to keep.
+///
+/// # Returns
+/// * `Result<String, String>` - The new snapshot's file name, or an error message.
+pub fn rotate_backups(rule_manager: &RuleManager, dir: &Path, now_unix: u64, retention: usize) -> Result<String, String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let name = format!("rules-{}.db", now_unix);
+    let path = dir.join(&name);
+    rule_manager
+        .backup_into(&path.to_string_lossy())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    let snapshots = list_backups(dir);
+    let excess = snapshots.len().saturating_sub(retention);
+    for old in &snapshots[..excess] {
+        if let Err(e) = std::fs::remove_file(dir.join(old)) {
+            log::warn!("Failed to prune backup {}: {}", old, e);
+        }
+    }
+    Ok(name)
+}
+
+/// Restores a snapshot from the backup directory.
+///
+/// Only names returned by `list_backups` are accepted, so arbitrary paths cannot
+/// be restored.
+///
+/// # Arguments
+/// * `rule_manager` - The `RuleManager` whose database is replaced.
+/// * `dir` - The backup directory.
+/// * `name` - The snapshot file name.
+///
+/// # Returns
+/// * `Result<(), String>` - Success, or an error message.
+pub fn restore_backup(rule_manager: &RuleManager, dir: &Path, name: &str) -> Result<(), String> {
+    if !list_backups(dir).iter().any(|snapshot| snapshot == name) {
+        return Err(format!("Unknown backup '{}'.", name));
+    }
+    rule_manager
+        .restore_from(&dir.join(name).to_string_lossy())
+        .map_err(|e| format!("Failed to restore {}: {}", name, e))
+}
+
+/// Spawns a background task that takes a snapshot whenever the newest one is
+/// older than the configured interval. Checks once a minute; does nothing until a
+/// schedule is configured.
+///
+/// # Arguments
+/// * `rule_manager` - The shared `RuleManager`.
+/// * `dir` - The backup directory.
+///
+/// # Returns
+/// * `JoinHandle<()>` - The handle of the spawned task.
+pub fn spawn_backup_task(rule_manager: Arc<RuleManager>, dir: String) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let dir = Path::new(&dir);
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let schedule = match get_backup_schedule(&rule_manager) {
+                Some(schedule) => schedule,
+                None => continue,
+            };
+            let now = crate::rules::now_unix() as u64;
+            let newest = list_backups(dir).last().and_then(|name| snapshot_timestamp(name));
+            if newest.is_none_or(|ts| now.saturating_sub(ts) >= schedule.interval_hours * 3600) {
+                match rotate_backups(&rule_manager, dir, now, schedule.retention) {
+                    Ok(name) => log::info!("Created backup {}", name),
+                    Err(e) => log::error!("{}", e),
+                }
+            }
+        }
+    })
+}
+
+/// Unit tests for the `backup` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_parse_backup_schedule() {
+        assert_eq!(
+            parse_backup_schedule("24 7"),
+            Ok(BackupSchedule { interval_hours: 24, retention: 7 })
+        );
+        assert!(parse_backup_schedule("0 7").is_err());
+        assert!(parse_backup_schedule("24").is_err());
+        assert!(parse_backup_schedule("daily 7").is_err());
+    }
+
+    #[test]
+    fn test_rotation_keeps_only_configured_number_of_snapshots() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let manager = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        for ts in 1..=5u64 {
+            rotate_backups(&manager, dir.path(), ts * 1000, 3).unwrap();
+        }
+        assert_eq!(
+            list_backups(dir.path()),
+            vec!["rules-3000.db", "rules-4000.db", "rules-5000.db"]
+        );
+    }
+
+    #[test]
+    fn test_restore_rejects_unknown_snapshots() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let manager = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        rotate_backups(&manager, dir.path(), 1000, 3).unwrap();
+
+        assert!(restore_backup(&manager, dir.path(), "../rules.db").is_err());
+        assert!(restore_backup(&manager, dir.path(), "rules-1000.db").is_ok());
+    }
+}
@@ -0,0 +1,228 @@
+//! A module for the naive Bayes spam classifier.
+//!
+//! Messages are reduced to their set of words. For each word, the model counts how
+//! many spam messages (reported by admins) and ham messages (that passed the checks)
+//! contained it, and combines the counts of a message's known words, with Laplace
+//! smoothing, into the probability that the message is spam. Unlike keyword rules,
+//! the model picks up words nobody thought to add and adapts as admins report spam.
+//!
+//! The counts live in the `bayes_tokens` and `bayes_totals` tables; `RuleManager`
+//! keeps them in memory and writes new training in batches.
+
+use rusqlite::{Connection, Result};
+use std::collections::HashMap;
+
+/// Spam and ham messages the model needs before it scores anything but neutral.
+pub const MIN_TRAINING_MESSAGES: u64 = 10;
+
+/// Shortest word, in characters, the model learns.
+const MIN_TOKEN_LEN: usize = 3;
+
+/// Longest word, in characters, the model learns.
+const MAX_TOKEN_LEN: usize = 32;
+
+/// Splits a message into the distinct lowercase words the model learns.
+///
+/// # Arguments
+/// * `text` - The message text.
+///
+/// # Returns
+/// * `Vec<String>` - The words, in order of first appearance.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    for word in text.split(|c: char| !c.is_alphanumeric()) {
+        let word = word.to_lowercase();
+        if (MIN_TOKEN_LEN..=MAX_TOKEN_LEN).contains(&word.chars().count()) && !tokens.contains(&word) {
+            tokens.push(word);
+        }
+    }
+    tokens
+}
+
+/// How many spam and ham messages contained a word.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TokenCounts {
+    /// Spam messages containing the word.
+    pub spam: u64,
+    /// Ham messages containing the word.
+    pub ham: u64,
+}
+
+/// Word counts learned from spam and ham messages.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BayesModel {
+    /// The counts of every word seen in training.
+    pub tokens: HashMap<String, TokenCounts>,
+    /// Spam messages trained on.
+    pub spam_messages: u64,
+    /// Ham messages trained on.
+    pub ham_messages: u64,
+}
+
+impl BayesModel {
+    /// Returns `true` if the model was not trained on any message.
+    pub fn is_empty(&self) -> bool {
+        self.spam_messages == 0 && self.ham_messages == 0
+    }
+
+    /// Learns the words of a message.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    /// * `is_spam` - Whether the message is spam.
+    pub fn train(&mut self, text: &str, is_spam: bool) {
+        for token in tokenize(text) {
+            let counts = self.tokens.entry(token).or_default();
+            if is_spam {
+                counts.spam += 1;
+            } else {
+                counts.ham += 1;
+            }
+        }
+        if is_spam {
+            self.spam_messages += 1;
+        } else {
+            self.ham_messages += 1;
+        }
+    }
+
+    /// Adds the counts of another model to this one.
+    pub fn merge(&mut self, other: &BayesModel) {
+        for (token, counts) in &other.tokens {
+            let merged = self.tokens.entry(token.clone()).or_default();
+            merged.spam += counts.spam;
+            merged.ham += counts.ham;
+        }
+        self.spam_messages += other.spam_messages;
+        self.ham_messages += other.ham_messages;
+    }
+
+    /// Estimates the probability that a message is spam.
+    ///
+    /// Spam and ham are weighted equally regardless of how many of each were seen, since
+    /// clean messages vastly outnumber reported spam. Words the model never saw are ignored.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    ///
+    /// # Returns
+    /// * `f32` - The probability, between 0 and 1; 0.5 until the model has seen
+    ///   `MIN_TRAINING_MESSAGES` messages of each kind.
+    pub fn spam_probability(&self, text: &str) -> f32 {
+        if self.spam_messages < MIN_TRAINING_MESSAGES || self.ham_messages < MIN_TRAINING_MESSAGES {
+            return 0.5;
+        }
+        let (spam_messages, ham_messages) = (self.spam_messages as f64, self.ham_messages as f64);
+        let log_odds: f64 = tokenize(text)
+            .iter()
+            .filter_map(|token| self.tokens.get(token))
+            .map(|counts| {
+                let p_spam = (counts.spam as f64 + 1.0) / (spam_messages + 2.0);
+                let p_ham = (counts.ham as f64 + 1.0) / (ham_messages + 2.0);
+                (p_spam / p_ham).ln()
+            })
+            .sum();
+        (1.0 / (1.0 + (-log_odds).exp())) as f32
+    }
+}
+
+/// Loads the model from the database.
+///
+/// # Arguments
+/// * `conn` - The database connection.
+///
+/// # Returns
+/// * `Result<BayesModel>` - The stored model, or a `rusqlite::Error` if a query fails.
+pub fn load_model(conn: &Connection) -> Result<BayesModel> {
+    let (spam_messages, ham_messages): (i64, i64) = conn.query_row(
+        "SELECT COALESCE(SUM(spam_messages), 0), COALESCE(SUM(ham_messages), 0) FROM bayes_totals",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let mut stmt = conn.prepare("SELECT token, spam_count, ham_count FROM bayes_tokens")?;
+    let tokens = stmt
+        .query_map([], |row| {
+            let (spam, ham): (i64, i64) = (row.get(1)?, row.get(2)?);
+            Ok((row.get(0)?, TokenCounts { spam: spam as u64, ham: ham as u64 }))
+        })?
+        .collect::<Result<HashMap<String, TokenCounts>>>()?;
+    Ok(BayesModel {
+        tokens,
+        spam_messages: spam_messages as u64,
+        ham_messages: ham_messages as u64,
+    })
+}
+
+/// Adds the counts of a model to the stored ones.
+///
+/// Callers should run this inside a transaction, so a failure leaves no partial counts.
+///
+/// # Arguments
+/// * `conn` - The database connection.
+/// * `model` - The counts to add, e.g. the training since the last write.
+///
+/// # Returns
+/// * `Result<()>` - A `Result` indicating success or a `rusqlite::Error` if a write fails.
+pub fn add_counts(conn: &Connection, model: &BayesModel) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO bayes_tokens (token, spam_count, ham_count) VALUES (?1, ?2, ?3)
+             ON CONFLICT(token) DO UPDATE SET spam_count = spam_count + ?2, ham_count = ham_count + ?3",
+    )?;
+    for (token, counts) in &model.tokens {
+        stmt.execute(rusqlite::params![token, counts.spam as i64, counts.ham as i64])?;
+    }
+    conn.execute(
+        "INSERT INTO bayes_totals (id, spam_messages, ham_messages) VALUES (1, ?1, ?2)
+             ON CONFLICT(id) DO UPDATE SET spam_messages = spam_messages + ?1, ham_messages = ham_messages + ?2",
+        rusqlite::params![model.spam_messages as i64, model.ham_messages as i64],
+    )?;
+    Ok(())
+}
+
+/// Unit tests for the `bayes` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trains a model on `MIN_TRAINING_MESSAGES` messages of each kind.
+    fn trained_model() -> BayesModel {
+        let mut model = BayesModel::default();
+        for i in 0..MIN_TRAINING_MESSAGES {
+            model.train(&format!("Claim your free crypto bonus now {}", i), true);
+            model.train(&format!("Meeting notes for the project are attached {}", i), false);
+        }
+        model
+    }
+
+    #[test]
+    fn test_tokenize_keeps_distinct_lowercase_words() {
+        assert_eq!(tokenize("Free FREE money, go to x.com!"), ["free", "money", "com"]);
+    }
+
+    #[test]
+    fn test_spam_probability_follows_the_training() {
+        let model = trained_model();
+        assert!(model.spam_probability("free crypto bonus") > 0.9);
+        assert!(model.spam_probability("project meeting notes") < 0.1);
+        assert_eq!(model.spam_probability("unrelated words only"), 0.5);
+
+        // An undertrained model stays neutral.
+        let mut sparse = BayesModel::default();
+        sparse.train("free crypto", true);
+        assert_eq!(sparse.spam_probability("free crypto"), 0.5);
+    }
+
+    #[test]
+    fn test_counts_round_trip_through_the_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migrations::migrate(&mut conn).unwrap();
+        assert!(load_model(&conn).unwrap().is_empty());
+
+        let model = trained_model();
+        add_counts(&conn, &model).unwrap();
+        add_counts(&conn, &model).unwrap();
+        let mut doubled = model.clone();
+        doubled.merge(&model);
+        assert_eq!(load_model(&conn).unwrap(), doubled);
+    }
+}
@@ -0,0 +1,255 @@
+//! A module for sharing confirmed spammers with an external blocklist service.
+//!
+//! The integration is optional and speaks a small JSON-over-HTTP protocol:
+//! * `POST {url}/report` with `{"user_id", "chat_id", "evidence"}` reports a confirmed spammer.
+//! * `GET {url}/check/{user_id}` answers `{"listed": true|false}`; a 404 means "not listed".
+//!
+//! Every call has a short timeout and failures are logged and treated as "not listed" /
+//! "not reported", so an unavailable service never blocks or breaks message handling.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Configuration for the blocklist integration, read from the environment.
+#[derive(Debug, Clone)]
+pub struct BlocklistConfig {
+    /// Base URL of the blocklist service, without a trailing slash.
+    pub url: String,
+    /// Optional bearer token sent with every request.
+    pub token: Option<String>,
+    /// Whether new chat members are looked up and pre-banned when listed.
+    pub preban: bool,
+    /// Timeout applied to every request.
+    pub timeout: Duration,
+}
+
+impl BlocklistConfig {
+    /// Builds the configuration from environment variables.
+    ///
+    /// * `BLOCKLIST_URL` - Required; the integration is disabled when it is unset or empty.
+    /// * `BLOCKLIST_TOKEN` - Optional bearer token.
+    /// * `BLOCKLIST_PREBAN` - Set to `true` to look up and ban listed users on join (default `false`).
+    /// * `BLOCKLIST_TIMEOUT_SECS` - Optional request timeout, defaults to 5.
+    ///
+    /// # Returns
+    /// * `Option<Self>` - The configuration, or `None` if the integration is disabled.
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("BLOCKLIST_URL").ok().filter(|u| !u.is_empty())?;
+        Some(Self {
+            url: url.trim_end_matches('/').to_string(),
+            token: std::env::var("BLOCKLIST_TOKEN").ok().filter(|t| !t.is_empty()),
+            preban: std::env::var("BLOCKLIST_PREBAN")
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            timeout: Duration::from_secs(
+                std::env::var("BLOCKLIST_TIMEOUT_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(5),
+            ),
+        })
+    }
+}
+
+/// The body sent when reporting a confirmed spammer.
+#[derive(Debug, Serialize)]
+struct SpamReport<'a> {
+    user_id: &'a str,
+    chat_id: i64,
+    evidence: &'a str,
+}
+
+/// The body returned by a lookup.
+#[derive(Debug, Deserialize)]
+struct LookupResponse {
+    listed: bool,
+}
+
+/// HTTP client for the blocklist service.
+pub struct BlocklistClient {
+    /// The integration configuration.
+    config: BlocklistConfig,
+    /// The underlying HTTP client, with the configured timeout.
+    http: reqwest::Client,
+}
+
+impl BlocklistClient {
+    /// Creates a client for the configured service.
+    pub fn new(config: BlocklistConfig) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .unwrap_or_else(|_| reqwest::Client::new());
+        Self { config, http }
+    }
+
+    /// Returns `true` if new members should be looked up and pre-banned.
+    pub fn preban_enabled(&self) -> bool {
+        self.config.preban
+    }
+
+    /// Adds the bearer token, if any, to a request.
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.config.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Reports a confirmed spammer to the service.
+    ///
+    /// # Arguments
+    /// * `user_id` - The ID of the spammer.
+    /// * `chat_id` - The chat the spam was posted in.
+    /// * `evidence` - The spam message text.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the service accepted the report; failures are logged.
+    pub async fn report(&self, user_id: &str, chat_id: i64, evidence: &str) -> bool {
+        let request = self
+            .http
+            .post(format!("{}/report", self.config.url))
+            .json(&SpamReport {
+                user_id,
+                chat_id,
+                evidence,
+            });
+        match self.authorize(request).send().await {
+            Ok(response) if response.status().is_success() => true,
+            Ok(response) => {
+                log::warn!("Blocklist rejected report for user {}: {}", user_id, response.status());
+                false
+            }
+            Err(e) => {
+                log::warn!("Failed to report user {} to blocklist: {}", user_id, e);
+                false
+            }
+        }
+    }
+
+    /// Looks up whether a user is listed as a known spammer.
+    ///
+    /// # Arguments
+    /// * `user_id` - The ID of the user to look up.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the service lists the user; `false` if not listed or
+    ///   if the service is unavailable.
+    pub async fn is_listed(&self, user_id: &str) -> bool {
+        let request = self.http.get(format!("{}/check/{}", self.config.url, user_id));
+        match self.authorize(request).send().await {
+            Ok(response) if response.status() == reqwest::StatusCode::NOT_FOUND => false,
+            Ok(response) if response.status().is_success() => match response.json::<LookupResponse>().await {
+                Ok(body) => body.listed,
+                Err(e) => {
+                    log::warn!("Invalid blocklist response for user {}: {}", user_id, e);
+                    false
+                }
+            },
+            Ok(response) => {
+                log::warn!("Blocklist lookup for user {} failed: {}", user_id, response.status());
+                false
+            }
+            Err(e) => {
+                log::warn!("Failed to look up user {} in blocklist: {}", user_id, e);
+                false
+            }
+        }
+    }
+}
+
+/// Unit tests for the `blocklist` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Starts a mock HTTP server answering a single request with `response`.
+    ///
+    /// Returns the server's base URL and a handle resolving to the raw request received.
+    async fn serve_once(response: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(header_end) = text.find("\r\n\r\n") {
+                    let content_length = text[..header_end]
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            if name.eq_ignore_ascii_case("content-length") {
+                                value.trim().parse::<usize>().ok()
+                            } else {
+                                None
+                            }
+                        })
+                        .unwrap_or(0);
+                    if request.len() >= header_end + 4 + content_length {
+                        break;
+                    }
+                }
+                if n == 0 {
+                    break;
+                }
+            }
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        (url, handle)
+    }
+
+    fn client(url: String) -> BlocklistClient {
+        BlocklistClient::new(BlocklistConfig {
+            url,
+            token: Some("secret".to_string()),
+            preban: true,
+            timeout: Duration::from_secs(2),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_report_posts_user_and_evidence() {
+        let (url, server) =
+            serve_once("HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await;
+        assert!(client(url).report("42", -100, "buy cheap followers").await);
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /report "));
+        assert!(request.to_lowercase().contains("authorization: bearer secret"));
+        assert!(request.contains(r#""user_id":"42""#));
+        assert!(request.contains(r#""evidence":"buy cheap followers""#));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_listed_user() {
+        let (url, server) = serve_once(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 15\r\nconnection: close\r\n\r\n{\"listed\":true}",
+        )
+        .await;
+        assert!(client(url).is_listed("42").await);
+        assert!(server.await.unwrap().starts_with("GET /check/42 "));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_unknown_user() {
+        let (url, _server) =
+            serve_once("HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await;
+        assert!(!client(url).is_listed("7").await);
+    }
+
+    #[tokio::test]
+    async fn test_service_down_is_tolerated() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let client = client(url);
+        assert!(!client.is_listed("42").await);
+        assert!(!client.report("42", -100, "spam").await);
+    }
+}
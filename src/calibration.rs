@@ -0,0 +1,115 @@
+//! A module for calibrating the spam threshold against labeled messages.
+//!
+//! Given the scores the rule engine assigns to a labeled corpus, this module computes
+//! precision, recall and F1 at a range of candidate thresholds and picks the threshold
+//! that maximizes F1. The functions are pure so they can be tested without a database.
+
+/// Classification quality of a single candidate threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThresholdMetrics {
+    /// The candidate threshold; a message is flagged when its score is >= this value.
+    pub threshold: f32,
+    /// Fraction of flagged messages that are labeled spam (0.0 when nothing is flagged).
+    pub precision: f32,
+    /// Fraction of spam messages that are flagged (0.0 when there is no labeled spam).
+    pub recall: f32,
+    /// Harmonic mean of precision and recall.
+    pub f1: f32,
+}
+
+/// Returns the default candidate thresholds, 1.0 through 20.0 in steps of 1.0.
+pub fn default_thresholds() -> Vec<f32> {
+    (1..=20).map(|t| t as f32).collect()
+}
+
+/// Computes precision, recall and F1 for each candidate threshold.
+///
+/// # Arguments
+/// * `samples` - Pairs of `(score, is_spam)` for every labeled message.
+/// * `thresholds` - The candidate thresholds to evaluate.
+///
+/// # Returns
+/// * `Vec<ThresholdMetrics>` - One entry per threshold, in the order given.
+pub fn evaluate_thresholds(samples: &[(f32, bool)], thresholds: &[f32]) -> Vec<ThresholdMetrics> {
+    thresholds
+        .iter()
+        .map(|&threshold| {
+            let mut true_positives = 0;
+            let mut false_positives = 0;
+            let mut false_negatives = 0;
+            for &(score, is_spam) in samples {
+                match (score >= threshold, is_spam) {
+                    (true, true) => true_positives += 1,
+                    (true, false) => false_positives += 1,
+                    (false, true) => false_negatives += 1,
+                    (false, false) => {}
+                }
+            }
+            let ratio = |num: i32, den: i32| if den == 0 { 0.0 } else { num as f32 / den as f32 };
+            let precision = ratio(true_positives, true_positives + false_positives);
+            let recall = ratio(true_positives, true_positives + false_negatives);
+            let f1 = if precision + recall == 0.0 {
+                0.0
+            } else {
+                2.0 * precision * recall / (precision + recall)
+            };
+            ThresholdMetrics {
+                threshold,
+                precision,
+                recall,
+                f1,
+            }
+        })
+        .collect()
+}
+
+/// Picks the threshold with the highest F1 score.
+///
+/// Ties are resolved in favour of the higher threshold, which flags fewer messages.
+///
+/// # Arguments
+/// * `results` - The per-threshold metrics from `evaluate_thresholds`.
+///
+/// # Returns
+/// * `Option<&ThresholdMetrics>` - The best entry, or `None` if `results` is empty.
+pub fn best_threshold(results: &[ThresholdMetrics]) -> Option<&ThresholdMetrics> {
+    results.iter().fold(None, |best: Option<&ThresholdMetrics>, current| match best {
+        Some(b) if b.f1 > current.f1 || (b.f1 == current.f1 && b.threshold > current.threshold) => Some(b),
+        _ => Some(current),
+    })
+}
+
+/// Unit tests for the `calibration` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate_thresholds_per_threshold_metrics() {
+        let samples = vec![(10.0, true), (6.0, true), (3.0, true), (5.0, false), (0.0, false)];
+        let results = evaluate_thresholds(&samples, &[1.0, 5.0, 7.0, 20.0]);
+
+        // >= 1.0 flags 10, 6, 3, 5: 3 TP, 1 FP, 0 FN
+        assert_eq!(results[0].precision, 0.75);
+        assert_eq!(results[0].recall, 1.0);
+        // >= 5.0 flags 10, 6, 5: 2 TP, 1 FP, 1 FN
+        assert!((results[1].precision - 2.0 / 3.0).abs() < 1e-6);
+        assert!((results[1].recall - 2.0 / 3.0).abs() < 1e-6);
+        assert!((results[1].f1 - 2.0 / 3.0).abs() < 1e-6);
+        // >= 7.0 flags 10 only: 1 TP, 0 FP, 2 FN
+        assert_eq!(results[2].precision, 1.0);
+        assert!((results[2].recall - 1.0 / 3.0).abs() < 1e-6);
+        // >= 20.0 flags nothing
+        assert_eq!(results[3].precision, 0.0);
+        assert_eq!(results[3].recall, 0.0);
+        assert_eq!(results[3].f1, 0.0);
+
+        let best = best_threshold(&results).unwrap();
+        assert_eq!(best.threshold, 1.0);
+    }
+
+    #[test]
+    fn test_best_threshold_empty() {
+        assert!(best_threshold(&[]).is_none());
+    }
+}
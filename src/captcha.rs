@@ -0,0 +1,91 @@
+//! A module for the CAPTCHA challenge shown to new members.
+//!
+//! When enabled, each new member is restricted and asked to press a button within a
+//! timeout. Pressing it lifts the restriction; otherwise the member is kicked. This
+//! module keeps track of the pending challenges and encodes the button's callback data,
+//! while the Telegram calls live in the handlers in `main`.
+
+use crate::sync::LockExt;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Prefix of the callback data of CAPTCHA buttons.
+pub const CAPTCHA_PREFIX: &str = "captcha:";
+
+/// Encodes the callback data of the button a new member must press.
+///
+/// # Arguments
+/// * `chat_id` - The chat the member joined.
+/// * `user_id` - The new member.
+pub fn encode_captcha(chat_id: i64, user_id: u64) -> String {
+    format!("{}{}:{}", CAPTCHA_PREFIX, chat_id, user_id)
+}
+
+/// Parses callback data produced by `encode_captcha`.
+///
+/// # Returns
+/// * `Option<(i64, u64)>` - The chat and member, or `None` if the data is not a CAPTCHA button.
+pub fn parse_captcha(data: &str) -> Option<(i64, u64)> {
+    let (chat_id, user_id) = data.strip_prefix(CAPTCHA_PREFIX)?.split_once(':')?;
+    Some((chat_id.parse().ok()?, user_id.parse().ok()?))
+}
+
+/// The challenges new members have not answered yet.
+#[derive(Default)]
+pub struct CaptchaChallenges {
+    /// The challenge message ID per `(chat_id, user_id)`.
+    pending: Mutex<HashMap<(i64, u64), i32>>,
+}
+
+impl CaptchaChallenges {
+    /// Creates an empty set of challenges.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a challenge sent to a new member.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the member joined.
+    /// * `user_id` - The new member.
+    /// * `message_id` - The message carrying the challenge button.
+    pub fn start(&self, chat_id: i64, user_id: u64, message_id: i32) {
+        self.pending.lock_or_recover().insert((chat_id, user_id), message_id);
+    }
+
+    /// Ends a challenge, either because it was solved or because it timed out.
+    ///
+    /// Whichever happens first wins, so a member is never both let in and kicked.
+    ///
+    /// # Returns
+    /// * `Option<i32>` - The challenge message ID if the challenge was still pending.
+    pub fn finish(&self, chat_id: i64, user_id: u64) -> Option<i32> {
+        self.pending.lock_or_recover().remove(&(chat_id, user_id))
+    }
+}
+
+/// Unit tests for the `captcha` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_captcha_data_round_trips() {
+        let data = encode_captcha(-1001234567890, 42);
+        assert_eq!(data, "captcha:-1001234567890:42");
+        assert_eq!(parse_captcha(&data), Some((-1001234567890, 42)));
+        assert_eq!(parse_captcha("spam:ban:-100:42"), None);
+        assert_eq!(parse_captcha("captcha:-100"), None);
+        assert_eq!(parse_captcha("captcha:-100:someone"), None);
+    }
+
+    #[test]
+    fn test_challenge_is_finished_only_once() {
+        let challenges = CaptchaChallenges::new();
+        challenges.start(-100, 42, 7);
+        assert_eq!(challenges.finish(-100, 41), None);
+        assert_eq!(challenges.finish(-100, 42), Some(7));
+        // The timeout firing after the member solved it does nothing.
+        assert_eq!(challenges.finish(-100, 42), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! A module for periodic maintenance of the `senders` table.
+//!
+//! A background task folds score decay into the stored scores and deletes senders
+//! with little history who have not been flagged for a long time, so the table does
+//! not grow with every user the bot has ever seen. It also deletes old score events,
+//! which are written on every spam hit.
+
+use crate::config::env_parse;
+use crate::rules::{self, now_unix, RuleManager};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often and how aggressively the `senders` table is cleaned up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SenderCleanup {
+    /// Time between cleanup runs.
+    pub interval: Duration,
+    /// Senders untouched for longer than this many days may be deleted.
+    pub retention_days: i64,
+    /// Only senders with fewer messages than this are deleted.
+    pub max_messages: i64,
+    /// Score events older than this many days are deleted; 0 keeps them forever.
+    pub history_days: i64,
+}
+
+impl Default for SenderCleanup {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(3600),
+            retention_days: 90,
+            max_messages: 10,
+            history_days: 180,
+        }
+    }
+}
+
+impl SenderCleanup {
+    /// Builds the cleanup settings from environment variables.
+    ///
+    /// * `SENDER_CLEANUP_INTERVAL_SECS` - Seconds between cleanup runs (default 3600).
+    /// * `SENDER_RETENTION_DAYS` - Days after which idle senders may be deleted (default 90).
+    /// * `SENDER_CLEANUP_MAX_MESSAGES` - Senders with fewer messages may be deleted (default 10).
+    /// * `SCORE_HISTORY_RETENTION_DAYS` - Days score events are kept for `/history` (default 180; 0 keeps them forever).
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            interval: Duration::from_secs(env_parse("SENDER_CLEANUP_INTERVAL_SECS", defaults.interval.as_secs())),
+            retention_days: env_parse("SENDER_RETENTION_DAYS", defaults.retention_days),
+            max_messages: env_parse("SENDER_CLEANUP_MAX_MESSAGES", defaults.max_messages),
+            history_days: env_parse("SCORE_HISTORY_RETENTION_DAYS", defaults.history_days),
+        }
+    }
+
+    /// Runs one cleanup pass: decays stored scores, deletes stale senders, then deletes
+    /// old score events.
+    ///
+    /// Decay runs first so senders whose score has just reached 0 can be deleted in
+    /// the same pass.
+    ///
+    /// # Arguments
+    /// * `rule_manager` - The manager owning the database.
+    /// * `now` - The current time in seconds since the Unix epoch.
+    ///
+    /// # Returns
+    /// * `rules::Result<(usize, usize, usize)>` - The number of scores decayed, senders
+    ///   deleted and score events deleted.
+    pub fn run(&self, rule_manager: &RuleManager, now: i64) -> rules::Result<(usize, usize, usize)> {
+        let decayed = rule_manager.decay_sender_scores(now)?;
+        let deleted = rule_manager.delete_stale_senders(self.max_messages, now - self.retention_days * 86_400)?;
+        let events = if self.history_days > 0 {
+            rule_manager.delete_score_events_before(now - self.history_days * 86_400)?
+        } else {
+            0
+        };
+        Ok((decayed, deleted, events))
+    }
+}
+
+/// Spawns a background task that runs a cleanup pass every `cleanup.interval`.
+///
+/// # Arguments
+/// * `rule_manager` - The manager owning the database.
+/// * `cleanup` - The cleanup settings.
+pub fn spawn_sender_cleanup(rule_manager: Arc<RuleManager>, cleanup: SenderCleanup) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(cleanup.interval);
+        loop {
+            interval.tick().await;
+            match cleanup.run(&rule_manager, now_unix()) {
+                Ok((decayed, deleted, events)) => log::info!(
+                    "Sender cleanup decayed {} scores and deleted {} senders and {} score events",
+                    decayed, deleted, events
+                ),
+                Err(e) => log::error!("Sender cleanup failed: {}", e),
+            }
+        }
+    })
+}
+
+/// Unit tests for the `cleanup` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_run_deletes_senders_once_their_score_decays() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let manager = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        manager.increment_sender_score(-100, "visitor", true).unwrap();
+        manager.increment_sender_score(-100, "member", false).unwrap();
+        let cleanup = SenderCleanup::default();
+
+        // Nothing is old enough yet.
+        assert_eq!(cleanup.run(&manager, now_unix()).unwrap(), (0, 0, 0));
+        // A year later the visitor's single spam point has decayed and both are idle.
+        assert_eq!(cleanup.run(&manager, now_unix() + 365 * 86_400).unwrap(), (1, 2, 1));
+        assert_eq!(manager.get_sender_stats(-100, "visitor"), None);
+        assert!(manager.score_history(-100, "visitor", 0).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,327 @@
+//! A module for bot-wide settings read from the environment.
+//!
+//! Settings that change how the message handlers behave, rather than how a single
+//! detector scores text, live in `Config` so they are loaded once at startup and
+//! shared with the handlers.
+
+use crate::lang::{Key, Lang};
+use crate::utils::SpamAction;
+use std::time::Duration;
+
+/// Reads a boolean environment variable, accepting `true`/`false` (case-insensitive).
+///
+/// # Arguments
+/// * `key` - The environment variable name.
+/// * `default` - The value used when the variable is unset or invalid.
+pub fn env_flag(key: &str, default: bool) -> bool {
+    match std::env::var(key) {
+        Ok(value) if value.eq_ignore_ascii_case("true") => true,
+        Ok(value) if value.eq_ignore_ascii_case("false") => false,
+        _ => default,
+    }
+}
+
+/// Reads and parses an environment variable.
+///
+/// # Arguments
+/// * `key` - The environment variable name.
+/// * `default` - The value used when the variable is unset or cannot be parsed.
+pub fn env_parse<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Bot-wide handler settings.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Whether posts automatically forwarded from a group's linked channel are
+    /// exempt from scoring and punishment.
+    pub exempt_linked_channel: bool,
+    /// Number of days of spam events covered by audit commands such as `/detectorstats`.
+    pub audit_window_days: i64,
+    /// Minimum number of human admins a chat needs before the bot bans or mutes
+    /// automatically; below it, destructive actions fall back to notifications.
+    pub min_human_admins: usize,
+    /// Path of the SQLite database; `:memory:` keeps it in memory for the life of the process.
+    pub database_path: String,
+    /// Directory rotating database snapshots are written to.
+    pub backup_dir: String,
+    /// Spam score at which a sender is banned automatically; 0 disables auto-bans.
+    pub auto_ban_score: i32,
+    /// Whether messages detected as spam are deleted from the chat.
+    pub delete_spam: bool,
+    /// Clean messages a sender needs before their messages are scored normally.
+    pub newcomer_clean_messages: i64,
+    /// Multiplier applied to the scores of senders with fewer clean messages.
+    pub newcomer_multiplier: f32,
+    /// Whether new members are restricted until they solve a CAPTCHA.
+    pub captcha: bool,
+    /// How long new members have to solve the CAPTCHA before they are kicked.
+    pub captcha_timeout: Duration,
+    /// Warnings at which a sender is muted; 0 never mutes.
+    pub warn_mute_at: i32,
+    /// Warnings at which a sender is banned; 0 never bans.
+    pub warn_ban_at: i32,
+    /// Whether spam is only logged: nothing is deleted, posted, warned or banned,
+    /// and sender scores are left alone, so thresholds can be tuned on live traffic.
+    pub shadow_mode: bool,
+    /// Whether the Bayes classifier scores messages and learns from reports and clean messages.
+    pub bayes_filter: bool,
+    /// Score the Bayes classifier adds for a certain spam verdict, and subtracts for a certain ham one.
+    pub bayes_weight: f32,
+    /// Whether the bot refuses to start when the startup self-check of the Lua script
+    /// or the database fails, instead of only logging the failure.
+    pub strict_startup: bool,
+    /// Text posted in the chat when spam is detected: `None` for the chat language's
+    /// default notice, an empty string to act silently.
+    pub spam_reply: Option<String>,
+    /// Whether a spam message adds points to its sender's score in proportion to how
+    /// far it exceeded the threshold, instead of a single point.
+    pub score_by_severity: bool,
+    /// User ID of the bot's operator, the only user allowed to run commands that change
+    /// every chat at once, such as restoring backups; `None` disables those commands.
+    pub operator_id: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            exempt_linked_channel: true,
+            audit_window_days: 7,
+            min_human_admins: 1,
+            database_path: "rules.db".to_string(),
+            backup_dir: "backups".to_string(),
+            auto_ban_score: 0,
+            delete_spam: false,
+            newcomer_clean_messages: 5,
+            newcomer_multiplier: 1.5,
+            captcha: false,
+            captcha_timeout: Duration::from_secs(120),
+            warn_mute_at: 3,
+            warn_ban_at: 5,
+            shadow_mode: false,
+            bayes_filter: false,
+            bayes_weight: 5.0,
+            strict_startup: false,
+            spam_reply: None,
+            score_by_severity: false,
+            operator_id: None,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from environment variables.
+    ///
+    /// * `EXEMPT_LINKED_CHANNEL` - Exempt linked-channel auto-forwards (default `true`).
+    /// * `AUDIT_WINDOW_DAYS` - Days of spam events covered by audit commands (default 7).
+    /// * `MIN_HUMAN_ADMINS` - Human admins required for automatic bans/mutes (default 1).
+    /// * `DATABASE_PATH` - Path of the SQLite database (default `rules.db`; `:memory:` for an ephemeral one).
+    /// * `BACKUP_DIR` - Directory for database snapshots (default `backups`).
+    /// * `AUTO_BAN_SCORE` - Spam score at which senders are banned automatically (default 0, disabled).
+    /// * `DELETE_SPAM` - Delete messages detected as spam (default `false`).
+    /// * `NEWCOMER_CLEAN_MESSAGES` - Clean messages needed to leave probation (default 5; 0 disables it).
+    /// * `NEWCOMER_SCORE_MULTIPLIER` - Score multiplier during probation (default 1.5).
+    /// * `CAPTCHA` - Restrict new members until they press a button (default `false`).
+    /// * `CAPTCHA_TIMEOUT_SECS` - Seconds new members have to press it before being kicked (default 120).
+    /// * `WARN_MUTE_AT` - Warnings at which senders are muted (default 3; 0 disables it).
+    /// * `WARN_BAN_AT` - Warnings at which senders are banned (default 5; 0 disables it).
+    /// * `SHADOW_MODE` - Log spam decisions without acting on them (default `false`).
+    /// * `BAYES_FILTER` - Blend the Bayes classifier into message scores (default `false`).
+    /// * `BAYES_WEIGHT` - Score the classifier adds at most, or subtracts for clean messages (default 5.0).
+    /// * `STRICT_STARTUP` - Refuse to start if the Lua script or the database fails the self-check (default `false`).
+    /// * `SPAM_REPLY_TEXT` - Text posted when spam is detected (default: the chat language's notice; empty posts nothing).
+    /// * `SCORE_BY_SEVERITY` - Weigh spam points added to sender scores by message score (default `false`).
+    /// * `BOT_OPERATOR_ID` - Telegram user ID allowed to run bot-wide commands (default: unset, disabling them).
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            exempt_linked_channel: env_flag("EXEMPT_LINKED_CHANNEL", defaults.exempt_linked_channel),
+            audit_window_days: env_parse("AUDIT_WINDOW_DAYS", defaults.audit_window_days),
+            min_human_admins: env_parse("MIN_HUMAN_ADMINS", defaults.min_human_admins),
+            database_path: std::env::var("DATABASE_PATH").unwrap_or(defaults.database_path),
+            backup_dir: std::env::var("BACKUP_DIR").unwrap_or(defaults.backup_dir),
+            auto_ban_score: env_parse("AUTO_BAN_SCORE", defaults.auto_ban_score),
+            delete_spam: env_flag("DELETE_SPAM", defaults.delete_spam),
+            newcomer_clean_messages: env_parse("NEWCOMER_CLEAN_MESSAGES", defaults.newcomer_clean_messages),
+            newcomer_multiplier: env_parse("NEWCOMER_SCORE_MULTIPLIER", defaults.newcomer_multiplier),
+            captcha: env_flag("CAPTCHA", defaults.captcha),
+            captcha_timeout: Duration::from_secs(env_parse("CAPTCHA_TIMEOUT_SECS", defaults.captcha_timeout.as_secs())),
+            warn_mute_at: env_parse("WARN_MUTE_AT", defaults.warn_mute_at),
+            warn_ban_at: env_parse("WARN_BAN_AT", defaults.warn_ban_at),
+            shadow_mode: env_flag("SHADOW_MODE", defaults.shadow_mode),
+            bayes_filter: env_flag("BAYES_FILTER", defaults.bayes_filter),
+            bayes_weight: env_parse("BAYES_WEIGHT", defaults.bayes_weight),
+            strict_startup: env_flag("STRICT_STARTUP", defaults.strict_startup),
+            spam_reply: std::env::var("SPAM_REPLY_TEXT").ok().or(defaults.spam_reply),
+            score_by_severity: env_flag("SCORE_BY_SEVERITY", defaults.score_by_severity),
+            operator_id: std::env::var("BOT_OPERATOR_ID").ok().and_then(|id| id.trim().parse().ok()).or(defaults.operator_id),
+        }
+    }
+
+    /// Returns `true` if the user is the bot's operator.
+    ///
+    /// Chat admins only control their own chat, and everyone is an admin of their
+    /// private chat with the bot, so commands that change every chat at once check
+    /// this instead of admin status.
+    pub fn is_operator(&self, user_id: u64) -> bool {
+        self.operator_id == Some(user_id)
+    }
+
+    /// Returns `true` if a sender with the given spam score should be banned automatically.
+    pub fn should_auto_ban(&self, spam_score: i32) -> bool {
+        self.auto_ban_score > 0 && spam_score >= self.auto_ban_score
+    }
+
+    /// Returns the spam points a spam message adds to its sender's score.
+    ///
+    /// Every spam message is worth one point unless `score_by_severity` is set, in
+    /// which case it is worth its score divided by the threshold, rounded down: a
+    /// message scoring 50 against a threshold of 5 adds 10 points, one scoring 6 adds 1.
+    ///
+    /// # Arguments
+    /// * `score` - The message's spam score.
+    /// * `threshold` - The threshold the score was compared against.
+    pub fn spam_points(&self, score: f32, threshold: f32) -> i32 {
+        if !self.score_by_severity || threshold <= 0.0 {
+            return 1;
+        }
+        ((score / threshold).floor() as i32).max(1)
+    }
+
+    /// Returns the action a sender with the given number of warnings has earned.
+    ///
+    /// Below `warn_mute_at` the warning itself is the only consequence, reported as
+    /// `SpamAction::Notify`.
+    pub fn warning_action(&self, warnings: i32) -> SpamAction {
+        if self.warn_ban_at > 0 && warnings >= self.warn_ban_at {
+            SpamAction::Ban
+        } else if self.warn_mute_at > 0 && warnings >= self.warn_mute_at {
+            SpamAction::Mute
+        } else {
+            SpamAction::Notify
+        }
+    }
+
+    /// Converts the Bayes classifier's spam probability into a score contribution.
+    ///
+    /// A neutral 0.5 contributes nothing; certain spam adds `bayes_weight` and certain
+    /// ham subtracts it, so the classifier can also vouch for messages the rules overrate.
+    pub fn bayes_contribution(&self, probability: f32) -> f32 {
+        if !self.bayes_filter {
+            return 0.0;
+        }
+        self.bayes_weight * (2.0 * probability - 1.0)
+    }
+
+    /// Returns the notice posted in a chat when spam is detected there.
+    ///
+    /// # Arguments
+    /// * `lang` - The chat's language, used for the default notice.
+    /// * `deleted` - Whether the spam was deleted.
+    ///
+    /// # Returns
+    /// * `Option<String>` - The notice, or `None` if the bot acts silently.
+    pub fn spam_notice(&self, lang: Lang, deleted: bool) -> Option<String> {
+        match self.spam_reply.as_deref() {
+            None => Some(lang.text(if deleted { Key::SpamDeleted } else { Key::SpamDetected }).to_string()),
+            Some(text) if text.trim().is_empty() => None,
+            Some(text) => Some(text.to_string()),
+        }
+    }
+
+    /// Returns the multiplier for the score of a sender with the given number of clean messages.
+    ///
+    /// New accounts are the main source of spam, so senders who have not yet sent
+    /// `newcomer_clean_messages` clean messages are on probation and scored more strictly.
+    pub fn newcomer_multiplier(&self, clean_messages: i64) -> f32 {
+        if clean_messages < self.newcomer_clean_messages {
+            self.newcomer_multiplier
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Unit tests for the `config` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_ban_disabled_by_default() {
+        let mut config = Config::default();
+        assert!(!config.should_auto_ban(1000));
+        config.auto_ban_score = 5;
+        assert!(!config.should_auto_ban(4));
+        assert!(config.should_auto_ban(5));
+    }
+
+    #[test]
+    fn test_nobody_is_operator_unless_configured() {
+        let mut config = Config::default();
+        assert!(!config.is_operator(7));
+        config.operator_id = Some(7);
+        assert!(config.is_operator(7));
+        assert!(!config.is_operator(8));
+    }
+
+    #[test]
+    fn test_spam_points_follow_the_score_only_by_severity() {
+        let mut config = Config::default();
+        assert_eq!(config.spam_points(50.0, 5.0), 1);
+        config.score_by_severity = true;
+        assert_eq!(config.spam_points(50.0, 5.0), 10);
+        assert_eq!(config.spam_points(6.0, 5.0), 1);
+        // Rate-limited messages can be spam with a score below the threshold
+        assert_eq!(config.spam_points(0.0, 5.0), 1);
+        assert_eq!(config.spam_points(50.0, 0.0), 1);
+    }
+
+    #[test]
+    fn test_warnings_escalate_from_warn_to_mute_to_ban() {
+        let mut config = Config::default();
+        let actions: Vec<SpamAction> = (1..=6).map(|warnings| config.warning_action(warnings)).collect();
+        assert_eq!(
+            actions,
+            [SpamAction::Notify, SpamAction::Notify, SpamAction::Mute, SpamAction::Mute, SpamAction::Ban, SpamAction::Ban]
+        );
+        config.warn_ban_at = 0;
+        assert_eq!(config.warning_action(10), SpamAction::Mute);
+        config.warn_mute_at = 0;
+        assert_eq!(config.warning_action(10), SpamAction::Notify);
+    }
+
+    #[test]
+    fn test_spam_notice_is_configurable_or_silent() {
+        let mut config = Config::default();
+        assert_eq!(config.spam_notice(Lang::En, false).as_deref(), Some("Spam detected! Admins notified."));
+        assert_eq!(config.spam_notice(Lang::En, true).as_deref(), Some("Spam message deleted. Admins notified."));
+        config.spam_reply = Some("Removed.".to_string());
+        assert_eq!(config.spam_notice(Lang::Ru, true).as_deref(), Some("Removed."));
+        config.spam_reply = Some(String::new());
+        assert_eq!(config.spam_notice(Lang::En, true), None);
+    }
+
+    #[test]
+    fn test_newcomers_are_scored_more_strictly_until_enough_clean_messages() {
+        let mut config = Config::default();
+        assert_eq!(config.newcomer_multiplier(0), 1.5);
+        assert_eq!(config.newcomer_multiplier(4), 1.5);
+        assert_eq!(config.newcomer_multiplier(5), 1.0);
+        config.newcomer_clean_messages = 0;
+        assert_eq!(config.newcomer_multiplier(0), 1.0);
+    }
+
+    #[test]
+    fn test_bayes_contribution_is_centered_on_a_neutral_verdict() {
+        let mut config = Config::default();
+        assert_eq!(config.bayes_contribution(1.0), 0.0);
+        config.bayes_filter = true;
+        assert_eq!(config.bayes_contribution(0.5), 0.0);
+        assert_eq!(config.bayes_contribution(1.0), 5.0);
+        assert_eq!(config.bayes_contribution(0.0), -5.0);
+    }
+}
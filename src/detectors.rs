@@ -0,0 +1,142 @@
+//! A module bundling the built-in content detectors that run alongside the Lua rules.
+//!
+//! Each detector scores a message independently; `Detectors` holds their configuration
+//! and adds their scores on top of the custom rule score in the message handler.
+
+use crate::duplicates::DuplicateDetector;
+use crate::flood::{ChatLockdown, LocationFlood};
+use crate::gibberish::GibberishDetector;
+use crate::links::{LinkCount, LinkHeuristics, LinkRepetition, NewSenderLinks};
+use crate::media::NewSenderMedia;
+use crate::repost::RepostTracker;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The configured built-in detectors.
+#[derive(Debug, Clone, Default)]
+pub struct Detectors {
+    /// Keyboard-mashing detector (opt-in).
+    pub gibberish: GibberishDetector,
+    /// Structural link heuristics (shorteners, raw IPs, subdomains, punycode).
+    pub links: LinkHeuristics,
+    /// Generic per-link scoring, escalating for messages with many links.
+    pub link_count: LinkCount,
+    /// Bonus for links from senders with only a few messages.
+    pub new_sender_links: NewSenderLinks,
+    /// Score for stickers, GIFs and files from senders with only a few messages.
+    pub new_sender_media: NewSenderMedia,
+    /// Per-user repeated-link tracker, shared between clones.
+    pub link_repetition: Arc<LinkRepetition>,
+    /// Tracker of the bot's deletions, scoring reposts of deleted content.
+    pub reposts: Arc<RepostTracker>,
+    /// Rapid location flooding from new users.
+    pub location_flood: Arc<LocationFlood>,
+    /// Per-chat message rate, locking raided chats down.
+    pub lockdown: Arc<ChatLockdown>,
+    /// Messages repeated within a chat, shared between clones.
+    pub duplicates: Arc<DuplicateDetector>,
+}
+
+impl Detectors {
+    /// Builds every detector from its environment configuration.
+    pub fn from_env() -> Self {
+        Self {
+            gibberish: GibberishDetector::from_env(),
+            links: LinkHeuristics::from_env(),
+            link_count: LinkCount::from_env(),
+            new_sender_links: NewSenderLinks::from_env(),
+            new_sender_media: NewSenderMedia::from_env(),
+            link_repetition: Arc::new(LinkRepetition::from_env()),
+            reposts: Arc::new(RepostTracker::default()),
+            location_flood: Arc::new(LocationFlood::from_env()),
+            lockdown: Arc::new(ChatLockdown::from_env()),
+            duplicates: Arc::new(DuplicateDetector::from_env()),
+        }
+    }
+
+    /// Names of the built-in detectors, as recorded in spam event contributions.
+    pub const NAMES: [&'static str; 10] = [
+        "links",
+        "link_count",
+        "gibberish",
+        "link_repetition",
+        "repost",
+        "duplicate",
+        "location_flood",
+        "new_sender_link",
+        "new_sender_media",
+        "lockdown",
+    ];
+
+    /// Scores a message from a known sender with every detector, including the
+    /// per-user ones, keeping each detector's contribution.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `user_id` - The sender of the message.
+    /// * `text` - The message text.
+    /// * `now` - The time the message was received.
+    /// * `allowed_domains` - The chat's trusted domains, ignored by every link-based detector.
+    ///
+    /// # Returns
+    /// * `Vec<(&'static str, f32)>` - Pairs of `(detector name, score)`, one per detector.
+    pub fn evaluate_message(
+        &self,
+        chat_id: i64,
+        user_id: u64,
+        text: &str,
+        now: Instant,
+        allowed_domains: &[String],
+    ) -> Vec<(&'static str, f32)> {
+        let mut scores = self.evaluate_content(text, allowed_domains);
+        scores.push(("link_repetition", self.link_repetition.check(user_id, text, now, allowed_domains)));
+        scores.push(("repost", self.reposts.check(chat_id, user_id, text, now)));
+        scores.push(("duplicate", self.duplicates.check(chat_id, text, now)));
+        scores
+    }
+
+    /// Scores a message with the content-only detectors, ignoring a chat's trusted domains.
+    ///
+    /// Unlike `evaluate_message`, this leaves the per-user and per-chat trackers
+    /// untouched, so it suits text that was already seen, such as edits.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    /// * `allowed_domains` - The chat's trusted domains, ignored by every link-based detector.
+    ///
+    /// # Returns
+    /// * `Vec<(&'static str, f32)>` - Pairs of `(detector name, score)`, one per detector.
+    pub fn evaluate_content(&self, text: &str, allowed_domains: &[String]) -> Vec<(&'static str, f32)> {
+        vec![
+            ("links", self.links.check_allowing(text, allowed_domains)),
+            ("link_count", self.link_count.check_allowing(text, allowed_domains)),
+            ("gibberish", self.gibberish.check(text)),
+        ]
+    }
+
+    /// Scores a message with every content-only detector, keeping each detector's contribution.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    ///
+    /// # Returns
+    /// * `Vec<(&'static str, f32)>` - Pairs of `(detector name, score)`, one per detector.
+    pub fn evaluate(&self, text: &str) -> Vec<(&'static str, f32)> {
+        vec![
+            ("links", self.links.check(text)),
+            ("link_count", self.link_count.check_allowing(text, &[])),
+            ("gibberish", self.gibberish.check(text)),
+        ]
+    }
+
+    /// Scores a message with every detector.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    ///
+    /// # Returns
+    /// * `f32` - The sum of all detector scores.
+    pub fn score(&self, text: &str) -> f32 {
+        self.evaluate(text).iter().map(|(_, score)| score).sum()
+    }
+}
@@ -0,0 +1,168 @@
+//! A module for detecting the same message flooded across a chat.
+//!
+//! Spam is often posted over and over, by one account or by several. `DuplicateDetector`
+//! keeps a small per-chat history of recent message hashes; a message whose normalized
+//! text (case and whitespace folded) was already seen within the last `history`
+//! messages and `window` receives a score bump, whoever sent it.
+
+use crate::config::env_parse;
+use crate::sync::LockExt;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Maximum number of chats whose history is kept.
+const MAX_TRACKED_CHATS: usize = 10_000;
+
+/// Scores messages repeated within a chat.
+#[derive(Debug)]
+pub struct DuplicateDetector {
+    /// Number of recent messages remembered per chat.
+    pub history: usize,
+    /// How long a message is remembered.
+    pub window: Duration,
+    /// Score added to a message already seen in the chat.
+    pub score: f32,
+    /// Hashes of recent normalized messages per chat, oldest first.
+    recent: Mutex<HashMap<i64, VecDeque<(u64, Instant)>>>,
+}
+
+impl Default for DuplicateDetector {
+    fn default() -> Self {
+        Self::new(50, Duration::from_secs(300), 3.0)
+    }
+}
+
+/// Folds case and whitespace, so near-identical copies hash the same.
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Hashes the normalized form of a message.
+fn text_hash(normalized: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    normalized.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl DuplicateDetector {
+    /// Creates a detector with an empty history.
+    ///
+    /// # Arguments
+    /// * `history` - Recent messages remembered per chat.
+    /// * `window` - How long a message is remembered.
+    /// * `score` - Score added to a duplicate message.
+    pub fn new(history: usize, window: Duration, score: f32) -> Self {
+        Self {
+            history,
+            window,
+            score,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Builds the detector from environment variables.
+    ///
+    /// * `DUPLICATE_HISTORY` - Recent messages remembered per chat (default 50).
+    /// * `DUPLICATE_WINDOW_SECS` - How long messages are remembered (default 300).
+    /// * `DUPLICATE_SCORE` - Score added to a duplicate message (default 3.0; 0 disables).
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self::new(
+            env_parse("DUPLICATE_HISTORY", defaults.history),
+            Duration::from_secs(env_parse("DUPLICATE_WINDOW_SECS", defaults.window.as_secs())),
+            env_parse("DUPLICATE_SCORE", defaults.score),
+        )
+    }
+
+    /// Records a message and reports whether it repeats a recent one in the chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `text` - The message text.
+    /// * `now` - The time the message was received.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the same normalized text is among the chat's last
+    ///   `history` messages within the window. Empty messages are never duplicates.
+    pub fn is_duplicate(&self, chat_id: i64, text: &str, now: Instant) -> bool {
+        let normalized = normalize(text);
+        if normalized.is_empty() || self.history == 0 {
+            return false;
+        }
+        let hash = text_hash(&normalized);
+        let window = self.window;
+        let mut recent = self.recent.lock_or_recover();
+        if !recent.contains_key(&chat_id) && recent.len() >= MAX_TRACKED_CHATS {
+            recent.retain(|_, seen| seen.back().is_some_and(|(_, at)| now.duration_since(*at) < window));
+            if recent.len() >= MAX_TRACKED_CHATS {
+                return false;
+            }
+        }
+        let seen = recent.entry(chat_id).or_default();
+        while let Some((_, at)) = seen.front() {
+            if now.duration_since(*at) < window {
+                break;
+            }
+            seen.pop_front();
+        }
+        let duplicate = seen.iter().any(|(seen_hash, _)| *seen_hash == hash);
+        if seen.len() >= self.history {
+            seen.pop_front();
+        }
+        seen.push_back((hash, now));
+        duplicate
+    }
+
+    /// Records a message and scores it if it is a duplicate.
+    ///
+    /// # Returns
+    /// * `f32` - `score` if the message repeats a recent one in the chat, otherwise 0.
+    pub fn check(&self, chat_id: i64, text: &str, now: Instant) -> f32 {
+        if self.score <= 0.0 {
+            return 0.0;
+        }
+        if self.is_duplicate(chat_id, text, now) {
+            self.score
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Unit tests for the `duplicates` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_near_identical_repeat_is_duplicate() {
+        let detector = DuplicateDetector::new(50, Duration::from_secs(300), 3.0);
+        let now = Instant::now();
+        assert_eq!(detector.check(1, "Join my   channel NOW", now), 0.0);
+        assert_eq!(detector.check(1, "join my channel now", now), 3.0);
+        // Other chats have their own history.
+        assert_eq!(detector.check(2, "join my channel now", now), 0.0);
+        assert!(!detector.is_duplicate(1, "", now));
+        assert!(!detector.is_duplicate(1, "", now));
+    }
+
+    #[test]
+    fn test_duplicates_are_forgotten_by_count_and_time() {
+        let detector = DuplicateDetector::new(2, Duration::from_secs(60), 3.0);
+        let start = Instant::now();
+        assert!(!detector.is_duplicate(1, "buy followers", start));
+        assert!(!detector.is_duplicate(1, "hello", start));
+        assert!(!detector.is_duplicate(1, "how are you", start));
+        // "buy followers" was pushed out of the two-message history.
+        assert!(!detector.is_duplicate(1, "buy followers", start));
+        assert!(!detector.is_duplicate(1, "see you", start + Duration::from_secs(61)));
+        // Everything older than the window has expired.
+        assert!(!detector.is_duplicate(1, "buy followers", start + Duration::from_secs(62)));
+    }
+}
@@ -0,0 +1,90 @@
+//! A module for remembering the verdicts of recent messages, so edits are checked consistently.
+//!
+//! Spammers can post a clean message and edit spam into it later, so edited messages
+//! are checked again. The verdict of each checked message is kept here, which lets the
+//! handler act only when an edit turns a clean message into spam, instead of counting
+//! the same message twice.
+
+use crate::config::env_parse;
+use crate::sync::LockExt;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Verdicts kept when `EDIT_VERDICT_CAPACITY` is unset.
+pub const DEFAULT_VERDICT_CAPACITY: usize = 10_000;
+
+/// Verdicts keyed by `(chat_id, message_id)`, and the keys in the order they were recorded.
+type VerdictLog = (HashMap<(i64, i32), bool>, VecDeque<(i64, i32)>);
+
+/// The spam verdicts of the most recently checked messages.
+pub struct MessageVerdicts {
+    /// The verdicts, and the order they were first recorded in for eviction.
+    inner: Mutex<VerdictLog>,
+    /// Maximum number of messages remembered; the oldest are forgotten first.
+    capacity: usize,
+}
+
+impl MessageVerdicts {
+    /// Creates an empty store remembering up to `capacity` messages.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new((HashMap::new(), VecDeque::new())),
+            capacity,
+        }
+    }
+
+    /// Creates an empty store sized by `EDIT_VERDICT_CAPACITY` (default `DEFAULT_VERDICT_CAPACITY`).
+    pub fn from_env() -> Self {
+        Self::new(env_parse("EDIT_VERDICT_CAPACITY", DEFAULT_VERDICT_CAPACITY))
+    }
+
+    /// Records the verdict of a message, replacing the verdict of an earlier version.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `message_id` - The message.
+    /// * `is_spam` - Whether the message was flagged as spam.
+    pub fn record(&self, chat_id: i64, message_id: i32, is_spam: bool) {
+        let mut inner = self.inner.lock_or_recover();
+        let (verdicts, order) = &mut *inner;
+        if verdicts.insert((chat_id, message_id), is_spam).is_none() {
+            order.push_back((chat_id, message_id));
+        }
+        while order.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                verdicts.remove(&oldest);
+            }
+        }
+    }
+
+    /// Returns the last recorded verdict of a message.
+    ///
+    /// # Returns
+    /// * `Option<bool>` - Whether it was spam, or `None` if it was never checked or
+    ///   has been forgotten.
+    pub fn previous(&self, chat_id: i64, message_id: i32) -> Option<bool> {
+        self.inner.lock_or_recover().0.get(&(chat_id, message_id)).copied()
+    }
+}
+
+/// Unit tests for the `edits` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verdicts_are_replaced_and_oldest_forgotten() {
+        let verdicts = MessageVerdicts::new(2);
+        verdicts.record(-100, 1, false);
+        verdicts.record(-100, 2, false);
+        verdicts.record(-100, 1, true);
+        assert_eq!(verdicts.previous(-100, 1), Some(true));
+        assert_eq!(verdicts.previous(-200, 1), None);
+
+        // Message 1 was recorded first, so it goes first even though it was updated.
+        verdicts.record(-100, 3, false);
+        assert_eq!(verdicts.previous(-100, 1), None);
+        assert_eq!(verdicts.previous(-100, 2), Some(false));
+        assert_eq!(verdicts.previous(-100, 3), Some(false));
+    }
+}
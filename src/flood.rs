@@ -0,0 +1,476 @@
+//! A module for shedding load during extreme update floods.
+//!
+//! Under a severe raid every message would otherwise run through the Lua rules and
+//! several database round-trips. `LoadShedder` counts incoming updates over a short
+//! sliding window; while the rate is above the configured ceiling, messages from
+//! established users (who have posted enough clean messages, tracked in memory) are
+//! fast-pathed without scoring, so the bot keeps up with the messages that matter.
+//!
+//! `LocationFlood` scores new users who send many location or venue messages in
+//! quick succession, a flooding technique that carries no text to score.
+//!
+//! `ChatLockdown` watches each chat's total message rate. A coordinated raid from many
+//! accounts stays under every per-user limit, but not under the chat's; once the chat
+//! is over its limit it is locked down for a cool-down period, during which messages
+//! from new users score extra.
+
+use crate::config::env_parse;
+use crate::sync::LockExt;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bound on the number of users tracked in memory.
+const MAX_TRACKED_USERS: usize = 100_000;
+
+/// Detects update floods and decides which messages may skip scoring.
+pub struct LoadShedder {
+    /// Updates per window above which shedding engages.
+    ceiling: usize,
+    /// Length of the sliding window.
+    window: Duration,
+    /// Clean messages a user needs before being fast-pathed during a flood.
+    established_after: u32,
+    /// Arrival times of the updates inside the current window.
+    arrivals: Mutex<VecDeque<Instant>>,
+    /// Clean message counts per user, kept in memory to avoid database hits.
+    clean_counts: Mutex<HashMap<u64, u32>>,
+}
+
+impl LoadShedder {
+    /// Creates a load shedder.
+    ///
+    /// # Arguments
+    /// * `ceiling` - Updates per window above which shedding engages.
+    /// * `window` - Length of the sliding window.
+    /// * `established_after` - Clean messages before a user is fast-pathed.
+    pub fn new(ceiling: usize, window: Duration, established_after: u32) -> Self {
+        Self {
+            ceiling,
+            window,
+            established_after,
+            arrivals: Mutex::new(VecDeque::new()),
+            clean_counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Builds a load shedder from environment variables.
+    ///
+    /// * `FLOOD_CEILING` - Updates per second above which shedding engages; unset or 0 disables it.
+    /// * `FLOOD_ESTABLISHED_MESSAGES` - Clean messages before a user is fast-pathed (default 20).
+    ///
+    /// # Returns
+    /// * `Option<Self>` - The load shedder, or `None` if shedding is disabled.
+    pub fn from_env() -> Option<Self> {
+        let ceiling: usize = env_parse("FLOOD_CEILING", 0);
+        if ceiling == 0 {
+            return None;
+        }
+        Some(Self::new(
+            ceiling,
+            Duration::from_secs(1),
+            env_parse("FLOOD_ESTABLISHED_MESSAGES", 20),
+        ))
+    }
+
+    /// Records an incoming update and reports whether shedding is engaged.
+    ///
+    /// # Arguments
+    /// * `now` - The arrival time of the update.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the update rate is above the ceiling.
+    pub fn record_update(&self, now: Instant) -> bool {
+        let mut arrivals = self.arrivals.lock_or_recover();
+        while let Some(&oldest) = arrivals.front() {
+            if now.duration_since(oldest) < self.window {
+                break;
+            }
+            arrivals.pop_front();
+        }
+        // Stop recording once well over the ceiling so memory stays bounded.
+        if arrivals.len() <= self.ceiling {
+            arrivals.push_back(now);
+        }
+        arrivals.len() > self.ceiling
+    }
+
+    /// Returns `true` if the user has posted enough clean messages to be fast-pathed.
+    pub fn is_established(&self, user_id: u64) -> bool {
+        self.clean_counts
+            .lock_or_recover()
+            .get(&user_id)
+            .is_some_and(|&count| count >= self.established_after)
+    }
+
+    /// Records an incoming message and decides whether it may skip scoring.
+    ///
+    /// # Arguments
+    /// * `user_id` - The sender of the message.
+    /// * `now` - The arrival time of the message.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if a flood is in progress and the sender is established.
+    pub fn should_skip(&self, user_id: u64, now: Instant) -> bool {
+        self.record_update(now) && self.is_established(user_id)
+    }
+
+    /// Records the verdict for a scored message.
+    ///
+    /// Clean messages count towards the user becoming established; spam resets
+    /// the count so a compromised account loses its fast path.
+    ///
+    /// # Arguments
+    /// * `user_id` - The sender of the message.
+    /// * `is_spam` - Whether the message was flagged as spam.
+    pub fn record_verdict(&self, user_id: u64, is_spam: bool) {
+        let mut clean_counts = self.clean_counts.lock_or_recover();
+        if is_spam {
+            clean_counts.remove(&user_id);
+        } else if let Some(count) = clean_counts.get_mut(&user_id) {
+            *count = count.saturating_add(1);
+        } else if clean_counts.len() < MAX_TRACKED_USERS {
+            clean_counts.insert(user_id, 1);
+        }
+    }
+}
+
+/// Scores rapid location flooding from new users.
+#[derive(Debug)]
+pub struct LocationFlood {
+    /// Location messages allowed per window before scoring starts.
+    pub max_per_window: usize,
+    /// Length of the sliding window.
+    pub window: Duration,
+    /// Users with fewer messages than this count as new.
+    pub new_user_messages: i64,
+    /// Score added to each location message beyond `max_per_window`.
+    pub score: f32,
+    /// Arrival times of recent location messages per `(chat_id, user_id)`.
+    recent: Mutex<HashMap<(i64, u64), VecDeque<Instant>>>,
+}
+
+impl Default for LocationFlood {
+    fn default() -> Self {
+        Self::new(3, Duration::from_secs(60), 10, 5.0)
+    }
+}
+
+impl LocationFlood {
+    /// Creates a location flood detector.
+    ///
+    /// # Arguments
+    /// * `max_per_window` - Location messages allowed per window.
+    /// * `window` - Length of the sliding window.
+    /// * `new_user_messages` - Message count below which a user is new.
+    /// * `score` - Score per location message beyond the allowance.
+    pub fn new(max_per_window: usize, window: Duration, new_user_messages: i64, score: f32) -> Self {
+        Self {
+            max_per_window,
+            window,
+            new_user_messages,
+            score,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Builds the detector from environment variables.
+    ///
+    /// * `LOCATION_FLOOD_MAX` - Location messages allowed per window (default 3).
+    /// * `LOCATION_FLOOD_WINDOW_SECS` - Window length (default 60).
+    /// * `LOCATION_FLOOD_NEW_USER_MESSAGES` - Messages below which a user is new (default 10).
+    /// * `LOCATION_FLOOD_SCORE` - Score per excess location message (default 5.0; 0 disables).
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self::new(
+            env_parse("LOCATION_FLOOD_MAX", defaults.max_per_window),
+            Duration::from_secs(env_parse("LOCATION_FLOOD_WINDOW_SECS", defaults.window.as_secs())),
+            env_parse("LOCATION_FLOOD_NEW_USER_MESSAGES", defaults.new_user_messages),
+            env_parse("LOCATION_FLOOD_SCORE", defaults.score),
+        )
+    }
+
+    /// Records a location message and scores it if a new user is flooding.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `user_id` - The sender of the message.
+    /// * `message_count` - The number of messages previously seen from the sender.
+    /// * `now` - The time the message was received.
+    ///
+    /// # Returns
+    /// * `f32` - `score` if the sender is new and over the allowance, otherwise 0.
+    pub fn check(&self, chat_id: i64, user_id: u64, message_count: i64, now: Instant) -> f32 {
+        if self.score <= 0.0 || message_count >= self.new_user_messages {
+            return 0.0;
+        }
+        let mut recent = self.recent.lock_or_recover();
+        let window = self.window;
+        if recent.len() >= MAX_TRACKED_USERS {
+            recent.retain(|_, times| times.back().is_some_and(|at| now.duration_since(*at) < window));
+        }
+        let times = recent.entry((chat_id, user_id)).or_default();
+        while let Some(&oldest) = times.front() {
+            if now.duration_since(oldest) < window {
+                break;
+            }
+            times.pop_front();
+        }
+        if times.len() <= self.max_per_window {
+            times.push_back(now);
+        }
+        if times.len() > self.max_per_window {
+            self.score
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Message arrivals and lockdown state of a single chat.
+#[derive(Debug, Default)]
+struct ChatTraffic {
+    /// Arrival times of the messages inside the current window.
+    arrivals: VecDeque<Instant>,
+    /// When the chat's lockdown ends, if it is locked down.
+    locked_until: Option<Instant>,
+}
+
+/// Locks a chat down while its total message rate is above a limit.
+#[derive(Debug)]
+pub struct ChatLockdown {
+    /// Messages per window above which a chat is locked down; 0 disables lockdowns.
+    pub max_messages: usize,
+    /// Length of the sliding window.
+    pub window: Duration,
+    /// How long a lockdown lasts after the last window over the limit.
+    pub duration: Duration,
+    /// Users with fewer messages than this count as new.
+    pub new_user_messages: i64,
+    /// Score added to each message from a new user while the chat is locked down.
+    pub score: f32,
+    /// Recent traffic per chat.
+    chats: Mutex<HashMap<i64, ChatTraffic>>,
+}
+
+impl Default for ChatLockdown {
+    fn default() -> Self {
+        Self::new(0, Duration::from_secs(10), Duration::from_secs(300), 10, 5.0)
+    }
+}
+
+impl ChatLockdown {
+    /// Creates a chat lockdown detector.
+    ///
+    /// # Arguments
+    /// * `max_messages` - Messages per window above which a chat is locked down; 0 disables it.
+    /// * `window` - Length of the sliding window.
+    /// * `duration` - How long a lockdown lasts after the rate drops below the limit.
+    /// * `new_user_messages` - Message count below which a user is new.
+    /// * `score` - Score per message from a new user during a lockdown.
+    pub fn new(max_messages: usize, window: Duration, duration: Duration, new_user_messages: i64, score: f32) -> Self {
+        Self {
+            max_messages,
+            window,
+            duration,
+            new_user_messages,
+            score,
+            chats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Builds the detector from environment variables.
+    ///
+    /// * `LOCKDOWN_MAX_MESSAGES` - Messages per window above which a chat is locked down
+    ///   (unset or 0 disables lockdowns).
+    /// * `LOCKDOWN_WINDOW_SECS` - Window length (default 10).
+    /// * `LOCKDOWN_DURATION_SECS` - Cool-down before a lockdown lifts (default 300).
+    /// * `LOCKDOWN_NEW_USER_MESSAGES` - Messages below which a user is new (default 10).
+    /// * `LOCKDOWN_SCORE` - Score per message from a new user during a lockdown (default 5.0).
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self::new(
+            env_parse("LOCKDOWN_MAX_MESSAGES", defaults.max_messages),
+            Duration::from_secs(env_parse("LOCKDOWN_WINDOW_SECS", defaults.window.as_secs())),
+            Duration::from_secs(env_parse("LOCKDOWN_DURATION_SECS", defaults.duration.as_secs())),
+            env_parse("LOCKDOWN_NEW_USER_MESSAGES", defaults.new_user_messages),
+            env_parse("LOCKDOWN_SCORE", defaults.score),
+        )
+    }
+
+    /// Returns `true` if lockdowns are enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.max_messages > 0
+    }
+
+    /// Records a message in a chat and locks the chat down if it is over the limit.
+    ///
+    /// While the rate stays over the limit, an ongoing lockdown is extended.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `now` - The time the message was received.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if this message started a lockdown.
+    pub fn record(&self, chat_id: i64, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let mut chats = self.chats.lock_or_recover();
+        let window = self.window;
+        if chats.len() >= MAX_TRACKED_USERS {
+            chats.retain(|_, traffic| {
+                traffic.locked_until.is_some() || traffic.arrivals.back().is_some_and(|at| now.duration_since(*at) < window)
+            });
+        }
+        let traffic = chats.entry(chat_id).or_default();
+        while let Some(&oldest) = traffic.arrivals.front() {
+            if now.duration_since(oldest) < window {
+                break;
+            }
+            traffic.arrivals.pop_front();
+        }
+        // Stop recording once over the limit so memory stays bounded.
+        if traffic.arrivals.len() <= self.max_messages {
+            traffic.arrivals.push_back(now);
+        }
+        if traffic.arrivals.len() <= self.max_messages {
+            return false;
+        }
+        let engaged = traffic.locked_until.is_none();
+        traffic.locked_until = Some(now + self.duration);
+        engaged
+    }
+
+    /// Returns `true` if a chat is locked down.
+    pub fn is_locked(&self, chat_id: i64, now: Instant) -> bool {
+        self.chats
+            .lock_or_recover()
+            .get(&chat_id)
+            .and_then(|traffic| traffic.locked_until)
+            .is_some_and(|until| now < until)
+    }
+
+    /// Scores a message from a new user in a locked-down chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `message_count` - The number of messages previously seen from the sender.
+    /// * `now` - The time the message was received.
+    ///
+    /// # Returns
+    /// * `f32` - `score` if the chat is locked down and the sender is new, otherwise 0.
+    pub fn check(&self, chat_id: i64, message_count: i64, now: Instant) -> f32 {
+        if message_count < self.new_user_messages && self.is_locked(chat_id, now) {
+            self.score
+        } else {
+            0.0
+        }
+    }
+
+    /// Ends the lockdowns whose cool-down has passed.
+    ///
+    /// # Arguments
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    /// * `Vec<i64>` - The chats whose lockdown was lifted.
+    pub fn take_lifted(&self, now: Instant) -> Vec<i64> {
+        let mut chats = self.chats.lock_or_recover();
+        let mut lifted = Vec::new();
+        for (chat_id, traffic) in chats.iter_mut() {
+            if traffic.locked_until.is_some_and(|until| now >= until) {
+                traffic.locked_until = None;
+                lifted.push(*chat_id);
+            }
+        }
+        lifted
+    }
+}
+
+/// Unit tests for the `flood` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shedding_engages_above_ceiling_and_fast_paths_established_users() {
+        let shedder = LoadShedder::new(10, Duration::from_secs(1), 3);
+        for _ in 0..3 {
+            shedder.record_verdict(1, false);
+        }
+        let start = Instant::now();
+
+        // Below the ceiling everyone is scored, established or not.
+        for i in 0..10 {
+            assert!(!shedder.should_skip(1, start + Duration::from_millis(i)));
+        }
+        // A burst pushes the rate over the ceiling.
+        for i in 10..50 {
+            let now = start + Duration::from_millis(i);
+            assert!(shedder.should_skip(1, now));
+            assert!(!shedder.should_skip(2, now));
+        }
+        // Once the window has passed without updates, shedding disengages.
+        assert!(!shedder.should_skip(1, start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_location_flood_from_new_user_is_scored() {
+        let flood = LocationFlood::new(3, Duration::from_secs(60), 10, 5.0);
+        let start = Instant::now();
+        for i in 0..3 {
+            assert_eq!(flood.check(1, 7, 0, start + Duration::from_secs(i)), 0.0);
+        }
+        assert_eq!(flood.check(1, 7, 0, start + Duration::from_secs(3)), 5.0);
+        // Established users may share as many locations as they like.
+        for i in 0..5 {
+            assert_eq!(flood.check(1, 8, 50, start + Duration::from_secs(i)), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_chat_lockdown_engages_extends_and_lifts() {
+        let lockdown = ChatLockdown::new(5, Duration::from_secs(10), Duration::from_secs(60), 10, 5.0);
+        let start = Instant::now();
+        for i in 0..5 {
+            assert!(!lockdown.record(1, start + Duration::from_secs(i)));
+        }
+        assert_eq!(lockdown.check(1, 0, start + Duration::from_secs(5)), 0.0);
+
+        // The sixth message within the window locks the chat down, once
+        assert!(lockdown.record(1, start + Duration::from_secs(5)));
+        assert!(!lockdown.record(1, start + Duration::from_secs(6)));
+        assert_eq!(lockdown.check(1, 0, start + Duration::from_secs(7)), 5.0);
+        assert_eq!(lockdown.check(1, 50, start + Duration::from_secs(7)), 0.0);
+        assert_eq!(lockdown.check(2, 0, start + Duration::from_secs(7)), 0.0);
+
+        // The cool-down counts from the last message over the limit
+        assert!(lockdown.take_lifted(start + Duration::from_secs(65)).is_empty());
+        assert_eq!(lockdown.take_lifted(start + Duration::from_secs(66)), vec![1]);
+        assert!(!lockdown.is_locked(1, start + Duration::from_secs(66)));
+        assert!(lockdown.take_lifted(start + Duration::from_secs(120)).is_empty());
+    }
+
+    #[test]
+    fn test_chat_lockdown_is_disabled_by_default() {
+        let lockdown = ChatLockdown::default();
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert!(!lockdown.record(1, now));
+        }
+        assert_eq!(lockdown.check(1, 0, now), 0.0);
+    }
+
+    #[test]
+    fn test_spam_resets_established_status() {
+        let shedder = LoadShedder::new(10, Duration::from_secs(1), 2);
+        shedder.record_verdict(1, false);
+        assert!(!shedder.is_established(1));
+        shedder.record_verdict(1, false);
+        assert!(shedder.is_established(1));
+        shedder.record_verdict(1, true);
+        assert!(!shedder.is_established(1));
+    }
+}
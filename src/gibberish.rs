@@ -0,0 +1,169 @@
+//! A module for detecting keyboard-mashing gibberish such as "asdkjfh qweoiru".
+//!
+//! Random-character text is a common bot signature. The detector uses a cheap character
+//! n-gram heuristic on each sufficiently long Latin-alphabet word: long consonant runs and
+//! a high share of keyboard-adjacent letter pairs (bigrams) are rare in natural language
+//! but typical of mashed keys. Words written in other alphabets, short words and slang are
+//! ignored, and a message is only scored when several words look like gibberish.
+
+/// Letter rows of a QWERTY keyboard, used to detect adjacent-key bigrams.
+const KEYBOARD_ROWS: [&str; 3] = ["qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+/// Configuration for the gibberish detector.
+#[derive(Debug, Clone)]
+pub struct GibberishDetector {
+    /// Whether the detector is active; it is opt-in and disabled by default.
+    pub enabled: bool,
+    /// Score added to a message that looks like gibberish.
+    pub score: f32,
+    /// Words shorter than this are ignored.
+    pub min_word_len: usize,
+    /// Minimum number of gibberish words required to score a message.
+    pub min_gibberish_words: usize,
+    /// Minimum fraction of the considered words that must look like gibberish.
+    pub min_ratio: f32,
+}
+
+impl Default for GibberishDetector {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            score: 5.0,
+            min_word_len: 6,
+            min_gibberish_words: 2,
+            min_ratio: 0.5,
+        }
+    }
+}
+
+impl GibberishDetector {
+    /// Builds the detector configuration from environment variables.
+    ///
+    /// * `GIBBERISH_DETECTION` - Set to `true` to enable the detector (default `false`).
+    /// * `GIBBERISH_SCORE` - Score added to gibberish messages (default 5.0).
+    /// * `GIBBERISH_MIN_WORDS` - Gibberish words required to score a message (default 2).
+    /// * `GIBBERISH_MIN_RATIO` - Required fraction of gibberish words (default 0.5).
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            enabled: std::env::var("GIBBERISH_DETECTION")
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(defaults.enabled),
+            score: std::env::var("GIBBERISH_SCORE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.score),
+            min_gibberish_words: std::env::var("GIBBERISH_MIN_WORDS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.min_gibberish_words),
+            min_ratio: std::env::var("GIBBERISH_MIN_RATIO")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.min_ratio),
+            ..defaults
+        }
+    }
+
+    /// Scores a message, returning `score` if it looks like gibberish and 0.0 otherwise.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    ///
+    /// # Returns
+    /// * `f32` - The gibberish score, always 0.0 when the detector is disabled.
+    pub fn check(&self, text: &str) -> f32 {
+        if !self.enabled {
+            return 0.0;
+        }
+        let words: Vec<String> = text
+            .split_whitespace()
+            .map(|w| w.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+            .filter(|w| w.len() >= self.min_word_len && w.chars().all(|c| c.is_ascii_alphabetic()))
+            .collect();
+        if words.is_empty() {
+            return 0.0;
+        }
+        let gibberish = words.iter().filter(|w| looks_like_gibberish(w)).count();
+        if gibberish >= self.min_gibberish_words
+            && gibberish as f32 / words.len() as f32 >= self.min_ratio
+        {
+            self.score
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Returns `true` if `a` and `b` are neighbouring keys on the same keyboard row.
+fn is_keyboard_neighbor(a: char, b: char) -> bool {
+    KEYBOARD_ROWS.iter().any(|row| {
+        row.as_bytes().windows(2).any(|pair| {
+            let (x, y) = (pair[0] as char, pair[1] as char);
+            (x == a && y == b) || (x == b && y == a)
+        })
+    })
+}
+
+/// Returns `true` for vowels, counting `y` as a vowel to spare words like "rhythm".
+fn is_vowel(c: char) -> bool {
+    matches!(c, 'a' | 'e' | 'i' | 'o' | 'u' | 'y')
+}
+
+/// Estimates whether a single lowercase ASCII word is keyboard-mashing.
+///
+/// A word is considered gibberish when it contains five or more consecutive consonants,
+/// or when at least half of its bigrams are adjacent keys on the keyboard.
+fn looks_like_gibberish(word: &str) -> bool {
+    let chars: Vec<char> = word.chars().collect();
+    if chars.len() < 2 {
+        return false;
+    }
+    let mut run: usize = 0;
+    let mut max_run: usize = 0;
+    for &c in &chars {
+        if is_vowel(c) {
+            run = 0;
+        } else {
+            run += 1;
+            max_run = max_run.max(run);
+        }
+    }
+    let neighbors = chars
+        .windows(2)
+        .filter(|pair| is_keyboard_neighbor(pair[0], pair[1]))
+        .count();
+    max_run >= 5 || neighbors as f32 / (chars.len() - 1) as f32 >= 0.5
+}
+
+/// Unit tests for the `gibberish` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_detector() -> GibberishDetector {
+        GibberishDetector {
+            enabled: true,
+            ..GibberishDetector::default()
+        }
+    }
+
+    #[test]
+    fn test_keyboard_mash_is_scored() {
+        let detector = enabled_detector();
+        assert_eq!(detector.check("asdkjfh qweoiru"), 5.0);
+    }
+
+    #[test]
+    fn test_normal_sentence_is_not_scored() {
+        let detector = enabled_detector();
+        assert_eq!(detector.check("The weather is lovely today, everyone should go outside!"), 0.0);
+        assert_eq!(detector.check("lol brb gtg"), 0.0);
+        assert_eq!(detector.check("Привет всем, как ваши дела сегодня"), 0.0);
+    }
+
+    #[test]
+    fn test_disabled_detector_never_scores() {
+        assert_eq!(GibberishDetector::default().check("asdkjfh qweoiru"), 0.0);
+    }
+}
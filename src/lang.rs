@@ -0,0 +1,314 @@
+//! A module for translating the bot's replies.
+//!
+//! Replies are looked up by `Key` in per-language string tables. Each chat can pick
+//! its language with `/set_language`; otherwise the language of the user the bot is
+//! answering is used, and English is the fallback for unsupported languages and for
+//! keys a table does not translate.
+
+use std::fmt;
+
+/// A language replies can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lang {
+    /// English, the fallback for everything else.
+    #[default]
+    En,
+    /// Russian.
+    Ru,
+    /// Spanish.
+    Es,
+}
+
+impl Lang {
+    /// Parses a language from its code, ignoring case and any region, so `es-419`
+    /// and Telegram's `language_code` values are accepted.
+    pub fn parse(code: &str) -> Option<Self> {
+        let language = code.split(['-', '_']).next().unwrap_or("").to_lowercase();
+        match language.as_str() {
+            "en" => Some(Lang::En),
+            "ru" => Some(Lang::Ru),
+            "es" => Some(Lang::Es),
+            _ => None,
+        }
+    }
+
+    /// Returns the language's code, as stored in the database.
+    pub fn code(self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Ru => "ru",
+            Lang::Es => "es",
+        }
+    }
+
+    /// Picks the language of a reply.
+    ///
+    /// # Arguments
+    /// * `chat_language` - The language chosen for the chat with `/set_language`, if any.
+    /// * `user_language` - The `language_code` of the user being answered, if any.
+    ///
+    /// # Returns
+    /// * `Lang` - The chat's language, else the user's if it is supported, else English.
+    pub fn resolve(chat_language: Option<&str>, user_language: Option<&str>) -> Self {
+        chat_language
+            .and_then(Lang::parse)
+            .or_else(|| user_language.and_then(Lang::parse))
+            .unwrap_or_default()
+    }
+
+    /// Returns a reply in the language, falling back to English if it is not translated.
+    pub fn text(self, key: Key) -> &'static str {
+        let translated = match self {
+            Lang::En => None,
+            Lang::Ru => russian(key),
+            Lang::Es => spanish(key),
+        };
+        translated.unwrap_or_else(|| english(key))
+    }
+
+    /// Returns a reply in the language with each `{}` replaced by the next argument.
+    pub fn format(self, key: Key, args: &[&dyn fmt::Display]) -> String {
+        let mut parts = self.text(key).split("{}");
+        let mut filled = parts.next().unwrap_or("").to_string();
+        for (index, part) in parts.enumerate() {
+            if let Some(arg) = args.get(index) {
+                filled.push_str(&arg.to_string());
+            }
+            filled.push_str(part);
+        }
+        filled
+    }
+}
+
+/// A translatable reply. Replies containing `{}` are filled in by `Lang::format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Start,
+    SpamDeleted,
+    SpamDetected,
+    /// Takes the user and their number of warnings.
+    WarningsOne,
+    /// Takes the user and their number of warnings.
+    WarningsMany,
+    Banned,
+    /// Takes the end of the mute.
+    MutedUntil,
+    OnlyChatAdmins,
+    LanguageSet,
+    LanguageUsage,
+    OnlyAdminsSetLanguage,
+    OnlyAdminsSetAction,
+    OnlyAdminsSetWelcome,
+    OnlyAdminsTestMessages,
+    OnlyAdminsAddRules,
+    OnlyAdminsListRules,
+    OnlyAdminsExportRules,
+    OnlyAdminsCalibrate,
+    OnlyAdminsAddNotes,
+    OnlyAdminsSetReportThreshold,
+    OnlyAdminsSetThreshold,
+    OnlyAdminsPardon,
+    OnlyAdminsDetectorStats,
+    OnlyAdminsRecentSpam,
+    OnlyOperator,
+    NoOperator,
+    OnlyAdminsListBackups,
+    OnlyAdminsTrustedDomains,
+    OnlyAdminsSafePatterns,
+    OnlyAdminsBlockMedia,
+    OnlyAdminsViewScores,
+    OnlyAdminsMute,
+    OnlyAdminsWhitelist,
+    OnlyAdminsResetScores,
+    OnlyAdminsUnban,
+    OnlyAdminsWarn,
+    OnlyAdminsClearWarnings,
+    OnlyAdminsViewStats,
+    OnlyAdminsWhois,
+    OnlyAdminsPause,
+    OnlyAdminsTopSpammers,
+    OnlyAdminsHistory,
+    ModerationPaused,
+    /// Takes the time moderation resumes.
+    ModerationPausedUntil,
+    ModerationResumed,
+}
+
+fn english(key: Key) -> &'static str {
+    match key {
+        Key::Start => "Hello! I'm a spam filter bot.",
+        Key::SpamDeleted => "Spam message deleted. Admins notified.",
+        Key::SpamDetected => "Spam detected! Admins notified.",
+        Key::WarningsOne => "User {} has {} warning.",
+        Key::WarningsMany => "User {} has {} warnings.",
+        Key::Banned => "They have been banned.",
+        Key::MutedUntil => "They have been muted until {} UTC.",
+        Key::OnlyChatAdmins => "Only admins of that chat can do this.",
+        Key::LanguageSet => "Replies in this chat are now in English.",
+        Key::LanguageUsage => "Usage: /set_language <en|ru|es>",
+        Key::OnlyAdminsSetLanguage => "Only admins can change the language.",
+        Key::OnlyAdminsSetAction => "Only admins can change what happens to spam.",
+        Key::OnlyAdminsSetWelcome => "Only admins can set the welcome message.",
+        Key::OnlyAdminsTestMessages => "Only admins can test messages against the rules.",
+        Key::OnlyAdminsAddRules => "Only admins can add rules.",
+        Key::OnlyAdminsListRules => "Only admins can list rules.",
+        Key::OnlyAdminsExportRules => "Only admins can export rules.",
+        Key::OnlyAdminsCalibrate => "Only admins can calibrate the threshold.",
+        Key::OnlyAdminsAddNotes => "Only admins can add notes.",
+        Key::OnlyAdminsSetReportThreshold => "Only admins can set the report threshold.",
+        Key::OnlyAdminsSetThreshold => "Only admins can set the spam threshold.",
+        Key::OnlyAdminsPardon => "Only admins can pardon users.",
+        Key::OnlyAdminsDetectorStats => "Only admins can view detector stats.",
+        Key::OnlyAdminsRecentSpam => "Only admins can view recent spam.",
+        Key::OnlyOperator => "Only the bot operator can change settings shared by every chat.",
+        Key::NoOperator => "This command changes every chat the bot moderates and is disabled until BOT_OPERATOR_ID is set.",
+        Key::OnlyAdminsListBackups => "Only admins can list backups.",
+        Key::OnlyAdminsTrustedDomains => "Only admins can manage trusted domains.",
+        Key::OnlyAdminsSafePatterns => "Only admins can manage safe patterns.",
+        Key::OnlyAdminsBlockMedia => "Only admins can block stickers and files.",
+        Key::OnlyAdminsViewScores => "Only admins can view scores.",
+        Key::OnlyAdminsMute => "Only admins can mute users.",
+        Key::OnlyAdminsWhitelist => "Only admins can manage the whitelist.",
+        Key::OnlyAdminsResetScores => "Only admins can reset scores.",
+        Key::OnlyAdminsUnban => "Only admins can unban users.",
+        Key::OnlyAdminsWarn => "Only admins can warn users.",
+        Key::OnlyAdminsClearWarnings => "Only admins can clear warnings.",
+        Key::OnlyAdminsViewStats => "Only admins can view stats.",
+        Key::OnlyAdminsWhois => "Only admins can look up users.",
+        Key::OnlyAdminsPause => "Only admins can pause or resume spam moderation.",
+        Key::OnlyAdminsTopSpammers => "Only admins can view the top spammers.",
+        Key::OnlyAdminsHistory => "Only admins can view score histories.",
+        Key::ModerationPaused => "Spam moderation is paused. Messages are not checked until an admin sends /resume.",
+        Key::ModerationPausedUntil => "Spam moderation is paused until {} UTC. Messages are not checked until then.",
+        Key::ModerationResumed => "Spam moderation has resumed.",
+    }
+}
+
+fn russian(key: Key) -> Option<&'static str> {
+    let text = match key {
+        Key::Start => "Привет! Я бот для фильтрации спама.",
+        Key::SpamDeleted => "Спам-сообщение удалено. Администраторы уведомлены.",
+        Key::SpamDetected => "Обнаружен спам! Администраторы уведомлены.",
+        // One wording avoids Russian plural forms
+        Key::WarningsOne | Key::WarningsMany => "Предупреждений у пользователя {}: {}.",
+        Key::Banned => "Пользователь заблокирован.",
+        Key::MutedUntil => "Пользователь не может писать до {} UTC.",
+        Key::OnlyChatAdmins => "Это могут делать только администраторы того чата.",
+        Key::LanguageSet => "Теперь бот отвечает в этом чате на русском.",
+        Key::OnlyAdminsSetLanguage => "Только администраторы могут менять язык.",
+        Key::OnlyAdminsSetAction => "Только администраторы могут менять действия против спама.",
+        Key::OnlyAdminsSetWelcome => "Только администраторы могут задавать приветствие.",
+        Key::OnlyAdminsTestMessages => "Только администраторы могут проверять сообщения по правилам.",
+        Key::OnlyAdminsAddRules => "Только администраторы могут добавлять правила.",
+        Key::OnlyAdminsListRules => "Только администраторы могут просматривать правила.",
+        Key::OnlyAdminsExportRules => "Только администраторы могут экспортировать правила.",
+        Key::OnlyAdminsCalibrate => "Только администраторы могут калибровать порог.",
+        Key::OnlyAdminsAddNotes => "Только администраторы могут добавлять заметки.",
+        Key::OnlyAdminsSetReportThreshold => "Только администраторы могут устанавливать порог жалоб.",
+        Key::OnlyAdminsSetThreshold => "Только администраторы могут устанавливать порог спама.",
+        Key::OnlyAdminsPardon => "Только администраторы могут прощать пользователей.",
+        Key::OnlyAdminsDetectorStats => "Только администраторы могут просматривать статистику детекторов.",
+        Key::OnlyAdminsRecentSpam => "Только администраторы могут просматривать недавний спам.",
+        Key::OnlyOperator => "Только оператор бота может менять настройки, общие для всех чатов.",
+        Key::NoOperator => "Эта команда меняет все чаты, которые модерирует бот, и отключена, пока не задан BOT_OPERATOR_ID.",
+        Key::OnlyAdminsListBackups => "Только администраторы могут просматривать резервные копии.",
+        Key::OnlyAdminsTrustedDomains => "Только администраторы могут управлять доверенными доменами.",
+        Key::OnlyAdminsSafePatterns => "Только администраторы могут управлять безопасными шаблонами.",
+        Key::OnlyAdminsBlockMedia => "Только администраторы могут блокировать стикеры и файлы.",
+        Key::OnlyAdminsViewScores => "Только администраторы могут просматривать оценки.",
+        Key::OnlyAdminsMute => "Только администраторы могут ограничивать пользователей.",
+        Key::OnlyAdminsWhitelist => "Только администраторы могут управлять белым списком.",
+        Key::OnlyAdminsResetScores => "Только администраторы могут сбрасывать оценки.",
+        Key::OnlyAdminsUnban => "Только администраторы могут разблокировать пользователей.",
+        Key::OnlyAdminsWarn => "Только администраторы могут выносить предупреждения.",
+        Key::OnlyAdminsClearWarnings => "Только администраторы могут снимать предупреждения.",
+        Key::OnlyAdminsViewStats => "Только администраторы могут просматривать статистику.",
+        Key::OnlyAdminsWhois => "Только администраторы могут искать сведения о пользователях.",
+        Key::OnlyAdminsPause => "Только администраторы могут приостанавливать и возобновлять модерацию спама.",
+        Key::OnlyAdminsTopSpammers => "Только администраторы могут просматривать список главных спамеров.",
+        Key::OnlyAdminsHistory => "Только администраторы могут просматривать историю баллов.",
+        Key::ModerationPaused => "Модерация спама приостановлена. Сообщения не проверяются, пока администратор не отправит /resume.",
+        Key::ModerationPausedUntil => "Модерация спама приостановлена до {} UTC. До этого сообщения не проверяются.",
+        Key::ModerationResumed => "Модерация спама возобновлена.",
+        Key::LanguageUsage => return None,
+    };
+    Some(text)
+}
+
+fn spanish(key: Key) -> Option<&'static str> {
+    let text = match key {
+        Key::Start => "¡Hola! Soy un bot que filtra spam.",
+        Key::SpamDeleted => "Mensaje de spam eliminado. Se ha avisado a los administradores.",
+        Key::SpamDetected => "¡Spam detectado! Se ha avisado a los administradores.",
+        Key::WarningsOne => "El usuario {} tiene {} advertencia.",
+        Key::WarningsMany => "El usuario {} tiene {} advertencias.",
+        Key::Banned => "Ha sido expulsado.",
+        Key::MutedUntil => "Ha sido silenciado hasta las {} UTC.",
+        Key::OnlyChatAdmins => "Solo los administradores de ese chat pueden hacer esto.",
+        Key::LanguageSet => "Ahora las respuestas en este chat son en español.",
+        Key::OnlyAdminsSetLanguage => "Solo los administradores pueden cambiar el idioma.",
+        Key::OnlyAdminsSetAction => "Solo los administradores pueden cambiar qué se hace con el spam.",
+        Key::OnlyAdminsSetWelcome => "Solo los administradores pueden configurar el mensaje de bienvenida.",
+        Key::OnlyAdminsTestMessages => "Solo los administradores pueden probar mensajes con las reglas.",
+        Key::OnlyAdminsAddRules => "Solo los administradores pueden añadir reglas.",
+        Key::OnlyAdminsListRules => "Solo los administradores pueden ver las reglas.",
+        Key::OnlyAdminsExportRules => "Solo los administradores pueden exportar reglas.",
+        Key::OnlyAdminsCalibrate => "Solo los administradores pueden calibrar el umbral.",
+        Key::OnlyAdminsAddNotes => "Solo los administradores pueden añadir notas.",
+        Key::OnlyAdminsSetReportThreshold => "Solo los administradores pueden fijar el umbral de denuncias.",
+        Key::OnlyAdminsSetThreshold => "Solo los administradores pueden fijar el umbral de spam.",
+        Key::OnlyAdminsPardon => "Solo los administradores pueden perdonar a usuarios.",
+        Key::OnlyAdminsDetectorStats => "Solo los administradores pueden ver las estadísticas de los detectores.",
+        Key::OnlyAdminsRecentSpam => "Solo los administradores pueden ver el spam reciente.",
+        Key::OnlyOperator => "Solo el operador del bot puede cambiar la configuración compartida por todos los chats.",
+        Key::NoOperator => "Este comando cambia todos los chats que modera el bot y está desactivado hasta que se configure BOT_OPERATOR_ID.",
+        Key::OnlyAdminsListBackups => "Solo los administradores pueden ver las copias de seguridad.",
+        Key::OnlyAdminsTrustedDomains => "Solo los administradores pueden gestionar los dominios de confianza.",
+        Key::OnlyAdminsSafePatterns => "Solo los administradores pueden gestionar los patrones seguros.",
+        Key::OnlyAdminsBlockMedia => "Solo los administradores pueden bloquear stickers y archivos.",
+        Key::OnlyAdminsViewScores => "Solo los administradores pueden ver las puntuaciones.",
+        Key::OnlyAdminsMute => "Solo los administradores pueden silenciar a usuarios.",
+        Key::OnlyAdminsWhitelist => "Solo los administradores pueden gestionar la lista blanca.",
+        Key::OnlyAdminsResetScores => "Solo los administradores pueden restablecer puntuaciones.",
+        Key::OnlyAdminsUnban => "Solo los administradores pueden desbloquear a usuarios.",
+        Key::OnlyAdminsWarn => "Solo los administradores pueden advertir a usuarios.",
+        Key::OnlyAdminsClearWarnings => "Solo los administradores pueden borrar advertencias.",
+        Key::OnlyAdminsViewStats => "Solo los administradores pueden ver estadísticas.",
+        Key::OnlyAdminsWhois => "Solo los administradores pueden consultar usuarios.",
+        Key::OnlyAdminsPause => "Solo los administradores pueden pausar o reanudar la moderación de spam.",
+        Key::OnlyAdminsTopSpammers => "Solo los administradores pueden ver a los principales spammers.",
+        Key::OnlyAdminsHistory => "Solo los administradores pueden ver el historial de puntuación.",
+        Key::ModerationPaused => "La moderación de spam está en pausa. Los mensajes no se revisan hasta que un administrador envíe /resume.",
+        Key::ModerationPausedUntil => "La moderación de spam está en pausa hasta las {} UTC. Hasta entonces no se revisan los mensajes.",
+        Key::ModerationResumed => "La moderación de spam se ha reanudado.",
+        Key::LanguageUsage => return None,
+    };
+    Some(text)
+}
+
+/// Unit tests for the `lang` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_language_wins_over_user_language() {
+        assert_eq!(Lang::resolve(Some("ru"), Some("es")), Lang::Ru);
+        assert_eq!(Lang::resolve(None, Some("es-419")), Lang::Es);
+        assert_eq!(Lang::resolve(None, Some("de")), Lang::En);
+        assert_eq!(Lang::resolve(None, None), Lang::En);
+        assert_eq!(Lang::parse("RU"), Some(Lang::Ru));
+    }
+
+    #[test]
+    fn test_untranslated_keys_fall_back_to_english() {
+        assert_eq!(Lang::Es.text(Key::OnlyAdminsAddRules), "Solo los administradores pueden añadir reglas.");
+        assert_eq!(Lang::Ru.text(Key::LanguageUsage), Lang::En.text(Key::LanguageUsage));
+    }
+
+    #[test]
+    fn test_format_fills_placeholders_in_order() {
+        assert_eq!(Lang::En.format(Key::WarningsMany, &[&42, &3]), "User 42 has 3 warnings.");
+        assert_eq!(Lang::Ru.format(Key::WarningsOne, &[&42, &1]), "Предупреждений у пользователя 42: 1.");
+    }
+}
@@ -2,5 +2,30 @@
 //!
 //! This library provides functionality to access to functionalities present in rspamd, rules and utils files. 
 
+pub mod backup;
+pub mod bayes;
+pub mod blocklist;
+pub mod calibration;
+pub mod captcha;
+pub mod cleanup;
+pub mod config;
+pub mod detectors;
+pub mod duplicates;
+pub mod edits;
+pub mod flood;
+pub mod gibberish;
+pub mod lang;
+pub mod links;
+pub mod media;
+pub mod metrics;
+pub mod migrations;
+pub mod rate_limiter;
+pub mod repost;
+pub mod reputation;
 pub mod rules;
-pub mod utils;
\ No newline at end of file
+pub mod schedule;
+pub mod suite;
+pub mod sync;
+pub mod telegram;
+pub mod utils;
+pub mod webhook;
\ No newline at end of file
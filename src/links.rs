@@ -0,0 +1,558 @@
+//! A module for scoring links by their structure, without any network calls.
+//!
+//! Malicious links tend to hide their destination: URL shorteners, raw IP-address
+//! hosts, long chains of subdomains, and punycode (`xn--`) domains that can imitate
+//! well-known sites. Each of these signals is detected offline from the link text
+//! and contributes a configurable weight to the message score.
+//!
+//! `LinkCount` scores every link generically, whatever its domain, and messages
+//! carrying many links more aggressively.
+//!
+//! `NewSenderLinks` adds a flat bonus to links from senders with only a few messages,
+//! since fresh accounts dropping a link are the most common spam pattern.
+//!
+//! `LinkRepetition` additionally remembers the domains each user recently linked to,
+//! so promoters who drop the same link across many messages are scored even when
+//! the surrounding text looks harmless.
+
+use crate::config::env_parse;
+use crate::sync::LockExt;
+use std::collections::{HashMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Shortener domains flagged by default.
+const DEFAULT_SHORTENERS: [&str; 9] = [
+    "bit.ly",
+    "tinyurl.com",
+    "t.co",
+    "goo.gl",
+    "is.gd",
+    "cutt.ly",
+    "ow.ly",
+    "rebrand.ly",
+    "shorturl.at",
+];
+
+/// Maximum number of recent links remembered per user.
+const MAX_LINKS_PER_USER: usize = 32;
+/// Maximum number of users whose link history is kept.
+const MAX_TRACKED_USERS: usize = 50_000;
+
+/// A link found in a message, split into the parts the heuristics look at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    /// The lowercase host name, without port, credentials or a leading `www.`.
+    pub host: String,
+    /// Whether the link was written with an explicit `http://` or `https://` scheme.
+    pub has_scheme: bool,
+}
+
+/// Weights and settings for the structural link heuristics.
+#[derive(Debug, Clone)]
+pub struct LinkHeuristics {
+    /// Known URL shortener domains (subdomains also match).
+    pub shorteners: Vec<String>,
+    /// Score added for each link to a URL shortener.
+    pub shortener_score: f32,
+    /// Score added for each link whose host is a raw IPv4 address.
+    pub ip_score: f32,
+    /// Score added for each link whose host has more than `max_labels` labels.
+    pub subdomain_score: f32,
+    /// Maximum number of host labels before a link counts as having excessive subdomains.
+    pub max_labels: usize,
+    /// Score added for each link containing a punycode (`xn--`) label.
+    pub punycode_score: f32,
+}
+
+impl Default for LinkHeuristics {
+    fn default() -> Self {
+        Self {
+            shorteners: DEFAULT_SHORTENERS.iter().map(|s| s.to_string()).collect(),
+            shortener_score: 3.0,
+            ip_score: 4.0,
+            subdomain_score: 2.0,
+            max_labels: 4,
+            punycode_score: 4.0,
+        }
+    }
+}
+
+impl LinkHeuristics {
+    /// Builds the heuristics configuration from environment variables.
+    ///
+    /// * `LINK_SHORTENERS` - Comma-separated shortener domains, replacing the defaults.
+    /// * `LINK_SHORTENER_SCORE` - Weight per shortener link (default 3.0).
+    /// * `LINK_IP_SCORE` - Weight per raw-IP link (default 4.0).
+    /// * `LINK_SUBDOMAIN_SCORE` - Weight per link with excessive subdomains (default 2.0).
+    /// * `LINK_MAX_LABELS` - Host labels allowed before subdomains are excessive (default 4).
+    /// * `LINK_PUNYCODE_SCORE` - Weight per punycode link (default 4.0).
+    ///
+    /// Setting a weight to 0 disables that heuristic.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let weight = |key: &str, default: f32| {
+            std::env::var(key)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            shorteners: std::env::var("LINK_SHORTENERS")
+                .map(|v| {
+                    v.split(',')
+                        .map(|d| d.trim().to_lowercase())
+                        .filter(|d| !d.is_empty())
+                        .collect()
+                })
+                .unwrap_or_else(|_| defaults.shorteners.clone()),
+            shortener_score: weight("LINK_SHORTENER_SCORE", defaults.shortener_score),
+            ip_score: weight("LINK_IP_SCORE", defaults.ip_score),
+            subdomain_score: weight("LINK_SUBDOMAIN_SCORE", defaults.subdomain_score),
+            max_labels: std::env::var("LINK_MAX_LABELS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.max_labels),
+            punycode_score: weight("LINK_PUNYCODE_SCORE", defaults.punycode_score),
+        }
+    }
+
+    /// Scores every link in a message by its structure.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    ///
+    /// # Returns
+    /// * `f32` - The sum of the weights of all signals found in all links.
+    pub fn check(&self, text: &str) -> f32 {
+        self.check_allowing(text, &[])
+    }
+
+    /// Scores every link in a message that is not on an allowlist.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    /// * `allowed_domains` - Trusted domains; links to them or their subdomains score nothing.
+    ///
+    /// # Returns
+    /// * `f32` - The sum of the weights of all signals found in the remaining links.
+    pub fn check_allowing(&self, text: &str, allowed_domains: &[String]) -> f32 {
+        untrusted_links(text, allowed_domains)
+            .iter()
+            .map(|link| self.score_link(link))
+            .sum()
+    }
+
+    /// Scores a single link.
+    fn score_link(&self, link: &Link) -> f32 {
+        let mut score = 0.0;
+        if self.shorteners.iter().any(|s| host_matches(&link.host, s)) {
+            score += self.shortener_score;
+        }
+        if link.host.parse::<Ipv4Addr>().is_ok() {
+            score += self.ip_score;
+        } else if link.host.split('.').count() > self.max_labels {
+            score += self.subdomain_score;
+        }
+        if link.host.split('.').any(|label| label.starts_with("xn--")) {
+            score += self.punycode_score;
+        }
+        score
+    }
+}
+
+/// Scores links by count, without a rule per domain.
+#[derive(Debug, Clone)]
+pub struct LinkCount {
+    /// Score added for each link, with or without a scheme.
+    pub per_link_score: f32,
+    /// Links a message may carry before each further link also adds `excess_score`.
+    pub max_links: usize,
+    /// Extra score added for each link beyond `max_links`.
+    pub excess_score: f32,
+}
+
+impl Default for LinkCount {
+    fn default() -> Self {
+        Self {
+            per_link_score: 1.0,
+            max_links: 2,
+            excess_score: 3.0,
+        }
+    }
+}
+
+impl LinkCount {
+    /// Builds the detector from environment variables.
+    ///
+    /// * `LINK_SCORE` - Weight per link (default 1.0; 0 disables it).
+    /// * `LINK_MAX_COUNT` - Links per message before the excess weight applies (default 2).
+    /// * `LINK_EXCESS_SCORE` - Extra weight per link beyond `LINK_MAX_COUNT` (default 3.0).
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            per_link_score: env_parse("LINK_SCORE", defaults.per_link_score),
+            max_links: env_parse("LINK_MAX_COUNT", defaults.max_links),
+            excess_score: env_parse("LINK_EXCESS_SCORE", defaults.excess_score),
+        }
+    }
+
+    /// Scores a message by the number of links it contains.
+    ///
+    /// Links are found by `extract_links`, so bare domains such as `example.com`
+    /// count as well as URLs with a scheme.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    /// * `allowed_domains` - Trusted domains; links to them or their subdomains are not counted.
+    ///
+    /// # Returns
+    /// * `f32` - `per_link_score` per link plus `excess_score` per link beyond `max_links`.
+    pub fn check_allowing(&self, text: &str, allowed_domains: &[String]) -> f32 {
+        let count = untrusted_links(text, allowed_domains).len();
+        let excess = count.saturating_sub(self.max_links);
+        self.per_link_score * count as f32 + self.excess_score * excess as f32
+    }
+}
+
+/// Scores links from senders who have sent only a few messages.
+#[derive(Debug, Clone)]
+pub struct NewSenderLinks {
+    /// Messages a sender needs before their links stop earning the bonus; 0 disables it.
+    pub min_messages: i64,
+    /// Score added once to a message with at least one link from a sender below `min_messages`.
+    pub bonus: f32,
+}
+
+impl Default for NewSenderLinks {
+    fn default() -> Self {
+        Self {
+            min_messages: 5,
+            bonus: 2.0,
+        }
+    }
+}
+
+impl NewSenderLinks {
+    /// Builds the detector from environment variables.
+    ///
+    /// * `NEW_SENDER_LINK_MIN_MESSAGES` - Messages needed before links are scored normally (default 5; 0 disables it).
+    /// * `NEW_SENDER_LINK_BONUS` - Score added to links from senders below it (default 2.0).
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            min_messages: env_parse("NEW_SENDER_LINK_MIN_MESSAGES", defaults.min_messages),
+            bonus: env_parse("NEW_SENDER_LINK_BONUS", defaults.bonus),
+        }
+    }
+
+    /// Scores a message containing links by how many messages its sender has sent.
+    ///
+    /// # Arguments
+    /// * `message_count` - Messages the sender had sent before this one, from the `senders` table.
+    /// * `text` - The message text.
+    /// * `allowed_domains` - Trusted domains; links to them do not earn the bonus.
+    ///
+    /// # Returns
+    /// * `f32` - `bonus` if the sender is below `min_messages` and the message has an untrusted link, otherwise 0.
+    pub fn check(&self, message_count: i64, text: &str, allowed_domains: &[String]) -> f32 {
+        if message_count < self.min_messages && !untrusted_links(text, allowed_domains).is_empty() {
+            self.bonus
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Scores users who keep posting links to the same domain.
+#[derive(Debug)]
+pub struct LinkRepetition {
+    /// Occurrences of the same domain within the window at which scoring starts.
+    pub repeat_count: usize,
+    /// How long a posted link is remembered.
+    pub window: Duration,
+    /// Score added per occurrence at or beyond `repeat_count`, escalating with each repeat.
+    pub score: f32,
+    /// Recently posted domains per user, oldest first.
+    history: Mutex<HashMap<u64, VecDeque<(String, Instant)>>>,
+}
+
+impl Default for LinkRepetition {
+    fn default() -> Self {
+        Self::new(3, Duration::from_secs(3600), 2.0)
+    }
+}
+
+impl LinkRepetition {
+    /// Creates a tracker with an empty history.
+    ///
+    /// # Arguments
+    /// * `repeat_count` - Occurrences within the window at which scoring starts.
+    /// * `window` - How long a posted link is remembered.
+    /// * `score` - Score per occurrence at or beyond `repeat_count`.
+    pub fn new(repeat_count: usize, window: Duration, score: f32) -> Self {
+        Self {
+            repeat_count,
+            window,
+            score,
+            history: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Builds the tracker from environment variables.
+    ///
+    /// * `LINK_REPEAT_COUNT` - Occurrences of a domain at which scoring starts (default 3).
+    /// * `LINK_REPEAT_WINDOW_SECS` - How long posted links are remembered (default 3600).
+    /// * `LINK_REPEAT_SCORE` - Weight per repeated occurrence (default 2.0; 0 disables).
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self::new(
+            env_parse("LINK_REPEAT_COUNT", defaults.repeat_count),
+            Duration::from_secs(env_parse("LINK_REPEAT_WINDOW_SECS", defaults.window.as_secs())),
+            env_parse("LINK_REPEAT_SCORE", defaults.score),
+        )
+    }
+
+    /// Records the links in a message and scores repeated domains.
+    ///
+    /// The n-th occurrence of a domain within the window scores
+    /// `score * (n - repeat_count + 1)` once `n` reaches `repeat_count`, so the
+    /// score escalates with every further repeat.
+    ///
+    /// # Arguments
+    /// * `user_id` - The sender of the message.
+    /// * `text` - The message text.
+    /// * `now` - The time the message was received.
+    /// * `allowed_domains` - Trusted domains, which are neither recorded nor scored.
+    ///
+    /// # Returns
+    /// * `f32` - The repetition score for the message.
+    pub fn check(&self, user_id: u64, text: &str, now: Instant, allowed_domains: &[String]) -> f32 {
+        if self.score <= 0.0 || self.repeat_count == 0 {
+            return 0.0;
+        }
+        let links = untrusted_links(text, allowed_domains);
+        if links.is_empty() {
+            return 0.0;
+        }
+        let mut history = self.history.lock_or_recover();
+        if !history.contains_key(&user_id) && history.len() >= MAX_TRACKED_USERS {
+            let window = self.window;
+            history.retain(|_, seen| seen.back().is_some_and(|(_, at)| now.duration_since(*at) < window));
+            if history.len() >= MAX_TRACKED_USERS {
+                return 0.0;
+            }
+        }
+        let seen = history.entry(user_id).or_default();
+        while let Some((_, at)) = seen.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            seen.pop_front();
+        }
+        let mut score = 0.0;
+        for link in links {
+            let occurrences = seen.iter().filter(|(host, _)| *host == link.host).count() + 1;
+            if occurrences >= self.repeat_count {
+                score += self.score * (occurrences - self.repeat_count + 1) as f32;
+            }
+            if seen.len() >= MAX_LINKS_PER_USER {
+                seen.pop_front();
+            }
+            seen.push_back((link.host, now));
+        }
+        score
+    }
+}
+
+/// Returns `true` if `host` is `domain` or one of its subdomains.
+pub fn host_matches(host: &str, domain: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+/// Normalizes a domain given by an admin, e.g. `https://www.Example.com/path` to `example.com`.
+///
+/// # Returns
+/// * `Option<String>` - The lowercase host, or `None` if the input is not a domain.
+pub fn normalize_domain(input: &str) -> Option<String> {
+    match extract_links(input.trim()).as_slice() {
+        [link] => Some(link.host.clone()),
+        _ => None,
+    }
+}
+
+/// Extracts the links from a message that do not point to an allowlisted domain.
+fn untrusted_links(text: &str, allowed_domains: &[String]) -> Vec<Link> {
+    extract_links(text)
+        .into_iter()
+        .filter(|link| !allowed_domains.iter().any(|domain| host_matches(&link.host, domain)))
+        .collect()
+}
+
+/// Extracts the links from a message.
+///
+/// A token counts as a link when it has an `http://`/`https://` scheme, starts with
+/// `www.`, or looks like `name.tld` with an alphabetic top-level domain. Raw IP hosts
+/// are only recognised with a scheme, so version numbers like `1.2.3.4` are ignored.
+///
+/// # Arguments
+/// * `text` - The message text.
+///
+/// # Returns
+/// * `Vec<Link>` - The links found, in order of appearance.
+pub fn extract_links(text: &str) -> Vec<Link> {
+    text.split_whitespace().filter_map(parse_link).collect()
+}
+
+/// Parses a single whitespace-separated token into a link, if it is one.
+fn parse_link(token: &str) -> Option<Link> {
+    let token = token.trim_matches(|c: char| matches!(c, '(' | ')' | '<' | '>' | '"' | '\'' | ',' | '!' | '?'));
+    let lower = token.to_lowercase();
+    let (rest, has_scheme) = match lower.split_once("://") {
+        Some((scheme, rest)) if scheme == "http" || scheme == "https" => (rest, true),
+        Some(_) => return None,
+        None => (lower.as_str(), false),
+    };
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host_port = authority.rsplit('@').next()?;
+    let host = host_port.split(':').next()?.trim_end_matches('.');
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    let labels: Vec<&str> = host.split('.').collect();
+    if labels.len() < 2 || labels.iter().any(|l| l.is_empty()) {
+        return None;
+    }
+    if !has_scheme {
+        let tld = labels[labels.len() - 1];
+        if tld.len() < 2 || !tld.chars().all(|c| c.is_ascii_alphabetic()) {
+            return None;
+        }
+    }
+    Some(Link {
+        host: host.to_string(),
+        has_scheme,
+    })
+}
+
+/// Unit tests for the `links` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_punycode_domain_is_scored() {
+        let heuristics = LinkHeuristics::default();
+        assert_eq!(heuristics.check("login at https://xn--pypal-4ve.com/account"), 4.0);
+    }
+
+    #[test]
+    fn test_raw_ip_url_is_scored() {
+        let heuristics = LinkHeuristics::default();
+        assert_eq!(heuristics.check("claim here: http://192.168.10.4:8080/claim"), 4.0);
+        // Version numbers without a scheme are not links.
+        assert_eq!(heuristics.check("upgrade to 1.2.3.4 today"), 0.0);
+    }
+
+    #[test]
+    fn test_normal_domain_is_not_scored() {
+        let heuristics = LinkHeuristics::default();
+        assert_eq!(heuristics.check("docs at https://www.rust-lang.org/learn and example.com"), 0.0);
+    }
+
+    #[test]
+    fn test_shortener_and_subdomains_are_scored() {
+        let heuristics = LinkHeuristics::default();
+        assert_eq!(heuristics.check("see bit.ly/abc123"), 3.0);
+        assert_eq!(heuristics.check("http://secure.login.account.verify.example.com"), 2.0);
+    }
+
+    #[test]
+    fn test_repeated_link_escalates() {
+        let repetition = LinkRepetition::new(3, Duration::from_secs(3600), 2.0);
+        let start = Instant::now();
+        assert_eq!(repetition.check(1, "check https://promo.example/deal", start, &[]), 0.0);
+        assert_eq!(repetition.check(1, "hello everyone", start, &[]), 0.0);
+        assert_eq!(repetition.check(1, "again promo.example/deal", start, &[]), 0.0);
+        assert_eq!(repetition.check(1, "last chance promo.example", start, &[]), 2.0);
+        assert_eq!(repetition.check(1, "promo.example!", start, &[]), 4.0);
+        // Other users have their own history.
+        assert_eq!(repetition.check(2, "promo.example", start, &[]), 0.0);
+        // Links outside the window are forgotten.
+        assert_eq!(repetition.check(1, "promo.example", start + Duration::from_secs(7200), &[]), 0.0);
+    }
+
+    #[test]
+    fn test_different_links_do_not_escalate() {
+        let repetition = LinkRepetition::new(3, Duration::from_secs(3600), 2.0);
+        let now = Instant::now();
+        for link in ["a.example", "b.example", "c.example", "d.example"] {
+            assert_eq!(repetition.check(1, link, now, &[]), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_allowlisted_domain_is_not_scored() {
+        let heuristics = LinkHeuristics::default();
+        let allowed = vec!["bit.ly".to_string()];
+        assert_eq!(heuristics.check_allowing("see bit.ly/abc and t.co/xyz", &allowed), 3.0);
+
+        let repetition = LinkRepetition::new(2, Duration::from_secs(3600), 2.0);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(repetition.check(1, "docs.rs/tokio", now, &["docs.rs".to_string()]), 0.0);
+        }
+        repetition.check(1, "promo.example", now, &allowed);
+        assert_eq!(repetition.check(1, "promo.example", now, &allowed), 2.0);
+    }
+
+    #[test]
+    fn test_links_are_counted_with_or_without_scheme() {
+        let count = LinkCount::default();
+        assert_eq!(count.check_allowing("no links here", &[]), 0.0);
+        assert_eq!(count.check_allowing("visit example.com", &[]), 1.0);
+        assert_eq!(count.check_allowing("https://a.example and b.example", &[]), 2.0);
+        // Links beyond the allowance are scored more aggressively.
+        assert_eq!(count.check_allowing("a.example b.example c.example d.example", &[]), 10.0);
+        assert_eq!(count.check_allowing("docs.rs/tokio", &["docs.rs".to_string()]), 0.0);
+    }
+
+    #[test]
+    fn test_links_from_new_senders_earn_a_bonus() {
+        let mut new_senders = NewSenderLinks::default();
+        assert_eq!(new_senders.check(0, "join example.com/free", &[]), 2.0);
+        assert_eq!(new_senders.check(4, "join example.com/free", &[]), 2.0);
+        assert_eq!(new_senders.check(5, "join example.com/free", &[]), 0.0);
+        assert_eq!(new_senders.check(0, "hello everyone", &[]), 0.0);
+        assert_eq!(new_senders.check(0, "docs at rust-lang.org", &["rust-lang.org".to_string()]), 0.0);
+        new_senders.min_messages = 0;
+        assert_eq!(new_senders.check(0, "join example.com/free", &[]), 0.0);
+    }
+
+    #[test]
+    fn test_normalize_domain() {
+        assert_eq!(normalize_domain("https://www.Example.com/path"), Some("example.com".to_string()));
+        assert_eq!(normalize_domain("github.com"), Some("github.com".to_string()));
+        assert_eq!(normalize_domain("youtube.com/"), Some("youtube.com".to_string()));
+        assert_eq!(normalize_domain("not a domain"), None);
+    }
+
+    #[test]
+    fn test_allowlisted_links_are_not_counted() {
+        let allowed = vec!["youtube.com".to_string()];
+        let scorer = LinkCount::default();
+        assert_eq!(scorer.check_allowing("www.youtube.com/watch?v=1 and https://m.youtube.com/", &allowed), 0.0);
+        assert!(scorer.check_allowing("https://youtube.com.evil.example/watch", &allowed) > 0.0);
+    }
+
+    #[test]
+    fn test_extract_links() {
+        let links = extract_links("(https://user@Sub.Example.com:443/path?q=1), www.test.org!");
+        assert_eq!(
+            links,
+            vec![
+                Link { host: "sub.example.com".to_string(), has_scheme: true },
+                Link { host: "test.org".to_string(), has_scheme: false },
+            ]
+        );
+    }
+}
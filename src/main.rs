@@ -1,8 +1,47 @@
 use teloxide::{prelude::*, utils::command::BotCommands};
+use teloxide::net::Download;
+use teloxide::update_listeners::webhooks;
+use teloxide::types::{
+    CallbackQuery, ChatId, ChatPermissions, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MessageId, ParseMode, User, UserId,
+};
 use dotenv::dotenv;
-use std::sync::Arc;
-use spam_bot_mvp::rules::RuleManager;
-use spam_bot_mvp::utils::{is_admin, notify_admins};
+use regex::Regex;
+use std::sync::{Arc, Mutex};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use spam_bot_mvp::backup::{
+    list_backups, parse_backup_schedule, restore_backup, set_backup_schedule, spawn_backup_task,
+};
+use spam_bot_mvp::blocklist::{BlocklistClient, BlocklistConfig};
+use spam_bot_mvp::calibration::{best_threshold, default_thresholds, evaluate_thresholds};
+use spam_bot_mvp::captcha::{encode_captcha, parse_captcha, CaptchaChallenges, CAPTCHA_PREFIX};
+use spam_bot_mvp::cleanup::{spawn_sender_cleanup, SenderCleanup};
+use spam_bot_mvp::config::Config;
+use spam_bot_mvp::detectors::Detectors;
+use spam_bot_mvp::edits::MessageVerdicts;
+use spam_bot_mvp::flood::LoadShedder;
+use spam_bot_mvp::lang::{Key, Lang};
+use spam_bot_mvp::links::normalize_domain;
+use spam_bot_mvp::media::{message_media, parse_media_block};
+use spam_bot_mvp::metrics::{spawn_prometheus_server, spawn_statsd_exporter, Metrics, PrometheusConfig, StatsdConfig};
+use spam_bot_mvp::rate_limiter::{restore_rate_limits, spawn_rate_limit_persistence, RateLimiter};
+use spam_bot_mvp::repost::RepostPolicy;
+use spam_bot_mvp::reputation::history_multiplier;
+use spam_bot_mvp::rules::{
+    net_score, now_unix, spawn_bayes_flush, spawn_rule_hit_flush, ActionPolicy, LuaRulesError, MatchMode, RuleManager, RuleManagerError, DEFAULT_CATEGORY, IN_MEMORY_PATH,
+    SELF_CHECK_MESSAGE,
+};
+use spam_bot_mvp::schedule::{format_days, parse_schedule, spawn_profile_scheduler, SystemClock};
+use spam_bot_mvp::sync::LockExt;
+use spam_bot_mvp::suite::{parse_suite, run_suite, DEFAULT_SUITE};
+use spam_bot_mvp::telegram::with_retry;
+use spam_bot_mvp::utils::{
+    add_rule_keyboard, auto_ban, count_human_admins, delete_message, effective_action, format_flagged_message, format_report_reply, format_score_breakdown, format_score_history, format_sender_record, format_test_result, format_top_senders, format_user_report, format_welcome, is_admin, is_linked_channel_post,
+    is_owner, lockdown_engaged_notice, message_admins, message_sender, message_text, notify_admins, parse_add_rule, parse_duration, parse_mute_duration,
+    parse_rule_batch, sender_burst_summary, spawn_lockdown_monitor, spawn_notification_summaries, spawn_pause_expiry, spawn_sender_burst_summaries, split_message, suppressed_summary, AdminCache, MessageSender, NotificationAction, NotificationThrottle, PendingConfirmations, SenderCooldown, SpamAction, SpamCallback,
+    ADD_RULE_PREFIX, DEFAULT_MUTE_DURATION, TELEGRAM_MESSAGE_LIMIT,
+};
+use spam_bot_mvp::webhook::WebhookConfig;
 
 /// The main entry point for the Telegram spam detection bot.
 ///
@@ -11,7 +50,8 @@ use spam_bot_mvp::utils::{is_admin, notify_admins};
 /// `rules` module for spam detection logic and the `utils` module for admin-related utilities.
 ///
 /// The bot supports the following features:
-/// - Responds to commands (`/start`, `/report`, `/add_rule`) for bot interaction.
+/// - Responds to commands (`/start`, `/report`, `/add_rule`, `/calibrate`, `/scheduleprofile`) for bot interaction.
+/// - Switches rule profiles on admin-defined recurring schedules.
 /// - Automatically checks incoming messages for spam using custom Lua rules.
 /// - Notifies admins when spam is detected, with a fallback to group notifications.
 /// - Logs bot activity and errors using the `log` crate and `env_logger`.
@@ -29,7 +69,7 @@ use spam_bot_mvp::utils::{is_admin, notify_admins};
 /// # Examples
 /// To run the bot:
 /// 1. Create a `.env` file with `TELOXIDE_TOKEN=your_bot_token`.
-/// 2. Ensure a `rules.db` SQLite database exists (created automatically if not).
+/// 2. Optionally set `DATABASE_PATH` (default `rules.db`, created automatically if missing).
 /// 3. Run the bot with `cargo run`.
 ///
 /// The bot will respond to commands in Telegram chats and detect spam messages.
@@ -40,148 +80,2728 @@ enum Command {
     #[command(description = "Start the bot")]
     Start,
 
-    /// Reports a message as spam by replying to it.
-    #[command(description = "Report a message as spam")]
-    Report,
+    /// Lists every command with its description, generated from this enum.
+    #[command(description = "Show this help message")]
+    Help,
+
+    /// Reports a message as spam by replying to it, or scores spam text given as
+    /// the argument, e.g. a sample that cannot be replied to.
+    ///
+    /// Format: `/report` as a reply, or `/report <spam text>`.
+    #[command(description = "Report a message as spam (reply to it, or pass its text)")]
+    Report(String),
+
+    /// Scores sample text as a message check would, without acting on it (admin only).
+    ///
+    /// Nothing is deleted, scored against a sender or reported, so rules can be
+    /// tried out safely. Format: `/testmessage <text>`.
+    #[command(description = "Score text against the rules without acting on it (admin only, format: /testmessage <text>)")]
+    TestMessage(String),
 
     /// Adds a custom spam rule (admin only).
     ///
-    /// Format: `/add_rule <keyword> <score>`.
-    /// Example: `/add_rule spam 10.0` adds a rule to flag "spam" with a score of 10.0.
-    #[command(description = "Add a custom spam rule (admin only, format: /add_rule <keyword> <score>)")]
+    /// Format: `/add_rule <keyword> <score> [category]`; the category defaults to "default".
+    /// Example: `/add_rule airdrop 10.0 crypto` adds a rule to flag "airdrop" with a score of 10.0.
+    /// Keywords with spaces can be quoted, e.g. `/add_rule "free money" 10`; see `parse_add_rule`.
+    #[command(description = "Add a custom spam rule (admin only, format: /add_rule <keyword> <score> [category], quoting keywords with spaces)")]
     AddRule(String),
+
+    /// Adds several keyword rules at once (bot operator only).
+    ///
+    /// Format: `/add_rules <keyword:score>, ...`, with pairs separated by commas or newlines.
+    /// The batch is all or nothing: if any pair fails to parse, no rule is added.
+    /// Example: `/add_rules airdrop:10, casino:4.5` adds two rules in the default category.
+    #[command(rename = "add_rules", description = "Add several rules at once (operator only, format: /add_rules <keyword:score>, ...)")]
+    AddRules(String),
+
+    /// Adds a custom spam rule matching a regular expression (bot operator only).
+    ///
+    /// Format: `/add_regex_rule <pattern> <score>`; the last word is the score, so the
+    /// pattern may contain spaces.
+    /// Example: `/add_regex_rule (?i)f\s*r\s*e\s*e 5.0` catches spaced-out "free".
+    #[command(rename = "add_regex_rule", description = "Add a regex spam rule (operator only, format: /add_regex_rule <pattern> <score>)")]
+    AddRegexRule(String),
+
+    /// Removes the custom spam rule with the given keyword (bot operator only).
+    ///
+    /// Format: `/remove_rule <keyword>`.
+    #[command(rename = "remove_rule", description = "Remove a custom spam rule (operator only, format: /remove_rule <keyword>)")]
+    RemoveRule(String),
+
+    /// Enables or disables every rule in a category (bot operator only).
+    ///
+    /// Format: `/set_category <category> <on|off>`.
+    #[command(rename = "set_category", description = "Enable or disable a rule category (operator only, format: /set_category <category> <on|off>)")]
+    SetCategory(String),
+
+    /// Sets how a keyword rule matches messages (bot operator only).
+    ///
+    /// Format: `/set_rule_mode <keyword> <substring|whole_word|prefix|fuzzy>`. Rules match
+    /// substrings by default; `whole_word` stops "cialis" from matching "specialist", and
+    /// `fuzzy` catches obfuscations such as "v i a g r a" and "cl1ck".
+    #[command(rename = "set_rule_mode", description = "Set how a keyword rule matches (operator only, format: /set_rule_mode <keyword> <substring|whole_word|prefix|fuzzy>)")]
+    SetRuleMode(String),
+
+    /// Sets the language the bot answers in in this chat (admin only).
+    ///
+    /// Format: `/set_language <en|ru|es>`. Without a chat language, replies follow the
+    /// language of the user the bot is answering, falling back to English.
+    #[command(rename = "set_language", description = "Set the language of the bot's replies in this chat (admin only, format: /set_language <en|ru|es>)")]
+    SetLanguage(String),
+
+    /// Sets what the bot does about spam in this chat (admin only).
+    ///
+    /// Format: `/set_action <notify|delete|mute|ban|default>`. Every policy notifies the
+    /// admins and warns the sender; `default` follows the bot-wide settings again, and
+    /// without an argument the current policy is shown.
+    #[command(rename = "set_action", description = "Set what happens to spam in this chat (admin only, format: /set_action <notify|delete|mute|ban|default>)")]
+    SetAction(String),
+
+    /// Sets the message new members of this chat are greeted with (admin only).
+    ///
+    /// Format: `/set_welcome <text>`, where `{name}` mentions the new members; `off` stops
+    /// greeting them, and without an argument the current message is shown. Chats do
+    /// not greet new members until one is set.
+    #[command(rename = "set_welcome", description = "Greet new members of this chat, mentioning them with {name} (admin only, format: /set_welcome <text|off>)")]
+    SetWelcome(String),
+
+    /// Pauses spam moderation in this chat, e.g. during an AMA (admin only).
+    ///
+    /// Format: `/pause [duration]`, e.g. `/pause 2h`; without a duration, moderation
+    /// stays paused until `/resume`. Rules and settings are kept, and the chat is told.
+    #[command(description = "Pause spam moderation in this chat (admin only, format: /pause [duration])")]
+    Pause(String),
+
+    /// Resumes spam moderation in this chat and tells the chat (admin only).
+    #[command(description = "Resume spam moderation in this chat (admin only)")]
+    Resume,
+
+    /// Lists every rule with the number of checked messages it matched, most first (admin only).
+    #[command(rename = "rule_stats", description = "List rules by how many messages they matched (admin only)")]
+    RuleStats,
+
+    /// Lists every custom spam rule as `keyword => score` (admin only).
+    #[command(rename = "list_rules", description = "List the custom spam rules (admin only)")]
+    ListRules,
+
+    /// Exports every rule as JSON, as a message or as a `rules.json` file when it is
+    /// too long for one (admin only).
+    #[command(rename = "export_rules", description = "Export the spam rules as JSON (admin only)")]
+    ExportRules,
+
+    /// Imports rules from JSON, replacing rules with the same keyword (bot operator only).
+    ///
+    /// Format: `/import_rules <json>`, or reply to a JSON file with `/import_rules`.
+    #[command(rename = "import_rules", description = "Import spam rules from JSON (operator only, format: /import_rules <json>, or reply to a file)")]
+    ImportRules(String),
+
+    /// Reports precision/recall of the rules over labeled messages at a range of
+    /// thresholds and suggests the threshold maximizing F1 (admin only).
+    #[command(description = "Calibrate the spam threshold against labeled messages (admin only)")]
+    Calibrate,
+
+    /// Schedules a recurring switch to a rule profile (bot operator only).
+    ///
+    /// Format: `/scheduleprofile <profile> <daily|weekdays|weekends|mon,tue,...> <HH:MM>` (UTC).
+    /// Example: `/scheduleprofile strict weekends 18:00` evaluates `rules_strict.lua`
+    /// from 18:00 every Saturday and Sunday until another schedule fires.
+    #[command(description = "Schedule a recurring rule profile switch (operator only, format: /scheduleprofile <profile> <days> <HH:MM>)")]
+    ScheduleProfile(String),
+
+    /// Attaches a moderator note to a user by replying to one of their messages (admin only).
+    ///
+    /// Format: `/note <text>`. Notes are shown by `/score` and in spam notifications.
+    #[command(description = "Attach a note to the replied-to user (admin only, format: /note <text>)")]
+    Note(String),
+
+    /// Shows the spam score and notes of a user by replying to one of their messages (admin only).
+    #[command(description = "Show the replied-to user's spam score and notes (admin only)")]
+    Score,
+
+    /// Temporarily mutes a user by replying to one of their messages (admin only).
+    ///
+    /// Format: `/mute [duration]`, e.g. `/mute 30m` or `/mute 2h`; defaults to one hour.
+    #[command(description = "Mute the replied-to user (admin only, format: /mute [30m|2h|7d], default 1h)")]
+    Mute(String),
+
+    /// Whitelists a user in this group by replying to one of their messages, so their
+    /// messages there bypass spam checks (admin only, not in private chats).
+    #[command(description = "Exempt the replied-to user from spam checks in this chat (admin only)")]
+    Whitelist,
+
+    /// Removes the replied-to user from this group's whitelist (admin only).
+    #[command(description = "Remove the replied-to user from this chat's whitelist (admin only)")]
+    Unwhitelist,
+
+    /// Resets a user's spam score and lifts an automatic ban (admin only).
+    ///
+    /// Format: `/reset_score <user id>`, or reply to one of the user's messages.
+    #[command(rename = "reset_score", description = "Reset a user's spam score and unban them (admin only, format: /reset_score <user id>)")]
+    ResetScore(String),
+
+    /// Unbans a user from this chat and removes the record of their ban (admin only).
+    ///
+    /// Format: `/unban <user id>`, or reply to one of the user's messages.
+    #[command(description = "Unban a user and show why they were banned (admin only, format: /unban <user id>)")]
+    Unban(String),
+
+    /// Warns a user by replying to one of their messages, escalating to a mute or ban
+    /// at `WARN_MUTE_AT` / `WARN_BAN_AT` warnings (admin only).
+    #[command(description = "Warn the replied-to user; enough warnings mute or ban them (admin only)")]
+    Warn,
+
+    /// Clears a user's warnings (admin only).
+    ///
+    /// Format: `/clearwarns <user id>`, or reply to one of the user's messages.
+    #[command(description = "Clear a user's warnings (admin only, format: /clearwarns <user id>)")]
+    ClearWarns(String),
+
+    /// Shows the spam score, message count and spam ratio of a user by replying to
+    /// one of their messages (admin only).
+    #[command(description = "Show the replied-to user's spam score, message count and spam ratio (admin only)")]
+    Stats,
+
+    /// Shows everything the bot stores about a user in this chat: spam score, messages,
+    /// warnings, whitelisting and bans (admin only).
+    ///
+    /// Format: `/whois <user id>`, or reply to one of the user's messages.
+    #[command(description = "Show everything the bot knows about a user (admin only, format: /whois <user id>)")]
+    Whois(String),
+
+    /// Sets the score at which `/report` considers a message spam in this chat (admin only).
+    ///
+    /// Format: `/setreportthreshold <score>`. This is independent of the automatic
+    /// detection threshold.
+    #[command(description = "Set the /report spam threshold (admin only, format: /setreportthreshold <score>)")]
+    SetReportThreshold(f32),
+
+    /// Sets the spam detection threshold of this chat (admin only).
+    ///
+    /// Format: `/set_threshold <score>`. Chats without their own threshold use
+    /// `SPAM_THRESHOLD`.
+    #[command(rename = "set_threshold", description = "Set this chat's spam threshold (admin only, format: /set_threshold <score>)")]
+    SetThreshold(f32),
+
+    /// Resets the spam score of every user in this chat below a threshold (admin only).
+    ///
+    /// Format: `/batchpardon <score>`. The command must be repeated within a minute
+    /// to confirm, since it affects many users at once.
+    #[command(description = "Reset all spam scores below a threshold (admin only, format: /batchpardon <score>)")]
+    BatchPardon(i32),
+
+    /// Shows how often each detector fired and how much it contributed to the
+    /// spam detected in this chat over the audit window (admin only).
+    #[command(description = "Show per-detector contributions to recent spam (admin only)")]
+    DetectorStats,
+
+    /// Lists the most recently flagged messages in this chat, newest first (admin only).
+    ///
+    /// Format: `/recent_spam [n]`; `n` defaults to 10 and is capped at 50.
+    #[command(rename = "recent_spam", description = "Show the most recently flagged messages in this chat (admin only, format: /recent_spam [n])")]
+    RecentSpam(String),
+
+    /// Lists the senders with the highest spam scores, highest first (admin only).
+    ///
+    /// Format: `/top_spammers [n]`; `n` defaults to 10 and is capped at 50.
+    #[command(rename = "top_spammers", description = "Show the senders with the highest spam scores (admin only, format: /top_spammers [n])")]
+    TopSpammers(String),
+
+    /// Lists the changes to a user's spam score in this chat, oldest first (admin only).
+    ///
+    /// Format: `/history <user id> [days]`, or reply to one of the user's messages with
+    /// `/history [days]`; `days` defaults to 30.
+    #[command(description = "Show how a user's spam score changed over time (admin only, format: /history <user id> [days])")]
+    History(String),
+
+    /// Configures rotating database backups (bot operator only).
+    ///
+    /// Format: `/setbackupschedule <interval hours> <snapshots to keep>`.
+    /// Example: `/setbackupschedule 24 7` keeps a week of daily snapshots.
+    #[command(description = "Configure rotating backups (operator only, format: /setbackupschedule <hours> <keep>)")]
+    SetBackupSchedule(String),
+
+    /// Lists the available database snapshots (admin only).
+    #[command(description = "List database backups (admin only)")]
+    ListBackups,
+
+    /// Restores a database snapshot listed by `/listbackups` (bot operator only).
+    ///
+    /// Format: `/restorebackup <name>`. The command must be repeated within a minute
+    /// to confirm, since it replaces all rules, scores and settings.
+    #[command(description = "Restore a database backup (operator only, format: /restorebackup <name>)")]
+    RestoreBackup(String),
+
+    /// Configures how reposts of messages the bot deleted are punished (bot operator only).
+    ///
+    /// Format: `/setrepostpolicy <window seconds> <escalation score>`. A message similar
+    /// to one the bot deleted from the same user within the window scores the escalation
+    /// once per such deletion; an escalation of 0 disables it.
+    #[command(description = "Configure repost escalation (operator only, format: /setrepostpolicy <window secs> <score>)")]
+    SetRepostPolicy(String),
+
+    /// Adds a domain to this chat's trusted-domain allowlist (admin only).
+    ///
+    /// Format: `/allow_domain <domain>`. Links to the domain and its subdomains are
+    /// never scored by the link detectors in this chat, even from new users.
+    #[command(rename = "allow_domain", description = "Trust a domain in this chat (admin only, format: /allow_domain <domain>)")]
+    AllowDomain(String),
+
+    /// Removes a domain from this chat's trusted-domain allowlist (admin only).
+    ///
+    /// Format: `/disallow_domain <domain>`. Without an argument, lists the trusted domains.
+    #[command(rename = "disallow_domain", description = "Stop trusting a domain in this chat (admin only, format: /disallow_domain <domain>)")]
+    DenyDomain(String),
+
+    /// Adds a safe pattern to this chat (admin only).
+    ///
+    /// Format: `/add_safe_pattern <text>`. Messages containing the text (case-insensitively)
+    /// are never flagged for their content in this chat, even if they match spam rules.
+    #[command(rename = "add_safe_pattern", description = "Let messages containing a text pass in this chat (admin only, format: /add_safe_pattern <text>)")]
+    AddSafePattern(String),
+
+    /// Removes a safe pattern from this chat (admin only).
+    ///
+    /// Format: `/remove_safe_pattern <text>`. Without an argument, lists the safe patterns.
+    #[command(rename = "remove_safe_pattern", description = "Remove a safe pattern from this chat (admin only, format: /remove_safe_pattern <text>)")]
+    RemoveSafePattern(String),
+
+    /// Blocks a sticker set or file in this chat (admin only).
+    ///
+    /// Format: `/block_sticker <set name, sticker set link or file ID>`, or reply to a
+    /// sticker, GIF or file. Replying to a sticker blocks its whole set. Messages with
+    /// blocked media are treated as spam. Without an argument or reply, lists the blocks.
+    #[command(rename = "block_sticker", description = "Treat a sticker set, GIF or file as spam in this chat (admin only, format: /block_sticker <set or file ID>, or reply to one)")]
+    BlockSticker(String),
+
+    /// Unblocks a sticker set or file in this chat (admin only).
+    ///
+    /// Format: `/unblock_sticker <set name or file ID>`, or reply to the sticker, GIF or file.
+    #[command(rename = "unblock_sticker", description = "Unblock a sticker set, GIF or file in this chat (admin only, format: /unblock_sticker <set or file ID>)")]
+    UnblockSticker(String),
+
+    /// Scores a labeled spam/ham suite and reports accuracy (chat owner only).
+    ///
+    /// Reply to an uploaded suite file (`spam` or `ham`, a tab, then the text, one sample
+    /// per line) to run it; otherwise the embedded suite is used. Samples are scored by
+    /// the rules and content detectors without changing any scores or state.
+    #[command(description = "Run a labeled spam/ham suite through the detectors (owner only)")]
+    RunSuite,
+}
+
+/// Largest suite file `/runsuite` accepts, in bytes.
+const MAX_SUITE_BYTES: u32 = 1024 * 1024;
+/// Most misclassified samples listed by `/runsuite`, keeping the reply within Telegram's limit.
+const MAX_REPORTED_MISSES: usize = 20;
+/// Flagged messages `/recent_spam` lists when no count is given.
+const DEFAULT_RECENT_SPAM: usize = 10;
+/// Most flagged messages `/recent_spam` lists.
+const MAX_RECENT_SPAM: usize = 50;
+/// Senders `/top_spammers` lists when no count is given.
+const DEFAULT_TOP_SPAMMERS: usize = 10;
+/// Most senders `/top_spammers` lists.
+const MAX_TOP_SPAMMERS: usize = 50;
+/// Longest welcome message `/set_welcome` accepts, in characters, leaving room for the mentions.
+const MAX_WELCOME_CHARS: usize = 1000;
+/// Days of score changes `/history` lists when no number is given.
+const DEFAULT_HISTORY_DAYS: i64 = 30;
+/// Most score changes `/history` lists; older ones in the period are left out.
+const MAX_HISTORY_EVENTS: usize = 50;
+/// Largest rules file `/import_rules` downloads, in bytes.
+const MAX_RULES_FILE_BYTES: u32 = 1024 * 1024;
+
+/// Handles bot commands (`/start`, `/report`, `/add_rule`, ...).
+///
+/// Processes incoming commands, performs the associated actions, and sends responses to
+/// the chat. Each command's description says who may run it: `/start`, `/help` and
+/// `/report` are open to everyone, `/setbackupschedule`, `/restorebackup`,
+/// `/import_rules`, `/set_category`, `/scheduleprofile`, `/remove_rule`,
+/// `/add_regex_rule`, `/add_rules`, `/set_rule_mode` and `/setrepostpolicy` are
+/// restricted to the bot operator, `/runsuite` to the chat owner, and every other
+/// command to chat admins. The `/report` verdict uses the report threshold rather than
+/// the detection threshold, and reports made by admins are stored as labeled spam
+/// samples. `/report` also scores text passed as its argument, offering admins buttons
+/// to add keywords from spam samples as rules.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance.
+/// * `msg` - The message containing the command.
+/// * `cmd` - The parsed command (e.g., `Start`, `Report`, `AddRule`).
+/// * `rule_manager` - A thread-safe reference to the `RuleManager` for rule operations.
+/// * `confirmations` - Destructive commands awaiting confirmation by their issuer.
+/// * `config` - Bot-wide handler settings.
+/// * `detectors` - The built-in detectors, some of which are configured by commands.
+/// * `admin_cache` - The shared cache of chat administrators used for permission checks.
+///
+/// # Returns
+/// * `Result<()>` - A `Result` indicating success or a `teloxide::RequestError` if the operation fails.
+#[allow(clippy::too_many_arguments)]
+async fn answer(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    rule_manager: Arc<RuleManager>,
+    confirmations: Arc<PendingConfirmations>,
+    config: Arc<Config>,
+    detectors: Arc<Detectors>,
+    admin_cache: Arc<AdminCache>,
+) -> Result<(), teloxide::RequestError> {
+    let lang = chat_lang(&rule_manager, msg.chat.id, msg.from());
+    match cmd {
+        Command::Start => {
+            bot.send_message(msg.chat.id, lang.text(Key::Start)).await?;
+        }
+        Command::Help => {
+            let mut lines: Vec<String> = Command::descriptions().to_string().lines().map(String::from).collect();
+            lines.push(String::new());
+            lines.push(
+                "Commands marked admin only are restricted to chat admins. /add_rule takes a keyword followed by its score, e.g. /add_rule spam 10.0; quote keywords with spaces, e.g. /add_rule \"free money\" 10."
+                    .to_string(),
+            );
+            for chunk in split_message(&lines, TELEGRAM_MESSAGE_LIMIT) {
+                bot.send_message(msg.chat.id, chunk).await?;
+            }
+        }
+        Command::Report(sample) => {
+            if let Some(reply) = msg.reply_to_message() {
+                let reported = reply.text().or_else(|| reply.caption());
+                let text = reported.unwrap_or("(non-text message)");
+                let evaluation = rule_manager.evaluate(text);
+                let (score, threshold) = (evaluation.total, rule_manager.get_report_threshold(msg.chat.id.0));
+                let is_spam = rule_manager.is_reported_spam(msg.chat.id.0, score);
+                // A forwarded sample was written by its original author, not by whoever forwarded it
+                let author = if reply.forward_date().is_some() {
+                    reply.forward_from_user()
+                } else {
+                    replied_user(&msg)
+                };
+                log::info!(
+                    user_id = author.map(|user| user.id.0).unwrap_or(0),
+                    chat_id = msg.chat.id.0,
+                    custom_score = score,
+                    threshold = threshold,
+                    is_spam = is_spam;
+                    "Reported message '{}': score {} (report threshold {}), spam: {}",
+                    text, score, threshold, is_spam
+                );
+                let breakdown = format_score_breakdown(&evaluation, threshold);
+                bot.send_message(msg.chat.id, format_report_reply(text, &breakdown)).await?;
+                if reported.is_some() && is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                    if let Err(e) = rule_manager.add_labeled_message(text, true) {
+                        log::error!("Failed to store labeled message: {}", e);
+                    }
+                    if config.bayes_filter {
+                        rule_manager.train_bayes(text, true);
+                    }
+                }
+                if is_spam {
+                    match author {
+                        Some(author) => {
+                            let user_id = author.id.to_string();
+                            let points = config.spam_points(score, threshold);
+                            if let Err(e) = rule_manager.increment_sender_score_by_async(msg.chat.id.0, &user_id, points).await {
+                                log::error!("Failed to update sender score: {}", e);
+                            }
+                            notify_admins(&bot, msg.chat.id, text, &rule_manager, &user_id, false, &admin_cache).await?;
+                        }
+                        None => log::info!("Reported forward hides its author; no sender score updated"),
+                    }
+                }
+            } else if !sample.trim().is_empty() {
+                let text = sample.trim();
+                let evaluation = rule_manager.evaluate(text);
+                let (score, threshold) = (evaluation.total, rule_manager.get_report_threshold(msg.chat.id.0));
+                let is_spam = rule_manager.is_reported_spam(msg.chat.id.0, score);
+                log::info!(
+                    chat_id = msg.chat.id.0,
+                    custom_score = score,
+                    threshold = threshold,
+                    is_spam = is_spam;
+                    "Reported sample '{}': score {} (report threshold {}), spam: {}",
+                    text, score, threshold, is_spam
+                );
+                let verdict = format_score_breakdown(&evaluation, threshold);
+                let suggestions = if is_spam && is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                    if let Err(e) = rule_manager.add_labeled_message(text, true) {
+                        log::error!("Failed to store labeled message: {}", e);
+                    }
+                    if config.bayes_filter {
+                        rule_manager.train_bayes(text, true);
+                    }
+                    rule_manager.suggest_keywords(text, 3)
+                } else {
+                    Vec::new()
+                };
+                if suggestions.is_empty() {
+                    bot.send_message(msg.chat.id, verdict).await?;
+                } else {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("{}\nTap a keyword to add it as a rule with score {}.", verdict, rule_manager.threshold),
+                    )
+                    .reply_markup(add_rule_keyboard(&suggestions))
+                    .await?;
+                }
+            } else {
+                bot.send_message(msg.chat.id, "Please reply to a message to report it, or pass the spam text: /report <text>.").await?;
+            }
+        }
+        Command::TestMessage(sample) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsTestMessages)).await?;
+                return Ok(());
+            }
+            let text = sample.trim();
+            if text.is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /testmessage <text>").await?;
+                return Ok(());
+            }
+            // Only stateless scoring: the per-user and per-chat detectors would record the sample as traffic
+            let evaluation = rule_manager.evaluate(text);
+            let allowed_domains = rule_manager.get_allowed_domains(msg.chat.id.0).unwrap_or_else(|e| {
+                log::error!("Failed to load allowed domains: {}", e);
+                Vec::new()
+            });
+            let mut contributions = detectors.evaluate_content(text, &allowed_domains);
+            if config.bayes_filter {
+                contributions.push(("bayes", config.bayes_contribution(rule_manager.bayes_score(text))));
+            }
+            let safe_pattern = rule_manager.matched_safe_pattern(msg.chat.id.0, text).unwrap_or_else(|e| {
+                log::error!("Failed to load safe patterns: {}", e);
+                None
+            });
+            let threshold = rule_manager.get_chat_threshold(msg.chat.id.0);
+            let result = format_test_result(&evaluation, &contributions, threshold, safe_pattern.as_deref());
+            bot.send_message(msg.chat.id, result).await?;
+        }
+        Command::AddRule(args) => {
+            if is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                match parse_add_rule(&args) {
+                    Ok((keyword, score, category)) => {
+                        let category = category.unwrap_or_else(|| DEFAULT_CATEGORY.to_string());
+                        let added = {
+                            let (keyword, category) = (keyword.clone(), category.clone());
+                            rule_manager
+                                .run_blocking(move |manager: &RuleManager| manager.add_rule_in_category(keyword, score, &category))
+                                .await
+                        };
+                        match added {
+                            Ok(true) => {
+                                bot.send_message(
+                                    msg.chat.id,
+                                    format!("Added rule: '{}' with score {} in category '{}'", keyword, score, category),
+                                )
+                                .await?;
+                            }
+                            Ok(false) => {
+                                bot.send_message(
+                                    msg.chat.id,
+                                    format!("Updated rule: '{}' now has score {} in category '{}'", keyword, score, category),
+                                )
+                                .await?;
+                            }
+                            Err(e) => {
+                                log::error!("Failed to add rule: {}", e);
+                                bot.send_message(msg.chat.id, "Failed to add rule.").await?;
+                            }
+                        }
+                    }
+                    Err(usage) => {
+                        bot.send_message(msg.chat.id, usage).await?;
+                    }
+                }
+            } else {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsAddRules)).await?;
+            }
+        }
+        Command::AddRules(args) => {
+            if !require_operator(&bot, &msg, &config, lang).await? {
+                return Ok(());
+            }
+            let (rules, invalid) = parse_rule_batch(&args);
+            if !invalid.is_empty() {
+                bot.send_message(
+                    msg.chat.id,
+                    format!("No rules were added. These entries are not keyword:score pairs:\n{}", invalid.join("\n")),
+                )
+                .await?;
+            } else if rules.is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /add_rules <keyword:score>, ... (one pair per line or comma-separated)")
+                    .await?;
+            } else {
+                match rule_manager.run_blocking(move |manager: &RuleManager| manager.add_rules(rules)).await {
+                    Ok(count) => {
+                        bot.send_message(msg.chat.id, format!("Added or updated {} rules.", count)).await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to add rules: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to add rules; none were added.").await?;
+                    }
+                }
+            }
+        }
+        Command::AddRegexRule(args) => {
+            if !require_operator(&bot, &msg, &config, lang).await? {
+                return Ok(());
+            }
+            let (pattern, score) = match args.trim().rsplit_once(char::is_whitespace) {
+                Some((pattern, score)) if !pattern.trim().is_empty() => (pattern.trim(), score),
+                _ => {
+                    bot.send_message(msg.chat.id, "Usage: /add_regex_rule <pattern> <score>").await?;
+                    return Ok(());
+                }
+            };
+            let score = match score.parse::<f32>() {
+                Ok(score) => score,
+                Err(_) => {
+                    bot.send_message(msg.chat.id, "Invalid score.").await?;
+                    return Ok(());
+                }
+            };
+            let regex = match Regex::new(pattern) {
+                Ok(regex) => regex,
+                Err(e) => {
+                    bot.send_message(msg.chat.id, format!("Invalid regex '{}': {}", pattern, e)).await?;
+                    return Ok(());
+                }
+            };
+            match rule_manager.add_regex_rule(regex, score) {
+                Ok(true) => {
+                    bot.send_message(msg.chat.id, format!("Added regex rule: '{}' with score {}", pattern, score)).await?;
+                }
+                Ok(false) => {
+                    bot.send_message(msg.chat.id, format!("Updated regex rule: '{}' now has score {}", pattern, score)).await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to add regex rule: {}", e);
+                    bot.send_message(msg.chat.id, "Failed to add rule.").await?;
+                }
+            }
+        }
+        Command::RemoveRule(keyword) => {
+            if !require_operator(&bot, &msg, &config, lang).await? {
+                return Ok(());
+            }
+            let keyword = keyword.trim();
+            if keyword.is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /remove_rule <keyword>").await?;
+                return Ok(());
+            }
+            match rule_manager.remove_rule(keyword) {
+                Ok(0) => {
+                    bot.send_message(msg.chat.id, format!("No rule found for '{}'.", keyword)).await?;
+                }
+                Ok(count) => {
+                    bot.send_message(msg.chat.id, format!("Removed {} rule(s) for '{}'.", count, keyword)).await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to remove rule: {}", e);
+                    bot.send_message(msg.chat.id, "Failed to remove rule.").await?;
+                }
+            }
+        }
+        Command::SetCategory(args) => {
+            if !require_operator(&bot, &msg, &config, lang).await? {
+                return Ok(());
+            }
+            let parts: Vec<&str> = args.split_whitespace().collect();
+            let enabled = match parts.as_slice() {
+                [_, "on"] => true,
+                [_, "off"] => false,
+                _ => {
+                    bot.send_message(msg.chat.id, "Usage: /set_category <category> <on|off>").await?;
+                    return Ok(());
+                }
+            };
+            let category = parts[0];
+            match rule_manager.set_category_enabled(category, enabled) {
+                Ok(0) => {
+                    bot.send_message(msg.chat.id, format!("No rules in category '{}'.", category)).await?;
+                }
+                Ok(count) => {
+                    let state = if enabled { "Enabled" } else { "Disabled" };
+                    bot.send_message(msg.chat.id, format!("{} {} rule(s) in category '{}'.", state, count, category)).await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to toggle rule category: {}", e);
+                    bot.send_message(msg.chat.id, "Failed to toggle the category.").await?;
+                }
+            }
+        }
+        Command::SetLanguage(code) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsSetLanguage)).await?;
+                return Ok(());
+            }
+            match Lang::parse(code.trim()) {
+                Some(chosen) => match rule_manager.set_chat_language(msg.chat.id.0, chosen.code()) {
+                    Ok(()) => {
+                        bot.send_message(msg.chat.id, chosen.text(Key::LanguageSet)).await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to set the language of chat {}: {}", msg.chat.id, e);
+                        bot.send_message(msg.chat.id, "Failed to set the language.").await?;
+                    }
+                },
+                None => {
+                    bot.send_message(msg.chat.id, lang.text(Key::LanguageUsage)).await?;
+                }
+            }
+        }
+        Command::SetAction(name) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsSetAction)).await?;
+                return Ok(());
+            }
+            let name = name.trim();
+            if name.is_empty() {
+                let current = rule_manager.get_action_policy(msg.chat.id.0).map_or("default", ActionPolicy::as_str);
+                bot.send_message(msg.chat.id, format!("Spam action in this chat: {}", current)).await?;
+                return Ok(());
+            }
+            let policy = if name.eq_ignore_ascii_case("default") {
+                None
+            } else {
+                match ActionPolicy::parse(name) {
+                    Some(policy) => Some(policy),
+                    None => {
+                        bot.send_message(msg.chat.id, "Usage: /set_action <notify|delete|mute|ban|default>").await?;
+                        return Ok(());
+                    }
+                }
+            };
+            match rule_manager.set_action_policy(msg.chat.id.0, policy) {
+                Ok(()) => {
+                    let reply = match policy {
+                        Some(policy) => format!("Spam in this chat will now be handled with: {}", policy.as_str()),
+                        None => "Spam in this chat will now be handled with the bot-wide settings.".to_string(),
+                    };
+                    bot.send_message(msg.chat.id, reply).await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to set the spam action of chat {}: {}", msg.chat.id, e);
+                    bot.send_message(msg.chat.id, "Failed to set the spam action.").await?;
+                }
+            }
+        }
+        Command::SetWelcome(text) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsSetWelcome)).await?;
+                return Ok(());
+            }
+            let text = text.trim();
+            if text.is_empty() {
+                let reply = match rule_manager.get_welcome_message(msg.chat.id.0) {
+                    Some(welcome) => format!("New members of this chat are greeted with:\n{}", welcome),
+                    None => "New members of this chat are not greeted. Usage: /set_welcome <text|off>".to_string(),
+                };
+                bot.send_message(msg.chat.id, reply).await?;
+                return Ok(());
+            }
+            let welcome = if text.eq_ignore_ascii_case("off") {
+                None
+            } else if text.chars().count() > MAX_WELCOME_CHARS {
+                bot.send_message(msg.chat.id, format!("The welcome message can be at most {} characters.", MAX_WELCOME_CHARS)).await?;
+                return Ok(());
+            } else {
+                Some(text)
+            };
+            match rule_manager.set_welcome_message(msg.chat.id.0, welcome) {
+                Ok(()) => {
+                    let reply = match welcome {
+                        Some(_) if !text.contains("{name}") => {
+                            "New members will now be greeted. Add {name} to the message to mention them."
+                        }
+                        Some(_) => "New members will now be greeted.",
+                        None => "New members will no longer be greeted.",
+                    };
+                    bot.send_message(msg.chat.id, reply).await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to set the welcome message of chat {}: {}", msg.chat.id, e);
+                    bot.send_message(msg.chat.id, "Failed to set the welcome message.").await?;
+                }
+            }
+        }
+        Command::Pause(spec) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsPause)).await?;
+                return Ok(());
+            }
+            let spec = spec.trim();
+            let duration = if spec.is_empty() {
+                None
+            } else {
+                match parse_duration(spec) {
+                    Some(duration) => Some(duration),
+                    None => {
+                        bot.send_message(msg.chat.id, "Usage: /pause [duration], e.g. /pause 30m, /pause 2h or /pause 1d (default: until /resume).").await?;
+                        return Ok(());
+                    }
+                }
+            };
+            let resume_at = duration.map(|duration| now_unix().saturating_add(i64::try_from(duration.as_secs()).unwrap_or(i64::MAX)));
+            match rule_manager.pause_chat(msg.chat.id.0, resume_at) {
+                Ok(()) => {
+                    log::info!("Admin {:?} paused spam moderation in chat {} until {:?}", msg.from().map(|user| user.id), msg.chat.id, resume_at);
+                    let until = resume_at.and_then(|resume_at| chrono::DateTime::<chrono::Utc>::from_timestamp(resume_at, 0));
+                    let notice = match until {
+                        Some(until) => lang.format(Key::ModerationPausedUntil, &[&until.format("%Y-%m-%d %H:%M")]),
+                        None => lang.text(Key::ModerationPaused).to_string(),
+                    };
+                    bot.send_message(msg.chat.id, notice).await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to pause chat {}: {}", msg.chat.id, e);
+                    bot.send_message(msg.chat.id, "Failed to pause spam moderation.").await?;
+                }
+            }
+        }
+        Command::Resume => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsPause)).await?;
+                return Ok(());
+            }
+            match rule_manager.resume_chat(msg.chat.id.0) {
+                Ok(true) => {
+                    log::info!("Admin {:?} resumed spam moderation in chat {}", msg.from().map(|user| user.id), msg.chat.id);
+                    bot.send_message(msg.chat.id, lang.text(Key::ModerationResumed)).await?;
+                }
+                Ok(false) => {
+                    bot.send_message(msg.chat.id, "Spam moderation is not paused in this chat.").await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to resume chat {}: {}", msg.chat.id, e);
+                    bot.send_message(msg.chat.id, "Failed to resume spam moderation.").await?;
+                }
+            }
+        }
+        Command::SetRuleMode(args) => {
+            if !require_operator(&bot, &msg, &config, lang).await? {
+                return Ok(());
+            }
+            let parts: Vec<&str> = args.split_whitespace().collect();
+            let (keyword, mode) = match parts.as_slice() {
+                [keyword, mode] => match MatchMode::parse(mode) {
+                    Some(mode) => (*keyword, mode),
+                    None => {
+                        bot.send_message(msg.chat.id, "Usage: /set_rule_mode <keyword> <substring|whole_word|prefix|fuzzy>").await?;
+                        return Ok(());
+                    }
+                },
+                _ => {
+                    bot.send_message(msg.chat.id, "Usage: /set_rule_mode <keyword> <substring|whole_word|prefix|fuzzy>").await?;
+                    return Ok(());
+                }
+            };
+            match rule_manager.set_match_mode(keyword, mode) {
+                Ok(true) => {
+                    bot.send_message(msg.chat.id, format!("Rule '{}' now matches as {}.", keyword, mode.as_str())).await?;
+                }
+                Ok(false) => {
+                    bot.send_message(msg.chat.id, format!("No keyword rule '{}'.", keyword)).await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to set the match mode of rule '{}': {}", keyword, e);
+                    bot.send_message(msg.chat.id, "Failed to change the rule.").await?;
+                }
+            }
+        }
+        Command::ListRules => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsListRules)).await?;
+                return Ok(());
+            }
+            let lines: Vec<String> = rule_manager
+                .rules
+                .lock_or_recover()
+                .iter()
+                .map(|rule| {
+                    let state = if rule.enabled { "" } else { ", disabled" };
+                    let mode = if rule.is_regex || rule.match_mode == MatchMode::Substring {
+                        String::new()
+                    } else {
+                        format!(", {}", rule.match_mode.as_str())
+                    };
+                    format!("{} => {} [{}{}{}]", rule.keyword, rule.score, rule.category, mode, state)
+                })
+                .collect();
+            if lines.is_empty() {
+                bot.send_message(msg.chat.id, "No custom rules configured.").await?;
+            }
+            for chunk in split_message(&lines, TELEGRAM_MESSAGE_LIMIT) {
+                bot.send_message(msg.chat.id, chunk).await?;
+            }
+        }
+        Command::RuleStats => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsListRules)).await?;
+                return Ok(());
+            }
+            match rule_manager.run_blocking(|manager: &RuleManager| manager.rule_stats()).await {
+                Ok(stats) if stats.is_empty() => {
+                    bot.send_message(msg.chat.id, "No custom rules configured.").await?;
+                }
+                Ok(stats) => {
+                    let lines: Vec<String> = stats
+                        .iter()
+                        .map(|(keyword, score, hits)| format!("{} => {}: {} hits", keyword, score, hits))
+                        .collect();
+                    for chunk in split_message(&lines, TELEGRAM_MESSAGE_LIMIT) {
+                        bot.send_message(msg.chat.id, chunk).await?;
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to load rule stats: {}", e);
+                    bot.send_message(msg.chat.id, "Failed to load rule stats.").await?;
+                }
+            }
+        }
+        Command::ExportRules => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsExportRules)).await?;
+                return Ok(());
+            }
+            let json = rule_manager.export_rules();
+            if json.chars().count() <= TELEGRAM_MESSAGE_LIMIT {
+                bot.send_message(msg.chat.id, json).await?;
+            } else {
+                bot.send_document(msg.chat.id, InputFile::memory(json.into_bytes()).file_name("rules.json")).await?;
+            }
+        }
+        Command::ImportRules(arg) => {
+            if !require_operator(&bot, &msg, &config, lang).await? {
+                return Ok(());
+            }
+            let json = match msg.document().or_else(|| msg.reply_to_message().and_then(|reply| reply.document())) {
+                Some(document) if document.file.size > MAX_RULES_FILE_BYTES => {
+                    bot.send_message(msg.chat.id, "The rules file is too large.").await?;
+                    return Ok(());
+                }
+                Some(document) => {
+                    let file = bot.get_file(&document.file.id).await?;
+                    let mut contents = Vec::new();
+                    if let Err(e) = bot.download_file(&file.path, &mut contents).await {
+                        log::error!("Failed to download rules file: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to download the rules file.").await?;
+                        return Ok(());
+                    }
+                    String::from_utf8_lossy(&contents).to_string()
+                }
+                None if !arg.trim().is_empty() => arg,
+                None => {
+                    bot.send_message(msg.chat.id, "Usage: /import_rules <json>, or reply to a JSON file exported with /export_rules.").await?;
+                    return Ok(());
+                }
+            };
+            let reply = match rule_manager.import_rules(&json) {
+                Ok(count) => format!("Imported {} rules. Existing rules with the same keyword were replaced.", count),
+                Err(e) => {
+                    log::error!("Failed to import rules: {}", e);
+                    format!("Failed to import rules: {}", e)
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Calibrate => {
+            if is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                let labeled = match rule_manager.get_labeled_messages() {
+                    Ok(labeled) => labeled,
+                    Err(e) => {
+                        log::error!("Failed to load labeled messages: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to load labeled messages.").await?;
+                        return Ok(());
+                    }
+                };
+                if labeled.is_empty() {
+                    bot.send_message(msg.chat.id, "No labeled messages available for calibration.").await?;
+                    return Ok(());
+                }
+                let samples: Vec<(f32, bool)> = labeled
+                    .iter()
+                    .map(|(text, is_spam)| (rule_manager.check_custom_rules_or_fallback(text), *is_spam))
+                    .collect();
+                let results = evaluate_thresholds(&samples, &default_thresholds());
+                let mut report = format!("Calibration over {} labeled messages:\n", samples.len());
+                for r in &results {
+                    report.push_str(&format!(
+                        ">= {:.1}: precision {:.2}, recall {:.2}, F1 {:.2}\n",
+                        r.threshold, r.precision, r.recall, r.f1
+                    ));
+                }
+                if let Some(best) = best_threshold(&results) {
+                    report.push_str(&format!("Suggested threshold: {:.1} (F1 {:.2})", best.threshold, best.f1));
+                }
+                bot.send_message(msg.chat.id, report).await?;
+            } else {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsCalibrate)).await?;
+            }
+        }
+        Command::ScheduleProfile(args) => {
+            if !require_operator(&bot, &msg, &config, lang).await? {
+                return Ok(());
+            }
+            match parse_schedule(&args) {
+                Ok((profile, days, minute_of_day)) => {
+                    if let Err(e) = rule_manager.add_profile_schedule(&profile, days, minute_of_day) {
+                        log::error!("Failed to add profile schedule: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to schedule profile.").await?;
+                    } else {
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "Scheduled profile '{}' on {} at {:02}:{:02} UTC",
+                                profile,
+                                format_days(days),
+                                minute_of_day / 60,
+                                minute_of_day % 60
+                            ),
+                        )
+                        .await?;
+                    }
+                }
+                Err(usage) => {
+                    bot.send_message(msg.chat.id, usage).await?;
+                }
+            }
+        }
+        Command::Note(note) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsAddNotes)).await?;
+            } else if let Some(user) = replied_user(&msg) {
+                let note = note.trim();
+                if note.is_empty() {
+                    bot.send_message(msg.chat.id, "Usage: reply to a message with /note <text>").await?;
+                } else if let Err(e) = rule_manager.add_note(msg.chat.id.0, &user.id.to_string(), note) {
+                    log::error!("Failed to add note: {}", e);
+                    bot.send_message(msg.chat.id, "Failed to add note.").await?;
+                } else {
+                    bot.send_message(msg.chat.id, format!("Note added for user {}.", user.id)).await?;
+                }
+            } else {
+                bot.send_message(msg.chat.id, "Please reply to a user's message to add a note.").await?;
+            }
+        }
+        Command::SetReportThreshold(threshold) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsSetReportThreshold)).await?;
+            } else if !threshold.is_finite() || threshold < 0.0 {
+                bot.send_message(msg.chat.id, "Invalid threshold.").await?;
+            } else if let Err(e) = rule_manager.set_report_threshold(msg.chat.id.0, threshold) {
+                log::error!("Failed to set report threshold: {}", e);
+                bot.send_message(msg.chat.id, "Failed to set report threshold.").await?;
+            } else {
+                bot.send_message(msg.chat.id, format!("Report threshold for this chat set to {}", threshold)).await?;
+            }
+        }
+        Command::SetThreshold(threshold) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsSetThreshold)).await?;
+            } else if !threshold.is_finite() || threshold < 0.0 {
+                bot.send_message(msg.chat.id, "Invalid threshold.").await?;
+            } else if let Err(e) = rule_manager.set_chat_threshold(msg.chat.id.0, threshold) {
+                log::error!("Failed to set chat threshold: {}", e);
+                bot.send_message(msg.chat.id, "Failed to set spam threshold.").await?;
+            } else {
+                bot.send_message(msg.chat.id, format!("Spam threshold for this chat set to {}", threshold)).await?;
+            }
+        }
+        Command::BatchPardon(threshold) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsPardon)).await?;
+                return Ok(());
+            }
+            let admin = match msg.from() {
+                Some(admin) => admin,
+                None => return Ok(()),
+            };
+            let action = format!("batchpardon {}", threshold);
+            if !confirmations.confirm(msg.chat.id.0, admin.id.0, &action) {
+                match rule_manager.count_senders_below(msg.chat.id.0, threshold) {
+                    Ok(count) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "This will reset the spam score of {} users with a score below {}. Send /batchpardon {} again within a minute to confirm.",
+                                count, threshold, threshold
+                            ),
+                        )
+                        .await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to count senders: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to count affected users.").await?;
+                    }
+                }
+            } else {
+                match rule_manager.pardon_senders_below(msg.chat.id.0, threshold) {
+                    Ok(count) => {
+                        bot.send_message(msg.chat.id, format!("Pardoned {} users with a score below {}.", count, threshold)).await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to pardon senders: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to pardon users; no scores were changed.").await?;
+                    }
+                }
+            }
+        }
+        Command::DetectorStats => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsDetectorStats)).await?;
+                return Ok(());
+            }
+            let since = now_unix() - config.audit_window_days * 86_400;
+            let stats = rule_manager
+                .count_spam_events(msg.chat.id.0, since)
+                .and_then(|events| Ok((events, rule_manager.detector_stats(msg.chat.id.0, since)?)));
+            match stats {
+                Ok((events, stats)) => {
+                    let mut report = format!(
+                        "Detector contributions over {} spam events in the last {} days:",
+                        events, config.audit_window_days
+                    );
+                    for stat in &stats {
+                        report.push_str(&format!(
+                            "\n{}: fired {} times, total {:.1}, average {:.1}",
+                            stat.detector,
+                            stat.fired,
+                            stat.total,
+                            stat.average()
+                        ));
+                    }
+                    let bayes = config.bayes_filter.then_some("bayes");
+                    let known = ["lua_rules", "builtin_rules"].into_iter().chain(Detectors::NAMES).chain(bayes);
+                    for name in known.filter(|name| !stats.iter().any(|s| s.detector == *name)) {
+                        report.push_str(&format!("\n{}: never fired", name));
+                    }
+                    bot.send_message(msg.chat.id, report).await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to load detector stats: {}", e);
+                    bot.send_message(msg.chat.id, "Failed to load detector stats.").await?;
+                }
+            }
+        }
+        Command::RecentSpam(args) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsRecentSpam)).await?;
+                return Ok(());
+            }
+            let args = args.trim();
+            let limit = if args.is_empty() {
+                DEFAULT_RECENT_SPAM
+            } else {
+                match args.parse::<usize>() {
+                    Ok(n) if n > 0 => n.min(MAX_RECENT_SPAM),
+                    _ => {
+                        bot.send_message(msg.chat.id, "Usage: /recent_spam [n]").await?;
+                        return Ok(());
+                    }
+                }
+            };
+            match rule_manager.recent_flagged_messages(msg.chat.id.0, limit) {
+                Ok(messages) if messages.is_empty() => {
+                    bot.send_message(msg.chat.id, "No spam has been flagged in this chat yet.").await?;
+                }
+                Ok(messages) => {
+                    let mut lines = vec![format!("Last {} flagged messages:", messages.len())];
+                    lines.extend(messages.iter().map(format_flagged_message));
+                    for chunk in split_message(&lines, TELEGRAM_MESSAGE_LIMIT) {
+                        bot.send_message(msg.chat.id, chunk).await?;
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to load flagged messages: {}", e);
+                    bot.send_message(msg.chat.id, "Failed to load recent spam.").await?;
+                }
+            }
+        }
+        Command::TopSpammers(args) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsTopSpammers)).await?;
+                return Ok(());
+            }
+            let args = args.trim();
+            let limit = if args.is_empty() {
+                DEFAULT_TOP_SPAMMERS
+            } else {
+                match args.parse::<usize>() {
+                    Ok(n) if n > 0 => n.min(MAX_TOP_SPAMMERS),
+                    _ => {
+                        bot.send_message(msg.chat.id, "Usage: /top_spammers [n]").await?;
+                        return Ok(());
+                    }
+                }
+            };
+            match rule_manager.top_senders(msg.chat.id.0, limit) {
+                Ok(senders) if senders.is_empty() => {
+                    bot.send_message(msg.chat.id, "No sender has a spam score yet.").await?;
+                }
+                Ok(senders) => {
+                    let mut lines = vec![format!("Top {} spammers:", senders.len())];
+                    lines.extend(format_top_senders(&senders));
+                    for chunk in split_message(&lines, TELEGRAM_MESSAGE_LIMIT) {
+                        bot.send_message(msg.chat.id, chunk).await?;
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to load top spammers: {}", e);
+                    bot.send_message(msg.chat.id, "Failed to load the top spammers.").await?;
+                }
+            }
+        }
+        Command::History(args) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsHistory)).await?;
+                return Ok(());
+            }
+            // When replying, the only argument is the number of days
+            let mut words = args.split_whitespace();
+            let user_id = match replied_user(&msg) {
+                Some(user) => Some(user.id),
+                None => words.next().and_then(|word| word.parse::<u64>().ok()).map(UserId),
+            };
+            let days = match words.next() {
+                Some(word) => word.parse::<i64>().ok().filter(|days| *days > 0),
+                None => Some(DEFAULT_HISTORY_DAYS),
+            };
+            let (Some(user_id), Some(days), None) = (user_id, days, words.next()) else {
+                bot.send_message(msg.chat.id, "Usage: /history <user id> [days], or reply to the user's message with /history [days].").await?;
+                return Ok(());
+            };
+            let since = now_unix().saturating_sub(days.saturating_mul(86_400));
+            match rule_manager.score_history(msg.chat.id.0, &user_id.to_string(), since) {
+                Ok(events) if events.is_empty() => {
+                    bot.send_message(msg.chat.id, format!("User {}'s spam score has not changed in the last {} days.", user_id, days))
+                        .await?;
+                }
+                Ok(events) => {
+                    let shown = &events[events.len().saturating_sub(MAX_HISTORY_EVENTS)..];
+                    let mut lines = vec![format!("Spam score changes of user {} in the last {} days:", user_id, days)];
+                    if shown.len() < events.len() {
+                        lines.push(format!("(the last {} of {})", shown.len(), events.len()));
+                    }
+                    lines.extend(format_score_history(shown));
+                    lines.push(format!("Current spam score: {}", rule_manager.get_sender_score(msg.chat.id.0, &user_id.to_string())));
+                    for chunk in split_message(&lines, TELEGRAM_MESSAGE_LIMIT) {
+                        bot.send_message(msg.chat.id, chunk).await?;
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to load score history: {}", e);
+                    bot.send_message(msg.chat.id, "Failed to load the score history.").await?;
+                }
+            }
+        }
+        Command::SetBackupSchedule(args) => {
+            if !require_operator(&bot, &msg, &config, lang).await? {
+                return Ok(());
+            }
+            match parse_backup_schedule(&args) {
+                Ok(schedule) => {
+                    if let Err(e) = set_backup_schedule(&rule_manager, schedule) {
+                        log::error!("Failed to store backup schedule: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to configure backups.").await?;
+                    } else {
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "Backing up every {} hours, keeping the last {} snapshots.",
+                                schedule.interval_hours, schedule.retention
+                            ),
+                        )
+                        .await?;
+                    }
+                }
+                Err(usage) => {
+                    bot.send_message(msg.chat.id, usage).await?;
+                }
+            }
+        }
+        Command::ListBackups => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsListBackups)).await?;
+                return Ok(());
+            }
+            let backups = list_backups(Path::new(&config.backup_dir));
+            if backups.is_empty() {
+                bot.send_message(msg.chat.id, "No backups available.").await?;
+            } else {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Available backups (oldest first):\n{}\nRestore one with /restorebackup <name>.",
+                        backups.join("\n")
+                    ),
+                )
+                .await?;
+            }
+        }
+        Command::RestoreBackup(name) => {
+            if !require_operator(&bot, &msg, &config, lang).await? {
+                return Ok(());
+            }
+            let admin = match msg.from() {
+                Some(admin) => admin,
+                None => return Ok(()),
+            };
+            let name = name.trim();
+            let action = format!("restorebackup {}", name);
+            if !confirmations.confirm(msg.chat.id.0, admin.id.0, &action) {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "This will replace all rules, scores and settings with backup '{}'. Send /restorebackup {} again within a minute to confirm.",
+                        name, name
+                    ),
+                )
+                .await?;
+            } else {
+                match restore_backup(&rule_manager, Path::new(&config.backup_dir), name) {
+                    Ok(()) => {
+                        bot.send_message(msg.chat.id, format!("Restored backup '{}'.", name)).await?;
+                    }
+                    Err(e) => {
+                        log::error!("{}", e);
+                        bot.send_message(msg.chat.id, e).await?;
+                    }
+                }
+            }
+        }
+        Command::SetRepostPolicy(args) => {
+            if !require_operator(&bot, &msg, &config, lang).await? {
+                return Ok(());
+            }
+            match RepostPolicy::parse(&args) {
+                Ok(policy) => {
+                    if let Err(e) = policy.save(&rule_manager) {
+                        log::error!("Failed to store repost policy: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to configure the repost policy.").await?;
+                    } else {
+                        detectors.reposts.set_policy(policy);
+                        bot.send_message(
+                            msg.chat.id,
+                            format!(
+                                "Reposts within {} seconds of a deletion now score {} per deleted message.",
+                                policy.window.as_secs(),
+                                policy.escalation
+                            ),
+                        )
+                        .await?;
+                    }
+                }
+                Err(usage) => {
+                    bot.send_message(msg.chat.id, usage).await?;
+                }
+            }
+        }
+        Command::AllowDomain(domain) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsTrustedDomains)).await?;
+            } else if let Some(domain) = normalize_domain(&domain) {
+                match rule_manager.add_allowed_domain(msg.chat.id.0, &domain) {
+                    Ok(true) => {
+                        bot.send_message(msg.chat.id, format!("Links to {} are now trusted in this chat.", domain)).await?;
+                    }
+                    Ok(false) => {
+                        bot.send_message(msg.chat.id, format!("{} is already trusted.", domain)).await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to add allowed domain: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to trust domain.").await?;
+                    }
+                }
+            } else {
+                bot.send_message(msg.chat.id, "Usage: /allow_domain <domain>").await?;
+            }
+        }
+        Command::DenyDomain(domain) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsTrustedDomains)).await?;
+            } else if domain.trim().is_empty() {
+                match rule_manager.get_allowed_domains(msg.chat.id.0) {
+                    Ok(domains) if domains.is_empty() => {
+                        bot.send_message(msg.chat.id, "No trusted domains in this chat.").await?;
+                    }
+                    Ok(domains) => {
+                        bot.send_message(msg.chat.id, format!("Trusted domains:\n{}", domains.join("\n"))).await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to load allowed domains: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to load trusted domains.").await?;
+                    }
+                }
+            } else if let Some(domain) = normalize_domain(&domain) {
+                match rule_manager.remove_allowed_domain(msg.chat.id.0, &domain) {
+                    Ok(true) => {
+                        bot.send_message(msg.chat.id, format!("{} is no longer trusted.", domain)).await?;
+                    }
+                    Ok(false) => {
+                        bot.send_message(msg.chat.id, format!("{} was not trusted.", domain)).await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to remove allowed domain: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to remove trusted domain.").await?;
+                    }
+                }
+            } else {
+                bot.send_message(msg.chat.id, "Usage: /disallow_domain <domain>").await?;
+            }
+        }
+        Command::AddSafePattern(pattern) => {
+            let pattern = pattern.trim();
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsSafePatterns)).await?;
+            } else if pattern.is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /add_safe_pattern <text>").await?;
+            } else {
+                match rule_manager.add_safe_pattern(msg.chat.id.0, pattern) {
+                    Ok(true) => {
+                        bot.send_message(msg.chat.id, format!("Messages containing '{}' now pass in this chat.", pattern)).await?;
+                    }
+                    Ok(false) => {
+                        bot.send_message(msg.chat.id, format!("'{}' is already a safe pattern.", pattern)).await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to add safe pattern: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to add safe pattern.").await?;
+                    }
+                }
+            }
+        }
+        Command::RemoveSafePattern(pattern) => {
+            let pattern = pattern.trim();
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsSafePatterns)).await?;
+            } else if pattern.is_empty() {
+                match rule_manager.get_safe_patterns(msg.chat.id.0) {
+                    Ok(patterns) if patterns.is_empty() => {
+                        bot.send_message(msg.chat.id, "No safe patterns in this chat.").await?;
+                    }
+                    Ok(patterns) => {
+                        bot.send_message(msg.chat.id, format!("Safe patterns:\n{}", patterns.join("\n"))).await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to load safe patterns: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to load safe patterns.").await?;
+                    }
+                }
+            } else {
+                match rule_manager.remove_safe_pattern(msg.chat.id.0, pattern) {
+                    Ok(true) => {
+                        bot.send_message(msg.chat.id, format!("'{}' is no longer a safe pattern.", pattern)).await?;
+                    }
+                    Ok(false) => {
+                        bot.send_message(msg.chat.id, format!("'{}' was not a safe pattern.", pattern)).await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to remove safe pattern: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to remove safe pattern.").await?;
+                    }
+                }
+            }
+        }
+        Command::BlockSticker(item) => {
+            let item = item.trim();
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsBlockMedia)).await?;
+            } else if item.is_empty() && msg.reply_to_message().is_none() {
+                match rule_manager.get_blocked_media(msg.chat.id.0) {
+                    Ok(items) if items.is_empty() => {
+                        bot.send_message(msg.chat.id, "No stickers or files are blocked in this chat.").await?;
+                    }
+                    Ok(items) => {
+                        bot.send_message(msg.chat.id, format!("Blocked sticker sets and files:\n{}", items.join("\n"))).await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to load blocked media: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to load blocked media.").await?;
+                    }
+                }
+            } else {
+                match media_block_item(item, msg.reply_to_message()) {
+                    None => {
+                        bot.send_message(
+                            msg.chat.id,
+                            "Usage: /block_sticker <set name, sticker set link or file ID>, or reply to a sticker, GIF or file",
+                        )
+                        .await?;
+                    }
+                    Some(item) => match rule_manager.block_media(msg.chat.id.0, &item) {
+                        Ok(true) => {
+                            bot.send_message(msg.chat.id, format!("'{}' is now blocked in this chat.", item)).await?;
+                        }
+                        Ok(false) => {
+                            bot.send_message(msg.chat.id, format!("'{}' is already blocked.", item)).await?;
+                        }
+                        Err(e) => {
+                            log::error!("Failed to block media: {}", e);
+                            bot.send_message(msg.chat.id, "Failed to block media.").await?;
+                        }
+                    },
+                }
+            }
+        }
+        Command::UnblockSticker(item) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsBlockMedia)).await?;
+            } else {
+                match media_block_item(item.trim(), msg.reply_to_message()) {
+                    None => {
+                        bot.send_message(msg.chat.id, "Usage: /unblock_sticker <set name or file ID>, or reply to the sticker, GIF or file").await?;
+                    }
+                    Some(item) => match rule_manager.unblock_media(msg.chat.id.0, &item) {
+                        Ok(true) => {
+                            bot.send_message(msg.chat.id, format!("'{}' is no longer blocked.", item)).await?;
+                        }
+                        Ok(false) => {
+                            bot.send_message(msg.chat.id, format!("'{}' was not blocked.", item)).await?;
+                        }
+                        Err(e) => {
+                            log::error!("Failed to unblock media: {}", e);
+                            bot.send_message(msg.chat.id, "Failed to unblock media.").await?;
+                        }
+                    },
+                }
+            }
+        }
+        Command::RunSuite => {
+            if !is_owner(&bot, &msg).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, "Only the chat owner can run the test suite.").await?;
+                return Ok(());
+            }
+            let suite = match msg.reply_to_message().and_then(|reply| reply.document()) {
+                Some(document) if document.file.size > MAX_SUITE_BYTES => {
+                    bot.send_message(msg.chat.id, "The suite file is too large.").await?;
+                    return Ok(());
+                }
+                Some(document) => {
+                    let file = bot.get_file(&document.file.id).await?;
+                    let mut contents = Vec::new();
+                    if let Err(e) = bot.download_file(&file.path, &mut contents).await {
+                        log::error!("Failed to download suite: {}", e);
+                        bot.send_message(msg.chat.id, "Failed to download the suite file.").await?;
+                        return Ok(());
+                    }
+                    String::from_utf8_lossy(&contents).to_string()
+                }
+                None => DEFAULT_SUITE.to_string(),
+            };
+            let samples = match parse_suite(&suite) {
+                Ok(samples) if samples.is_empty() => {
+                    bot.send_message(msg.chat.id, "The suite contains no samples.").await?;
+                    return Ok(());
+                }
+                Ok(samples) => samples,
+                Err(e) => {
+                    bot.send_message(msg.chat.id, e).await?;
+                    return Ok(());
+                }
+            };
+            let report = run_suite(&samples, rule_manager.threshold, |text| {
+                rule_manager.check_custom_rules_or_fallback(text) + detectors.score(text)
+            });
+            let mut text = format!(
+                "Suite results: {}/{} correct, precision {:.2}, recall {:.2}, F1 {:.2}",
+                report.correct, report.total, report.metrics.precision, report.metrics.recall, report.metrics.f1
+            );
+            for miss in report.misclassified.iter().take(MAX_REPORTED_MISSES) {
+                text.push_str(&format!(
+                    "\n{} scored {:.1}: {}",
+                    if miss.is_spam { "Missed spam" } else { "False positive" },
+                    miss.score,
+                    miss.text
+                ));
+            }
+            if report.misclassified.len() > MAX_REPORTED_MISSES {
+                text.push_str(&format!("\n...and {} more", report.misclassified.len() - MAX_REPORTED_MISSES));
+            }
+            bot.send_message(msg.chat.id, text).await?;
+        }
+        Command::Score => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsViewScores)).await?;
+            } else if let Some(user) = replied_user(&msg) {
+                let user_id = user.id.to_string();
+                let notes = rule_manager.get_notes(msg.chat.id.0, &user_id).unwrap_or_else(|e| {
+                    log::error!("Failed to load notes: {}", e);
+                    Vec::new()
+                });
+                let spam_score = rule_manager.get_sender_score_async(msg.chat.id.0, &user_id).await;
+                let (tier, decayed) = rule_manager.effective_sender_score(msg.chat.id.0, &user_id, &rule_manager.decay_rates, now_unix());
+                let report = format!(
+                    "{}\nReputation: {} (decayed score {:.1})",
+                    format_user_report(&user_id, spam_score, &notes),
+                    tier,
+                    decayed
+                );
+                bot.send_message(msg.chat.id, report).await?;
+            } else {
+                bot.send_message(msg.chat.id, "Please reply to a user's message to view their score.").await?;
+            }
+        }
+        Command::Mute(spec) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsMute)).await?;
+                return Ok(());
+            }
+            let user = match replied_user(&msg) {
+                Some(user) => user.clone(),
+                None => {
+                    bot.send_message(msg.chat.id, "Please reply to a user's message to mute them.").await?;
+                    return Ok(());
+                }
+            };
+            let duration = match parse_mute_duration(&spec) {
+                Ok(duration) => duration,
+                Err(usage) => {
+                    bot.send_message(msg.chat.id, usage).await?;
+                    return Ok(());
+                }
+            };
+            let until = chrono::Utc::now() + chrono::Duration::seconds(duration.as_secs() as i64);
+            match with_retry(|| bot.restrict_chat_member(msg.chat.id, user.id, ChatPermissions::empty()).until_date(until)).await {
+                Ok(_) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("Muted user {} until {} UTC.", user.id, until.format("%Y-%m-%d %H:%M")),
+                    )
+                    .await?;
+                }
+                Err(e) => {
+                    log::error!("Failed to mute user {} in chat {}: {}", user.id, msg.chat.id, e);
+                    bot.send_message(msg.chat.id, "Failed to mute the user. Is the bot an admin with permission to restrict members?").await?;
+                }
+            }
+        }
+        Command::Whitelist | Command::Unwhitelist => {
+            // Everyone is an admin of their private chat with the bot, and the whitelist there exempts nobody
+            if msg.chat.is_private() {
+                bot.send_message(msg.chat.id, "The whitelist is kept per group; use this command in the group.").await?;
+                return Ok(());
+            }
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsWhitelist)).await?;
+                return Ok(());
+            }
+            let user_id = match replied_user(&msg) {
+                Some(user) => user.id.to_string(),
+                None => {
+                    bot.send_message(msg.chat.id, "Please reply to a user's message to manage the whitelist.").await?;
+                    return Ok(());
+                }
+            };
+            let reply = if matches!(cmd, Command::Whitelist) {
+                match rule_manager.add_to_whitelist(msg.chat.id.0, &user_id) {
+                    Ok(true) => format!("User {} is now whitelisted.", user_id),
+                    Ok(false) => format!("User {} is already whitelisted.", user_id),
+                    Err(e) => {
+                        log::error!("Failed to whitelist user: {}", e);
+                        "Failed to update the whitelist.".to_string()
+                    }
+                }
+            } else {
+                match rule_manager.remove_from_whitelist(msg.chat.id.0, &user_id) {
+                    Ok(true) => format!("User {} is no longer whitelisted.", user_id),
+                    Ok(false) => format!("User {} is not whitelisted.", user_id),
+                    Err(e) => {
+                        log::error!("Failed to unwhitelist user: {}", e);
+                        "Failed to update the whitelist.".to_string()
+                    }
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::ResetScore(arg) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsResetScores)).await?;
+                return Ok(());
+            }
+            let replied_to = replied_user(&msg).map(|user| user.id);
+            let user_id = match arg.trim().parse::<u64>().ok().map(UserId).or(replied_to) {
+                Some(user_id) => user_id,
+                None => {
+                    bot.send_message(msg.chat.id, "Usage: /reset_score <user id>, or reply to the user's message.").await?;
+                    return Ok(());
+                }
+            };
+            match rule_manager.reset_sender_score(msg.chat.id.0, &user_id.to_string()) {
+                Ok(true) => {}
+                Ok(false) => {
+                    bot.send_message(msg.chat.id, format!("User {} has a clean history.", user_id)).await?;
+                    return Ok(());
+                }
+                Err(e) => {
+                    log::error!("Failed to reset sender score: {}", e);
+                    bot.send_message(msg.chat.id, "Failed to reset score.").await?;
+                    return Ok(());
+                }
+            }
+            if msg.chat.is_group() || msg.chat.is_supergroup() {
+                match with_retry(|| bot.unban_chat_member(msg.chat.id, user_id).only_if_banned(true)).await {
+                    Ok(_) => {
+                        if let Err(e) = rule_manager.remove_ban(msg.chat.id.0, &user_id.to_string()) {
+                            log::error!("Failed to remove ban record: {}", e);
+                        }
+                    }
+                    Err(e) => log::error!("Failed to unban user {} in chat {}: {}", user_id, msg.chat.id, e),
+                }
+            }
+            bot.send_message(msg.chat.id, format!("Reset the spam score of user {} and lifted any ban.", user_id)).await?;
+        }
+        Command::Unban(arg) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsUnban)).await?;
+                return Ok(());
+            }
+            if !(msg.chat.is_group() || msg.chat.is_supergroup()) {
+                bot.send_message(msg.chat.id, "Use /unban in the group the user was banned from.").await?;
+                return Ok(());
+            }
+            let replied_to = replied_user(&msg).map(|user| user.id);
+            let user_id = match arg.trim().parse::<u64>().ok().map(UserId).or(replied_to) {
+                Some(user_id) => user_id,
+                None => {
+                    bot.send_message(msg.chat.id, "Usage: /unban <user id>, or reply to the user's message.").await?;
+                    return Ok(());
+                }
+            };
+            if let Err(e) = with_retry(|| bot.unban_chat_member(msg.chat.id, user_id).only_if_banned(true)).await {
+                log::error!("Failed to unban user {} in chat {}: {}", user_id, msg.chat.id, e);
+                bot.send_message(msg.chat.id, format!("Failed to unban user {}. Is the bot an admin with permission to ban members?", user_id)).await?;
+                return Ok(());
+            }
+            let ban = rule_manager.get_ban(msg.chat.id.0, &user_id.to_string());
+            if let Err(e) = rule_manager.remove_ban(msg.chat.id.0, &user_id.to_string()) {
+                log::error!("Failed to remove ban record: {}", e);
+            }
+            log::info!("Admin {:?} unbanned user {} in chat {}", msg.from().map(|user| user.id), user_id, msg.chat.id);
+            let reply = match ban {
+                Some((reason, banned_at)) => {
+                    let banned_at = chrono::DateTime::<chrono::Utc>::from_timestamp(banned_at, 0)
+                        .map_or_else(|| banned_at.to_string(), |time| time.format("%Y-%m-%d %H:%M UTC").to_string());
+                    format!("Unbanned user {}. They were banned on {}: {}.", user_id, banned_at, reason)
+                }
+                None => format!("Unbanned user {}. The bot has no record of banning them.", user_id),
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Warn => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsWarn)).await?;
+                return Ok(());
+            }
+            if !(msg.chat.is_group() || msg.chat.is_supergroup()) {
+                bot.send_message(msg.chat.id, "Warnings can only be issued in groups.").await?;
+                return Ok(());
+            }
+            let user_id = match replied_user(&msg) {
+                Some(user) => user.id,
+                None => {
+                    bot.send_message(msg.chat.id, "Please reply to a user's message to warn them.").await?;
+                    return Ok(());
+                }
+            };
+            let reply = match rule_manager.add_warning(msg.chat.id.0, &user_id.to_string()) {
+                Ok(warnings) => {
+                    apply_warnings(&bot, msg.chat.id, user_id, warnings, SpamAction::Notify, &config, &admin_cache, &rule_manager, lang)
+                        .await
+                }
+                Err(e) => {
+                    log::error!("Failed to add warning: {}", e);
+                    "Failed to warn the user.".to_string()
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::ClearWarns(arg) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsClearWarnings)).await?;
+                return Ok(());
+            }
+            let replied_to = replied_user(&msg).map(|user| user.id);
+            let user_id = match arg.trim().parse::<u64>().ok().map(UserId).or(replied_to) {
+                Some(user_id) => user_id,
+                None => {
+                    bot.send_message(msg.chat.id, "Usage: /clearwarns <user id>, or reply to the user's message.").await?;
+                    return Ok(());
+                }
+            };
+            let reply = match rule_manager.clear_warnings(msg.chat.id.0, &user_id.to_string()) {
+                Ok(true) => format!("Cleared the warnings of user {}.", user_id),
+                Ok(false) => format!("User {} has no warnings.", user_id),
+                Err(e) => {
+                    log::error!("Failed to clear warnings: {}", e);
+                    "Failed to clear warnings.".to_string()
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Whois(arg) => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsWhois)).await?;
+                return Ok(());
+            }
+            let replied_to = replied_user(&msg).map(|user| user.id);
+            let user_id = match arg.trim().parse::<u64>().ok().map(UserId).or(replied_to) {
+                Some(user_id) => user_id.to_string(),
+                None => {
+                    bot.send_message(msg.chat.id, "Usage: /whois <user id>, or reply to the user's message.").await?;
+                    return Ok(());
+                }
+            };
+            let chat_id = msg.chat.id.0;
+            let record = rule_manager
+                .run_blocking(move |manager: &RuleManager| manager.get_sender_record(chat_id, &user_id))
+                .await;
+            bot.send_message(msg.chat.id, format_sender_record(&record)).await?;
+        }
+        Command::Stats => {
+            if !is_admin(&bot, &msg, &admin_cache).await.unwrap_or(false) {
+                bot.send_message(msg.chat.id, lang.text(Key::OnlyAdminsViewStats)).await?;
+            } else if let Some(user) = replied_user(&msg) {
+                let user_id = user.id.to_string();
+                let report = match rule_manager.get_sender_stats(msg.chat.id.0, &user_id) {
+                    Some((spam_score, message_count)) => format!(
+                        "User {}\nSpam score: {}\nMessages: {}\nSpam ratio: {:.0}%",
+                        user_id,
+                        spam_score,
+                        message_count,
+                        rule_manager.spam_ratio(msg.chat.id.0, &user_id) * 100.0
+                    ),
+                    None => format!("User {} has a clean history.", user_id),
+                };
+                bot.send_message(msg.chat.id, report).await?;
+            } else {
+                bot.send_message(msg.chat.id, "Please reply to a user's message to view their stats.").await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the language to answer in in a chat, see `Lang::resolve`.
+///
+/// # Arguments
+/// * `rule_manager` - The manager chat languages are stored with.
+/// * `chat_id` - The chat being answered.
+/// * `user` - The user being answered, whose `language_code` is used if the chat has no language.
+fn chat_lang(rule_manager: &RuleManager, chat_id: ChatId, user: Option<&User>) -> Lang {
+    Lang::resolve(
+        rule_manager.get_chat_language(chat_id.0).as_deref(),
+        user.and_then(|user| user.language_code.as_deref()),
+    )
+}
+
+/// Checks that a command was sent by the bot operator (`BOT_OPERATOR_ID`), replying
+/// with why not otherwise.
+///
+/// Commands that change every chat at once, such as restoring backups, use this
+/// rather than `is_admin`, which holds for anyone in a private chat with the bot.
+///
+/// # Returns
+/// * `ResponseResult<bool>` - `true` if the sender is the operator, or a
+///   `RequestError` if the refusal could not be sent.
+async fn require_operator(bot: &Bot, msg: &Message, config: &Config, lang: Lang) -> ResponseResult<bool> {
+    if msg.from().is_some_and(|user| config.is_operator(user.id.0)) {
+        return Ok(true);
+    }
+    let key = if config.operator_id.is_some() { Key::OnlyOperator } else { Key::NoOperator };
+    bot.send_message(msg.chat.id, lang.text(key)).await?;
+    Ok(false)
+}
+
+/// Returns the user who wrote the message `msg` replies to.
+///
+/// # Returns
+/// * `Option<&User>` - The author, or `None` if `msg` is not a reply or the reply was
+///   posted by an anonymous admin or on behalf of a channel, whose placeholder `from`
+///   user must not be scored, muted or banned.
+fn replied_user(msg: &Message) -> Option<&User> {
+    let reply = msg.reply_to_message()?;
+    match message_sender(reply) {
+        MessageSender::User(_) => reply.from(),
+        _ => None,
+    }
+}
+
+/// Returns the blocklist entry `/block_sticker` and `/unblock_sticker` act on.
+///
+/// # Returns
+/// * `Option<String>` - The parsed argument if one was given, otherwise the sticker set
+///   or file ID of the replied-to message, or `None` if neither names any media.
+fn media_block_item(arg: &str, reply: Option<&Message>) -> Option<String> {
+    if arg.is_empty() {
+        reply.and_then(message_media).map(|media| media.block_key().to_string())
+    } else {
+        parse_media_block(arg)
+    }
+}
+
+/// Returns `true` for messages `check_message` scores: text, captions, venues, locations,
+/// stickers, GIFs and files.
+fn is_checkable(msg: Message) -> bool {
+    msg.text().is_some()
+        || msg.caption().is_some()
+        || msg.venue().is_some()
+        || msg.location().is_some()
+        || message_media(&msg).is_some()
 }
 
-/// Handles bot commands (`/start`, `/report`, `/add_rule`).
+/// Checks incoming messages for spam and notifies admins if detected.
+///
+/// Evaluates each text message against custom spam rules. If a message is flagged as spam
+/// (score at or above the chat's threshold), it increments the sender’s spam score, sends a notification to the chat,
+/// and attempts to notify admins. Non-spam messages increment the sender’s message count
+/// without affecting the spam score. Media captions are scored like text, venues are
+/// scored by their title and address, and location or venue messages from new users are
+/// also checked for rapid flooding.
+/// The score of senders with an established, mostly clean history is discounted by
+/// their spam ratio, so one tripped rule weighs less for them than for a new account.
+/// Senders with fewer than `NEWCOMER_CLEAN_MESSAGES` clean messages are on probation
+/// and their score is multiplied by `NEWCOMER_SCORE_MULTIPLIER`.
 ///
-/// Processes incoming commands, performs the associated actions, and sends responses
-/// to the chat. Only admins can use `/add_rule` to add custom spam rules.
+/// The database lookups made for every message (pause, whitelist, sender history and
+/// threshold) and the sender score updates run in the blocking thread pool. Rules, the
+/// Bayes model, allowed domains, safe patterns and blocked media are read from caches
+/// that only reach the database on a miss, and the calls made only for flagged messages
+/// (`record_spam_event`, `record_flagged_message`, `get_action_policy`, `add_warning`)
+/// still run on the async worker.
+///
+/// Edited messages are checked again, skipping the rate limiter and the detectors that
+/// track repeats. Only an edit that turns a message not yet flagged into spam is acted
+/// on, and it adds a spam point without counting another message.
+///
+/// Channel posts have no sender. They are scored by content only and spam is still
+/// recorded, deleted and reported to the admins under the channel's ID, but no sender
+/// score, warning or ban applies and no notice is posted in the channel.
+/// Messages from anonymous admins are not checked. Messages a user posts as their
+/// channel are treated like channel posts, since their `from` is a placeholder user.
 ///
 /// # Arguments
 /// * `bot` - The Telegram bot instance.
-/// * `msg` - The message containing the command.
-/// * `cmd` - The parsed command (e.g., `Start`, `Report`, `AddRule`).
+/// * `msg` - The incoming message to check.
 /// * `rule_manager` - A thread-safe reference to the `RuleManager` for rule operations.
+/// * `metrics` - The shared metrics aggregator updated for every checked message.
+/// * `detectors` - The built-in detectors, whose scores are added on top of the rule score.
+/// * `blocklist` - The optional external blocklist that confirmed spammers are reported to.
+/// * `config` - Bot-wide handler settings.
+/// * `shedder` - The optional load shedder; during a flood, established users skip scoring.
+/// * `rate_limiter` - Per-user message rate limiter; users over the limit are flagged as spam.
+/// * `admin_cache` - The shared cache of chat administrators.
+/// * `throttle` - Limits admin notifications per chat during spam bursts.
+/// * `cooldown` - Collapses the notifications about one sender's spam burst.
+/// * `verdicts` - The verdicts of recent messages, consulted when they are edited.
 ///
 /// # Returns
 /// * `Result<()>` - A `Result` indicating success or a `teloxide::RequestError` if the operation fails.
-///
-/// # Panics
-/// * Panics if `msg.from()` is `None` in contexts where the sender is required.
-async fn answer(
+#[allow(clippy::too_many_arguments)]
+async fn check_message(
     bot: Bot,
     msg: Message,
-    cmd: Command,
     rule_manager: Arc<RuleManager>,
+    metrics: Arc<Metrics>,
+    detectors: Arc<Detectors>,
+    blocklist: Option<Arc<BlocklistClient>>,
+    config: Arc<Config>,
+    shedder: Option<Arc<LoadShedder>>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    admin_cache: Arc<AdminCache>,
+    throttle: Arc<NotificationThrottle>,
+    cooldown: Arc<SenderCooldown>,
+    verdicts: Arc<MessageVerdicts>,
 ) -> Result<(), teloxide::RequestError> {
-    match cmd {
-        Command::Start => {
-            bot.send_message(msg.chat.id, "Hello! I'm a spam filter bot.").await?;
+    if let Some(text) = message_text(&msg) {
+        let text = text.as_str();
+        let media = message_media(&msg);
+        // Stickers, GIFs and files without a caption are shown to admins by what they are
+        let shown = match &media {
+            Some(media) if text.is_empty() => media.describe(),
+            _ => text.to_string(),
+        };
+        // Skip if the message is a command (captions cannot carry commands)
+        if msg.text().is_some() && text.starts_with('/') {
+            return Ok(());
         }
-        Command::Report => {
-            if let Some(reply) = msg.reply_to_message() {
-                let text = reply.text().unwrap_or("(non-text message)");
-                let is_spam = rule_manager.check_custom_rules(text) >= 5.0;
-                bot.send_message(msg.chat.id, format!("Reported: {}\nSpam: {}", text, is_spam)).await?;
-                if is_spam {
-                    let user_id = reply.from().unwrap().id.to_string();
-                    if let Err(e) = rule_manager.increment_sender_score(&user_id, true) {
-                        log::error!("Failed to update sender score: {}", e);
+        // While an admin has paused moderation, messages are only logged
+        if rule_manager.is_paused_async(msg.chat.id.0, now_unix()).await {
+            log::info!("Moderation paused in chat {}; not checking message {}: '{}'", msg.chat.id, msg.id.0, text);
+            return Ok(());
+        }
+        // Skip official posts reflected from the group's linked channel
+        if config.exempt_linked_channel && msg.is_automatic_forward() {
+            let linked_chat_id = match bot.get_chat(msg.chat.id).await {
+                Ok(chat) => chat.linked_chat_id().map(ChatId),
+                Err(e) => {
+                    log::warn!("Failed to fetch linked channel of chat {}: {}", msg.chat.id, e);
+                    None
+                }
+            };
+            if is_linked_channel_post(msg.sender_chat().map(|chat| chat.id), true, linked_chat_id) {
+                log::info!("Skipping linked channel post in chat {}", msg.chat.id);
+                return Ok(());
+            }
+        }
+        // Posts on behalf of a chat have no sender; they are flagged and deleted, but nobody is scored or punished
+        let sender_id = match message_sender(&msg) {
+            MessageSender::User(user_id) => Some(user_id.0),
+            MessageSender::AnonymousAdmin => {
+                log::debug!("Skipping anonymous admin message in chat {}", msg.chat.id);
+                return Ok(());
+            }
+            MessageSender::Chat(_) | MessageSender::Unknown => None,
+        };
+        // Every new message counts towards the chat's rate, including those skipped below
+        if msg.edit_date().is_none() && detectors.lockdown.record(msg.chat.id.0, Instant::now()) {
+            log::warn!("Lockdown engaged in chat {}", msg.chat.id);
+            if !config.shadow_mode {
+                let notice = lockdown_engaged_notice(&detectors.lockdown);
+                if let Err(e) = message_admins(&bot, msg.chat.id, &notice, &admin_cache).await {
+                    log::error!("Failed to notify admins about the lockdown: {}", e);
+                }
+            }
+        }
+        // Under a flood, skip scoring for established users without touching the database
+        if let (Some(shedder), Some(sender_id)) = (&shedder, sender_id) {
+            if shedder.should_skip(sender_id, Instant::now()) {
+                log::debug!("Load shedding: skipping established user {}", sender_id);
+                return Ok(());
+            }
+        }
+        let user_id = sender_id.map(|id| id.to_string());
+        // Whitelisted senders are never scored, so they never accumulate spam points
+        if let Some(user_id) = &user_id {
+            if rule_manager.is_whitelisted_async(msg.chat.id.0, user_id).await {
+                log::debug!("Skipping whitelisted user {}", user_id);
+                return Ok(());
+            }
+        }
+        // Who the spam is attributed to in logs, spam events and notifications
+        let author = user_id.clone().unwrap_or_else(|| msg.sender_chat().unwrap_or(&msg.chat).id.to_string());
+        let edited = msg.edit_date().is_some();
+        let started = Instant::now();
+        let lua_score = match rule_manager.check_custom_rules(text) {
+            Ok(score) => score,
+            Err(e) => {
+                // Keep scoring with the built-in rules and make sure admins learn the script is broken
+                if matches!(e, RuleManagerError::Lua(LuaRulesError::Timeout { .. })) {
+                    log::warn!("Lua rules timed out, scoring built-in rules only: {}", e);
+                } else {
+                    log::error!("Lua rules failed, scoring built-in rules only: {}", e);
+                }
+                if rule_manager.should_alert_lua_failure(msg.chat.id.0, started) {
+                    let alert = format!(
+                        "Lua spam rules are broken ({}). Messages are only scored by the built-in rules and detectors until the script is fixed.",
+                        e
+                    );
+                    if let Err(e) = message_admins(&bot, msg.chat.id, &alert, &admin_cache).await {
+                        log::error!("Failed to alert admins about broken Lua rules: {}", e);
                     }
-                    notify_admins(&bot, msg.chat.id, text, &rule_manager, &user_id).await?;
                 }
-            } else {
-                bot.send_message(msg.chat.id, "Please reply to a message to report it.").await?;
+                0.0
             }
+        };
+        metrics.record_lua_latency(started.elapsed());
+        let rule_matches = rule_manager.matched_rules(text);
+        if !edited {
+            rule_manager.record_rule_hits(&rule_matches);
         }
-        Command::AddRule(args) => {
-            if is_admin(&bot, &msg).await.unwrap_or(false) {
-                let parts: Vec<&str> = args.split_whitespace().collect();
-                if parts.len() == 2 {
-                    let keyword = parts[0].to_string();
-                    if let Ok(score) = parts[1].parse::<f32>() {
-                        if let Err(e) = rule_manager.add_rule(keyword.clone(), score) {
-                            log::error!("Failed to add rule: {}", e);
-                            bot.send_message(msg.chat.id, "Failed to add rule.").await?;
-                        } else {
-                            bot.send_message(msg.chat.id, format!("Added rule: '{}' with score {}", keyword, score)).await?;
-                        }
+        let builtin_score: f32 = rule_matches.iter().map(|(_, score)| score).sum();
+        let mut contributions = vec![("lua_rules", lua_score), ("builtin_rules", builtin_score)];
+        let allowed_domains = rule_manager.get_allowed_domains(msg.chat.id.0).unwrap_or_else(|e| {
+            log::error!("Failed to load allowed domains: {}", e);
+            Vec::new()
+        });
+        match sender_id {
+            Some(sender_id) if !edited => {
+                contributions.extend(detectors.evaluate_message(msg.chat.id.0, sender_id, text, started, &allowed_domains))
+            }
+            // The per-user and per-chat detectors already saw the original and would take the edit for a repeat
+            _ => contributions.extend(detectors.evaluate_content(text, &allowed_domains)),
+        }
+        let message_count = match &user_id {
+            Some(user_id) => rule_manager.get_message_count_async(msg.chat.id.0, user_id).await,
+            None => 0,
+        };
+        if user_id.is_some() {
+            contributions.push((
+                "new_sender_link",
+                detectors.new_sender_links.check(message_count, text, &allowed_domains),
+            ));
+            contributions.push(("lockdown", detectors.lockdown.check(msg.chat.id.0, message_count, started)));
+            contributions.push(("new_sender_media", detectors.new_sender_media.check(message_count, media.is_some())));
+        }
+        if config.bayes_filter {
+            contributions.push(("bayes", config.bayes_contribution(rule_manager.bayes_score(text))));
+        }
+        if let Some(sender_id) = sender_id {
+            if !edited && (msg.location().is_some() || msg.venue().is_some()) {
+                contributions.push((
+                    "location_flood",
+                    detectors.location_flood.check(msg.chat.id.0, sender_id, message_count, started),
+                ));
+            }
+        }
+        let raw_score: f32 = contributions.iter().map(|(_, score)| score).sum();
+        // A safe pattern lets the message pass, however many spam rules it also matches
+        let safe_pattern = rule_manager.matched_safe_pattern(msg.chat.id.0, text).unwrap_or_else(|e| {
+            log::error!("Failed to load safe patterns: {}", e);
+            None
+        });
+        if let Some(pattern) = &safe_pattern {
+            log::debug!("Message in chat {} matches safe pattern '{}'", msg.chat.id, pattern);
+        }
+        let raw_score = net_score(raw_score, safe_pattern.is_some());
+        // Established senders with a mostly clean history are given the benefit of the doubt,
+        // while senders without enough clean messages yet are on probation
+        let custom_score = match &user_id {
+            Some(user_id) => {
+                let clean_messages = message_count - rule_manager.get_spam_message_count_async(msg.chat.id.0, user_id).await;
+                let spam_ratio = rule_manager.spam_ratio_async(msg.chat.id.0, user_id).await;
+                raw_score * history_multiplier(spam_ratio, message_count) * config.newcomer_multiplier(clean_messages)
+            }
+            None => raw_score,
+        };
+        metrics.incr_messages_processed();
+        let rate_limited = match &user_id {
+            Some(user_id) => !edited && !rate_limiter.lock_or_recover().check(user_id, started),
+            None => false,
+        };
+        if rate_limited {
+            log::info!("User {} exceeded the message rate limit", author);
+        }
+        // A blocked sticker set or file is spam whatever the score
+        let blocked_media = media.as_ref().and_then(|media| {
+            rule_manager.matched_blocked_media(msg.chat.id.0, media).unwrap_or_else(|e| {
+                log::error!("Failed to load blocked media: {}", e);
+                None
+            })
+        });
+        if let Some(item) = &blocked_media {
+            log::info!("Message {} in chat {} contains blocked media '{}'", msg.id.0, msg.chat.id, item);
+        }
+        let threshold = rule_manager.get_chat_threshold_async(msg.chat.id.0).await;
+        let is_spam = rate_limited || blocked_media.is_some() || custom_score >= threshold;
+        log::info!(
+            user_id = author.as_str(),
+            chat_id = msg.chat.id.0,
+            custom_score = custom_score,
+            threshold = threshold,
+            is_spam = is_spam,
+            rate_limited = rate_limited,
+            edited = edited;
+            "Checked message '{}' from user {}: score {} (threshold {}), spam: {}",
+            shown, author, custom_score, threshold, is_spam
+        );
+        let previous_verdict = verdicts.previous(msg.chat.id.0, msg.id.0);
+        verdicts.record(msg.chat.id.0, msg.id.0, is_spam);
+        // The original message was already counted; only an edit that turns it into spam needs acting on
+        if edited && (!is_spam || previous_verdict == Some(true)) {
+            log::debug!("Edit of message {} in chat {} needs no action (spam: {})", msg.id.0, msg.chat.id, is_spam);
+            return Ok(());
+        }
+        if let (Some(shedder), Some(sender_id)) = (&shedder, sender_id) {
+            shedder.record_verdict(sender_id, is_spam);
+        }
+        let is_group = msg.chat.is_group() || msg.chat.is_supergroup();
+        if is_spam && config.shadow_mode {
+            metrics.incr_would_flag();
+            // The message still counts towards the sender's history, but adds no spam points
+            if let (Some(user_id), false) = (&user_id, edited) {
+                if let Err(e) = rule_manager.increment_sender_score_async(msg.chat.id.0, user_id, false).await {
+                    log::error!("Failed to update sender score: {}", e);
+                }
+            }
+            let points = config.spam_points(custom_score, threshold);
+            let actions = shadow_actions(&msg, user_id.as_deref(), is_group, points, &config, &rule_manager);
+            log::info!(
+                user_id = author.as_str(),
+                chat_id = msg.chat.id.0,
+                custom_score = custom_score,
+                shadow = true;
+                "Shadow mode: would have flagged message {} in chat {} from user {} (score {}); would {}",
+                msg.id.0, msg.chat.id, author, custom_score, actions.join(", ")
+            );
+            return Ok(());
+        }
+        if is_spam {
+            metrics.incr_spam_flagged();
+            let (position, finished) = match sender_id {
+                Some(sender_id) => cooldown.record(msg.chat.id, UserId(sender_id), &author, Instant::now()),
+                None => (1, None),
+            };
+            if let Some(messages) = finished {
+                if let Err(e) = message_admins(&bot, msg.chat.id, &sender_burst_summary(&author, messages), &admin_cache).await {
+                    log::error!("Failed to send spam burst summary: {}", e);
+                }
+            }
+            if let (Some(user_id), Some(sender_id)) = (&user_id, sender_id) {
+                // Past the cap, a burst's messages are still counted but add no more spam points
+                let scored = if !cooldown.counts_score(position) {
+                    if edited {
+                        Ok(())
                     } else {
-                        bot.send_message(msg.chat.id, "Invalid score.").await?;
+                        rule_manager.increment_sender_score_async(msg.chat.id.0, user_id, false).await
                     }
+                } else if edited {
+                    rule_manager.add_spam_points_async(msg.chat.id.0, user_id, config.spam_points(custom_score, threshold)).await
                 } else {
-                    bot.send_message(msg.chat.id, "Usage: /add_rule <keyword> <score>").await?;
+                    rule_manager.increment_sender_score_by_async(msg.chat.id.0, user_id, config.spam_points(custom_score, threshold)).await
+                };
+                if let Err(e) = scored {
+                    log::error!("Failed to update sender score: {}", e);
+                }
+                let spam_score = rule_manager.get_sender_score_async(msg.chat.id.0, user_id).await;
+                if is_group && config.should_auto_ban(spam_score) {
+                    auto_ban(&bot, msg.chat.id, UserId(sender_id), spam_score, &config, &admin_cache, &metrics, &rule_manager).await;
                 }
+                if let Some(blocklist) = blocklist {
+                    let (user_id, chat_id, evidence) = (user_id.clone(), msg.chat.id.0, shown.clone());
+                    tokio::spawn(async move {
+                        blocklist.report(&user_id, chat_id, &evidence).await;
+                    });
+                }
+            }
+            if let Err(e) = rule_manager.record_spam_event(msg.chat.id.0, &author, custom_score, &contributions) {
+                log::error!("Failed to record spam event: {}", e);
+            }
+            if let Err(e) = rule_manager.record_flagged_message(msg.chat.id.0, &author, &shown, custom_score) {
+                log::error!("Failed to record flagged message: {}", e);
+            }
+            // Delete first, so the spam disappears even if notifying the admins fails
+            let policy = rule_manager.get_action_policy(msg.chat.id.0);
+            let mut deleted = false;
+            if policy.map_or(config.delete_spam, ActionPolicy::deletes) {
+                match delete_message(&bot, msg.chat.id, msg.id).await {
+                    Ok(()) => {
+                        deleted = true;
+                        if let Some(sender_id) = sender_id {
+                            detectors.reposts.record_deletion(msg.chat.id.0, sender_id, text, Instant::now());
+                        }
+                        metrics.incr_actions_taken();
+                    }
+                    Err(e) => log::warn!(
+                        "Failed to delete spam message {} in chat {}; does the bot have permission to delete messages? {}",
+                        msg.id.0, msg.chat.id, e
+                    ),
+                }
+            }
+            let lang = chat_lang(&rule_manager, msg.chat.id, msg.from());
+            let mut notice = config.spam_notice(lang, deleted);
+            if let (Some(user_id), Some(sender_id), true) = (&user_id, sender_id, is_group) {
+                match rule_manager.add_warning(msg.chat.id.0, user_id) {
+                    Ok(warnings) => {
+                        let minimum = policy.map_or(SpamAction::Notify, ActionPolicy::sender_action);
+                        let outcome = apply_warnings(
+                            &bot,
+                            msg.chat.id,
+                            UserId(sender_id),
+                            warnings,
+                            minimum,
+                            &config,
+                            &admin_cache,
+                            &rule_manager,
+                            lang,
+                        )
+                        .await;
+                        notice = notice.map(|notice| format!("{}\n{}", notice, outcome));
+                    }
+                    Err(e) => log::error!("Failed to add warning: {}", e),
+                }
+            }
+            // A notice in a channel would be broadcast to every subscriber
+            match notice {
+                Some(notice) if !msg.chat.is_channel() => {
+                    bot.send_message(msg.chat.id, notice).await?;
+                    metrics.incr_actions_taken();
+                }
+                _ => {}
+            }
+            if position > 1 {
+                log::debug!("Collapsed admin notification for spam message {} of {}'s burst in chat {}", position, author, msg.chat.id);
             } else {
-                bot.send_message(msg.chat.id, "Only admins can add rules.").await?;
+                match throttle.admit(msg.chat.id, Instant::now()) {
+                    Some(suppressed) => {
+                        if suppressed > 0 {
+                            if let Err(e) = message_admins(&bot, msg.chat.id, &suppressed_summary(suppressed), &admin_cache).await {
+                                log::error!("Failed to send suppressed notification summary: {}", e);
+                            }
+                        }
+                        match notify_admins(&bot, msg.chat.id, &shown, &rule_manager, &author, deleted, &admin_cache).await {
+                            Ok(_) => {
+                                metrics.incr_actions_taken();
+                                log::info!("Successfully notified admins for spam message: '{}'", shown)
+                            }
+                            Err(e) => log::error!("Failed to notify admins for spam message '{}': {}", shown, e),
+                        }
+                    }
+                    None => log::debug!("Throttled admin notification for spam in chat {}", msg.chat.id),
+                }
+            }
+        } else {
+            if config.bayes_filter && !edited && !text.is_empty() {
+                rule_manager.train_bayes(text, false);
+            }
+            if let Some(user_id) = &user_id {
+                if let Err(e) = rule_manager.increment_sender_score_async(msg.chat.id.0, user_id, false).await {
+                    log::error!("Failed to update sender score: {}", e);
+                }
             }
         }
     }
     Ok(())
 }
 
-/// Checks incoming messages for spam and notifies admins if detected.
+/// Lists what `check_message` would do about a spam message if shadow mode were off.
 ///
-/// Evaluates each text message against custom spam rules. If a message is flagged as spam
-/// (score >= 5.0), it increments the sender’s spam score, sends a notification to the chat,
-/// and attempts to notify admins. Non-spam messages increment the sender’s message count
-/// without affecting the spam score.
+/// # Arguments
+/// * `msg` - The spam message.
+/// * `user_id` - The sender, if the message has one.
+/// * `is_group` - Whether the message was posted in a group.
+/// * `points` - The spam points the message would add to the sender's score.
+/// * `config` - Bot-wide handler settings.
+/// * `rule_manager` - The manager the sender's score and warnings are read from.
+///
+/// # Returns
+/// * `Vec<String>` - The actions, in the order they would be taken.
+fn shadow_actions(
+    msg: &Message,
+    user_id: Option<&str>,
+    is_group: bool,
+    points: i32,
+    config: &Config,
+    rule_manager: &RuleManager,
+) -> Vec<String> {
+    let mut actions = Vec::new();
+    let policy = rule_manager.get_action_policy(msg.chat.id.0);
+    if policy.map_or(config.delete_spam, ActionPolicy::deletes) {
+        actions.push("delete the message".to_string());
+    }
+    if let (Some(user_id), true) = (user_id, is_group) {
+        let spam_score = rule_manager.get_sender_score(msg.chat.id.0, user_id).saturating_add(points);
+        if config.should_auto_ban(spam_score) {
+            actions.push(format!("ban the sender (spam score {})", spam_score));
+        }
+        let warnings = rule_manager.get_warnings(msg.chat.id.0, user_id) + 1;
+        match config.warning_action(warnings).max(policy.map_or(SpamAction::Notify, ActionPolicy::sender_action)) {
+            SpamAction::Ban => actions.push(format!("warn and ban the sender ({} warnings)", warnings)),
+            SpamAction::Mute => actions.push(format!("warn and mute the sender ({} warnings)", warnings)),
+            SpamAction::Notify => actions.push(format!("warn the sender ({} warnings)", warnings)),
+        }
+    }
+    if !msg.chat.is_channel() {
+        actions.push("post a notice in the chat".to_string());
+    }
+    actions.push("notify the admins".to_string());
+    actions
+}
+
+/// Applies the action a sender's warnings have earned in a group, see `Config::warning_action`,
+/// or `minimum` if that is harsher.
+///
+/// Like every destructive action, mutes and bans fall back to the warning alone if
+/// the chat has fewer human admins than configured. Failures are logged, so a failed
+/// mute or ban never stops the caller.
 ///
 /// # Arguments
 /// * `bot` - The Telegram bot instance.
-/// * `msg` - The incoming message to check.
-/// * `rule_manager` - A thread-safe reference to the `RuleManager` for rule operations.
+/// * `chat_id` - The group the sender was warned in.
+/// * `user_id` - The warned sender.
+/// * `warnings` - The sender's number of warnings, including the new one.
+/// * `minimum` - The least the chat's action policy does to the sender.
+/// * `config` - Bot-wide handler settings.
+/// * `admin_cache` - The shared cache of chat administrators.
+/// * `rule_manager` - The manager bans are recorded with.
+/// * `lang` - The language of the chat.
 ///
 /// # Returns
-/// * `Result<()>` - A `Result` indicating success or a `teloxide::RequestError` if the operation fails.
+/// * `String` - A line for the chat telling the sender where they stand.
+#[allow(clippy::too_many_arguments)]
+async fn apply_warnings(
+    bot: &Bot,
+    chat_id: ChatId,
+    user_id: UserId,
+    warnings: i32,
+    minimum: SpamAction,
+    config: &Config,
+    admin_cache: &AdminCache,
+    rule_manager: &RuleManager,
+    lang: Lang,
+) -> String {
+    let key = if warnings == 1 { Key::WarningsOne } else { Key::WarningsMany };
+    let status = lang.format(key, &[&user_id, &warnings]);
+    let requested = config.warning_action(warnings).max(minimum);
+    if !requested.is_destructive() {
+        return status;
+    }
+    let human_admins = count_human_admins(bot, chat_id, admin_cache).await.unwrap_or_else(|e| {
+        log::error!("Failed to fetch admins for chat {}: {}", chat_id, e);
+        0
+    });
+    match effective_action(requested, human_admins, config.min_human_admins) {
+        SpamAction::Ban => match with_retry(|| bot.ban_chat_member(chat_id, user_id)).await {
+            Ok(_) => {
+                log::info!("Banned user {} in chat {} after {} warnings", user_id, chat_id, warnings);
+                let reason = format!("banned after {} warnings", warnings);
+                if let Err(e) = rule_manager.record_ban(chat_id.0, &user_id.to_string(), &reason) {
+                    log::error!("Failed to record ban: {}", e);
+                }
+                format!("{} {}", status, lang.text(Key::Banned))
+            }
+            Err(e) => {
+                log::error!("Failed to ban user {} in chat {}: {}", user_id, chat_id, e);
+                status
+            }
+        },
+        SpamAction::Mute => {
+            let until = chrono::Utc::now() + chrono::Duration::seconds(DEFAULT_MUTE_DURATION.as_secs() as i64);
+            match with_retry(|| bot.restrict_chat_member(chat_id, user_id, ChatPermissions::empty()).until_date(until)).await {
+                Ok(_) => {
+                    log::info!("Muted user {} in chat {} after {} warnings", user_id, chat_id, warnings);
+                    let until = until.format("%Y-%m-%d %H:%M");
+                    format!("{} {}", status, lang.format(Key::MutedUntil, &[&until]))
+                }
+                Err(e) => {
+                    log::error!("Failed to mute user {} in chat {}: {}", user_id, chat_id, e);
+                    status
+                }
+            }
+        }
+        SpamAction::Notify => {
+            log::warn!(
+                "Not escalating warnings of user {}: chat {} has {} human admins (minimum {})",
+                user_id, chat_id, human_admins, config.min_human_admins
+            );
+            status
+        }
+    }
+}
+
+/// Handles a click on one of the Ban / Mute / Dismiss buttons of a spam notification.
 ///
-/// # Panics
-/// * Panics if `msg.from()` is `None` (i.e., no sender information).
-async fn check_message(
+/// Only admins of the chat the spam was posted in may act, since the notification
+/// may have been sent in the group itself. The callback query is always answered so
+/// Telegram stops showing the loading spinner, and on success the buttons are
+/// replaced with the outcome so the action is not taken twice.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance.
+/// * `query` - The callback query from the clicked button.
+/// * `rule_manager` - The manager bans are recorded with.
+/// * `admin_cache` - The shared cache of chat administrators.
+async fn handle_spam_callback(
     bot: Bot,
-    msg: Message,
+    query: CallbackQuery,
     rule_manager: Arc<RuleManager>,
-) -> Result<(), teloxide::RequestError> {
-    if let Some(text) = msg.text() {
-        // Skip if the message is a command
-        if text.starts_with('/') {
+    admin_cache: Arc<AdminCache>,
+) -> ResponseResult<()> {
+    let callback = match query.data.as_deref().and_then(SpamCallback::parse) {
+        Some(callback) => callback,
+        None => {
+            bot.answer_callback_query(query.id).await?;
             return Ok(());
         }
-        let user_id = msg.from().unwrap().id.to_string();
-        let custom_score = rule_manager.check_custom_rules(text);
-        let is_spam = custom_score >= 5.0;
-        log::info!(
-            "Message: '{}', User ID: {}, Custom Score: {}, Is Spam: {}",
-            text, user_id, custom_score, is_spam
-        );
-        if is_spam {
-            if let Err(e) = rule_manager.increment_sender_score(&user_id, true) {
-                log::error!("Failed to update sender score: {}", e);
+    };
+    let is_chat_admin = match admin_cache.admins(&bot, callback.chat_id).await {
+        Ok(admins) => admins.iter().any(|admin| admin.id == query.from.id),
+        Err(e) => {
+            log::error!("Failed to fetch admins for chat {}: {}", callback.chat_id, e);
+            false
+        }
+    };
+    if !is_chat_admin {
+        let lang = chat_lang(&rule_manager, callback.chat_id, Some(&query.from));
+        bot.answer_callback_query(query.id)
+            .text(lang.text(Key::OnlyChatAdmins))
+            .show_alert(true)
+            .await?;
+        return Ok(());
+    }
+    let (chat_id, user_id) = (callback.chat_id, callback.user_id);
+    let outcome = match callback.action {
+        NotificationAction::Ban => match with_retry(|| bot.ban_chat_member(chat_id, user_id)).await {
+            Ok(_) => {
+                let reason = format!("banned from a spam notification by admin {}", query.from.id);
+                if let Err(e) = rule_manager.record_ban(chat_id.0, &user_id.to_string(), &reason) {
+                    log::error!("Failed to record ban: {}", e);
+                }
+                format!("Banned user {} by {}.", user_id, query.from.id)
             }
-            bot.send_message(msg.chat.id, "Spam detected! Admins notified.").await?;
-            match notify_admins(&bot, msg.chat.id, text, &rule_manager, &user_id).await {
-                Ok(_) => log::info!("Successfully notified admins for spam message: '{}'", text),
-                Err(e) => log::error!("Failed to notify admins for spam message '{}': {}", text, e),
+            Err(e) => {
+                log::error!("Failed to ban user {} in chat {}: {}", user_id, chat_id, e);
+                format!("Failed to ban user {}. Is the bot an admin with permission to ban members?", user_id)
             }
-        } else {
-            if let Err(e) = rule_manager.increment_sender_score(&user_id, false) {
-                log::error!("Failed to update sender score: {}", e);
+        },
+        NotificationAction::Mute => {
+            let until = chrono::Utc::now() + chrono::Duration::seconds(DEFAULT_MUTE_DURATION.as_secs() as i64);
+            match with_retry(|| bot.restrict_chat_member(chat_id, user_id, ChatPermissions::empty()).until_date(until)).await {
+                Ok(_) => format!("Muted user {} until {} UTC by {}.", user_id, until.format("%Y-%m-%d %H:%M"), query.from.id),
+                Err(e) => {
+                    log::error!("Failed to mute user {} in chat {}: {}", user_id, chat_id, e);
+                    format!("Failed to mute user {}. Is the bot an admin with permission to restrict members?", user_id)
+                }
             }
         }
+        NotificationAction::Dismiss => format!("Dismissed by {}.", query.from.id),
+    };
+    log::info!("Spam notification action {:?} on user {} in chat {}: {}", callback.action, user_id, chat_id, outcome);
+    bot.answer_callback_query(query.id).text(outcome.clone()).await?;
+    if let Some(message) = query.message {
+        let text = format!("{}\n\n{}", message.text().unwrap_or_default(), outcome);
+        if let Err(e) = bot.edit_message_text(message.chat.id, message.id, text).await {
+            log::error!("Failed to update spam notification: {}", e);
+        }
     }
     Ok(())
 }
 
-/// Logs when the bot is added to a new chat.
+/// Handles a click on one of the keyword buttons offered by `/report <text>`.
+///
+/// Only admins of the group the buttons were sent in, or the bot operator, may add
+/// rules; in a private chat with the bot only the operator may. The keyword is added
+/// with the bot-wide detection threshold as its score, so messages containing it are
+/// flagged on their own.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance.
+/// * `query` - The callback query from the clicked button.
+/// * `rule_manager` - The manager the rule is added to.
+/// * `admin_cache` - The shared cache of chat administrators.
+/// * `config` - Bot-wide handler settings, for the bot operator.
+async fn handle_add_rule_callback(
+    bot: Bot,
+    query: CallbackQuery,
+    rule_manager: Arc<RuleManager>,
+    admin_cache: Arc<AdminCache>,
+    config: Arc<Config>,
+) -> ResponseResult<()> {
+    let keyword = query.data.as_deref().and_then(|data| data.strip_prefix(ADD_RULE_PREFIX)).map(str::to_string);
+    let (keyword, chat) = match (keyword, query.message.as_ref().map(|message| &message.chat)) {
+        (Some(keyword), Some(chat)) => (keyword, chat),
+        _ => {
+            bot.answer_callback_query(query.id).await?;
+            return Ok(());
+        }
+    };
+    let allowed = config.is_operator(query.from.id.0)
+        || !chat.is_private()
+            && match admin_cache.admins(&bot, chat.id).await {
+                Ok(admins) => admins.iter().any(|admin| admin.id == query.from.id),
+                Err(e) => {
+                    log::error!("Failed to fetch admins for chat {}: {}", chat.id, e);
+                    false
+                }
+            };
+    let answer = if !allowed {
+        chat_lang(&rule_manager, chat.id, Some(&query.from)).text(Key::OnlyAdminsAddRules).to_string()
+    } else {
+        match rule_manager.add_rule_async(keyword.clone(), rule_manager.threshold).await {
+            Ok(_) => format!("Added rule: '{}' with score {}", keyword, rule_manager.threshold),
+            Err(e) => {
+                log::error!("Failed to add rule '{}': {}", keyword, e);
+                "Failed to add the rule.".to_string()
+            }
+        }
+    };
+    bot.answer_callback_query(query.id).text(answer).show_alert(!allowed).await?;
+    Ok(())
+}
+
+/// Handles new members joining a chat.
 ///
-/// This function is triggered when the bot is added to a group or channel.
-/// It logs the chat details for debugging purposes but does not send any messages.
+/// Logs the chat details for debugging purposes. When the external blocklist is
+/// configured with pre-banning enabled, each new (non-bot) member is looked up and
+/// banned if the service lists them as a known spammer. If the chat has fewer human
+/// admins than configured, the ban is replaced by a notification in the chat.
+/// When `CAPTCHA` is enabled, every other new (non-bot) member is challenged, see
+/// `start_captcha`.
 ///
 /// # Arguments
-/// * `bot` - The Telegram bot instance (unused in this function).
-/// * `msg` - The message indicating the bot was added to a chat.
-async fn handle_new_chat_members(_bot: Bot, msg: Message) {
+/// * `bot` - The Telegram bot instance.
+/// * `msg` - The message announcing the new chat members.
+/// * `rule_manager` - The manager pre-bans are recorded with.
+/// * `blocklist` - The optional external blocklist.
+/// * `config` - Bot-wide handler settings.
+/// * `admin_cache` - The shared cache of chat administrators.
+/// * `challenges` - The pending CAPTCHA challenges.
+async fn handle_new_chat_members(
+    bot: Bot,
+    msg: Message,
+    rule_manager: Arc<RuleManager>,
+    blocklist: Option<Arc<BlocklistClient>>,
+    config: Arc<Config>,
+    admin_cache: Arc<AdminCache>,
+    challenges: Arc<CaptchaChallenges>,
+) {
     log::info!("Bot added to chat: {:?}", msg.chat);
+    let blocklist = blocklist.filter(|blocklist| blocklist.preban_enabled());
+    let welcome = rule_manager.get_welcome_message(msg.chat.id.0);
+    if blocklist.is_none() && !config.captcha && welcome.is_none() {
+        return;
+    }
+    // Members who join together are greeted in one message; blocklisted members are not greeted
+    let mut greeted = Vec::new();
+    for member in msg.new_chat_members().unwrap_or_default() {
+        if member.is_bot {
+            continue;
+        }
+        let listed = match &blocklist {
+            Some(blocklist) => blocklist.is_listed(&member.id.to_string()).await,
+            None => false,
+        };
+        if !listed {
+            greeted.push((member.id, member.full_name()));
+        }
+        if !listed && !config.captcha {
+            continue;
+        }
+        let human_admins = count_human_admins(&bot, msg.chat.id, &admin_cache).await.unwrap_or_else(|e| {
+            log::error!("Failed to fetch admins for chat {}: {}", msg.chat.id, e);
+            0
+        });
+        if !listed {
+            if effective_action(SpamAction::Mute, human_admins, config.min_human_admins) == SpamAction::Mute {
+                start_captcha(&bot, msg.chat.id, member, &config, &challenges).await;
+            } else {
+                log::warn!(
+                    "Not challenging new member {}: chat {} has {} human admins (minimum {})",
+                    member.id, msg.chat.id, human_admins, config.min_human_admins
+                );
+            }
+            continue;
+        }
+        match effective_action(SpamAction::Ban, human_admins, config.min_human_admins) {
+            SpamAction::Ban => match with_retry(|| bot.ban_chat_member(msg.chat.id, member.id)).await {
+                Ok(_) => {
+                    log::info!("Pre-banned blocklisted user {} in chat {}", member.id, msg.chat.id);
+                    if let Err(e) = rule_manager.record_ban(msg.chat.id.0, &member.id.to_string(), "listed in the shared blocklist") {
+                        log::error!("Failed to record ban: {}", e);
+                    }
+                }
+                Err(e) => log::error!("Failed to pre-ban blocklisted user {}: {}", member.id, e),
+            },
+            _ => {
+                log::warn!(
+                    "Not banning blocklisted user {}: chat {} has {} human admins (minimum {})",
+                    member.id, msg.chat.id, human_admins, config.min_human_admins
+                );
+                let notice = format!(
+                    "User {} is listed in the shared blocklist. Not banned automatically because this chat has too few human admins.",
+                    member.id
+                );
+                if let Err(e) = bot.send_message(msg.chat.id, notice).await {
+                    log::error!("Failed to send blocklist notice: {}", e);
+                }
+            }
+        }
+    }
+    if let Some(welcome) = welcome.filter(|_| !greeted.is_empty()) {
+        if let Err(e) = bot.send_message(msg.chat.id, format_welcome(&welcome, &greeted)).parse_mode(ParseMode::Html).await {
+            log::error!("Failed to send welcome message in chat {}: {}", msg.chat.id, e);
+        }
+    }
+}
+
+/// Restricts a new member and asks them to press a button within `CAPTCHA_TIMEOUT_SECS`.
+///
+/// A background task kicks the member (ban followed by unban, so they can rejoin
+/// later) if the challenge is still pending when the timeout elapses.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance.
+/// * `chat_id` - The chat the member joined.
+/// * `member` - The new member.
+/// * `config` - Bot-wide handler settings.
+/// * `challenges` - The pending CAPTCHA challenges.
+async fn start_captcha(bot: &Bot, chat_id: ChatId, member: &User, config: &Config, challenges: &Arc<CaptchaChallenges>) {
+    if let Err(e) = with_retry(|| bot.restrict_chat_member(chat_id, member.id, ChatPermissions::empty())).await {
+        log::error!("Failed to restrict new member {} in chat {}: {}", member.id, chat_id, e);
+        return;
+    }
+    let keyboard = InlineKeyboardMarkup::new([[InlineKeyboardButton::callback(
+        "I'm not a bot",
+        encode_captcha(chat_id.0, member.id.0),
+    )]]);
+    let prompt = format!(
+        "Welcome, {}! Please press the button within {} seconds to be able to post.",
+        member.first_name,
+        config.captcha_timeout.as_secs()
+    );
+    let challenge = match bot.send_message(chat_id, prompt).reply_markup(keyboard).await {
+        Ok(challenge) => challenge,
+        Err(e) => {
+            log::error!("Failed to send CAPTCHA to new member {} in chat {}: {}", member.id, chat_id, e);
+            return;
+        }
+    };
+    challenges.start(chat_id.0, member.id.0, challenge.id.0);
+    let (bot, challenges, user_id, timeout) = (bot.clone(), challenges.clone(), member.id, config.captcha_timeout);
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        let message_id = match challenges.finish(chat_id.0, user_id.0) {
+            Some(message_id) => message_id,
+            None => return,
+        };
+        log::info!("New member {} did not solve the CAPTCHA in chat {}; kicking", user_id, chat_id);
+        if let Err(e) = with_retry(|| bot.ban_chat_member(chat_id, user_id)).await {
+            log::error!("Failed to kick new member {} from chat {}: {}", user_id, chat_id, e);
+        } else if let Err(e) = with_retry(|| bot.unban_chat_member(chat_id, user_id).only_if_banned(true)).await {
+            log::error!("Failed to unban kicked member {} in chat {}: {}", user_id, chat_id, e);
+        }
+        if let Err(e) = delete_message(&bot, chat_id, MessageId(message_id)).await {
+            log::error!("Failed to delete CAPTCHA message: {}", e);
+        }
+    });
+}
+
+/// Handles a click on a new member's CAPTCHA button.
+///
+/// Only the member being challenged can solve it. On success the restriction is lifted
+/// and the challenge message is deleted. The callback query is always answered.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance.
+/// * `query` - The callback query from the clicked button.
+/// * `challenges` - The pending CAPTCHA challenges.
+async fn handle_captcha_callback(bot: Bot, query: CallbackQuery, challenges: Arc<CaptchaChallenges>) -> ResponseResult<()> {
+    let (chat_id, user_id) = match query.data.as_deref().and_then(parse_captcha) {
+        Some(challenge) => challenge,
+        None => {
+            bot.answer_callback_query(query.id).await?;
+            return Ok(());
+        }
+    };
+    if query.from.id.0 != user_id {
+        bot.answer_callback_query(query.id)
+            .text("This button is for the new member only.")
+            .show_alert(true)
+            .await?;
+        return Ok(());
+    }
+    let message_id = match challenges.finish(chat_id, user_id) {
+        Some(message_id) => message_id,
+        None => {
+            bot.answer_callback_query(query.id).text("This challenge has expired.").await?;
+            return Ok(());
+        }
+    };
+    let (chat_id, user_id) = (ChatId(chat_id), UserId(user_id));
+    match with_retry(|| bot.restrict_chat_member(chat_id, user_id, ChatPermissions::all())).await {
+        Ok(_) => {
+            log::info!("New member {} solved the CAPTCHA in chat {}", user_id, chat_id);
+            bot.answer_callback_query(query.id).text("Thanks, you can post now.").await?;
+        }
+        Err(e) => {
+            log::error!("Failed to lift restriction of member {} in chat {}: {}", user_id, chat_id, e);
+            bot.answer_callback_query(query.id)
+                .text("Verified, but the bot could not lift the restriction. Please ask an admin.")
+                .show_alert(true)
+                .await?;
+        }
+    }
+    if let Err(e) = delete_message(&bot, chat_id, MessageId(message_id)).await {
+        log::error!("Failed to delete CAPTCHA message: {}", e);
+    }
+    Ok(())
 }
 
 /// The main entry point for the bot application.
@@ -189,11 +2809,16 @@ async fn handle_new_chat_members(_bot: Bot, msg: Message) {
 /// Initializes the bot, sets up the `RuleManager`, and starts the event dispatcher.
 /// The bot listens for:
 /// - Commands (`/start`, `/report`, `/add_rule`) via the `answer` handler.
-/// - Text messages to check for spam via the `check_message` handler.
-/// - Events when the bot is added to a chat via the `handle_new_chat_members` handler.
+/// - Text messages and channel posts, and edits of them, to check for spam via the `check_message` handler.
+/// - New chat members via the `handle_new_chat_members` handler.
+/// - Clicks on spam notification buttons via the `handle_spam_callback` handler.
+/// - Clicks on new members' CAPTCHA buttons via the `handle_captcha_callback` handler.
+/// - Clicks on `/report` keyword buttons via the `handle_add_rule_callback` handler.
 ///
 /// # Panics
 /// * Panics if the `RuleManager` cannot be initialized (e.g., database failure).
+/// * Panics if `STRICT_STARTUP` is set and the Lua script or the database fails the self-check.
+/// * Panics if `WEBHOOK_URL` is set but the webhook settings are invalid or cannot be registered.
 /// * Panics if the `TELOXIDE_TOKEN` environment variable is not set.
 #[tokio::main]
 async fn main() {
@@ -201,47 +2826,209 @@ async fn main() {
     env_logger::init();
 
     let bot = Bot::from_env();
-    let rule_manager = Arc::new(RuleManager::new("rules.db").expect("Failed to initialize database"));
+    let config = Arc::new(Config::from_env());
+    let rule_manager = Arc::new(RuleManager::new(&config.database_path).expect("Failed to initialize database"));
+    if config.database_path == IN_MEMORY_PATH {
+        log::warn!("Using an in-memory database (DATABASE_PATH=:memory:): rules and scores are lost on exit");
+    }
+    let self_check = rule_manager.self_check();
+    match &self_check.lua {
+        Ok(score) => log::info!("Self-check PASS: {} loaded, check_spam(\"{}\") = {}", rule_manager.rules_script_path(), SELF_CHECK_MESSAGE, score),
+        Err(e) => log::error!("Self-check FAIL: Lua rules are broken, messages will be scored without them: {}", e),
+    }
+    match &self_check.database {
+        Ok(()) => log::info!("Self-check PASS: database {} is readable and writable", config.database_path),
+        Err(e) => log::error!("Self-check FAIL: database {} cannot be written: {}", config.database_path, e),
+    }
+    if !self_check.passed() && config.strict_startup {
+        panic!("Startup self-check failed; refusing to start because STRICT_STARTUP=true");
+    }
+    let confirmations = Arc::new(PendingConfirmations::new(Duration::from_secs(60)));
+    let metrics = Arc::new(Metrics::new());
+    let detectors = Arc::new(Detectors::from_env());
+    detectors.reposts.set_policy(RepostPolicy::load(&rule_manager));
+    if config.shadow_mode {
+        log::warn!(
+            "SHADOW MODE is on (SHADOW_MODE=true): spam is only logged; no message is deleted, no notice is posted, \
+             no admin is notified and no sender is warned, muted or banned"
+        );
+    }
+    let blocklist = BlocklistConfig::from_env().map(|config| Arc::new(BlocklistClient::new(config)));
+    let shedder = LoadShedder::from_env().map(Arc::new);
+    let mut limiter = RateLimiter::from_env();
+    restore_rate_limits(&mut limiter, &rule_manager);
+    let rate_limiter = Arc::new(Mutex::new(limiter));
+    let admin_cache = Arc::new(AdminCache::from_env());
+    let throttle = Arc::new(NotificationThrottle::from_env());
+    let cooldown = Arc::new(SenderCooldown::from_env());
+    let challenges = Arc::new(CaptchaChallenges::new());
+    let verdicts = Arc::new(MessageVerdicts::from_env());
+
+    spawn_profile_scheduler(rule_manager.clone(), Arc::new(SystemClock));
+    spawn_backup_task(rule_manager.clone(), config.backup_dir.clone());
+    spawn_sender_cleanup(rule_manager.clone(), SenderCleanup::from_env());
+    spawn_rule_hit_flush(rule_manager.clone());
+    spawn_rate_limit_persistence(rule_manager.clone(), rate_limiter.clone());
+    if config.bayes_filter {
+        spawn_bayes_flush(rule_manager.clone());
+    }
+    spawn_notification_summaries(bot.clone(), throttle.clone(), admin_cache.clone());
+    spawn_sender_burst_summaries(bot.clone(), cooldown.clone(), admin_cache.clone());
+    spawn_lockdown_monitor(bot.clone(), detectors.lockdown.clone(), admin_cache.clone(), !config.shadow_mode);
+    spawn_pause_expiry(bot.clone(), rule_manager.clone());
+
+    if let Some(config) = StatsdConfig::from_env() {
+        log::info!("Exporting metrics to StatsD at {}:{}", config.host, config.port);
+        spawn_statsd_exporter(config, metrics.clone());
+    }
+    if let Some(config) = PrometheusConfig::from_env() {
+        log::info!("Serving Prometheus metrics on port {}", config.port);
+        spawn_prometheus_server(config, metrics.clone(), rule_manager.clone());
+    }
 
-    println!("Bot started!");
+    println!("Bot started!{}", if config.shadow_mode { " (shadow mode: spam is logged, not acted on)" } else { "" });
 
-    let handler = Update::filter_message()
+    let callback_handler = Update::filter_callback_query()
         .branch(
-            dptree::entry()
-                .filter_command::<Command>()
+            dptree::filter(|query: CallbackQuery| query.data.as_deref().is_some_and(|data| data.starts_with(CAPTCHA_PREFIX)))
+                .endpoint({
+                    let challenges = challenges.clone();
+                    move |bot: Bot, query: CallbackQuery| {
+                        let challenges = challenges.clone();
+                        async move { handle_captcha_callback(bot, query, challenges).await }
+                    }
+                }),
+        )
+        .branch(
+            dptree::filter(|query: CallbackQuery| query.data.as_deref().is_some_and(|data| data.starts_with(ADD_RULE_PREFIX)))
                 .endpoint({
                     let rule_manager = rule_manager.clone();
-                    move |bot: Bot, msg: Message, cmd: Command| {
+                    let admin_cache = admin_cache.clone();
+                    let config = config.clone();
+                    move |bot: Bot, query: CallbackQuery| {
                         let rule_manager = rule_manager.clone();
-                        async move {
-                            answer(bot, msg, cmd, rule_manager).await
-                        }
+                        let admin_cache = admin_cache.clone();
+                        let config = config.clone();
+                        async move { handle_add_rule_callback(bot, query, rule_manager, admin_cache, config).await }
                     }
                 }),
         )
+        .branch(dptree::endpoint({
+            let rule_manager = rule_manager.clone();
+            let admin_cache = admin_cache.clone();
+            move |bot: Bot, query: CallbackQuery| {
+                let rule_manager = rule_manager.clone();
+                let admin_cache = admin_cache.clone();
+                async move { handle_spam_callback(bot, query, rule_manager, admin_cache).await }
+            }
+        }));
+
+    // Shared by new and edited messages, so an edit cannot turn a clean message into unnoticed spam
+    let check = {
+        let rule_manager = rule_manager.clone();
+        let metrics = metrics.clone();
+        let detectors = detectors.clone();
+        let blocklist = blocklist.clone();
+        let config = config.clone();
+        let shedder = shedder.clone();
+        let rate_limiter = rate_limiter.clone();
+        let admin_cache = admin_cache.clone();
+        let throttle = throttle.clone();
+        let cooldown = cooldown.clone();
+        let verdicts = verdicts.clone();
+        move |bot: Bot, msg: Message| {
+            let rule_manager = rule_manager.clone();
+            let metrics = metrics.clone();
+            let detectors = detectors.clone();
+            let blocklist = blocklist.clone();
+            let config = config.clone();
+            let shedder = shedder.clone();
+            let rate_limiter = rate_limiter.clone();
+            let admin_cache = admin_cache.clone();
+            let throttle = throttle.clone();
+            let cooldown = cooldown.clone();
+            let verdicts = verdicts.clone();
+            async move {
+                check_message(
+                    bot,
+                    msg,
+                    rule_manager,
+                    metrics,
+                    detectors,
+                    blocklist,
+                    config,
+                    shedder,
+                    rate_limiter,
+                    admin_cache,
+                    throttle,
+                    cooldown,
+                    verdicts,
+                )
+                .await
+            }
+        }
+    };
+
+    let message_handler = Update::filter_message()
         .branch(
-            dptree::filter(|msg: Message| msg.text().is_some())
+            dptree::entry()
+                .filter_command::<Command>()
                 .endpoint({
                     let rule_manager = rule_manager.clone();
-                    move |bot: Bot, msg: Message| {
+                    let confirmations = confirmations.clone();
+                    let config = config.clone();
+                    let detectors = detectors.clone();
+                    let admin_cache = admin_cache.clone();
+                    move |bot: Bot, msg: Message, cmd: Command| {
                         let rule_manager = rule_manager.clone();
+                        let confirmations = confirmations.clone();
+                        let config = config.clone();
+                        let detectors = detectors.clone();
+                        let admin_cache = admin_cache.clone();
                         async move {
-                            check_message(bot, msg, rule_manager).await
+                            answer(bot, msg, cmd, rule_manager, confirmations, config, detectors, admin_cache).await
                         }
                     }
                 }),
         )
+        .branch(dptree::filter(is_checkable).endpoint(check.clone()))
         .branch(
-            dptree::filter(|msg: Message| msg.new_chat_members().is_some() && !msg.new_chat_members().unwrap().is_empty())
-                .endpoint(|bot: Bot, msg: Message| async move {
-                    handle_new_chat_members(bot, msg).await;
-                    Ok(())
+            dptree::filter(|msg: Message| msg.new_chat_members().is_some_and(|members| !members.is_empty()))
+                .endpoint(move |bot: Bot, msg: Message| {
+                    let rule_manager = rule_manager.clone();
+                    let blocklist = blocklist.clone();
+                    let config = config.clone();
+                    let admin_cache = admin_cache.clone();
+                    let challenges = challenges.clone();
+                    async move {
+                        handle_new_chat_members(bot, msg, rule_manager, blocklist, config, admin_cache, challenges).await;
+                        Ok(())
+                    }
                 }),
         );
 
-    Dispatcher::builder(bot, handler)
-        .enable_ctrlc_handler()
-        .build()
-        .dispatch()
-        .await;
+    let edited_message_handler = Update::filter_edited_message().branch(dptree::filter(is_checkable).endpoint(check.clone()));
+    let channel_post_handler = Update::filter_channel_post().branch(dptree::filter(is_checkable).endpoint(check.clone()));
+    let edited_channel_post_handler =
+        Update::filter_edited_channel_post().branch(dptree::filter(is_checkable).endpoint(check));
+
+    let handler = dptree::entry()
+        .branch(message_handler)
+        .branch(edited_message_handler)
+        .branch(channel_post_handler)
+        .branch(edited_channel_post_handler)
+        .branch(callback_handler);
+
+    let webhook = WebhookConfig::from_env().map(|config| config.expect("Invalid webhook settings"));
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler).enable_ctrlc_handler().build();
+    match webhook {
+        Some(webhook) => {
+            log::info!("Receiving updates through the webhook {} on port {}", webhook.url, webhook.port);
+            let listener = webhooks::axum(bot, webhook.options()).await.expect("Failed to register the webhook");
+            dispatcher
+                .dispatch_with_listener(listener, LoggingErrorHandler::with_custom_text("An error from the webhook listener"))
+                .await;
+        }
+        None => dispatcher.dispatch().await,
+    }
 }
\ No newline at end of file
@@ -0,0 +1,218 @@
+//! A module for scoring stickers, GIFs and files, which carry no text to score.
+//!
+//! Spammers post NSFW stickers and GIFs without a caption, so the text detectors never
+//! fire on them. Two checks cover them instead: `NewSenderMedia` scores media from
+//! senders with only a few messages as mildly suspicious, and admins can block sticker
+//! sets and individual files per chat with `/block_sticker`.
+//!
+//! The Bot API limits what can be moderated. Bots receive a file's metadata, not its
+//! content, so nothing here looks at the image itself; downloading and classifying
+//! every file would need an external service. Stickers name their set, so a whole
+//! NSFW set can be blocked at once, while GIFs and files are only identified by their
+//! `file_unique_id`, which stays the same when the file is forwarded or re-sent but
+//! changes if it is re-encoded or re-uploaded. File names and MIME types are chosen by
+//! the sender and are not used. Custom emoji inside text messages are not covered.
+
+use crate::config::env_parse;
+use teloxide::types::Message;
+
+/// The kinds of media checked without a caption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    /// A sticker.
+    Sticker,
+    /// A GIF or silent video clip.
+    Animation,
+    /// A file.
+    Document,
+}
+
+impl MediaKind {
+    /// Returns the kind's name as shown to admins.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Sticker => "sticker",
+            Self::Animation => "GIF",
+            Self::Document => "file",
+        }
+    }
+}
+
+/// What the Bot API tells about a sticker, GIF or file in a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageMedia {
+    /// The kind of media.
+    pub kind: MediaKind,
+    /// The file's ID, the same for every bot and every copy of the file.
+    pub file_unique_id: String,
+    /// The sticker set a sticker belongs to, if any.
+    pub set_name: Option<String>,
+}
+
+impl MessageMedia {
+    /// Returns the blocklist entries that match this media: its sticker set, if any,
+    /// and its file ID.
+    pub fn block_keys(&self) -> Vec<&str> {
+        self.set_name.as_deref().into_iter().chain([self.file_unique_id.as_str()]).collect()
+    }
+
+    /// Returns the entry `/block_sticker` adds for this media: its sticker set if it
+    /// has one, so the whole set is blocked, otherwise its file ID.
+    pub fn block_key(&self) -> &str {
+        self.set_name.as_deref().unwrap_or(&self.file_unique_id)
+    }
+
+    /// Describes the media for admins, e.g. `(sticker from set NsfwPack)`.
+    pub fn describe(&self) -> String {
+        match &self.set_name {
+            Some(set_name) => format!("({} from set {})", self.kind.as_str(), set_name),
+            None => format!("({} {})", self.kind.as_str(), self.file_unique_id),
+        }
+    }
+}
+
+/// Extracts the sticker, GIF or file of a message.
+///
+/// # Arguments
+/// * `msg` - The incoming message.
+///
+/// # Returns
+/// * `Option<MessageMedia>` - The media, or `None` if the message has none of these.
+pub fn message_media(msg: &Message) -> Option<MessageMedia> {
+    if let Some(sticker) = msg.sticker() {
+        Some(MessageMedia {
+            kind: MediaKind::Sticker,
+            file_unique_id: sticker.file.unique_id.clone(),
+            set_name: sticker.set_name.clone(),
+        })
+    } else if let Some(animation) = msg.animation() {
+        Some(MessageMedia {
+            kind: MediaKind::Animation,
+            file_unique_id: animation.file.unique_id.clone(),
+            set_name: None,
+        })
+    } else {
+        msg.document().map(|document| MessageMedia {
+            kind: MediaKind::Document,
+            file_unique_id: document.file.unique_id.clone(),
+            set_name: None,
+        })
+    }
+}
+
+/// Parses a `/block_sticker` argument into a blocklist entry.
+///
+/// Accepts a sticker set name, a sticker set link such as
+/// `https://t.me/addstickers/NsfwPack`, or a file ID.
+///
+/// # Arguments
+/// * `input` - The argument as typed.
+///
+/// # Returns
+/// * `Option<String>` - The set name or file ID, or `None` if the input is not one.
+pub fn parse_media_block(input: &str) -> Option<String> {
+    let input = input.trim();
+    let item = input
+        .strip_prefix("https://")
+        .or_else(|| input.strip_prefix("http://"))
+        .unwrap_or(input);
+    let item = item.strip_prefix("t.me/addstickers/").unwrap_or(item).trim_end_matches('/');
+    let valid = !item.is_empty() && item.len() <= 128 && item.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    valid.then(|| item.to_string())
+}
+
+/// Scores stickers, GIFs and files from senders who have sent only a few messages.
+#[derive(Debug, Clone)]
+pub struct NewSenderMedia {
+    /// Messages a sender needs before their media stops being scored; 0 disables it.
+    pub min_messages: i64,
+    /// Score added to media from a sender below `min_messages`.
+    pub score: f32,
+}
+
+impl Default for NewSenderMedia {
+    fn default() -> Self {
+        Self {
+            min_messages: 5,
+            score: 1.0,
+        }
+    }
+}
+
+impl NewSenderMedia {
+    /// Builds the detector from environment variables.
+    ///
+    /// * `NEW_SENDER_MEDIA_MIN_MESSAGES` - Messages needed before media is no longer scored (default 5; 0 disables it).
+    /// * `NEW_SENDER_MEDIA_SCORE` - Score added to media from senders below it (default 1.0).
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            min_messages: env_parse("NEW_SENDER_MEDIA_MIN_MESSAGES", defaults.min_messages),
+            score: env_parse("NEW_SENDER_MEDIA_SCORE", defaults.score),
+        }
+    }
+
+    /// Scores a message by whether it carries media and how many messages its sender has sent.
+    ///
+    /// # Arguments
+    /// * `message_count` - Messages the sender had sent before this one, from the `senders` table.
+    /// * `has_media` - Whether the message is a sticker, GIF or file.
+    ///
+    /// # Returns
+    /// * `f32` - `score` if the sender is below `min_messages` and sent media, otherwise 0.
+    pub fn check(&self, message_count: i64, has_media: bool) -> f32 {
+        if has_media && message_count < self.min_messages {
+            self.score
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Unit tests for the `media` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_media_from_new_senders_is_mildly_suspicious() {
+        let mut detector = NewSenderMedia::default();
+        assert_eq!(detector.check(0, true), 1.0);
+        assert_eq!(detector.check(4, true), 1.0);
+        assert_eq!(detector.check(5, true), 0.0);
+        assert_eq!(detector.check(0, false), 0.0);
+        detector.min_messages = 0;
+        assert_eq!(detector.check(0, true), 0.0);
+    }
+
+    #[test]
+    fn test_stickers_are_blocked_by_set_and_files_by_id() {
+        let sticker = MessageMedia {
+            kind: MediaKind::Sticker,
+            file_unique_id: "AgADBQADwDZPEw".to_string(),
+            set_name: Some("NsfwPack".to_string()),
+        };
+        assert_eq!(sticker.block_keys(), ["NsfwPack", "AgADBQADwDZPEw"]);
+        assert_eq!(sticker.block_key(), "NsfwPack");
+        assert_eq!(sticker.describe(), "(sticker from set NsfwPack)");
+        let gif = MessageMedia {
+            kind: MediaKind::Animation,
+            file_unique_id: "AgADxQIAAmKa".to_string(),
+            set_name: None,
+        };
+        assert_eq!(gif.block_keys(), ["AgADxQIAAmKa"]);
+        assert_eq!(gif.block_key(), "AgADxQIAAmKa");
+        assert_eq!(gif.describe(), "(GIF AgADxQIAAmKa)");
+    }
+
+    #[test]
+    fn test_block_arguments_accept_set_links() {
+        assert_eq!(parse_media_block(" NsfwPack ").as_deref(), Some("NsfwPack"));
+        assert_eq!(parse_media_block("https://t.me/addstickers/NsfwPack").as_deref(), Some("NsfwPack"));
+        assert_eq!(parse_media_block("t.me/addstickers/Nsfw_Pack/").as_deref(), Some("Nsfw_Pack"));
+        assert_eq!(parse_media_block("AgADxQIAAmKa-1").as_deref(), Some("AgADxQIAAmKa-1"));
+        assert_eq!(parse_media_block(""), None);
+        assert_eq!(parse_media_block("two words"), None);
+        assert_eq!(parse_media_block("https://example.com/pack"), None);
+    }
+}
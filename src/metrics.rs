@@ -0,0 +1,387 @@
+use crate::config::env_parse;
+use crate::rules::RuleManager;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+/// A module for aggregating bot metrics and exporting them to StatsD or Prometheus.
+///
+/// Handlers only touch the `Metrics` aggregator through cheap atomic increments. Separate
+/// background tasks, started when an exporter is configured, periodically drain the
+/// aggregator and send the counters over UDP, or serve running totals over HTTP, keeping
+/// the export path out of message handling.
+use tokio::net::UdpSocket;
+
+/// Thread-safe aggregator for counters collected while handling updates.
+///
+/// Counters are accumulated between exports and reset each time a snapshot is taken,
+/// matching the delta semantics of StatsD counters. The `*_total` counters served to
+/// Prometheus are kept separately and only ever grow.
+#[derive(Default)]
+pub struct Metrics {
+    /// Number of messages evaluated for spam.
+    messages_processed: AtomicU64,
+    /// Number of messages flagged as spam.
+    spam_flagged: AtomicU64,
+    /// Number of moderation actions taken (chat notices, admin notifications, ...).
+    actions_taken: AtomicU64,
+    /// Number of messages that would have been flagged as spam outside shadow mode.
+    would_flag: AtomicU64,
+    /// Total time spent evaluating Lua rules, in microseconds.
+    lua_latency_us_total: AtomicU64,
+    /// Number of Lua evaluations included in `lua_latency_us_total`.
+    lua_evaluations: AtomicU64,
+    /// Messages evaluated since startup; never reset, unlike the StatsD deltas.
+    messages_checked_total: AtomicU64,
+    /// Messages flagged as spam since startup.
+    spam_messages_total: AtomicU64,
+    /// Senders banned automatically since startup.
+    auto_bans_total: AtomicU64,
+    /// Messages that would have been flagged as spam outside shadow mode since startup.
+    would_flag_total: AtomicU64,
+}
+
+/// A point-in-time view of the counters accumulated since the previous snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Messages evaluated for spam.
+    pub messages_processed: u64,
+    /// Messages flagged as spam.
+    pub spam_flagged: u64,
+    /// Moderation actions taken.
+    pub actions_taken: u64,
+    /// Messages that would have been flagged outside shadow mode.
+    pub would_flag: u64,
+    /// Average Lua evaluation time in milliseconds, or `None` if no evaluation happened.
+    pub lua_latency_ms: Option<f64>,
+}
+
+impl Metrics {
+    /// Creates a new aggregator with all counters set to zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that a message was evaluated for spam.
+    pub fn incr_messages_processed(&self) {
+        self.messages_processed.fetch_add(1, Ordering::Relaxed);
+        self.messages_checked_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a message was flagged as spam.
+    pub fn incr_spam_flagged(&self) {
+        self.spam_flagged.fetch_add(1, Ordering::Relaxed);
+        self.spam_messages_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a message would have been flagged as spam, had shadow mode been off.
+    pub fn incr_would_flag(&self) {
+        self.would_flag.fetch_add(1, Ordering::Relaxed);
+        self.would_flag_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a sender was banned automatically.
+    pub fn incr_auto_bans(&self) {
+        self.auto_bans_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that the bot took a moderation action.
+    pub fn incr_actions_taken(&self) {
+        self.actions_taken.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the duration of a single Lua rule evaluation.
+    ///
+    /// # Arguments
+    /// * `elapsed` - The time taken by the evaluation.
+    pub fn record_lua_latency(&self, elapsed: Duration) {
+        self.lua_latency_us_total
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.lua_evaluations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Drains the aggregator, returning the counters accumulated since the last call.
+    ///
+    /// # Returns
+    /// * `MetricsSnapshot` - The accumulated values; all counters are reset to zero.
+    pub fn take_snapshot(&self) -> MetricsSnapshot {
+        let latency_total = self.lua_latency_us_total.swap(0, Ordering::Relaxed);
+        let evaluations = self.lua_evaluations.swap(0, Ordering::Relaxed);
+        MetricsSnapshot {
+            messages_processed: self.messages_processed.swap(0, Ordering::Relaxed),
+            spam_flagged: self.spam_flagged.swap(0, Ordering::Relaxed),
+            actions_taken: self.actions_taken.swap(0, Ordering::Relaxed),
+            would_flag: self.would_flag.swap(0, Ordering::Relaxed),
+            lua_latency_ms: if evaluations > 0 {
+                Some(latency_total as f64 / evaluations as f64 / 1000.0)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Configuration for the StatsD exporter, read from the environment.
+#[derive(Debug, Clone)]
+pub struct StatsdConfig {
+    /// Host name or IP address of the StatsD server.
+    pub host: String,
+    /// UDP port of the StatsD server.
+    pub port: u16,
+    /// Prefix prepended to every metric name (e.g. "spam_bot").
+    pub prefix: String,
+    /// How often the aggregated metrics are pushed.
+    pub interval: Duration,
+}
+
+impl StatsdConfig {
+    /// Builds the exporter configuration from environment variables.
+    ///
+    /// * `STATSD_HOST` - Required; the exporter is disabled when it is unset or empty.
+    /// * `STATSD_PORT` - Optional, defaults to 8125.
+    /// * `STATSD_PREFIX` - Optional, defaults to "spam_bot".
+    /// * `STATSD_INTERVAL_SECS` - Optional, defaults to 10.
+    ///
+    /// # Returns
+    /// * `Option<Self>` - The configuration, or `None` if exporting is disabled.
+    pub fn from_env() -> Option<Self> {
+        let host = std::env::var("STATSD_HOST").ok().filter(|h| !h.is_empty())?;
+        let port = std::env::var("STATSD_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(8125);
+        let prefix = std::env::var("STATSD_PREFIX").unwrap_or_else(|_| "spam_bot".to_string());
+        let interval = std::env::var("STATSD_INTERVAL_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(10);
+        Some(Self {
+            host,
+            port,
+            prefix,
+            interval: Duration::from_secs(interval),
+        })
+    }
+}
+
+/// Formats a snapshot as newline-separated StatsD lines.
+///
+/// Counters use the `|c` type and the Lua latency uses the `|ms` timing type.
+/// The latency line is omitted when no evaluation happened during the interval.
+///
+/// # Arguments
+/// * `prefix` - The metric name prefix; an empty prefix produces bare metric names.
+/// * `snapshot` - The values to format.
+///
+/// # Returns
+/// * `String` - The StatsD payload.
+pub fn format_statsd(prefix: &str, snapshot: &MetricsSnapshot) -> String {
+    let name = |metric: &str| {
+        if prefix.is_empty() {
+            metric.to_string()
+        } else {
+            format!("{}.{}", prefix, metric)
+        }
+    };
+    let mut lines = vec![
+        format!("{}:{}|c", name("messages_processed"), snapshot.messages_processed),
+        format!("{}:{}|c", name("spam_flagged"), snapshot.spam_flagged),
+        format!("{}:{}|c", name("actions_taken"), snapshot.actions_taken),
+        format!("{}:{}|c", name("would_flag"), snapshot.would_flag),
+    ];
+    if let Some(latency) = snapshot.lua_latency_ms {
+        lines.push(format!("{}:{:.3}|ms", name("lua_latency"), latency));
+    }
+    lines.join("\n")
+}
+
+/// Spawns a background task that periodically pushes metrics to StatsD.
+///
+/// Send failures are logged and the task keeps running, so an unreachable
+/// StatsD server never affects message handling.
+///
+/// # Arguments
+/// * `config` - The exporter configuration.
+/// * `metrics` - The shared aggregator updated by the handlers.
+///
+/// # Returns
+/// * `JoinHandle<()>` - The handle of the spawned task.
+pub fn spawn_statsd_exporter(
+    config: StatsdConfig,
+    metrics: Arc<Metrics>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let socket = match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(socket) => socket,
+            Err(e) => {
+                log::error!("Failed to bind StatsD socket: {}", e);
+                return;
+            }
+        };
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            interval.tick().await;
+            let payload = format_statsd(&config.prefix, &metrics.take_snapshot());
+            if let Err(e) = socket
+                .send_to(payload.as_bytes(), (config.host.as_str(), config.port))
+                .await
+            {
+                log::warn!("Failed to send metrics to StatsD {}:{}: {}", config.host, config.port, e);
+            }
+        }
+    })
+}
+
+/// Configuration for the Prometheus endpoint, read from the environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrometheusConfig {
+    /// TCP port the `/metrics` endpoint listens on, on all interfaces.
+    pub port: u16,
+}
+
+impl PrometheusConfig {
+    /// Builds the endpoint configuration from environment variables.
+    ///
+    /// * `METRICS_PORT` - Required; the endpoint is disabled when it is unset, invalid or 0.
+    ///
+    /// # Returns
+    /// * `Option<Self>` - The configuration, or `None` if the endpoint is disabled.
+    pub fn from_env() -> Option<Self> {
+        match env_parse("METRICS_PORT", 0u16) {
+            0 => None,
+            port => Some(Self { port }),
+        }
+    }
+}
+
+/// Formats the running totals in the Prometheus text exposition format.
+///
+/// # Arguments
+/// * `metrics` - The shared aggregator updated by the handlers.
+/// * `rules_count` - The current number of keyword and regex rules.
+///
+/// # Returns
+/// * `String` - The body of a `/metrics` response.
+pub fn format_prometheus(metrics: &Metrics, rules_count: usize) -> String {
+    let counter = |name: &str, help: &str, value: &AtomicU64| {
+        format!("# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n", value.load(Ordering::Relaxed))
+    };
+    [
+        counter("messages_checked_total", "Messages evaluated for spam.", &metrics.messages_checked_total),
+        counter("spam_messages_total", "Messages flagged as spam.", &metrics.spam_messages_total),
+        counter("auto_bans_total", "Senders banned automatically.", &metrics.auto_bans_total),
+        counter(
+            "would_flag_total",
+            "Messages shadow mode would have flagged as spam.",
+            &metrics.would_flag_total,
+        ),
+        format!("# HELP rules_count Keyword and regex rules.\n# TYPE rules_count gauge\nrules_count {}\n", rules_count),
+    ]
+    .concat()
+}
+
+/// Spawns a background HTTP server exposing the metrics at `/metrics` for Prometheus.
+///
+/// The server runs on its own task, so scrapes never block the dispatcher. Other paths
+/// get a 404. If the port cannot be bound, the error is logged and the bot keeps running.
+///
+/// # Arguments
+/// * `config` - The endpoint configuration.
+/// * `metrics` - The shared aggregator updated by the handlers.
+/// * `rule_manager` - The manager whose rules are counted.
+///
+/// # Returns
+/// * `JoinHandle<()>` - The handle of the spawned task.
+pub fn spawn_prometheus_server(
+    config: PrometheusConfig,
+    metrics: Arc<Metrics>,
+    rule_manager: Arc<RuleManager>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let make_service = make_service_fn(move |_| {
+            let (metrics, rule_manager) = (metrics.clone(), rule_manager.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let response = if request.method() == Method::GET && request.uri().path() == "/metrics" {
+                        Response::builder()
+                            .header("Content-Type", "text/plain; version=0.0.4")
+                            .body(Body::from(format_prometheus(&metrics, rule_manager.rule_count())))
+                    } else {
+                        Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty())
+                    };
+                    async move { response }
+                }))
+            }
+        });
+        let address = SocketAddr::from(([0, 0, 0, 0], config.port));
+        match Server::try_bind(&address) {
+            Ok(builder) => {
+                if let Err(e) = builder.serve(make_service).await {
+                    log::error!("Prometheus metrics server failed: {}", e);
+                }
+            }
+            Err(e) => log::error!("Failed to bind Prometheus metrics server to {}: {}", address, e),
+        }
+    })
+}
+
+/// Unit tests for the `metrics` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_statsd_sample_batch() {
+        let metrics = Metrics::new();
+        for _ in 0..3 {
+            metrics.incr_messages_processed();
+        }
+        metrics.incr_spam_flagged();
+        metrics.incr_actions_taken();
+        metrics.incr_actions_taken();
+        metrics.record_lua_latency(Duration::from_millis(2));
+        metrics.record_lua_latency(Duration::from_millis(4));
+
+        let payload = format_statsd("spam_bot", &metrics.take_snapshot());
+        assert_eq!(
+            payload,
+            "spam_bot.messages_processed:3|c\n\
+             spam_bot.spam_flagged:1|c\n\
+             spam_bot.actions_taken:2|c\n\
+             spam_bot.would_flag:0|c\n\
+             spam_bot.lua_latency:3.000|ms"
+        );
+    }
+
+    #[test]
+    fn test_prometheus_totals_survive_statsd_snapshots() {
+        let metrics = Metrics::new();
+        metrics.incr_messages_processed();
+        metrics.incr_messages_processed();
+        metrics.incr_spam_flagged();
+        metrics.incr_auto_bans();
+        metrics.incr_would_flag();
+        metrics.take_snapshot();
+
+        let body = format_prometheus(&metrics, 4);
+        assert!(body.contains("# TYPE messages_checked_total counter\nmessages_checked_total 2\n"));
+        assert!(body.contains("spam_messages_total 1\n"));
+        assert!(body.contains("auto_bans_total 1\n"));
+        assert!(body.contains("would_flag_total 1\n"));
+        assert!(body.contains("# TYPE rules_count gauge\nrules_count 4\n"));
+    }
+
+    #[test]
+    fn test_snapshot_resets_counters() {
+        let metrics = Metrics::new();
+        metrics.incr_spam_flagged();
+        metrics.take_snapshot();
+        let snapshot = metrics.take_snapshot();
+        assert_eq!(snapshot.spam_flagged, 0);
+        assert_eq!(snapshot.lua_latency_ms, None);
+        assert_eq!(format_statsd("", &snapshot), "messages_processed:0|c\nspam_flagged:0|c\nactions_taken:0|c\nwould_flag:0|c");
+    }
+}
@@ -0,0 +1,496 @@
+//! A module for versioned database schema migrations.
+//!
+//! The schema version is stored in the `schema_version` table. `migrate` applies every
+//! migration newer than that version in order, each inside its own transaction that
+//! also bumps the version, so an interrupted upgrade resumes where it stopped and an
+//! existing `rules.db` is upgraded in place instead of having to be deleted.
+//!
+//! Databases created before versioning have no `schema_version` table and are treated
+//! as version 0. Migrations therefore only create tables and columns that are missing,
+//! so they also apply cleanly to such databases, whatever features they were created with.
+
+use crate::bayes::{add_counts, BayesModel};
+use rusqlite::{Connection, Result, Transaction, TransactionBehavior};
+
+/// A single schema change, run inside a transaction.
+type Migration = fn(&Transaction) -> Result<()>;
+
+/// Every migration in order; the schema version is the number applied.
+const MIGRATIONS: &[Migration] = &[
+    initial_schema,
+    add_feature_tables,
+    add_sender_last_updated,
+    add_regex_rules,
+    add_rule_categories,
+    make_keywords_unique,
+    add_whitelist,
+    add_banned,
+    add_sender_warnings,
+    add_rule_match_modes,
+    add_chat_languages,
+    add_chat_action_policies,
+    add_rule_hit_counts,
+    add_bayes_tables,
+    add_rate_limit_state,
+    add_flagged_messages,
+    add_safe_patterns,
+    add_chat_pauses,
+    scope_senders_per_chat,
+    add_blocked_media,
+    add_welcome_messages,
+    add_score_events,
+    scope_whitelist_per_chat,
+    add_sender_spam_messages,
+    add_chat_report_thresholds,
+];
+
+/// Chat ID that sender scores and whitelist entries recorded before they were kept per
+/// chat are filed under.
+///
+/// Telegram never assigns chat ID 0, so these rows are kept for reference but never
+/// count towards a sender's score, or exempt them, in any real chat.
+pub const LEGACY_SENDERS_CHAT_ID: i64 = 0;
+
+/// The schema version of a fully migrated database.
+pub const LATEST_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Returns the schema version of a database.
+///
+/// # Arguments
+/// * `conn` - The database connection.
+///
+/// # Returns
+/// * `Result<u32>` - The version, 0 for databases that were never migrated, or a
+///   `rusqlite::Error` if the query fails.
+pub fn schema_version(conn: &Connection) -> Result<u32> {
+    conn.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)", [])?;
+    conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))
+}
+
+/// Applies all pending migrations.
+///
+/// # Arguments
+/// * `conn` - The database connection.
+///
+/// # Returns
+/// * `Result<u32>` - The number of migrations applied, or a `rusqlite::Error` if one
+///   fails, in which case that migration is rolled back and later ones are not run.
+pub fn migrate(conn: &mut Connection) -> Result<u32> {
+    let current = schema_version(conn)?;
+    for (version, migration) in (1..).zip(MIGRATIONS).skip(current as usize) {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        migration(&tx)?;
+        tx.execute("DELETE FROM schema_version", [])?;
+        tx.execute("INSERT INTO schema_version (version) VALUES (?1)", [version])?;
+        tx.commit()?;
+        log::info!("Applied database migration {}", version);
+    }
+    Ok(LATEST_VERSION.saturating_sub(current))
+}
+
+/// Adds a column to a table unless it already exists.
+fn add_column(tx: &Transaction, table: &str, column: &str, definition: &str) -> Result<()> {
+    if tx.prepare(&format!("SELECT {} FROM {} LIMIT 0", column, table)).is_err() {
+        tx.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+/// Version 1: the original rules and senders tables.
+fn initial_schema(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS rules (
+                id INTEGER PRIMARY KEY,
+                keyword TEXT NOT NULL,
+                score REAL NOT NULL
+            )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS senders (
+                user_id TEXT PRIMARY KEY,
+                spam_score INTEGER DEFAULT 0,
+                message_count INTEGER DEFAULT 0
+            )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 2: labeled messages, settings, notes, spam events, profile schedules,
+/// per-chat thresholds and allowed domains.
+fn add_feature_tables(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS labeled_messages (
+                id INTEGER PRIMARY KEY,
+                text TEXT NOT NULL,
+                is_spam INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS notes (
+                id INTEGER PRIMARY KEY,
+                chat_id INTEGER NOT NULL,
+                user_id TEXT NOT NULL,
+                note TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS spam_events (
+                id INTEGER PRIMARY KEY,
+                chat_id INTEGER NOT NULL,
+                user_id TEXT NOT NULL,
+                score REAL NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS spam_event_contributions (
+                event_id INTEGER NOT NULL REFERENCES spam_events(id),
+                detector TEXT NOT NULL,
+                contribution REAL NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS profile_schedules (
+                id INTEGER PRIMARY KEY,
+                profile TEXT NOT NULL,
+                days INTEGER NOT NULL,
+                minute_of_day INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS chat_settings (
+                chat_id INTEGER PRIMARY KEY,
+                threshold REAL
+            );
+            CREATE TABLE IF NOT EXISTS allowed_domains (
+                chat_id INTEGER NOT NULL,
+                domain TEXT NOT NULL,
+                PRIMARY KEY (chat_id, domain)
+            );",
+    )
+}
+
+/// Version 3: when each sender's spam score last changed, for score decay.
+fn add_sender_last_updated(tx: &Transaction) -> Result<()> {
+    add_column(tx, "senders", "last_updated", "INTEGER NOT NULL DEFAULT 0")
+}
+
+/// Version 4: regex rules.
+fn add_regex_rules(tx: &Transaction) -> Result<()> {
+    add_column(tx, "rules", "is_regex", "INTEGER NOT NULL DEFAULT 0")
+}
+
+/// Version 5: rule categories that can be toggled.
+fn add_rule_categories(tx: &Transaction) -> Result<()> {
+    add_column(tx, "rules", "category", "TEXT NOT NULL DEFAULT 'default'")?;
+    add_column(tx, "rules", "enabled", "INTEGER NOT NULL DEFAULT 1")
+}
+
+/// Version 6: unique keywords, keeping the most recently added of any duplicates.
+fn make_keywords_unique(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "DELETE FROM rules WHERE id NOT IN (SELECT MAX(id) FROM rules GROUP BY keyword)",
+        [],
+    )?;
+    tx.execute("CREATE UNIQUE INDEX IF NOT EXISTS idx_rules_keyword ON rules (keyword)", [])?;
+    Ok(())
+}
+
+/// Version 7: the whitelist of senders that are never scored.
+fn add_whitelist(tx: &Transaction) -> Result<()> {
+    tx.execute("CREATE TABLE IF NOT EXISTS whitelist (user_id TEXT PRIMARY KEY)", [])?;
+    Ok(())
+}
+
+/// Version 8: the users the bot banned, and why.
+fn add_banned(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS banned (
+                chat_id INTEGER NOT NULL,
+                user_id TEXT NOT NULL,
+                reason TEXT NOT NULL,
+                banned_at INTEGER NOT NULL,
+                PRIMARY KEY (chat_id, user_id)
+            )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 9: warnings issued to each sender.
+fn add_sender_warnings(tx: &Transaction) -> Result<()> {
+    add_column(tx, "senders", "warnings", "INTEGER NOT NULL DEFAULT 0")
+}
+
+/// Version 10: how each keyword rule matches; existing rules keep matching substrings.
+fn add_rule_match_modes(tx: &Transaction) -> Result<()> {
+    add_column(tx, "rules", "match_mode", "TEXT NOT NULL DEFAULT 'substring'")
+}
+
+/// Version 11: the language each chat is answered in; `NULL` follows the users' languages.
+fn add_chat_languages(tx: &Transaction) -> Result<()> {
+    add_column(tx, "chat_settings", "language", "TEXT")
+}
+
+/// Version 12: what each chat does about spam; `NULL` keeps the bot-wide behavior.
+fn add_chat_action_policies(tx: &Transaction) -> Result<()> {
+    add_column(tx, "chat_settings", "action_policy", "TEXT")
+}
+
+/// Version 13: how many checked messages each rule has matched.
+fn add_rule_hit_counts(tx: &Transaction) -> Result<()> {
+    add_column(tx, "rules", "hit_count", "INTEGER NOT NULL DEFAULT 0")
+}
+
+/// Version 14: the word counts of the Bayes classifier, seeded from the labeled messages.
+///
+/// Sender scores carry no message text, so admins' labeled reports are the only
+/// history the classifier can start from.
+fn add_bayes_tables(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS bayes_tokens (
+                token TEXT PRIMARY KEY,
+                spam_count INTEGER NOT NULL DEFAULT 0,
+                ham_count INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS bayes_totals (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                spam_messages INTEGER NOT NULL DEFAULT 0,
+                ham_messages INTEGER NOT NULL DEFAULT 0
+            );",
+    )?;
+    let mut model = BayesModel::default();
+    {
+        let mut stmt = tx.prepare("SELECT text, is_spam FROM labeled_messages ORDER BY id")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let text: String = row.get(0)?;
+            model.train(&text, row.get(1)?);
+        }
+    }
+    if model.is_empty() {
+        return Ok(());
+    }
+    add_counts(tx, &model)
+}
+
+/// Version 15: the rate limiter's recent messages, so they survive a restart.
+fn add_rate_limit_state(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS rate_limit_messages (
+                user_id TEXT NOT NULL,
+                sent_at INTEGER NOT NULL
+            )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 16: the text of recently flagged messages, listed by `/recent_spam`.
+fn add_flagged_messages(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS flagged_messages (
+                id INTEGER PRIMARY KEY,
+                chat_id INTEGER NOT NULL,
+                user_id TEXT NOT NULL,
+                text TEXT NOT NULL,
+                score REAL NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+        [],
+    )?;
+    tx.execute("CREATE INDEX IF NOT EXISTS idx_flagged_messages_chat ON flagged_messages (chat_id, id)", [])?;
+    Ok(())
+}
+
+/// Version 17: per-chat safe patterns, which let a matching message pass.
+fn add_safe_patterns(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS safe_patterns (
+                chat_id INTEGER NOT NULL,
+                pattern TEXT NOT NULL,
+                PRIMARY KEY (chat_id, pattern)
+            )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 18: whether spam moderation is paused in each chat, and when it resumes
+/// by itself; `NULL` pauses until an admin resumes it.
+fn add_chat_pauses(tx: &Transaction) -> Result<()> {
+    add_column(tx, "chat_settings", "paused", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column(tx, "chat_settings", "resume_at", "INTEGER")
+}
+
+/// Version 19: sender scores, message counts and warnings kept per chat, so a sender
+/// flagged in one group is not penalized in every other group they share with the bot.
+/// Existing rows are kept under `LEGACY_SENDERS_CHAT_ID`.
+fn scope_senders_per_chat(tx: &Transaction) -> Result<()> {
+    if tx.prepare("SELECT chat_id FROM senders LIMIT 0").is_ok() {
+        return Ok(());
+    }
+    tx.execute_batch(&format!(
+        "CREATE TABLE senders_per_chat (
+                chat_id INTEGER NOT NULL,
+                user_id TEXT NOT NULL,
+                spam_score INTEGER DEFAULT 0,
+                message_count INTEGER DEFAULT 0,
+                last_updated INTEGER NOT NULL DEFAULT 0,
+                warnings INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (chat_id, user_id)
+            );
+         INSERT INTO senders_per_chat (chat_id, user_id, spam_score, message_count, last_updated, warnings)
+             SELECT {}, user_id, spam_score, message_count, last_updated, warnings FROM senders;
+         DROP TABLE senders;
+         ALTER TABLE senders_per_chat RENAME TO senders;",
+        LEGACY_SENDERS_CHAT_ID
+    ))
+}
+
+/// Version 24: how many of each sender's messages were spam, now that a spam message can
+/// add more than one point to the score. Existing senders are assumed to have had one
+/// point per spam message.
+fn add_sender_spam_messages(tx: &Transaction) -> Result<()> {
+    add_column(tx, "senders", "spam_messages", "INTEGER NOT NULL DEFAULT 0")?;
+    tx.execute("UPDATE senders SET spam_messages = MIN(spam_score, message_count)", [])?;
+    Ok(())
+}
+
+/// Version 25: the `/report` threshold of each chat; `NULL` keeps the bot-wide one.
+fn add_chat_report_thresholds(tx: &Transaction) -> Result<()> {
+    add_column(tx, "chat_settings", "report_threshold", "REAL")
+}
+
+/// Version 20: each chat's blocked sticker sets and files.
+fn add_blocked_media(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS blocked_media (
+                chat_id INTEGER NOT NULL,
+                item TEXT NOT NULL,
+                PRIMARY KEY (chat_id, item)
+            )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 21: the message each chat greets new members with; `NULL` greets nobody.
+fn add_welcome_messages(tx: &Transaction) -> Result<()> {
+    add_column(tx, "chat_settings", "welcome", "TEXT")
+}
+
+/// Version 22: every change to a sender's spam score, for `/history`.
+fn add_score_events(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS score_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                chat_id INTEGER NOT NULL,
+                user_id TEXT NOT NULL,
+                delta INTEGER NOT NULL,
+                reason TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS score_events_by_sender ON score_events (chat_id, user_id, created_at);",
+    )?;
+    Ok(())
+}
+
+/// Version 23: the whitelist kept per chat, so whitelisting a user in one chat (or in a
+/// private chat with the bot) does not exempt them in every other chat. Existing entries
+/// cannot be traced to a chat and are kept under `LEGACY_SENDERS_CHAT_ID`.
+fn scope_whitelist_per_chat(tx: &Transaction) -> Result<()> {
+    if tx.prepare("SELECT chat_id FROM whitelist LIMIT 0").is_ok() {
+        return Ok(());
+    }
+    tx.execute_batch(&format!(
+        "CREATE TABLE whitelist_per_chat (
+                chat_id INTEGER NOT NULL,
+                user_id TEXT NOT NULL,
+                PRIMARY KEY (chat_id, user_id)
+            );
+         INSERT INTO whitelist_per_chat (chat_id, user_id) SELECT {}, user_id FROM whitelist;
+         DROP TABLE whitelist;
+         ALTER TABLE whitelist_per_chat RENAME TO whitelist;",
+        LEGACY_SENDERS_CHAT_ID
+    ))
+}
+
+/// Unit tests for the `migrations` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a database as the first release of the bot left it, at version 1.
+    fn v1_database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE rules (id INTEGER PRIMARY KEY, keyword TEXT NOT NULL, score REAL NOT NULL);
+             CREATE TABLE senders (user_id TEXT PRIMARY KEY, spam_score INTEGER DEFAULT 0, message_count INTEGER DEFAULT 0);
+             CREATE TABLE schema_version (version INTEGER NOT NULL);
+             INSERT INTO schema_version (version) VALUES (1);
+             INSERT INTO rules (keyword, score) VALUES ('spam', 2.0), ('free', 1.0), ('spam', 4.0);
+             INSERT INTO senders (user_id, spam_score, message_count) VALUES ('user1', 3, 10);",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_v1_database_is_migrated_to_latest() {
+        let mut conn = v1_database();
+        assert_eq!(schema_version(&conn).unwrap(), 1);
+        assert_eq!(migrate(&mut conn).unwrap(), LATEST_VERSION - 1);
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_VERSION);
+
+        // Existing data survives and new columns get their defaults.
+        let (score, category, enabled, match_mode): (f32, String, bool, String) = conn
+            .query_row("SELECT score, category, enabled, match_mode FROM rules WHERE keyword = 'spam'", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap();
+        assert_eq!((score, category.as_str(), enabled, match_mode.as_str()), (4.0, "default", true, "substring"));
+        let (chat_id, spam_score, last_updated, spam_messages): (i64, i32, i64, i64) = conn
+            .query_row(
+                "SELECT chat_id, spam_score, last_updated, spam_messages FROM senders WHERE user_id = 'user1'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!((chat_id, spam_score, last_updated, spam_messages), (LEGACY_SENDERS_CHAT_ID, 3, 0, 3));
+        conn.execute("INSERT INTO senders (chat_id, user_id) VALUES (-100, 'user1')", []).unwrap();
+        assert!(conn.execute("INSERT INTO rules (keyword, score) VALUES ('free', 5.0)", []).is_err());
+        conn.execute("INSERT INTO whitelist (chat_id, user_id) VALUES (-100, 'user1')", []).unwrap();
+        conn.execute("INSERT INTO banned (chat_id, user_id, reason, banned_at) VALUES (-100, 'user1', 'spam', 0)", [])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_unversioned_database_is_migrated_and_rerun_is_a_no_op() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        // A pre-versioning database that already has some later columns.
+        conn.execute_batch(
+            "CREATE TABLE rules (id INTEGER PRIMARY KEY, keyword TEXT NOT NULL, score REAL NOT NULL,
+                                 is_regex INTEGER NOT NULL DEFAULT 0);
+             CREATE TABLE senders (user_id TEXT PRIMARY KEY, spam_score INTEGER DEFAULT 0,
+                                   message_count INTEGER DEFAULT 0, last_updated INTEGER NOT NULL DEFAULT 0);",
+        )
+        .unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+        assert_eq!(migrate(&mut conn).unwrap(), LATEST_VERSION);
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_VERSION);
+        assert_eq!(migrate(&mut conn).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_bayes_model_is_seeded_from_labeled_messages() {
+        let mut conn = v1_database();
+        conn.execute_batch(
+            "CREATE TABLE labeled_messages (id INTEGER PRIMARY KEY, text TEXT NOT NULL, is_spam INTEGER NOT NULL);
+             INSERT INTO labeled_messages (text, is_spam) VALUES ('free crypto', 1), ('hello there', 0), ('free lunch', 0);",
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+        let model = crate::bayes::load_model(&conn).unwrap();
+        assert_eq!((model.spam_messages, model.ham_messages), (1, 2));
+        let free = model.tokens["free"];
+        assert_eq!((free.spam, free.ham), (1, 1));
+    }
+}
@@ -0,0 +1,252 @@
+//! A module for limiting how many messages a user may send within a time window.
+//!
+//! The `RateLimiter` keeps the timestamps of each user's recent messages in memory.
+//! A user who sends more than `max_messages` messages within `time_window` is
+//! rate-limited, which the message handler treats as spam.
+//!
+//! So a restart (e.g. a deploy) does not hand every spammer a fresh window, the
+//! timestamps are written to the `rate_limit_messages` table every
+//! `RATE_LIMIT_PERSIST_SECS` seconds and restored at startup. Each write replaces the
+//! whole snapshot, which is small since only messages inside the window are kept.
+//! Writing more often makes the restored state more accurate, at the cost of one
+//! transaction per write: messages received after the last write are forgotten on a
+//! crash, so a user may get up to that many seconds' worth of messages through again.
+
+use crate::config::env_parse;
+use crate::rules::{now_unix_millis, RuleManager};
+use crate::sync::LockExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Number of tracked users above which idle users are pruned on the next check.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Seconds between writes of the rate limiter state when `RATE_LIMIT_PERSIST_SECS` is unset.
+pub const DEFAULT_PERSIST_SECS: u64 = 30;
+
+/// Tracks recent messages per user and flags users who exceed the limit.
+pub struct RateLimiter {
+    /// Timestamps of recent messages per user, oldest first.
+    messages: HashMap<String, Vec<Instant>>,
+    /// Maximum number of messages allowed within `time_window`.
+    pub max_messages: usize,
+    /// Length of the sliding window.
+    pub time_window: Duration,
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter`.
+    ///
+    /// # Arguments
+    /// * `max_messages` - Maximum number of messages allowed within the window.
+    /// * `time_window` - Length of the sliding window.
+    pub fn new(max_messages: usize, time_window: Duration) -> Self {
+        Self {
+            messages: HashMap::new(),
+            max_messages,
+            time_window,
+        }
+    }
+
+    /// Creates a `RateLimiter` from environment variables.
+    ///
+    /// * `RATE_LIMIT_MAX_MESSAGES` - Messages allowed within the window (default 5).
+    /// * `RATE_LIMIT_WINDOW_SECS` - Window length in seconds (default 10).
+    pub fn from_env() -> Self {
+        Self::new(
+            env_parse("RATE_LIMIT_MAX_MESSAGES", 5),
+            Duration::from_secs(env_parse("RATE_LIMIT_WINDOW_SECS", 10)),
+        )
+    }
+
+    /// Records a message from a user and checks it against the limit.
+    ///
+    /// Timestamps older than the window are evicted first. The message is only
+    /// counted if it is allowed, so exactly `max_messages` messages pass per window
+    /// and the next one is limited.
+    ///
+    /// # Arguments
+    /// * `user_id` - The unique identifier of the sender.
+    /// * `now` - The time the message was received.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the message is within the limit, `false` if the user is rate-limited.
+    pub fn check(&mut self, user_id: &str, now: Instant) -> bool {
+        if self.messages.len() >= PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+        let window = self.time_window;
+        let timestamps = self.messages.entry(user_id.to_string()).or_default();
+        timestamps.retain(|&t| now.duration_since(t) < window);
+        if timestamps.len() >= self.max_messages {
+            return false;
+        }
+        timestamps.push(now);
+        true
+    }
+
+    /// Removes users without messages inside the window, bounding memory use.
+    ///
+    /// # Arguments
+    /// * `now` - The current time.
+    pub fn prune(&mut self, now: Instant) {
+        let window = self.time_window;
+        self.messages
+            .retain(|_, timestamps| timestamps.last().is_some_and(|&t| now.duration_since(t) < window));
+    }
+
+    /// Lists the messages inside the window with wall-clock timestamps, for persisting.
+    ///
+    /// # Arguments
+    /// * `now` - The current time.
+    /// * `now_millis` - The current time in milliseconds since the Unix epoch.
+    ///
+    /// # Returns
+    /// * `Vec<(String, i64)>` - `(user ID, milliseconds since the Unix epoch)` pairs.
+    pub fn snapshot(&self, now: Instant, now_millis: i64) -> Vec<(String, i64)> {
+        let mut entries = Vec::new();
+        for (user_id, timestamps) in &self.messages {
+            for &t in timestamps {
+                let age = now.duration_since(t);
+                if age < self.time_window {
+                    entries.push((user_id.clone(), now_millis - age.as_millis() as i64));
+                }
+            }
+        }
+        entries
+    }
+
+    /// Restores messages listed by `snapshot`, e.g. after a restart.
+    ///
+    /// Messages that have left the window since, or lie in the future (a clock
+    /// change), are skipped.
+    ///
+    /// # Arguments
+    /// * `entries` - `(user ID, milliseconds since the Unix epoch)` pairs.
+    /// * `now` - The current time.
+    /// * `now_millis` - The current time in milliseconds since the Unix epoch.
+    pub fn restore(&mut self, entries: Vec<(String, i64)>, now: Instant, now_millis: i64) {
+        for (user_id, sent_at) in entries {
+            let Ok(age) = u64::try_from(now_millis - sent_at) else {
+                continue;
+            };
+            let age = Duration::from_millis(age);
+            if age >= self.time_window {
+                continue;
+            }
+            if let Some(t) = now.checked_sub(age) {
+                self.messages.entry(user_id).or_default().push(t);
+            }
+        }
+        for timestamps in self.messages.values_mut() {
+            timestamps.sort();
+        }
+    }
+}
+
+/// Restores the rate limiter state saved by a previous run.
+///
+/// # Arguments
+/// * `limiter` - The rate limiter to fill.
+/// * `rule_manager` - The manager owning the database.
+pub fn restore_rate_limits(limiter: &mut RateLimiter, rule_manager: &RuleManager) {
+    match rule_manager.load_rate_limits() {
+        Ok(entries) => limiter.restore(entries, Instant::now(), now_unix_millis()),
+        Err(e) => log::error!("Failed to restore rate limiter state: {}", e),
+    }
+}
+
+/// Spawns a background task that writes the rate limiter state to the database every
+/// `RATE_LIMIT_PERSIST_SECS` seconds (default `DEFAULT_PERSIST_SECS`; 0 disables it).
+///
+/// # Arguments
+/// * `rule_manager` - The manager owning the database.
+/// * `limiter` - The shared rate limiter.
+pub fn spawn_rate_limit_persistence(rule_manager: Arc<RuleManager>, limiter: Arc<Mutex<RateLimiter>>) {
+    let period = Duration::from_secs(env_parse("RATE_LIMIT_PERSIST_SECS", DEFAULT_PERSIST_SECS));
+    if period.is_zero() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let entries = limiter.lock_or_recover().snapshot(Instant::now(), now_unix_millis());
+            let result = rule_manager.run_blocking(move |manager: &RuleManager| manager.save_rate_limits(&entries)).await;
+            if let Err(e) = result {
+                log::error!("Failed to write rate limiter state: {}", e);
+            }
+        }
+    });
+}
+
+/// Unit tests for the `rate_limiter` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_is_limited_after_max_messages() {
+        let mut limiter = RateLimiter::new(3, Duration::from_secs(10));
+        let start = Instant::now();
+        assert!(limiter.check("user1", start));
+        assert!(limiter.check("user1", start + Duration::from_secs(1)));
+        assert!(limiter.check("user1", start + Duration::from_secs(2)));
+        assert!(!limiter.check("user1", start + Duration::from_secs(3)));
+        // Other users are unaffected.
+        assert!(limiter.check("user2", start + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_window_eviction_allows_new_messages() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        assert!(limiter.check("user1", start));
+        assert!(limiter.check("user1", start + Duration::from_secs(5)));
+        assert!(!limiter.check("user1", start + Duration::from_secs(6)));
+        // The first message has left the window.
+        assert!(limiter.check("user1", start + Duration::from_secs(10)));
+        assert!(!limiter.check("user1", start + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn test_prune_drops_idle_users() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        limiter.check("user1", start);
+        limiter.prune(start + Duration::from_secs(20));
+        assert!(limiter.messages.is_empty());
+    }
+
+    #[test]
+    fn test_state_survives_a_restart() {
+        let mut limiter = RateLimiter::new(3, Duration::from_secs(10));
+        let start = Instant::now();
+        limiter.check("user1", start);
+        limiter.check("user1", start + Duration::from_secs(6));
+        limiter.check("user1", start + Duration::from_secs(7));
+        let now = start + Duration::from_secs(8);
+        let mut entries = limiter.snapshot(now, 1_000_000);
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![("user1".to_string(), 992_000), ("user1".to_string(), 998_000), ("user1".to_string(), 999_000)]
+        );
+
+        // The restarted process has its own monotonic clock.
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let manager = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        manager.save_rate_limits(&entries).unwrap();
+        let restarted = Instant::now();
+        let mut restored = RateLimiter::new(3, Duration::from_secs(10));
+        // Five seconds later, the first message has left the window.
+        restored.restore(manager.load_rate_limits().unwrap(), restarted, 1_005_000);
+        assert!(restored.check("user1", restarted));
+        assert!(!restored.check("user1", restarted));
+        // Messages from the future are ignored.
+        let mut skewed = RateLimiter::new(1, Duration::from_secs(10));
+        skewed.restore(vec![("user1".to_string(), 2_000_000)], restarted, 1_005_000);
+        assert!(skewed.check("user1", restarted));
+    }
+}
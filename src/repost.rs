@@ -0,0 +1,232 @@
+//! A module for escalating against users who repost deleted spam.
+//!
+//! Spammers evade deletion by posting the same message again right after the bot
+//! removes it. `RepostTracker` remembers the messages the bot deleted per chat and
+//! user; a new message from the same user that is similar to one deleted within the
+//! configured window receives an escalation score for every such deletion, so each
+//! repost is punished harder than the last.
+
+use crate::rules::{self, RuleManager};
+use crate::sync::LockExt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Setting key for the repost window in seconds.
+const WINDOW_KEY: &str = "repost_window_secs";
+/// Setting key for the escalation score per recent deletion.
+const ESCALATION_KEY: &str = "repost_escalation";
+/// Maximum number of deletions remembered per chat and user.
+const MAX_DELETIONS_PER_USER: usize = 10;
+/// Maximum number of chat/user pairs tracked.
+const MAX_TRACKED_USERS: usize = 50_000;
+/// Word-set similarity at or above which a message counts as a repost.
+const SIMILARITY_THRESHOLD: f32 = 0.8;
+
+/// How reposts after a deletion are punished.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RepostPolicy {
+    /// How long after a deletion a similar message counts as a repost.
+    pub window: Duration,
+    /// Score added per matching deletion within the window; 0 disables escalation.
+    pub escalation: f32,
+}
+
+impl Default for RepostPolicy {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(600),
+            escalation: 5.0,
+        }
+    }
+}
+
+impl RepostPolicy {
+    /// Parses a policy of the form `<window seconds> <escalation score>`.
+    ///
+    /// # Arguments
+    /// * `spec` - The command argument, e.g. `600 5`.
+    ///
+    /// # Returns
+    /// * `Result<Self, String>` - The policy, or a human-readable error message.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let usage = "Usage: /setrepostpolicy <window seconds> <escalation score>".to_string();
+        let parts: Vec<&str> = spec.split_whitespace().collect();
+        if parts.len() != 2 {
+            return Err(usage);
+        }
+        match (parts[0].parse::<u64>(), parts[1].parse::<f32>()) {
+            (Ok(secs), Ok(escalation)) if escalation.is_finite() && escalation >= 0.0 => Ok(Self {
+                window: Duration::from_secs(secs),
+                escalation,
+            }),
+            _ => Err(usage),
+        }
+    }
+
+    /// Loads the policy stored in the `settings` table, falling back to the defaults.
+    pub fn load(rule_manager: &RuleManager) -> Self {
+        let defaults = Self::default();
+        Self {
+            window: rule_manager
+                .get_setting(WINDOW_KEY)
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.window),
+            escalation: rule_manager
+                .get_setting(ESCALATION_KEY)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.escalation),
+        }
+    }
+
+    /// Stores the policy in the `settings` table.
+    ///
+    /// # Returns
+    /// * `rules::Result<()>` - A `Result` indicating success or a `RuleManagerError`.
+    pub fn save(&self, rule_manager: &RuleManager) -> rules::Result<()> {
+        rule_manager.set_setting(WINDOW_KEY, &self.window.as_secs().to_string())?;
+        rule_manager.set_setting(ESCALATION_KEY, &self.escalation.to_string())
+    }
+}
+
+/// Splits a message into its set of lowercase alphanumeric words.
+fn word_set(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+/// Returns `true` if two word sets overlap enough to count as the same message.
+fn is_similar(a: &HashSet<String>, b: &HashSet<String>) -> bool {
+    let union = a.union(b).count();
+    union > 0 && a.intersection(b).count() as f32 / union as f32 >= SIMILARITY_THRESHOLD
+}
+
+/// The word sets of a user's recent deletions, with when each was deleted.
+type Deletions = VecDeque<(HashSet<String>, Instant)>;
+
+/// Remembers the bot's recent deletions and scores reposts of deleted content.
+#[derive(Debug, Default)]
+pub struct RepostTracker {
+    /// The active policy.
+    policy: Mutex<RepostPolicy>,
+    /// Recently deleted messages per `(chat_id, user_id)`, oldest first.
+    deletions: Mutex<HashMap<(i64, u64), Deletions>>,
+}
+
+impl RepostTracker {
+    /// Creates a tracker with the given policy and no recorded deletions.
+    pub fn new(policy: RepostPolicy) -> Self {
+        Self {
+            policy: Mutex::new(policy),
+            deletions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the active policy.
+    pub fn policy(&self) -> RepostPolicy {
+        *self.policy.lock_or_recover()
+    }
+
+    /// Replaces the active policy.
+    pub fn set_policy(&self, policy: RepostPolicy) {
+        *self.policy.lock_or_recover() = policy;
+    }
+
+    /// Records a message the bot deleted.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was deleted from.
+    /// * `user_id` - The author of the message.
+    /// * `text` - The deleted text.
+    /// * `now` - The time of the deletion.
+    pub fn record_deletion(&self, chat_id: i64, user_id: u64, text: &str, now: Instant) {
+        let window = self.policy().window;
+        let mut deletions = self.deletions.lock_or_recover();
+        if !deletions.contains_key(&(chat_id, user_id)) && deletions.len() >= MAX_TRACKED_USERS {
+            deletions.retain(|_, seen| seen.back().is_some_and(|(_, at)| now.duration_since(*at) < window));
+            if deletions.len() >= MAX_TRACKED_USERS {
+                return;
+            }
+        }
+        let seen = deletions.entry((chat_id, user_id)).or_default();
+        if seen.len() >= MAX_DELETIONS_PER_USER {
+            seen.pop_front();
+        }
+        seen.push_back((word_set(text), now));
+    }
+
+    /// Scores a new message against the user's recent deletions.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `user_id` - The author of the message.
+    /// * `text` - The message text.
+    /// * `now` - The time the message was received.
+    ///
+    /// # Returns
+    /// * `f32` - The escalation score times the number of similar messages deleted
+    ///   within the window; 0 if the message is not a repost.
+    pub fn check(&self, chat_id: i64, user_id: u64, text: &str, now: Instant) -> f32 {
+        let policy = self.policy();
+        if policy.escalation <= 0.0 {
+            return 0.0;
+        }
+        let mut deletions = self.deletions.lock_or_recover();
+        let seen = match deletions.get_mut(&(chat_id, user_id)) {
+            Some(seen) => seen,
+            None => return 0.0,
+        };
+        seen.retain(|(_, at)| now.duration_since(*at) < policy.window);
+        let words = word_set(text);
+        let reposts = seen.iter().filter(|(deleted, _)| is_similar(deleted, &words)).count();
+        policy.escalation * reposts as f32
+    }
+}
+
+/// Unit tests for the `repost` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quick_repost_after_delete_escalates() {
+        let tracker = RepostTracker::new(RepostPolicy {
+            window: Duration::from_secs(600),
+            escalation: 5.0,
+        });
+        let start = Instant::now();
+        let spam = "Join my channel for FREE crypto signals!";
+        assert_eq!(tracker.check(1, 7, spam, start), 0.0);
+
+        tracker.record_deletion(1, 7, spam, start);
+        let later = start + Duration::from_secs(30);
+        assert_eq!(tracker.check(1, 7, "join my channel for free crypto signals", later), 5.0);
+        // Each further deletion escalates the next repost.
+        tracker.record_deletion(1, 7, spam, later);
+        assert_eq!(tracker.check(1, 7, spam, later + Duration::from_secs(30)), 10.0);
+    }
+
+    #[test]
+    fn test_unrelated_or_late_messages_are_not_reposts() {
+        let tracker = RepostTracker::new(RepostPolicy::default());
+        let start = Instant::now();
+        tracker.record_deletion(1, 7, "buy cheap followers now", start);
+
+        assert_eq!(tracker.check(1, 7, "good morning everyone", start), 0.0);
+        assert_eq!(tracker.check(2, 7, "buy cheap followers now", start), 0.0);
+        assert_eq!(tracker.check(1, 7, "buy cheap followers now", start + Duration::from_secs(601)), 0.0);
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!(
+            RepostPolicy::parse("300 2.5"),
+            Ok(RepostPolicy { window: Duration::from_secs(300), escalation: 2.5 })
+        );
+        assert!(RepostPolicy::parse("300").is_err());
+        assert!(RepostPolicy::parse("300 -1").is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! A module for reputation tiers and tier-dependent score decay.
+//!
+//! A sender's tier is derived from their history: long-standing users with at most a
+//! single slip are `Trusted`, users with several spam hits are `Suspicious`, and
+//! everyone else is `Neutral`. Spam scores decay exponentially from the time they last
+//! changed, with a half-life chosen per tier, so a trusted user who slipped once
+//! recovers within days while a known spammer stays flagged for weeks.
+
+use crate::config::env_parse;
+use std::fmt;
+
+/// Messages a sender needs before they can be `Trusted`.
+pub const TRUSTED_MIN_MESSAGES: i64 = 50;
+/// Highest spam score a `Trusted` sender may have.
+pub const TRUSTED_MAX_SCORE: i32 = 1;
+/// Spam score from which a sender is `Suspicious`.
+pub const SUSPICIOUS_MIN_SCORE: i32 = 3;
+/// Messages a sender needs before their spam ratio adjusts message scores.
+pub const HISTORY_MIN_MESSAGES: i64 = 20;
+/// Lowest multiplier a clean history can apply to a message score.
+pub const MIN_HISTORY_MULTIPLIER: f32 = 0.5;
+
+/// A sender's reputation tier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReputationTier {
+    /// An established sender with at most a single slip.
+    Trusted,
+    /// A sender without notable history either way.
+    Neutral,
+    /// A sender with repeated spam.
+    Suspicious,
+}
+
+impl ReputationTier {
+    /// Classifies a sender from their stored history.
+    ///
+    /// # Arguments
+    /// * `spam_score` - The sender's stored spam score.
+    /// * `message_count` - The number of messages seen from the sender.
+    pub fn classify(spam_score: i32, message_count: i64) -> Self {
+        if spam_score >= SUSPICIOUS_MIN_SCORE {
+            ReputationTier::Suspicious
+        } else if message_count >= TRUSTED_MIN_MESSAGES && spam_score <= TRUSTED_MAX_SCORE {
+            ReputationTier::Trusted
+        } else {
+            ReputationTier::Neutral
+        }
+    }
+}
+
+impl fmt::Display for ReputationTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ReputationTier::Trusted => "Trusted",
+            ReputationTier::Neutral => "Neutral",
+            ReputationTier::Suspicious => "Suspicious",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Score half-lives per reputation tier, in days.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecayRates {
+    /// Half-life for `Trusted` senders.
+    pub trusted_days: f64,
+    /// Half-life for `Neutral` senders.
+    pub neutral_days: f64,
+    /// Half-life for `Suspicious` senders.
+    pub suspicious_days: f64,
+}
+
+impl Default for DecayRates {
+    fn default() -> Self {
+        Self {
+            trusted_days: 1.0,
+            neutral_days: 7.0,
+            suspicious_days: 30.0,
+        }
+    }
+}
+
+impl DecayRates {
+    /// Builds the decay rates from environment variables.
+    ///
+    /// * `DECAY_HALF_LIFE_TRUSTED_DAYS` - Half-life for trusted senders (default 1).
+    /// * `DECAY_HALF_LIFE_NEUTRAL_DAYS` - Half-life for neutral senders (default 7).
+    /// * `DECAY_HALF_LIFE_SUSPICIOUS_DAYS` - Half-life for suspicious senders (default 30).
+    ///
+    /// A half-life of 0 or less disables decay for that tier.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            trusted_days: env_parse("DECAY_HALF_LIFE_TRUSTED_DAYS", defaults.trusted_days),
+            neutral_days: env_parse("DECAY_HALF_LIFE_NEUTRAL_DAYS", defaults.neutral_days),
+            suspicious_days: env_parse("DECAY_HALF_LIFE_SUSPICIOUS_DAYS", defaults.suspicious_days),
+        }
+    }
+
+    /// Returns the half-life in days for a tier.
+    pub fn half_life_days(&self, tier: ReputationTier) -> f64 {
+        match tier {
+            ReputationTier::Trusted => self.trusted_days,
+            ReputationTier::Neutral => self.neutral_days,
+            ReputationTier::Suspicious => self.suspicious_days,
+        }
+    }
+}
+
+/// Applies exponential decay to a score.
+///
+/// # Arguments
+/// * `score` - The stored score.
+/// * `elapsed_secs` - Seconds since the score last changed.
+/// * `half_life_days` - The half-life; 0 or less disables decay.
+///
+/// # Returns
+/// * `f64` - The decayed score.
+pub fn decayed_score(score: f64, elapsed_secs: i64, half_life_days: f64) -> f64 {
+    if half_life_days <= 0.0 || elapsed_secs <= 0 {
+        return score;
+    }
+    let elapsed_days = elapsed_secs as f64 / 86_400.0;
+    score * 0.5f64.powf(elapsed_days / half_life_days)
+}
+
+/// Computes how much a sender's history discounts the score of a new message.
+///
+/// Senders with too few messages get no discount, so a brand-new account is scored
+/// at full weight. Established senders are discounted by up to half, less the more
+/// of their messages were spam.
+///
+/// # Arguments
+/// * `spam_ratio` - The sender's spam messages divided by their total messages.
+/// * `message_count` - The number of messages seen from the sender.
+///
+/// # Returns
+/// * `f32` - The multiplier, between `MIN_HISTORY_MULTIPLIER` and 1.0.
+pub fn history_multiplier(spam_ratio: f32, message_count: i64) -> f32 {
+    if message_count < HISTORY_MIN_MESSAGES {
+        return 1.0;
+    }
+    (MIN_HISTORY_MULTIPLIER + spam_ratio).min(1.0)
+}
+
+/// Unit tests for the `reputation` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(ReputationTier::classify(1, 200), ReputationTier::Trusted);
+        assert_eq!(ReputationTier::classify(1, 5), ReputationTier::Neutral);
+        assert_eq!(ReputationTier::classify(4, 200), ReputationTier::Suspicious);
+    }
+
+    #[test]
+    fn test_trusted_score_decays_faster_than_suspicious() {
+        let rates = DecayRates::default();
+        let week = 7 * 86_400;
+        let trusted = decayed_score(1.0, week, rates.half_life_days(ReputationTier::Trusted));
+        let suspicious = decayed_score(1.0, week, rates.half_life_days(ReputationTier::Suspicious));
+        assert!(trusted < suspicious);
+        assert!((trusted - 1.0 / 128.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_clean_history_discounts_score() {
+        assert_eq!(history_multiplier(0.0, 100), 0.5);
+        assert_eq!(history_multiplier(0.0, 1), 1.0);
+        assert_eq!(history_multiplier(0.1, 100), 0.6);
+        assert_eq!(history_multiplier(0.8, 100), 1.0);
+    }
+
+    #[test]
+    fn test_disabled_decay_keeps_score() {
+        assert_eq!(decayed_score(4.0, 86_400, 0.0), 4.0);
+        assert_eq!(decayed_score(4.0, 0, 7.0), 4.0);
+    }
+}
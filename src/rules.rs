@@ -3,196 +3,3143 @@
 ///
 /// This module provides a `RuleManager` struct that handles the storage and application
 /// of spam detection rules, as well as tracking sender behavior through a database.
-/// It uses `rusqlite` for database operations, `std::sync` for thread-safe access,
-/// and `rlua` for executing Lua scripts to evaluate custom rules.
+/// It uses `rusqlite` through an `r2d2` connection pool for database operations,
+/// `std::sync` for thread-safe access to in-memory state, and `rlua` for executing
+/// Lua scripts to evaluate custom rules.
 /// 
-use rlua::Lua;
-use rusqlite::{Connection, Result};
+use crate::bayes::{add_counts, load_model, BayesModel};
+use crate::config::{env_flag, env_parse};
+use crate::media::MessageMedia;
+use crate::migrations::migrate;
+use crate::reputation::{decayed_score, DecayRates, ReputationTier};
+use crate::sync::LockExt;
+use crate::utils::SpamAction;
+use regex::Regex;
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rlua::{HookTriggers, Lua};
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
+
+/// How a keyword rule matches message text. Regex rules ignore it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    /// The keyword may appear anywhere, even inside a word.
+    #[default]
+    Substring,
+    /// The keyword must appear as a whole word: "cialis" matches "cialis!" but not "specialist".
+    WholeWord,
+    /// The keyword must start a word: "crypto" matches "cryptocurrency" but not "anticrypto".
+    Prefix,
+    /// The keyword matches despite inserted spaces, punctuation, leetspeak and repeated
+    /// letters: "viagra" matches "v i a g r a" and "click" matches "cl1ck"; see
+    /// `RuleManager::fuzzy_match`.
+    Fuzzy,
+}
+
+impl MatchMode {
+    /// Parses a mode by its name: `substring`, `whole_word`, `prefix` or `fuzzy`.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "substring" => Some(MatchMode::Substring),
+            "whole_word" => Some(MatchMode::WholeWord),
+            "prefix" => Some(MatchMode::Prefix),
+            "fuzzy" => Some(MatchMode::Fuzzy),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the mode, as stored in the database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MatchMode::Substring => "substring",
+            MatchMode::WholeWord => "whole_word",
+            MatchMode::Prefix => "prefix",
+            MatchMode::Fuzzy => "fuzzy",
+        }
+    }
+
+    /// Returns `true` if the match of a keyword at `start..end` in `text` satisfies the mode.
+    ///
+    /// A word boundary is any position not surrounded by letters, digits or `_` on
+    /// the side being checked.
+    fn accepts(self, text: &str, start: usize, end: usize) -> bool {
+        let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+        let word_before = text[..start].chars().next_back().is_some_and(is_word_char);
+        let word_after = text[end..].chars().next().is_some_and(is_word_char);
+        match self {
+            MatchMode::Substring | MatchMode::Fuzzy => true,
+            MatchMode::WholeWord => !word_before && !word_after,
+            MatchMode::Prefix => !word_before,
+        }
+    }
+}
+
+/// What a chat does about a message flagged as spam, set with `/set_action`.
+///
+/// Every policy notifies the admins and warns the sender; chats without a policy
+/// follow the bot-wide `DELETE_SPAM` setting instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionPolicy {
+    /// Leave the message up and only notify.
+    Notify,
+    /// Delete the message.
+    Delete,
+    /// Delete the message and mute the sender.
+    Mute,
+    /// Delete the message and ban the sender.
+    Ban,
+}
+
+impl ActionPolicy {
+    /// Parses a policy by its name: `notify`, `delete`, `mute` or `ban`, ignoring case.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "notify" => Some(ActionPolicy::Notify),
+            "delete" => Some(ActionPolicy::Delete),
+            "mute" => Some(ActionPolicy::Mute),
+            "ban" => Some(ActionPolicy::Ban),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the policy, as stored in the database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ActionPolicy::Notify => "notify",
+            ActionPolicy::Delete => "delete",
+            ActionPolicy::Mute => "mute",
+            ActionPolicy::Ban => "ban",
+        }
+    }
+
+    /// Returns `true` if the policy deletes the spam message.
+    pub fn deletes(self) -> bool {
+        self != ActionPolicy::Notify
+    }
+
+    /// Returns the least the policy does to the sender, whatever their warnings.
+    pub fn sender_action(self) -> SpamAction {
+        match self {
+            ActionPolicy::Notify | ActionPolicy::Delete => SpamAction::Notify,
+            ActionPolicy::Mute => SpamAction::Mute,
+            ActionPolicy::Ban => SpamAction::Ban,
+        }
+    }
+}
 
 /// Represents a single spam detection rule consisting of a keyword and an associated score.
 ///
 /// The `Rule` struct is used to define patterns (keywords) and their corresponding
 /// spam scores, which are evaluated against messages to determine spam likelihood.
+/// A negative score makes a negative rule, which subtracts from the score of a matching message.
 /// It is marked as `Clone` to allow easy duplication of rule instances.
 #[derive(Clone)]
 pub struct Rule {
     /// The keyword or pattern to match against messages (e.g., "spam", "http").
     pub keyword: String,
-    /// The score associated with the keyword, indicating its spam weight (e.g., 10.0 for "spam").
+    /// The score associated with the keyword, indicating its spam weight (e.g., 10.0 for "spam");
+    /// negative for words that mark a message as legitimate.
+    pub score: f32,
+    /// Whether `keyword` is a regular expression rather than a plain keyword.
+    pub is_regex: bool,
+    /// The compiled pattern of a regex rule, cached so it is compiled only once.
+    /// `None` for keyword rules and for stored patterns that fail to compile.
+    pub regex: Option<Regex>,
+    /// The group the rule belongs to (e.g. "crypto"), toggled as a whole.
+    pub category: String,
+    /// Whether the rule is scored; disabled rules are kept but skipped.
+    pub enabled: bool,
+    /// How a keyword rule matches; `Substring` unless set with `/set_rule_mode`.
+    pub match_mode: MatchMode,
+}
+
+impl Rule {
+    /// Creates a plain keyword rule.
+    pub fn keyword(keyword: String, score: f32) -> Self {
+        Self {
+            keyword,
+            score,
+            is_regex: false,
+            regex: None,
+            category: DEFAULT_CATEGORY.to_string(),
+            enabled: true,
+            match_mode: MatchMode::Substring,
+        }
+    }
+
+    /// Creates a regex rule from an already compiled pattern.
+    pub fn regex(regex: Regex, score: f32) -> Self {
+        Self {
+            keyword: regex.as_str().to_string(),
+            score,
+            is_regex: true,
+            regex: Some(regex),
+            category: DEFAULT_CATEGORY.to_string(),
+            enabled: true,
+            match_mode: MatchMode::Substring,
+        }
+    }
+
+    /// Returns `true` if the rule matches a message.
+    ///
+    /// Keyword rules match case-insensitively according to their `match_mode`; regex
+    /// rules match according to their pattern (use `(?i)` for case-insensitive patterns).
+    /// Disabled rules never match.
+    pub fn matches(&self, message: &str) -> bool {
+        if !self.enabled {
+            false
+        } else if self.is_regex {
+            self.regex.as_ref().is_some_and(|regex| regex.is_match(message))
+        } else if self.match_mode == MatchMode::Substring {
+            message.to_lowercase().contains(&self.folded_keyword())
+        } else if self.match_mode == MatchMode::Fuzzy {
+            RuleManager::fuzzy_match(message, &self.keyword)
+        } else {
+            self.keyword_occurrences(message) > 0
+        }
+    }
+
+    /// Counts the non-overlapping occurrences of the rule in a message.
+    ///
+    /// Matching follows `matches`, so disabled rules never occur.
+    pub fn occurrences(&self, message: &str) -> usize {
+        if !self.enabled || self.keyword.is_empty() {
+            0
+        } else if self.is_regex {
+            self.regex.as_ref().map_or(0, |regex| regex.find_iter(message).count())
+        } else if self.match_mode == MatchMode::Fuzzy {
+            fuzzy_occurrences(message, &self.keyword)
+        } else {
+            self.keyword_occurrences(message)
+        }
+    }
+
+    /// Counts the non-overlapping, case-insensitive occurrences of the keyword that
+    /// satisfy the rule's `match_mode`.
+    fn keyword_occurrences(&self, message: &str) -> usize {
+        let message = message.to_lowercase();
+        let keyword = self.folded_keyword();
+        message
+            .match_indices(&keyword)
+            .filter(|(start, found)| self.match_mode.accepts(&message, *start, *start + found.len()))
+            .count()
+    }
+
+    /// Returns the keyword normalized like messages are and lowercased, so a keyword
+    /// written with Cyrillic or Greek letters still matches the normalized message.
+    fn folded_keyword(&self) -> String {
+        RuleManager::normalize(&self.keyword).to_lowercase()
+    }
+}
+
+/// Digits and symbols that fuzzy matching reads as the letters they imitate.
+const LEETSPEAK: &[(char, char)] = &[
+    ('0', 'o'), ('1', 'i'), ('3', 'e'), ('4', 'a'), ('5', 's'), ('7', 't'), ('@', 'a'), ('$', 's'),
+];
+
+/// Folds text for fuzzy matching in a single pass: lowercases it, reads leetspeak
+/// as letters, drops everything but letters and digits, and collapses runs of the
+/// same character, so "V i a a g r @" becomes "viagra".
+fn fuzzy_fold(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        let c = LEETSPEAK.iter().find(|(leet, _)| *leet == c).map_or(c, |(_, letter)| *letter);
+        if c.is_alphanumeric() && !folded.ends_with(c) {
+            folded.push(c);
+        }
+    }
+    folded
+}
+
+/// Counts the non-overlapping fuzzy matches of a keyword in a message.
+fn fuzzy_occurrences(message: &str, keyword: &str) -> usize {
+    let keyword = fuzzy_fold(&RuleManager::normalize(keyword));
+    if keyword.is_empty() {
+        return 0;
+    }
+    fuzzy_fold(message).matches(keyword.as_str()).count()
+}
+
+/// The result of scoring a message with the Lua script and the stored rules.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RuleEvaluation {
+    /// The Lua score plus the scores of every matching stored rule.
+    pub total: f32,
+    /// The score of the Lua script, 0 if it failed.
+    pub lua_score: f32,
+    /// The stored rules that matched, as `(keyword, score)` pairs with the score each contributed.
+    pub matches: Vec<(String, f32)>,
+}
+
+/// A rule as written by `/export_rules` and read by `/import_rules`.
+///
+/// Only `keyword` and `score` are required, so rule sets can also be written by hand.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedRule {
+    /// The keyword, or the pattern of a regex rule.
+    pub keyword: String,
+    /// The spam score of the rule.
+    pub score: f32,
+    /// Whether `keyword` is a regular expression.
+    #[serde(default)]
+    pub is_regex: bool,
+    /// The group the rule belongs to.
+    #[serde(default = "default_category")]
+    pub category: String,
+    /// Whether the rule is scored.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// How a keyword rule matches.
+    #[serde(default)]
+    pub match_mode: MatchMode,
+}
+
+fn default_category() -> String {
+    DEFAULT_CATEGORY.to_string()
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl From<&Rule> for ExportedRule {
+    fn from(rule: &Rule) -> Self {
+        Self {
+            keyword: rule.keyword.clone(),
+            score: rule.score,
+            is_regex: rule.is_regex,
+            category: rule.category.clone(),
+            enabled: rule.enabled,
+            match_mode: rule.match_mode,
+        }
+    }
+}
+
+/// A recurring switch to a rule profile, stored in the `profile_schedules` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileSchedule {
+    /// The database id; later schedules win ties.
+    pub id: i64,
+    /// The profile activated by this schedule.
+    pub profile: String,
+    /// Bitmask of weekdays the switch fires on (bit 0 = Sunday, bit 6 = Saturday).
+    pub days: u8,
+    /// Minute after midnight (UTC) the switch fires at.
+    pub minute_of_day: u32,
+}
+
+/// A message flagged as spam, as listed by `/recent_spam`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlaggedMessage {
+    /// The sender, or the chat a post was made on behalf of.
+    pub user_id: String,
+    /// The message text, cut to `FLAGGED_TEXT_LIMIT` characters.
+    pub text: String,
+    /// The score the message was flagged with.
     pub score: f32,
+    /// When the message was flagged, in seconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+/// A change to a sender's spam score, as listed by `/history`.
+///
+/// Decay is applied when scores are read and is not recorded, so the deltas of a
+/// sender's events can add up to more than their current score.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScoreEvent {
+    /// The unique identifier of the sender.
+    pub user_id: String,
+    /// The chat the score is kept for.
+    pub chat_id: i64,
+    /// Points added to the score, or removed if negative.
+    pub delta: i32,
+    /// Why the score changed: `spam`, `edited into spam`, `reset` or `pardoned`.
+    pub reason: String,
+    /// When the score changed, in seconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+/// Everything the bot stores about a sender, as shown by `/whois`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SenderRecord {
+    /// The unique identifier of the sender.
+    pub user_id: String,
+    /// Whether the sender has a row in the `senders` table for the chat.
+    pub known: bool,
+    /// The sender's current spam score, decayed and rounded as by `get_sender_score`.
+    pub spam_score: i32,
+    /// Messages the bot has checked from the sender.
+    pub message_count: i32,
+    /// Warnings the sender has received.
+    pub warnings: i32,
+    /// Whether the sender is exempt from spam checks in the chat.
+    pub whitelisted: bool,
+    /// The bot's bans of the sender in the chat, as `(chat_id, reason, banned_at)`, newest first.
+    pub bans: Vec<(i64, String, i64)>,
+}
+
+/// The outcome of `RuleManager::self_check`, run once at startup.
+#[derive(Debug)]
+pub struct SelfCheck {
+    /// The Lua script's score for `SELF_CHECK_MESSAGE`, or why the script failed.
+    pub lua: Result<f32>,
+    /// Whether a row could be written to the database and read back.
+    pub database: Result<()>,
+}
+
+impl SelfCheck {
+    /// Returns `true` if both the Lua script and the database work.
+    pub fn passed(&self) -> bool {
+        self.lua.is_ok() && self.database.is_ok()
+    }
+}
+
+/// Aggregated contribution of one detector over the spam events in a window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetectorStat {
+    /// The detector name (e.g. "lua_rules", "links").
+    pub detector: String,
+    /// Number of spam events the detector contributed a non-zero score to.
+    pub fired: i64,
+    /// Sum of the detector's contributions.
+    pub total: f64,
+}
+
+impl DetectorStat {
+    /// Average contribution per event the detector fired on.
+    pub fn average(&self) -> f64 {
+        if self.fired == 0 {
+            0.0
+        } else {
+            self.total / self.fired as f64
+        }
+    }
+}
+
+/// Applies a chat's safe patterns to a message's score.
+///
+/// Negative rules only subtract their own score, so a message matching a safe pattern
+/// has its score capped at 0 instead, however many spam rules and detectors fired.
+///
+/// # Arguments
+/// * `score` - The message's score, net of any negative rules.
+/// * `safe` - Whether the message matched one of the chat's safe patterns.
+///
+/// # Returns
+/// * `f32` - The score the message is judged by.
+pub fn net_score(score: f32, safe: bool) -> f32 {
+    if safe {
+        score.min(0.0)
+    } else {
+        score
+    }
+}
+
+/// Returns the current time as seconds since the Unix epoch.
+pub fn now_unix() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub fn now_unix_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Returns `true` if `name` is a non-empty profile name made of ASCII letters,
+/// digits, `_` or `-`, so it can safely be used in a script file name.
+pub fn is_valid_profile_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Reads a sender's reputation tier in a chat and their spam score there decayed to `now`.
+///
+/// # Returns
+/// * `Option<(ReputationTier, f64)>` - The tier and decayed score, or `None` for unknown senders.
+fn decayed_sender_score(
+    conn: &Connection,
+    chat_id: i64,
+    user_id: &str,
+    rates: &DecayRates,
+    now: i64,
+) -> Option<(ReputationTier, f64)> {
+    let (spam_score, message_count, last_updated): (i32, i64, i64) = conn
+        .query_row(
+            "SELECT spam_score, message_count, last_updated FROM senders WHERE chat_id = ?1 AND user_id = ?2",
+            rusqlite::params![chat_id, user_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .ok()?;
+    Some(decay_stored_score(spam_score, message_count, last_updated, rates, now))
+}
+
+/// Classifies a sender from their stored row and decays their stored spam score to
+/// `now` at the tier's rate.
+///
+/// # Returns
+/// * `(ReputationTier, f64)` - The tier and decayed score.
+fn decay_stored_score(spam_score: i32, message_count: i64, last_updated: i64, rates: &DecayRates, now: i64) -> (ReputationTier, f64) {
+    let tier = ReputationTier::classify(spam_score, message_count);
+    (tier, decayed_score(spam_score as f64, now - last_updated, rates.half_life_days(tier)))
+}
+
+/// Records a change to a sender's spam score in `score_events`, within the transaction
+/// that makes the change.
+fn record_score_event(conn: &Connection, chat_id: i64, user_id: &str, delta: i32, reason: &str, now: i64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO score_events (chat_id, user_id, delta, reason, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        rusqlite::params![chat_id, user_id, delta, reason, now],
+    )?;
+    Ok(())
+}
+
+/// Loads every rule stored in the `rules` table, compiling regex rules once.
+fn load_rules(conn: &Connection) -> Result<Vec<Rule>> {
+    let mut stmt = conn.prepare("SELECT keyword, score, is_regex, category, enabled, match_mode FROM rules")?;
+    let rule_iter = stmt.query_map([], |row| {
+        let keyword: String = row.get(0)?;
+        let score: f32 = row.get(1)?;
+        let is_regex: bool = row.get(2)?;
+        let regex = if is_regex {
+            Regex::new(&keyword)
+                .map_err(|e| log::error!("Ignoring invalid regex rule '{}': {}", keyword, e))
+                .ok()
+        } else {
+            None
+        };
+        let match_mode: String = row.get(5)?;
+        Ok(Rule {
+            keyword,
+            score,
+            is_regex,
+            regex,
+            category: row.get(3)?,
+            enabled: row.get(4)?,
+            match_mode: MatchMode::parse(&match_mode).unwrap_or_default(),
+        })
+    })?;
+    Ok(rule_iter.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Minimum time between two alerts to a chat's admins about broken Lua rules.
+pub const LUA_ALERT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// An error evaluating the Lua rules script.
+#[derive(Debug, Error)]
+pub enum LuaRulesError {
+    /// The script file could not be read.
+    #[error("failed to read {path}: {source}")]
+    Read {
+        /// The script path.
+        path: String,
+        /// The underlying I/O error.
+        source: std::io::Error,
+    },
+    /// The script failed to load or run, or does not define `check_spam`.
+    #[error("Lua error: {0}")]
+    Lua(#[from] rlua::Error),
+    /// The script ran past `lua_max_instructions` or `lua_timeout` and was aborted.
+    #[error("Lua rules aborted after {instructions} instructions and {} ms; does the script loop forever?", elapsed.as_millis())]
+    Timeout {
+        /// Instructions run before the script was aborted, to the nearest check.
+        instructions: u64,
+        /// Time taken before the script was aborted.
+        elapsed: Duration,
+    },
+}
+
+/// An error importing a JSON rule set.
+#[derive(Debug, Error)]
+pub enum RuleImportError {
+    /// The text is not a JSON array of rules.
+    #[error("invalid rules JSON: {0}")]
+    Json(#[source] serde_json::Error),
+    /// A rule has an empty keyword, which would match every message.
+    #[error("a rule has an empty keyword")]
+    EmptyKeyword,
+    /// A regex rule's pattern does not compile.
+    #[error("invalid regex '{pattern}': {source}")]
+    InvalidRegex {
+        /// The pattern.
+        pattern: String,
+        /// The underlying regex error.
+        source: regex::Error,
+    },
+    /// The rules could not be stored.
+    #[error(transparent)]
+    Database(#[from] RuleManagerError),
+}
+
+impl From<rusqlite::Error> for RuleImportError {
+    fn from(e: rusqlite::Error) -> Self {
+        RuleImportError::Database(e.into())
+    }
+}
+
+/// An error returned by `RuleManager`.
+#[derive(Debug, Error)]
+pub enum RuleManagerError {
+    /// A database query or write failed.
+    #[error("database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    /// No database connection became available.
+    #[error("database connection unavailable: {0}")]
+    Pool(#[from] r2d2::Error),
+    /// The Lua rules script could not be evaluated.
+    #[error(transparent)]
+    Lua(#[from] LuaRulesError),
+    /// A file could not be read or written.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// The result of a `RuleManager` operation.
+pub type Result<T, E = RuleManagerError> = std::result::Result<T, E>;
+
+/// Writes a rule to the `rules` table keyed by its keyword and mirrors it in `rules`.
+///
+/// # Returns
+/// * `Result<bool>` - `true` if the rule was newly added, `false` if an existing
+///   rule was updated, or a `RuleManagerError` if the database operation fails.
+fn store_rule(conn: &Connection, rules: &mut Vec<Rule>, rule: Rule) -> Result<bool> {
+    conn.execute(
+        "INSERT INTO rules (keyword, score, is_regex, category, enabled, match_mode) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(keyword) DO UPDATE SET score = ?2, is_regex = ?3, category = ?4, enabled = ?5, match_mode = ?6",
+        rusqlite::params![rule.keyword, rule.score, rule.is_regex, rule.category, rule.enabled, rule.match_mode.as_str()],
+    )?;
+    match rules.iter_mut().find(|existing| existing.keyword == rule.keyword) {
+        Some(existing) => {
+            *existing = rule;
+            Ok(false)
+        }
+        None => {
+            rules.push(rule);
+            Ok(true)
+        }
+    }
+}
+
+/// Carries the state of the cached rules over to a rule about to be stored.
+///
+/// A rule in a disabled category starts out disabled as well, and a rule replacing
+/// one with the same keyword keeps its match mode.
+fn inherit_rule_state(rules: &[Rule], rule: &mut Rule) {
+    rule.enabled = !rules.iter().any(|existing| existing.category == rule.category && !existing.enabled);
+    if let Some(existing) = rules.iter().find(|existing| existing.keyword == rule.keyword) {
+        rule.match_mode = existing.match_mode;
+    }
 }
 
 /// Manages spam detection rules and sender scores using a SQLite database.
 ///
-/// The `RuleManager` struct maintains a thread-safe connection to a SQLite database
+/// The `RuleManager` struct maintains a pool of connections to a SQLite database
 /// and an in-memory cache of rules. It provides methods to initialize the database,
 /// add rules, update sender scores, retrieve sender scores, and evaluate messages
 /// against custom rules defined in a Lua script.
 pub struct RuleManager {
-    /// A thread-safe wrapper around the SQLite database connection.
+    /// A pool of SQLite connections to the database.
     ///
-    /// The `Mutex` ensures that database operations are performed safely in a
-    /// multi-threaded environment, while the `Connection` handles SQL queries.
-    pub conn: Mutex<Connection>,
+    /// Handlers check out their own connection, so reads run concurrently; the
+    /// database is in WAL mode, so they also run alongside a writer.
+    pub pool: Pool<SqliteConnectionManager>,
     /// A thread-safe cache of active rules loaded from the database.
     ///
     /// The `Arc<Mutex<Vec<Rule>>>` allows shared ownership and safe mutation of
     /// the rule list across threads.
     pub rules: Arc<Mutex<Vec<Rule>>>,
+    /// The name of the active rule profile.
+    ///
+    /// The "default" profile evaluates `rules.lua`; any other profile `name`
+    /// evaluates `rules_<name>.lua`, allowing stricter or looser rule sets to be
+    /// switched in without editing the script.
+    pub active_profile: Mutex<String>,
+    /// Path of the default profile's Lua script.
+    ///
+    /// Read from the `RULES_LUA_PATH` environment variable, defaulting to
+    /// `DEFAULT_RULES_PATH`. Other profiles' scripts live next to it.
+    pub rules_path: String,
+    /// When each chat's admins were last alerted about broken Lua rules.
+    lua_failure_alerts: Mutex<HashMap<i64, Instant>>,
+    /// Each chat's trusted domains, loaded from the `allowed_domains` table on first use.
+    ///
+    /// The allowlist is consulted for every message with text, so it is kept in
+    /// memory and refreshed whenever an admin changes it.
+    allowed_domains: Mutex<HashMap<i64, Vec<String>>>,
+    /// Each chat's safe patterns, loaded from the `safe_patterns` table on first use
+    /// and refreshed whenever an admin changes them, like `allowed_domains`.
+    safe_patterns: Mutex<HashMap<i64, Vec<String>>>,
+    /// Each chat's blocked sticker sets and file IDs, loaded from the `blocked_media`
+    /// table on first use and refreshed whenever an admin changes them.
+    blocked_media: Mutex<HashMap<i64, Vec<String>>>,
+    /// Rule hits counted since they were last written to the `hit_count` column.
+    ///
+    /// Hits are counted in memory on the hot path and written in one transaction by
+    /// `flush_rule_hits`, instead of one database write per rule per message.
+    rule_hits: Mutex<HashMap<String, i64>>,
+    /// The Bayes classifier's word counts, loaded from the database at startup.
+    bayes: Mutex<BayesModel>,
+    /// Bayes training since it was last written to the database by `flush_bayes`.
+    bayes_pending: Mutex<BayesModel>,
+    /// Score at or above which a message is considered spam in chats without their own
+    /// threshold, see `get_chat_threshold`.
+    ///
+    /// Read from the `SPAM_THRESHOLD` environment variable, defaulting to
+    /// `DEFAULT_THRESHOLD`, so it can be tuned per deployment.
+    pub threshold: f32,
+    /// Spam score half-lives per reputation tier, applied whenever a sender's score is read.
+    pub decay_rates: DecayRates,
+    /// Whether stored rules score once per occurrence rather than once per message.
+    ///
+    /// Read from the `RULE_OCCURRENCE_WEIGHTING` environment variable, defaulting to `false`.
+    pub weight_by_occurrences: bool,
+    /// Maximum number of occurrences counted per rule when weighting by occurrences.
+    ///
+    /// Read from the `RULE_OCCURRENCE_CAP` environment variable, defaulting to
+    /// `DEFAULT_OCCURRENCE_CAP`; 0 counts every occurrence.
+    pub occurrence_cap: usize,
+    /// Lua instructions a single evaluation may run before it is aborted.
+    ///
+    /// Read from the `LUA_MAX_INSTRUCTIONS` environment variable, defaulting to
+    /// `DEFAULT_LUA_MAX_INSTRUCTIONS`; 0 removes the limit.
+    pub lua_max_instructions: u64,
+    /// Time a single Lua evaluation may take before it is aborted.
+    ///
+    /// Read from the `LUA_TIMEOUT_MS` environment variable, defaulting to
+    /// `DEFAULT_LUA_TIMEOUT`; 0 removes the limit.
+    pub lua_timeout: Duration,
 }
 
+/// Default score at or above which a message is considered spam.
+pub const DEFAULT_THRESHOLD: f32 = 5.0;
+
+/// Lua script evaluated by the default profile when `RULES_LUA_PATH` is unset.
+pub const DEFAULT_RULES_PATH: &str = "rules.lua";
+
+/// Category of rules added without an explicit one.
+pub const DEFAULT_CATEGORY: &str = "default";
+
+/// Name of the rule profile that evaluates the plain `rules.lua` script.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Cyrillic and Greek letters that look like ASCII letters, mapped to the letter they imitate.
+///
+/// NFKC already folds fullwidth and styled forms such as `ｐ` or `𝐩`, but keeps letters
+/// from other scripts, which spammers use to write e.g. "paypal" without matching it.
+const HOMOGLYPHS: &[(char, char)] = &[
+    // Cyrillic lowercase
+    ('а', 'a'), ('в', 'b'), ('е', 'e'), ('һ', 'h'), ('і', 'i'), ('ј', 'j'), ('к', 'k'), ('м', 'm'),
+    ('н', 'h'), ('о', 'o'), ('р', 'p'), ('ԛ', 'q'), ('с', 'c'), ('ѕ', 's'), ('т', 't'), ('у', 'y'),
+    ('ԝ', 'w'), ('х', 'x'), ('ԁ', 'd'),
+    // Cyrillic uppercase
+    ('А', 'A'), ('В', 'B'), ('Е', 'E'), ('І', 'I'), ('Ј', 'J'), ('К', 'K'), ('М', 'M'), ('Н', 'H'),
+    ('О', 'O'), ('Р', 'P'), ('С', 'C'), ('Ѕ', 'S'), ('Т', 'T'), ('У', 'Y'), ('Х', 'X'),
+    // Greek lowercase
+    ('α', 'a'), ('ι', 'i'), ('κ', 'k'), ('ν', 'v'), ('ο', 'o'), ('ρ', 'p'), ('τ', 't'), ('υ', 'u'),
+    // Greek uppercase
+    ('Α', 'A'), ('Β', 'B'), ('Ε', 'E'), ('Ζ', 'Z'), ('Η', 'H'), ('Ι', 'I'), ('Κ', 'K'), ('Μ', 'M'),
+    ('Ν', 'N'), ('Ο', 'O'), ('Ρ', 'P'), ('Τ', 'T'), ('Υ', 'Y'), ('Χ', 'X'),
+];
+
+/// Occurrences counted per rule when `RULE_OCCURRENCE_CAP` is unset.
+pub const DEFAULT_OCCURRENCE_CAP: usize = 5;
+
+/// Lua instructions per evaluation when `LUA_MAX_INSTRUCTIONS` is unset.
+pub const DEFAULT_LUA_MAX_INSTRUCTIONS: u64 = 10_000_000;
+
+/// Time allowed per Lua evaluation when `LUA_TIMEOUT_MS` is unset.
+pub const DEFAULT_LUA_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Lua instructions run between two checks of the limits.
+const LUA_HOOK_INTERVAL: u32 = 1000;
+
+/// Number of pooled database connections when `DB_POOL_SIZE` is unset.
+pub const DEFAULT_POOL_SIZE: u32 = 8;
+
+/// Database path selecting an ephemeral in-memory database, see `RuleManager::new`.
+pub const IN_MEMORY_PATH: &str = ":memory:";
+
+/// In-memory databases opened so far, numbering their shared-cache names.
+static MEMORY_DATABASES: AtomicU64 = AtomicU64::new(0);
+
+/// Seconds between writes of the rule hit counts when `RULE_HITS_FLUSH_SECS` is unset.
+pub const DEFAULT_RULE_HITS_FLUSH_SECS: u64 = 60;
+
+/// Message the startup self-check passes to the Lua script's `check_spam`.
+pub const SELF_CHECK_MESSAGE: &str = "test";
+
+/// Setting the startup self-check writes and reads back; the write is rolled back.
+const SELF_CHECK_SETTING: &str = "self_check";
+
+/// Flagged messages kept per chat for `/recent_spam`; older ones are deleted.
+pub const FLAGGED_MESSAGES_KEPT: i64 = 100;
+
+/// Characters of a flagged message's text that are stored.
+pub const FLAGGED_TEXT_LIMIT: usize = 1000;
+
+/// Seconds between writes of the Bayes classifier's training when `BAYES_FLUSH_SECS` is unset.
+pub const DEFAULT_BAYES_FLUSH_SECS: u64 = 60;
+
 impl RuleManager {
     /// Creates a new `RuleManager` instance with the specified database path.
     ///
-    /// Initializes a SQLite database connection and creates the necessary tables
-    /// (`rules` and `senders`) if they do not exist. Loads existing rules from
-    /// the database into an in-memory cache.
+    /// Opens a pool of SQLite connections in WAL mode, sized by `DB_POOL_SIZE`
+    /// (default `DEFAULT_POOL_SIZE`), and applies any pending schema migrations, so
+    /// databases created by older versions are upgraded in place. Loads existing
+    /// rules from the database into an in-memory cache.
+    ///
+    /// `IN_MEMORY_PATH` opens a fresh in-memory database instead. A plain `:memory:`
+    /// connection is private, so every pooled connection would see its own empty
+    /// database; the pool's connections share one uniquely named in-memory database
+    /// through SQLite's shared cache instead, and are never retired, since the
+    /// database is dropped with its last connection. Shared-cache connections lock
+    /// whole tables, so concurrent writes may fail with "database table is locked";
+    /// in-memory databases suit tests and short-lived runs.
     ///
     /// # Arguments
-    /// * `db_path` - The file path to the SQLite database (e.g., "rules.db").
+    /// * `db_path` - The file path to the SQLite database (e.g., "rules.db"), or `IN_MEMORY_PATH`.
     ///
     /// # Returns
     /// * `Result<Self>` - A `Result` containing the new `RuleManager` instance
-    ///   on success, or a `rusqlite::Error` if database operations fail.
+    ///   on success, or a `RuleManagerError` if database operations fail.
     pub fn new(db_path: &str) -> Result<Self> {
-        let conn = Mutex::new(Connection::open(db_path)?);
-        {
-            let conn = conn.lock().unwrap();
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS rules (
-                        id INTEGER PRIMARY KEY,
-                        keyword TEXT NOT NULL,
-                        score REAL NOT NULL
-                    )",
-                [],
-            )?;
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS senders (
-                        user_id TEXT PRIMARY KEY,
-                        spam_score INTEGER DEFAULT 0,
-                        message_count INTEGER DEFAULT 0
-                    )",
-                [],
-            )?;
-        }
-        let rules = {
-            let conn = conn.lock().unwrap();
-            let mut stmt = conn.prepare("SELECT keyword, score FROM rules")?;
-            let rule_iter = stmt.query_map([], |row| {
-                Ok(Rule {
-                    keyword: row.get(0)?,
-                    score: row.get(1)?,
-                })
-            })?;
-            rule_iter.collect::<Result<Vec<_>>>()?
-        };
+        let builder = Pool::builder().max_size(env_parse("DB_POOL_SIZE", DEFAULT_POOL_SIZE));
+        let pool = if db_path == IN_MEMORY_PATH {
+            let name = format!(
+                "file:spam_bot_{}_{}?mode=memory&cache=shared",
+                std::process::id(),
+                MEMORY_DATABASES.fetch_add(1, Ordering::Relaxed)
+            );
+            builder.idle_timeout(None).max_lifetime(None).build(SqliteConnectionManager::file(name))
+        } else {
+            builder.build(SqliteConnectionManager::file(db_path).with_init(|conn| {
+                conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+                Ok(())
+            }))
+        }?;
+        let mut conn = pool.get()?;
+        migrate(&mut conn)?;
+        let rules = load_rules(&conn)?;
+        let bayes = load_model(&conn)?;
+        drop(conn);
         Ok(Self {
-            conn,
+            pool,
             rules: Arc::new(Mutex::new(rules)),
+            active_profile: Mutex::new(DEFAULT_PROFILE.to_string()),
+            rules_path: std::env::var("RULES_LUA_PATH").unwrap_or_else(|_| DEFAULT_RULES_PATH.to_string()),
+            lua_failure_alerts: Mutex::new(HashMap::new()),
+            allowed_domains: Mutex::new(HashMap::new()),
+            safe_patterns: Mutex::new(HashMap::new()),
+            blocked_media: Mutex::new(HashMap::new()),
+            rule_hits: Mutex::new(HashMap::new()),
+            bayes: Mutex::new(bayes),
+            bayes_pending: Mutex::new(BayesModel::default()),
+            threshold: env_parse("SPAM_THRESHOLD", DEFAULT_THRESHOLD),
+            decay_rates: DecayRates::from_env(),
+            weight_by_occurrences: env_flag("RULE_OCCURRENCE_WEIGHTING", false),
+            occurrence_cap: env_parse("RULE_OCCURRENCE_CAP", DEFAULT_OCCURRENCE_CAP),
+            lua_max_instructions: env_parse("LUA_MAX_INSTRUCTIONS", DEFAULT_LUA_MAX_INSTRUCTIONS),
+            lua_timeout: Duration::from_millis(env_parse("LUA_TIMEOUT_MS", DEFAULT_LUA_TIMEOUT.as_millis() as u64)),
         })
     }
 
-    /// Adds a new rule to the database and in-memory cache.
+    /// Checks a connection out of the pool.
+    ///
+    /// # Returns
+    /// * `Result<PooledConnection<SqliteConnectionManager>>` - The connection, or
+    ///   `RuleManagerError::Pool` if none became available within the pool's timeout.
+    fn conn(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
+        Ok(self.pool.get()?)
+    }
+
+    /// Checks a connection out of the pool for a lookup that cannot return an error,
+    /// logging the failure so the caller can fall back to its documented default.
+    fn conn_or_log(&self) -> Option<PooledConnection<SqliteConnectionManager>> {
+        self.conn().map_err(|e| log::error!("Failed to get a database connection: {}", e)).ok()
+    }
+
+    /// Runs a blocking operation on the manager in tokio's blocking thread pool.
     ///
-    /// Inserts the specified keyword and score into the `rules` table and
-    /// updates the in-memory rule cache. If the keyword already exists,
-    /// it will be duplicated in the cache (no uniqueness constraint).
+    /// SQLite calls block the calling thread, and a slow one (a busy writer, a large
+    /// import) would otherwise stall every other update handled on the same runtime
+    /// worker. A panic in `f` is resumed in the caller, as if `f` had been called directly.
     ///
     /// # Arguments
-    /// * `keyword` - The keyword to match against messages.
-    /// * `score` - The spam score associated with the keyword.
+    /// * `f` - The operation, given the manager.
+    ///
+    /// # Returns
+    /// * `T` - The result of `f`.
+    pub async fn run_blocking<T, F>(self: &Arc<Self>, f: F) -> T
+    where
+        F: FnOnce(&RuleManager) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let manager = Arc::clone(self);
+        match tokio::task::spawn_blocking(move || f(&manager)).await {
+            Ok(value) => value,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    /// Async version of `add_rule`, run with `run_blocking`.
+    pub async fn add_rule_async(self: &Arc<Self>, keyword: String, score: f32) -> Result<bool> {
+        self.run_blocking(move |manager: &RuleManager| manager.add_rule(keyword, score)).await
+    }
+
+    /// Async version of `increment_sender_score`, run with `run_blocking`.
+    pub async fn increment_sender_score_async(self: &Arc<Self>, chat_id: i64, user_id: &str, is_spam: bool) -> Result<()> {
+        let user_id = user_id.to_string();
+        self.run_blocking(move |manager: &RuleManager| manager.increment_sender_score(chat_id, &user_id, is_spam))
+            .await
+    }
+
+    /// Async version of `increment_sender_score_by`, run with `run_blocking`.
+    pub async fn increment_sender_score_by_async(self: &Arc<Self>, chat_id: i64, user_id: &str, points: i32) -> Result<()> {
+        let user_id = user_id.to_string();
+        self.run_blocking(move |manager: &RuleManager| manager.increment_sender_score_by(chat_id, &user_id, points))
+            .await
+    }
+
+    /// Async version of `get_sender_score`, run with `run_blocking`.
+    pub async fn get_sender_score_async(self: &Arc<Self>, chat_id: i64, user_id: &str) -> i32 {
+        let user_id = user_id.to_string();
+        self.run_blocking(move |manager: &RuleManager| manager.get_sender_score(chat_id, &user_id)).await
+    }
+
+    /// Async version of `add_spam_points`, run with `run_blocking`.
+    pub async fn add_spam_points_async(self: &Arc<Self>, chat_id: i64, user_id: &str, points: i32) -> Result<()> {
+        let user_id = user_id.to_string();
+        self.run_blocking(move |manager: &RuleManager| manager.add_spam_points(chat_id, &user_id, points)).await
+    }
+
+    /// Async version of `get_message_count`, run with `run_blocking`.
+    pub async fn get_message_count_async(self: &Arc<Self>, chat_id: i64, user_id: &str) -> i64 {
+        let user_id = user_id.to_string();
+        self.run_blocking(move |manager: &RuleManager| manager.get_message_count(chat_id, &user_id)).await
+    }
+
+    /// Async version of `get_spam_message_count`, run with `run_blocking`.
+    pub async fn get_spam_message_count_async(self: &Arc<Self>, chat_id: i64, user_id: &str) -> i64 {
+        let user_id = user_id.to_string();
+        self.run_blocking(move |manager: &RuleManager| manager.get_spam_message_count(chat_id, &user_id)).await
+    }
+
+    /// Async version of `spam_ratio`, run with `run_blocking`.
+    pub async fn spam_ratio_async(self: &Arc<Self>, chat_id: i64, user_id: &str) -> f32 {
+        let user_id = user_id.to_string();
+        self.run_blocking(move |manager: &RuleManager| manager.spam_ratio(chat_id, &user_id)).await
+    }
+
+    /// Async version of `is_whitelisted`, run with `run_blocking`.
+    pub async fn is_whitelisted_async(self: &Arc<Self>, chat_id: i64, user_id: &str) -> bool {
+        let user_id = user_id.to_string();
+        self.run_blocking(move |manager: &RuleManager| manager.is_whitelisted(chat_id, &user_id)).await
+    }
+
+    /// Async version of `is_paused`, run with `run_blocking`.
+    pub async fn is_paused_async(self: &Arc<Self>, chat_id: i64, now: i64) -> bool {
+        self.run_blocking(move |manager: &RuleManager| manager.is_paused(chat_id, now)).await
+    }
+
+    /// Async version of `get_chat_threshold`, run with `run_blocking`.
+    pub async fn get_chat_threshold_async(self: &Arc<Self>, chat_id: i64) -> f32 {
+        self.run_blocking(move |manager: &RuleManager| manager.get_chat_threshold(chat_id)).await
+    }
+
+    /// Sets the spam threshold of a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to configure.
+    /// * `threshold` - Score at or above which messages in the chat are spam.
     ///
     /// # Returns
-    /// * `Result<()>` - A `Result` indicating success or a `rusqlite::Error`
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
     ///   if the database operation fails.
-    pub fn add_rule(&self, keyword: String, score: f32) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
+    pub fn set_chat_threshold(&self, chat_id: i64, threshold: f32) -> Result<()> {
+        let conn = self.conn()?;
         conn.execute(
-            "INSERT INTO rules (keyword, score) VALUES (?1, ?2)",
-            &[&keyword, &score.to_string()],
+            "INSERT INTO chat_settings (chat_id, threshold) VALUES (?1, ?2)
+                 ON CONFLICT(chat_id) DO UPDATE SET threshold = ?2",
+            rusqlite::params![chat_id, threshold],
         )?;
-        let mut rules = self.rules.lock().unwrap();
-        rules.push(Rule { keyword, score });
         Ok(())
     }
 
-    /// Increments the spam score for a sender based on message type.
+    /// Returns the spam threshold of a chat.
     ///
-    /// Updates the `senders` table by incrementing the `spam_score` by 1
-    /// if the message is spam, or by 0 (no change) if it is not spam.
-    /// Also increments the `message_count` for the sender.
+    /// # Arguments
+    /// * `chat_id` - The chat to look up.
+    ///
+    /// # Returns
+    /// * `f32` - The chat's threshold, or the bot-wide `threshold` if none is set.
+    pub fn get_chat_threshold(&self, chat_id: i64) -> f32 {
+        let Some(conn) = self.conn_or_log() else {
+            return self.threshold;
+        };
+        conn.query_row(
+            "SELECT threshold FROM chat_settings WHERE chat_id = ?1 AND threshold IS NOT NULL",
+            [chat_id],
+            |row| row.get(0),
+        )
+        .unwrap_or(self.threshold)
+    }
+
+    /// Sets the language the bot answers in in a chat.
     ///
     /// # Arguments
-    /// * `user_id` - The unique identifier of the sender.
-    /// * `is_spam` - A boolean indicating whether the message is spam.
+    /// * `chat_id` - The chat to configure.
+    /// * `language` - The language code, e.g. "ru".
     ///
     /// # Returns
-    /// * `Result<()>` - A `Result` indicating success or a `rusqlite::Error`
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
     ///   if the database operation fails.
-    pub fn increment_sender_score(&self, user_id: &str, is_spam: bool) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        let increment = if is_spam { 1 } else { 0 }; // Only increment for spam, don’t decrement
+    pub fn set_chat_language(&self, chat_id: i64, language: &str) -> Result<()> {
+        let conn = self.conn()?;
         conn.execute(
-            "INSERT INTO senders (user_id, spam_score, message_count)
-                 VALUES (?1, ?2, 1)
-                 ON CONFLICT(user_id) DO UPDATE
-                 SET spam_score = spam_score + ?2, message_count = message_count + 1",
-            &[&user_id, &increment.to_string()[..]],
+            "INSERT INTO chat_settings (chat_id, language) VALUES (?1, ?2)
+                 ON CONFLICT(chat_id) DO UPDATE SET language = ?2",
+            rusqlite::params![chat_id, language],
         )?;
         Ok(())
     }
 
-    /// Retrieves the current spam score for a given sender.
+    /// Returns the language chosen for a chat.
     ///
-    /// Queries the `senders` table to get the `spam_score` for the specified
-    /// `user_id`. Returns 0 if no record exists for the user.
+    /// # Arguments
+    /// * `chat_id` - The chat to look up.
+    ///
+    /// # Returns
+    /// * `Option<String>` - The language code, or `None` if the chat has not chosen one.
+    pub fn get_chat_language(&self, chat_id: i64) -> Option<String> {
+        let conn = self.conn_or_log()?;
+        conn.query_row(
+            "SELECT language FROM chat_settings WHERE chat_id = ?1 AND language IS NOT NULL",
+            [chat_id],
+            |row| row.get(0),
+        )
+        .ok()
+    }
+
+    /// Sets or clears what a chat does about spam.
     ///
     /// # Arguments
-    /// * `user_id` - The unique identifier of the sender.
+    /// * `chat_id` - The chat to configure.
+    /// * `policy` - The policy, or `None` to follow the bot-wide settings again.
     ///
     /// # Returns
-    /// * `i32` - The sender's current spam score, or 0 if not found.
-    pub fn get_sender_score(&self, user_id: &str) -> i32 {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT spam_score FROM senders WHERE user_id = ?1")
-            .unwrap();
-        stmt.query_row(&[user_id], |row| row.get(0)).unwrap_or(0)
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn set_action_policy(&self, chat_id: i64, policy: Option<ActionPolicy>) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO chat_settings (chat_id, action_policy) VALUES (?1, ?2)
+                 ON CONFLICT(chat_id) DO UPDATE SET action_policy = ?2",
+            rusqlite::params![chat_id, policy.map(ActionPolicy::as_str)],
+        )?;
+        Ok(())
     }
 
-    /// Evaluates a message against custom rules defined in a Lua script.
+    /// Returns what a chat does about spam.
     ///
-    /// Loads the `rules.lua` script and executes the `check_spam` function
-    /// with the provided message. Returns the total score based on matching
-    /// keywords. Logs an error and returns 0.0 if the script fails to load.
+    /// # Arguments
+    /// * `chat_id` - The chat to look up.
+    ///
+    /// # Returns
+    /// * `Option<ActionPolicy>` - The chat's policy, or `None` if it follows the bot-wide settings.
+    pub fn get_action_policy(&self, chat_id: i64) -> Option<ActionPolicy> {
+        let conn = self.conn_or_log()?;
+        conn.query_row(
+            "SELECT action_policy FROM chat_settings WHERE chat_id = ?1 AND action_policy IS NOT NULL",
+            [chat_id],
+            |row| row.get::<_, String>(0),
+        )
+        .ok()
+        .and_then(|name| ActionPolicy::parse(&name))
+    }
+
+    /// Sets or clears the message a chat greets new members with.
     ///
     /// # Arguments
-    /// * `message` - The text message to evaluate for spam.
+    /// * `chat_id` - The chat to configure.
+    /// * `welcome` - The message, with `{name}` where the members are mentioned, or
+    ///   `None` to stop greeting them.
     ///
     /// # Returns
-    /// * `f32` - The cumulative spam score for the message, or 0.0 on error.
-    pub fn check_custom_rules(&self, message: &str) -> f32 {
-        let lua = Lua::new();
-        let score: f32 = lua
-            .context(|lua_ctx| {
-                let script = match std::fs::read_to_string("rules.lua") {
-                    Ok(content) => content,
-                    Err(e) => {
-                        log::error!("Failed to read rules.lua: {}", e);
-                        return Ok::<f32, rlua::Error>(0.0);
-                    }
-                };
-                lua_ctx.load(&script).exec()?;
-                let globals = lua_ctx.globals();
-                globals.set("message", message)?;
-                let result: f32 = lua_ctx.load("return check_spam(message)").eval()?;
-                Ok(result)
-            })
-            .unwrap_or(0.0);
-        score
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn set_welcome_message(&self, chat_id: i64, welcome: Option<&str>) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO chat_settings (chat_id, welcome) VALUES (?1, ?2)
+                 ON CONFLICT(chat_id) DO UPDATE SET welcome = ?2",
+            rusqlite::params![chat_id, welcome],
+        )?;
+        Ok(())
     }
-}
+
+    /// Returns the message a chat greets new members with.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to look up.
+    ///
+    /// # Returns
+    /// * `Option<String>` - The message, or `None` if the chat does not greet new members.
+    pub fn get_welcome_message(&self, chat_id: i64) -> Option<String> {
+        let conn = self.conn_or_log()?;
+        conn.query_row(
+            "SELECT welcome FROM chat_settings WHERE chat_id = ?1 AND welcome IS NOT NULL",
+            [chat_id],
+            |row| row.get(0),
+        )
+        .ok()
+    }
+
+    /// Pauses spam moderation in a chat, replacing any earlier pause.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to pause.
+    /// * `resume_at` - When moderation resumes by itself, in seconds since the Unix
+    ///   epoch, or `None` to pause until `resume_chat`.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn pause_chat(&self, chat_id: i64, resume_at: Option<i64>) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO chat_settings (chat_id, paused, resume_at) VALUES (?1, 1, ?2)
+                 ON CONFLICT(chat_id) DO UPDATE SET paused = 1, resume_at = ?2",
+            rusqlite::params![chat_id, resume_at],
+        )?;
+        Ok(())
+    }
+
+    /// Resumes spam moderation in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to resume.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if moderation was paused, or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn resume_chat(&self, chat_id: i64) -> Result<bool> {
+        let conn = self.conn()?;
+        let resumed = conn.execute(
+            "UPDATE chat_settings SET paused = 0, resume_at = NULL WHERE chat_id = ?1 AND paused = 1",
+            [chat_id],
+        )?;
+        Ok(resumed > 0)
+    }
+
+    /// Returns `true` if spam moderation is paused in a chat.
+    ///
+    /// A pause whose `resume_at` has passed no longer counts, even before
+    /// `take_expired_pauses` clears it.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to look up.
+    /// * `now` - The current time, in seconds since the Unix epoch.
+    pub fn is_paused(&self, chat_id: i64, now: i64) -> bool {
+        let Some(conn) = self.conn_or_log() else {
+            return false;
+        };
+        conn.query_row(
+            "SELECT 1 FROM chat_settings WHERE chat_id = ?1 AND paused = 1 AND (resume_at IS NULL OR resume_at > ?2)",
+            rusqlite::params![chat_id, now],
+            |_| Ok(()),
+        )
+        .is_ok()
+    }
+
+    /// Resumes every chat whose pause has run out.
+    ///
+    /// # Arguments
+    /// * `now` - The current time, in seconds since the Unix epoch.
+    ///
+    /// # Returns
+    /// * `Result<Vec<i64>>` - The chats that were resumed, or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn take_expired_pauses(&self, now: i64) -> Result<Vec<i64>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "UPDATE chat_settings SET paused = 0, resume_at = NULL
+                 WHERE paused = 1 AND resume_at IS NOT NULL AND resume_at <= ?1
+                 RETURNING chat_id",
+        )?;
+        let rows = stmt.query_map([now], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Adds a rule to the database and in-memory cache, or updates its score.
+    ///
+    /// Keywords are unique, so adding an existing keyword replaces its score
+    /// instead of creating a duplicate that would be counted twice.
+    ///
+    /// # Arguments
+    /// * `keyword` - The keyword to match against messages.
+    /// * `score` - The spam score associated with the keyword.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the rule was newly added, `false` if an existing
+    ///   rule was updated, or a `RuleManagerError` if the database operation fails.
+    pub fn add_rule(&self, keyword: String, score: f32) -> Result<bool> {
+        self.add_rule_in_category(keyword, score, DEFAULT_CATEGORY)
+    }
+
+    /// Adds a rule to a category, or updates the score and category of an existing rule.
+    ///
+    /// A new rule in a disabled category starts out disabled as well, and matches
+    /// as a substring; an updated rule keeps its match mode.
+    ///
+    /// # Arguments
+    /// * `keyword` - The keyword to match against messages.
+    /// * `score` - The spam score associated with the keyword.
+    /// * `category` - The group the rule belongs to.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the rule was newly added, `false` if an existing
+    ///   rule was updated, or a `RuleManagerError` if the database operation fails.
+    pub fn add_rule_in_category(&self, keyword: String, score: f32, category: &str) -> Result<bool> {
+        let mut rule = Rule::keyword(keyword, score);
+        rule.category = category.to_string();
+        self.upsert_rule(rule)
+    }
+
+    /// Adds or updates several keyword rules in the default category at once.
+    ///
+    /// The batch is written in a single transaction, so either every rule is stored
+    /// or, if the database operation fails, none is. Rules behave as if added one by
+    /// one with `add_rule`.
+    ///
+    /// # Arguments
+    /// * `rules` - The `(keyword, score)` pairs to store.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - The number of rules stored, or a `RuleManagerError` if the
+    ///   database operation fails.
+    pub fn add_rules(&self, rules: Vec<(String, f32)>) -> Result<usize> {
+        let mut cached = self.rules.lock_or_recover();
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut updated = cached.clone();
+        let count = rules.len();
+        for (keyword, score) in rules {
+            let mut rule = Rule::keyword(keyword, score);
+            inherit_rule_state(&updated, &mut rule);
+            store_rule(&tx, &mut updated, rule)?;
+        }
+        tx.commit()?;
+        *cached = updated;
+        Ok(count)
+    }
+
+    /// Enables or disables every rule in a category.
+    ///
+    /// # Arguments
+    /// * `category` - The category to toggle.
+    /// * `enabled` - Whether the category's rules are scored.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - The number of rules in the category, or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn set_category_enabled(&self, category: &str, enabled: bool) -> Result<usize> {
+        let mut rules = self.rules.lock_or_recover();
+        let conn = self.conn()?;
+        let updated = conn.execute(
+            "UPDATE rules SET enabled = ?2 WHERE category = ?1",
+            rusqlite::params![category, enabled],
+        )?;
+        for rule in rules.iter_mut().filter(|rule| rule.category == category) {
+            rule.enabled = enabled;
+        }
+        Ok(updated)
+    }
+
+    /// Adds a regex rule to the database and in-memory cache, or updates its score.
+    ///
+    /// The pattern is taken already compiled, so invalid patterns are rejected by
+    /// the caller before anything is stored.
+    ///
+    /// # Arguments
+    /// * `regex` - The compiled pattern to match against messages.
+    /// * `score` - The spam score added when the pattern matches.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the rule was newly added, `false` if an existing
+    ///   rule was updated, or a `RuleManagerError` if the database operation fails.
+    pub fn add_regex_rule(&self, regex: Regex, score: f32) -> Result<bool> {
+        self.upsert_rule(Rule::regex(regex, score))
+    }
+
+    /// Stores a rule keyed by its keyword and mirrors the change in the cache.
+    ///
+    /// The cache lock is held across the write, so concurrent rule changes reach the
+    /// database and the cache in the same order.
+    fn upsert_rule(&self, mut rule: Rule) -> Result<bool> {
+        let mut rules = self.rules.lock_or_recover();
+        let conn = self.conn()?;
+        inherit_rule_state(&rules, &mut rule);
+        store_rule(&conn, &mut rules, rule)
+    }
+
+    /// Sets how a keyword rule matches messages.
+    ///
+    /// # Arguments
+    /// * `keyword` - The keyword of the rule.
+    /// * `mode` - The new match mode.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the rule was updated, `false` if there is no
+    ///   keyword rule with that keyword, or a `RuleManagerError` if the database operation fails.
+    pub fn set_match_mode(&self, keyword: &str, mode: MatchMode) -> Result<bool> {
+        let mut rules = self.rules.lock_or_recover();
+        let rule = match rules.iter_mut().find(|rule| rule.keyword == keyword && !rule.is_regex) {
+            Some(rule) => rule,
+            None => return Ok(false),
+        };
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE rules SET match_mode = ?2 WHERE keyword = ?1",
+            rusqlite::params![keyword, mode.as_str()],
+        )?;
+        rule.match_mode = mode;
+        Ok(true)
+    }
+
+    /// Serializes every stored rule, including disabled ones, as a JSON array.
+    ///
+    /// # Returns
+    /// * `String` - Pretty-printed JSON that `import_rules` accepts.
+    pub fn export_rules(&self) -> String {
+        let rules: Vec<ExportedRule> = self.rules.lock_or_recover().iter().map(ExportedRule::from).collect();
+        serde_json::to_string_pretty(&rules).expect("rules always serialize to JSON")
+    }
+
+    /// Imports a JSON array of rules as written by `export_rules`.
+    ///
+    /// Keywords are unique, so an imported rule replaces an existing rule with the same
+    /// keyword, and the last of several imported rules with the same keyword wins. The
+    /// whole set is validated first and stored in one transaction, so a bad rule
+    /// imports nothing.
+    ///
+    /// # Arguments
+    /// * `json` - The rule set.
+    ///
+    /// # Returns
+    /// * `Result<usize, RuleImportError>` - The number of rules imported, or an error if
+    ///   the JSON or a rule is invalid or the database operation fails.
+    pub fn import_rules(&self, json: &str) -> std::result::Result<usize, RuleImportError> {
+        let imported: Vec<ExportedRule> = serde_json::from_str(json).map_err(RuleImportError::Json)?;
+        let mut parsed = Vec::with_capacity(imported.len());
+        for exported in imported {
+            if exported.keyword.is_empty() {
+                return Err(RuleImportError::EmptyKeyword);
+            }
+            let mut rule = if exported.is_regex {
+                let regex = Regex::new(&exported.keyword)
+                    .map_err(|source| RuleImportError::InvalidRegex { pattern: exported.keyword.clone(), source })?;
+                Rule::regex(regex, exported.score)
+            } else {
+                Rule::keyword(exported.keyword, exported.score)
+            };
+            rule.category = exported.category;
+            rule.enabled = exported.enabled;
+            rule.match_mode = exported.match_mode;
+            parsed.push(rule);
+        }
+
+        let mut rules = self.rules.lock_or_recover();
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut updated = rules.clone();
+        let count = parsed.len();
+        for rule in parsed {
+            store_rule(&tx, &mut updated, rule)?;
+        }
+        tx.commit()?;
+        *rules = updated;
+        Ok(count)
+    }
+
+    /// Lists the stored rules matching a message, natively and without Lua.
+    ///
+    /// Keyword rules match when their keyword appears anywhere in the message,
+    /// ignoring case; regex rules match their pattern. Disabled rules are skipped.
+    /// This keeps messages scored even when the Lua script is missing or broken.
+    /// The message is matched after `normalize`. When `weight_by_occurrences` is set,
+    /// each rule's score is multiplied by its number of occurrences, up to `occurrence_cap`.
+    ///
+    /// # Arguments
+    /// * `message` - The text message to evaluate.
+    ///
+    /// # Returns
+    /// * `Vec<(String, f32)>` - The keyword or pattern of each matching rule and the
+    ///   score it contributed, in rule order; the built-in score is their sum.
+    pub fn matched_rules(&self, message: &str) -> Vec<(String, f32)> {
+        let message = Self::normalize(message);
+        let message = message.as_str();
+        let rules = self.rules.lock_or_recover();
+        if self.weight_by_occurrences {
+            rules
+                .iter()
+                .filter_map(|rule| {
+                    let occurrences = rule.occurrences(message);
+                    let counted = if self.occurrence_cap > 0 { occurrences.min(self.occurrence_cap) } else { occurrences };
+                    (counted > 0).then(|| (rule.keyword.clone(), rule.score * counted as f32))
+                })
+                .collect()
+        } else {
+            rules
+                .iter()
+                .filter(|rule| rule.matches(message))
+                .map(|rule| (rule.keyword.clone(), rule.score))
+                .collect()
+        }
+    }
+
+    /// Counts a hit for each rule that matched a checked message.
+    ///
+    /// The hits are kept in memory until the next `flush_rule_hits`.
+    ///
+    /// # Arguments
+    /// * `matches` - The matching rules, as returned by `matched_rules`.
+    pub fn record_rule_hits(&self, matches: &[(String, f32)]) {
+        if matches.is_empty() {
+            return;
+        }
+        let mut hits = self.rule_hits.lock_or_recover();
+        for (keyword, _) in matches {
+            *hits.entry(keyword.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// Adds the rule hits counted in memory to the `hit_count` column.
+    ///
+    /// All hits are written in a single transaction. If it fails, the hits are kept
+    /// in memory for the next flush.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - The number of rules whose count was updated, or a
+    ///   `RuleManagerError` if the database operation fails.
+    pub fn flush_rule_hits(&self) -> Result<usize> {
+        let pending = std::mem::take(&mut *self.rule_hits.lock_or_recover());
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        let write = || -> Result<usize> {
+            let mut conn = self.conn()?;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let mut updated = 0;
+            {
+                let mut stmt = tx.prepare("UPDATE rules SET hit_count = hit_count + ?2 WHERE keyword = ?1")?;
+                for (keyword, count) in &pending {
+                    updated += stmt.execute(rusqlite::params![keyword, count])?;
+                }
+            }
+            tx.commit()?;
+            Ok(updated)
+        };
+        let result = write();
+        if result.is_err() {
+            let mut hits = self.rule_hits.lock_or_recover();
+            for (keyword, count) in pending {
+                *hits.entry(keyword).or_insert(0) += count;
+            }
+        }
+        result
+    }
+
+    /// Estimates the probability that a message is spam with the Bayes classifier.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    ///
+    /// # Returns
+    /// * `f32` - The probability, between 0 and 1; 0.5 (neutral) until the classifier
+    ///   has been trained on enough spam and ham.
+    pub fn bayes_score(&self, text: &str) -> f32 {
+        self.bayes.lock_or_recover().spam_probability(text)
+    }
+
+    /// Trains the Bayes classifier on a message.
+    ///
+    /// The model is updated at once; the database is updated by the next `flush_bayes`.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    /// * `is_spam` - Whether the message is spam.
+    pub fn train_bayes(&self, text: &str, is_spam: bool) {
+        self.bayes.lock_or_recover().train(text, is_spam);
+        self.bayes_pending.lock_or_recover().train(text, is_spam);
+    }
+
+    /// Writes the Bayes training since the last flush to the database in one transaction.
+    ///
+    /// If the write fails, the training is kept in memory for the next flush.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - The number of messages written, or a `RuleManagerError` if the
+    ///   transaction fails.
+    pub fn flush_bayes(&self) -> Result<usize> {
+        let pending = std::mem::take(&mut *self.bayes_pending.lock_or_recover());
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        let write = || -> Result<()> {
+            let mut conn = self.conn()?;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            add_counts(&tx, &pending)?;
+            Ok(tx.commit()?)
+        };
+        match write() {
+            Ok(()) => Ok((pending.spam_messages + pending.ham_messages) as usize),
+            Err(e) => {
+                self.bayes_pending.lock_or_recover().merge(&pending);
+                Err(e)
+            }
+        }
+    }
+
+    /// Replaces the stored rate limiter state with a snapshot, in one transaction.
+    ///
+    /// # Arguments
+    /// * `entries` - `(user ID, milliseconds since the Unix epoch)` pairs of recent
+    ///   messages, as listed by `RateLimiter::snapshot`.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError` if the
+    ///   transaction fails, in which case the previous snapshot is kept.
+    pub fn save_rate_limits(&self, entries: &[(String, i64)]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute("DELETE FROM rate_limit_messages", [])?;
+        {
+            let mut stmt = tx.prepare("INSERT INTO rate_limit_messages (user_id, sent_at) VALUES (?1, ?2)")?;
+            for (user_id, sent_at) in entries {
+                stmt.execute(rusqlite::params![user_id, sent_at])?;
+            }
+        }
+        Ok(tx.commit()?)
+    }
+
+    /// Loads the rate limiter state saved by `save_rate_limits`.
+    ///
+    /// # Returns
+    /// * `Result<Vec<(String, i64)>>` - `(user ID, milliseconds since the Unix epoch)`
+    ///   pairs, or a `RuleManagerError` if the query fails.
+    pub fn load_rate_limits(&self) -> Result<Vec<(String, i64)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT user_id, sent_at FROM rate_limit_messages")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Lists every rule with its number of hits, most hits first.
+    ///
+    /// Pending hits are flushed first, so the counts are up to date.
+    ///
+    /// # Returns
+    /// * `Result<Vec<(String, f32, i64)>>` - The keyword or pattern, score and hit count
+    ///   of each rule, ties in keyword order, or a `RuleManagerError` if the query fails.
+    pub fn rule_stats(&self) -> Result<Vec<(String, f32, i64)>> {
+        self.flush_rule_hits()?;
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT keyword, score, hit_count FROM rules ORDER BY hit_count DESC, keyword")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Normalizes message text so rules also match obfuscated spellings.
+    ///
+    /// Applies Unicode NFKC normalization, then replaces Cyrillic and Greek lookalikes
+    /// of ASCII letters (`HOMOGLYPHS`), so "раypal" written with Cyrillic letters
+    /// becomes "paypal". Case is kept; keyword rules already match case-insensitively.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    ///
+    /// # Returns
+    /// * `String` - The normalized text, used only for matching.
+    pub fn normalize(text: &str) -> String {
+        text.nfkc()
+            .map(|c: char| HOMOGLYPHS.iter().find(|(glyph, _)| *glyph == c).map_or(c, |(_, ascii)| *ascii))
+            .collect()
+    }
+
+    /// Returns `true` if a keyword appears in text despite obfuscation.
+    ///
+    /// Both are folded the same way: lowercased, leetspeak digits and symbols read as
+    /// letters (`1` as `i`, `0` as `o`, `@` as `a`, ...), everything but letters and
+    /// digits dropped and runs of a repeated character collapsed. The folded keyword is
+    /// then searched for in the folded text. Folding is a single pass and the search is
+    /// linear, so the cost stays O(n) in the length of the text for each rule.
+    ///
+    /// Since spaces are dropped, a short keyword can match across words ("ass" in
+    /// "class session"), so fuzzy matching suits long, distinctive keywords.
+    ///
+    /// # Arguments
+    /// * `text` - The message text, normalized with `normalize`.
+    /// * `keyword` - The rule's keyword, normalized here like the text.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the folded keyword is non-empty and appears in the folded text.
+    pub fn fuzzy_match(text: &str, keyword: &str) -> bool {
+        fuzzy_occurrences(text, keyword) > 0
+    }
+
+    /// Returns the number of keyword and regex rules, including disabled ones.
+    pub fn rule_count(&self) -> usize {
+        self.rules.lock_or_recover().len()
+    }
+
+    /// Suggests words from a spam sample that could be added as keyword rules.
+    ///
+    /// Candidates are distinct lowercase alphanumeric words of 4 to 32 characters that
+    /// no existing rule matches yet, longest first, since longer words are less likely
+    /// to appear in legitimate messages.
+    ///
+    /// # Arguments
+    /// * `text` - The spam sample.
+    /// * `max` - The maximum number of suggestions.
+    ///
+    /// # Returns
+    /// * `Vec<String>` - The suggested keywords.
+    pub fn suggest_keywords(&self, text: &str, max: usize) -> Vec<String> {
+        let rules = self.rules.lock_or_recover();
+        let mut words: Vec<String> = Vec::new();
+        for word in text.split(|c: char| !c.is_alphanumeric()) {
+            let word = word.to_lowercase();
+            let len = word.chars().count();
+            if (4..=32).contains(&len) && !words.contains(&word) && !rules.iter().any(|rule| rule.matches(&word)) {
+                words.push(word);
+            }
+        }
+        words.sort_by_key(|word| std::cmp::Reverse(word.chars().count()));
+        words.truncate(max);
+        words
+    }
+
+    /// Removes the rule with the given keyword from the database and in-memory cache.
+    ///
+    /// # Arguments
+    /// * `keyword` - The keyword of the rules to remove.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - The number of rules deleted (0 if none matched), or a
+    ///   `RuleManagerError` if the database operation fails.
+    pub fn remove_rule(&self, keyword: &str) -> Result<usize> {
+        let mut rules = self.rules.lock_or_recover();
+        let conn = self.conn()?;
+        let deleted = conn.execute("DELETE FROM rules WHERE keyword = ?1", [keyword])?;
+        rules.retain(|rule| rule.keyword != keyword);
+        Ok(deleted)
+    }
+
+    /// Increments the spam score for a sender in a chat based on message type.
+    ///
+    /// Updates the `senders` table by incrementing the `spam_score` by 1
+    /// if the message is spam, or by 0 (no change) if it is not spam.
+    /// Also increments the `message_count` for the sender. `last_updated` records
+    /// when the spam score last changed, which is where score decay starts from.
+    /// Scores are kept per chat, so spam in one chat does not count against the
+    /// sender in the others.
+    ///
+    /// On a spam hit the decay accumulated since `last_updated` is written back
+    /// before incrementing, so points that have expired do not come back.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `user_id` - The unique identifier of the sender.
+    /// * `is_spam` - A boolean indicating whether the message is spam.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn increment_sender_score(&self, chat_id: i64, user_id: &str, is_spam: bool) -> Result<()> {
+        self.increment_sender_score_by(chat_id, user_id, i32::from(is_spam))
+    }
+
+    /// Counts a message for a sender and adds `points` to their spam score.
+    ///
+    /// Like `increment_sender_score`, but a spam message can weigh more than one
+    /// point, e.g. in proportion to how far its score exceeded the threshold.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `user_id` - The unique identifier of the sender.
+    /// * `points` - The spam points to add; 0 or less counts a clean message.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn increment_sender_score_by(&self, chat_id: i64, user_id: &str, points: i32) -> Result<()> {
+        if points > 0 {
+            return self.add_spam_hit(chat_id, user_id, 1, points);
+        }
+        let conn = self.conn()?;
+        // Only increment for spam, don’t decrement
+        conn.execute(
+            "INSERT INTO senders (chat_id, user_id, spam_score, message_count, last_updated)
+                 VALUES (?1, ?2, 0, 1, ?3)
+                 ON CONFLICT(chat_id, user_id) DO UPDATE SET message_count = message_count + 1",
+            rusqlite::params![chat_id, user_id, now_unix()],
+        )?;
+        Ok(())
+    }
+
+    /// Adds spam points to a sender without counting another message, e.g. when a
+    /// message that was already counted as clean is edited into spam.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `user_id` - The unique identifier of the sender.
+    /// * `points` - The spam points to add.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn add_spam_points(&self, chat_id: i64, user_id: &str, points: i32) -> Result<()> {
+        self.add_spam_hit(chat_id, user_id, 0, points)
+    }
+
+    /// Adds `points` to a sender's decayed spam score in a chat and `messages` to
+    /// their message count there, counts one more of their messages as spam, and
+    /// records the change in `score_events`.
+    fn add_spam_hit(&self, chat_id: i64, user_id: &str, messages: i64, points: i32) -> Result<()> {
+        let mut conn = self.conn()?;
+        let now = now_unix();
+        // The read and the write must not interleave with another hit for the same sender.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let decayed =
+            decayed_sender_score(&tx, chat_id, user_id, &self.decay_rates, now).map_or(0, |(_, score)| score.round() as i32);
+        tx.execute(
+            "INSERT INTO senders (chat_id, user_id, spam_score, message_count, spam_messages, last_updated)
+                 VALUES (?1, ?2, ?3, ?5, ?5, ?4)
+                 ON CONFLICT(chat_id, user_id) DO UPDATE
+                 SET spam_score = ?3, message_count = message_count + ?5,
+                     spam_messages = MIN(spam_messages + 1, message_count + ?5), last_updated = ?4",
+            rusqlite::params![chat_id, user_id, decayed.saturating_add(points), now, messages],
+        )?;
+        let reason = if messages > 0 { "spam" } else { "edited into spam" };
+        record_score_event(&tx, chat_id, user_id, points, reason, now)?;
+        Ok(tx.commit()?)
+    }
+
+    /// Lists the changes to a sender's spam score in a chat, oldest first.
+    ///
+    /// Clean messages do not change the score and are not listed; events older than
+    /// the cleanup task's `SCORE_HISTORY_RETENTION_DAYS` are gone.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the score is kept for.
+    /// * `user_id` - The unique identifier of the sender.
+    /// * `since` - Unix timestamp; older events are left out.
+    ///
+    /// # Returns
+    /// * `Result<Vec<ScoreEvent>>` - The events, or a `RuleManagerError` if the query fails.
+    pub fn score_history(&self, chat_id: i64, user_id: &str, since: i64) -> Result<Vec<ScoreEvent>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT delta, reason, created_at FROM score_events
+                 WHERE chat_id = ?1 AND user_id = ?2 AND created_at >= ?3 ORDER BY created_at, id",
+        )?;
+        let rows = stmt.query_map(rusqlite::params![chat_id, user_id, since], |row| {
+            Ok(ScoreEvent {
+                user_id: user_id.to_string(),
+                chat_id,
+                delta: row.get(0)?,
+                reason: row.get(1)?,
+                created_at: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Deletes score events older than a cutoff.
+    ///
+    /// # Arguments
+    /// * `cutoff` - Unix timestamp; events before it are deleted.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - The number of events deleted, or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn delete_score_events_before(&self, cutoff: i64) -> Result<usize> {
+        let conn = self.conn()?;
+        Ok(conn.execute("DELETE FROM score_events WHERE created_at < ?1", [cutoff])?)
+    }
+
+    /// Retrieves the current spam score for a given sender in a chat.
+    ///
+    /// Queries the `senders` table to get the `spam_score` for the specified
+    /// `user_id` in `chat_id`. Returns 0 if no record exists for the user there.
+    ///
+    /// Old spam points expire: the score is decayed at read time from `last_updated`
+    /// using `decay_rates`, rather than by a periodic task rewriting every row, so
+    /// it is exact however rarely the score is read.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the score is kept for.
+    /// * `user_id` - The unique identifier of the sender.
+    ///
+    /// # Returns
+    /// * `i32` - The sender's current spam score, rounded, or 0 if not found.
+    pub fn get_sender_score(&self, chat_id: i64, user_id: &str) -> i32 {
+        let Some(conn) = self.conn_or_log() else {
+            return 0;
+        };
+        decayed_sender_score(&conn, chat_id, user_id, &self.decay_rates, now_unix()).map_or(0, |(_, score)| score.round() as i32)
+    }
+
+    /// Gathers everything the bot stores about a sender across its tables.
+    ///
+    /// Lookups that fail are logged and treated as empty, so an admin investigating a
+    /// report still sees whatever could be read.
+    ///
+    /// Everything, bans included, is the sender's in `chat_id`, so admins of one chat
+    /// cannot read the moderation history of chats they do not belong to.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat whose score, messages, warnings, whitelisting and bans are shown.
+    /// * `user_id` - The unique identifier of the sender.
+    ///
+    /// # Returns
+    /// * `SenderRecord` - The sender's score, messages, warnings, whitelisting and bans.
+    pub fn get_sender_record(&self, chat_id: i64, user_id: &str) -> SenderRecord {
+        let Some(conn) = self.conn_or_log() else {
+            return SenderRecord { user_id: user_id.to_string(), ..Default::default() };
+        };
+        let sender = conn
+            .query_row(
+                "SELECT message_count, warnings FROM senders WHERE chat_id = ?1 AND user_id = ?2",
+                rusqlite::params![chat_id, user_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .unwrap_or_else(|e| {
+                log::error!("Failed to load sender {}: {}", user_id, e);
+                None
+            });
+        let spam_score =
+            decayed_sender_score(&conn, chat_id, user_id, &self.decay_rates, now_unix()).map_or(0, |(_, score)| score.round() as i32);
+        let whitelisted = conn
+            .query_row("SELECT 1 FROM whitelist WHERE chat_id = ?1 AND user_id = ?2", rusqlite::params![chat_id, user_id], |_| Ok(()))
+            .is_ok();
+        let bans = conn
+            .prepare("SELECT chat_id, reason, banned_at FROM banned WHERE chat_id = ?1 AND user_id = ?2 ORDER BY banned_at DESC")
+            .and_then(|mut stmt| {
+                stmt.query_map(rusqlite::params![chat_id, user_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                    .collect::<rusqlite::Result<_>>()
+            })
+            .unwrap_or_else(|e| {
+                log::error!("Failed to load bans of {}: {}", user_id, e);
+                Vec::new()
+            });
+        let (message_count, warnings) = sender.unwrap_or((0, 0));
+        SenderRecord {
+            user_id: user_id.to_string(),
+            known: sender.is_some(),
+            spam_score,
+            message_count,
+            warnings,
+            whitelisted,
+            bans,
+        }
+    }
+
+    /// Retrieves a sender's spam score, decayed like `get_sender_score`, and message
+    /// count in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the sender's record is kept for.
+    /// * `user_id` - The unique identifier of the sender.
+    ///
+    /// # Returns
+    /// * `Option<(i32, i32)>` - `(spam_score, message_count)`, or `None` if the sender
+    ///   has no record in the chat.
+    pub fn get_sender_stats(&self, chat_id: i64, user_id: &str) -> Option<(i32, i32)> {
+        let conn = self.conn_or_log()?;
+        let message_count = conn
+            .query_row(
+                "SELECT message_count FROM senders WHERE chat_id = ?1 AND user_id = ?2",
+                rusqlite::params![chat_id, user_id],
+                |row| row.get(0),
+            )
+            .ok()?;
+        let spam_score =
+            decayed_sender_score(&conn, chat_id, user_id, &self.decay_rates, now_unix()).map_or(0, |(_, score)| score.round() as i32);
+        Some((spam_score, message_count))
+    }
+
+    /// Lists the senders with the highest spam scores in a chat, highest first.
+    ///
+    /// Scores are decayed and rounded as by `get_sender_score` before they are ranked,
+    /// so the list agrees with `/score` and `/stats` between runs of the cleanup task.
+    /// Senders without spam points left are left out.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to rank senders in.
+    /// * `limit` - The maximum number of senders to return.
+    ///
+    /// # Returns
+    /// * `Result<Vec<(String, i32)>>` - `(user_id, spam_score)` pairs, or a
+    ///   `RuleManagerError` if the query fails.
+    pub fn top_senders(&self, chat_id: i64, limit: usize) -> Result<Vec<(String, i32)>> {
+        let conn = self.conn()?;
+        let now = now_unix();
+        let mut stmt =
+            conn.prepare("SELECT user_id, spam_score, message_count, last_updated FROM senders WHERE chat_id = ?1 AND spam_score > 0")?;
+        let rows = stmt.query_map([chat_id], |row| Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+        let mut senders = Vec::new();
+        for row in rows {
+            let (user_id, spam_score, message_count, last_updated) = row?;
+            let (_, decayed) = decay_stored_score(spam_score, message_count, last_updated, &self.decay_rates, now);
+            let decayed = decayed.round() as i32;
+            if decayed > 0 {
+                senders.push((user_id, decayed));
+            }
+        }
+        senders.sort_by(|(a_user, a_score), (b_user, b_score)| b_score.cmp(a_score).then_with(|| a_user.cmp(b_user)));
+        senders.truncate(limit);
+        Ok(senders)
+    }
+
+    /// Computes the share of a sender's messages in a chat that were spam.
+    ///
+    /// Spam messages count for as much of the stored spam score as is left after decay,
+    /// as `get_sender_score` reports it, so old spam fades from the ratio just as it
+    /// fades from the score.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the sender's record is kept for.
+    /// * `user_id` - The unique identifier of the sender.
+    ///
+    /// # Returns
+    /// * `f32` - The decayed `spam_messages / message_count`, between 0.0 and 1.0, or
+    ///   0.0 if the sender has no messages or no spam score left.
+    pub fn spam_ratio(&self, chat_id: i64, user_id: &str) -> f32 {
+        let Some(conn) = self.conn_or_log() else {
+            return 0.0;
+        };
+        let Ok((spam_score, message_count, spam_messages)) = conn.query_row(
+            "SELECT spam_score, message_count, spam_messages FROM senders WHERE chat_id = ?1 AND user_id = ?2",
+            rusqlite::params![chat_id, user_id],
+            |row| Ok((row.get::<_, i32>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?)),
+        ) else {
+            return 0.0;
+        };
+        if spam_score <= 0 || message_count == 0 {
+            return 0.0;
+        }
+        let decayed =
+            decayed_sender_score(&conn, chat_id, user_id, &self.decay_rates, now_unix()).map_or(0.0, |(_, score)| score.round());
+        (spam_messages as f64 * decayed / spam_score as f64 / message_count as f64) as f32
+    }
+
+    /// Computes a sender's reputation tier and their spam score decayed at the
+    /// tier's rate since the score last changed.
+    ///
+    /// The stored score is left untouched; decay is applied at read time, so it is
+    /// exact no matter how rarely the score is read.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the sender's score is kept for.
+    /// * `user_id` - The unique identifier of the sender.
+    /// * `rates` - The per-tier half-lives.
+    /// * `now` - The current time in seconds since the Unix epoch.
+    ///
+    /// # Returns
+    /// * `(ReputationTier, f64)` - The tier and decayed score; `(Neutral, 0.0)` for unknown senders.
+    pub fn effective_sender_score(&self, chat_id: i64, user_id: &str, rates: &DecayRates, now: i64) -> (ReputationTier, f64) {
+        let Some(conn) = self.conn_or_log() else {
+            return (ReputationTier::Neutral, 0.0);
+        };
+        decayed_sender_score(&conn, chat_id, user_id, rates, now).unwrap_or((ReputationTier::Neutral, 0.0))
+    }
+
+    /// Retrieves the number of messages seen from a given sender in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the messages were posted in.
+    /// * `user_id` - The unique identifier of the sender.
+    ///
+    /// # Returns
+    /// * `i64` - The sender's message count, or 0 if not found.
+    pub fn get_message_count(&self, chat_id: i64, user_id: &str) -> i64 {
+        let Some(conn) = self.conn_or_log() else {
+            return 0;
+        };
+        conn.query_row(
+            "SELECT message_count FROM senders WHERE chat_id = ?1 AND user_id = ?2",
+            rusqlite::params![chat_id, user_id],
+            |row| row.get(0),
+        )
+        .unwrap_or(0)
+    }
+
+    /// Retrieves the number of a sender's messages in a chat that were spam, however
+    /// many points each of them added to the spam score.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the messages were posted in.
+    /// * `user_id` - The unique identifier of the sender.
+    ///
+    /// # Returns
+    /// * `i64` - The sender's spam message count, or 0 if not found.
+    pub fn get_spam_message_count(&self, chat_id: i64, user_id: &str) -> i64 {
+        let Some(conn) = self.conn_or_log() else {
+            return 0;
+        };
+        conn.query_row(
+            "SELECT spam_messages FROM senders WHERE chat_id = ?1 AND user_id = ?2",
+            rusqlite::params![chat_id, user_id],
+            |row| row.get(0),
+        )
+        .unwrap_or(0)
+    }
+
+    /// Counts the senders in a chat with a non-zero spam score below a threshold.
+    ///
+    /// Scores are decayed and rounded as by `get_sender_score` before they are
+    /// compared, so the count matches what `pardon_senders_below` would reset.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat whose senders are counted.
+    /// * `threshold` - Scores strictly below this value are counted.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - The number of matching senders, or a `RuleManagerError`
+    ///   if the query fails.
+    pub fn count_senders_below(&self, chat_id: i64, threshold: i32) -> Result<usize> {
+        let conn = self.conn()?;
+        Ok(self.senders_below(&conn, chat_id, threshold, now_unix())?.len())
+    }
+
+    /// Resets the spam score of every sender in a chat whose score is below a threshold.
+    ///
+    /// Scores are decayed and rounded as by `get_sender_score` before they are
+    /// compared, and each pardon is logged with the decayed score it cleared. The
+    /// selection and the reset run in a single transaction, so the returned count
+    /// matches exactly the rows that were reset.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat whose senders are pardoned.
+    /// * `threshold` - Scores strictly below this value are reset to 0.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - The number of senders pardoned, or a `RuleManagerError`
+    ///   if the database operation fails (in which case nothing is changed).
+    pub fn pardon_senders_below(&self, chat_id: i64, threshold: i32) -> Result<usize> {
+        let mut conn = self.conn()?;
+        let now = now_unix();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let senders = self.senders_below(&tx, chat_id, threshold, now)?;
+        for (user_id, score) in &senders {
+            record_score_event(&tx, chat_id, user_id, -score, "pardoned", now)?;
+            tx.execute(
+                "UPDATE senders SET spam_score = 0, spam_messages = 0 WHERE chat_id = ?1 AND user_id = ?2",
+                rusqlite::params![chat_id, user_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(senders.len())
+    }
+
+    /// Lists the senders in a chat whose decayed, rounded spam score is non-zero and
+    /// below a threshold.
+    ///
+    /// # Returns
+    /// * `Result<Vec<(String, i32)>>` - `(user_id, spam_score)` pairs, or a
+    ///   `RuleManagerError` if the query fails.
+    fn senders_below(&self, conn: &Connection, chat_id: i64, threshold: i32, now: i64) -> Result<Vec<(String, i32)>> {
+        let mut stmt =
+            conn.prepare("SELECT user_id, spam_score, message_count, last_updated FROM senders WHERE chat_id = ?1 AND spam_score > 0")?;
+        let rows = stmt.query_map([chat_id], |row| Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+        let mut senders = Vec::new();
+        for row in rows {
+            let (user_id, spam_score, message_count, last_updated) = row?;
+            let (_, decayed) = decay_stored_score(spam_score, message_count, last_updated, &self.decay_rates, now);
+            let decayed = decayed.round() as i32;
+            if decayed > 0 && decayed < threshold {
+                senders.push((user_id, decayed));
+            }
+        }
+        Ok(senders)
+    }
+
+    /// Resets a single sender's spam score in a chat to 0, e.g. to undo an automatic ban.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the score is kept for.
+    /// * `user_id` - The unique identifier of the sender.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the sender had a record, or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn reset_sender_score(&self, chat_id: i64, user_id: &str) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let now = now_unix();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let decayed =
+            decayed_sender_score(&tx, chat_id, user_id, &self.decay_rates, now).map_or(0, |(_, score)| score.round() as i32);
+        let updated = tx.execute(
+            "UPDATE senders SET spam_score = 0, spam_messages = 0 WHERE chat_id = ?1 AND user_id = ?2",
+            rusqlite::params![chat_id, user_id],
+        )?;
+        if decayed > 0 {
+            record_score_event(&tx, chat_id, user_id, -decayed, "reset", now)?;
+        }
+        tx.commit()?;
+        Ok(updated > 0)
+    }
+
+    /// Adds a warning to a sender in a chat, creating their record if needed.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the sender is warned in.
+    /// * `user_id` - The unique identifier of the sender.
+    ///
+    /// # Returns
+    /// * `Result<i32>` - The sender's new number of warnings, or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn add_warning(&self, chat_id: i64, user_id: &str) -> Result<i32> {
+        let conn = self.conn()?;
+        Ok(conn.query_row(
+            "INSERT INTO senders (chat_id, user_id, warnings) VALUES (?1, ?2, 1)
+             ON CONFLICT(chat_id, user_id) DO UPDATE SET warnings = warnings + 1
+             RETURNING warnings",
+            rusqlite::params![chat_id, user_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Returns a sender's number of warnings in a chat, 0 if they have no record there.
+    pub fn get_warnings(&self, chat_id: i64, user_id: &str) -> i32 {
+        let Some(conn) = self.conn_or_log() else {
+            return 0;
+        };
+        conn.query_row(
+            "SELECT warnings FROM senders WHERE chat_id = ?1 AND user_id = ?2",
+            rusqlite::params![chat_id, user_id],
+            |row| row.get(0),
+        )
+        .unwrap_or(0)
+    }
+
+    /// Clears a sender's warnings in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the sender was warned in.
+    /// * `user_id` - The unique identifier of the sender.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the sender had any warnings, or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn clear_warnings(&self, chat_id: i64, user_id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let updated = conn.execute(
+            "UPDATE senders SET warnings = 0 WHERE chat_id = ?1 AND user_id = ?2 AND warnings > 0",
+            rusqlite::params![chat_id, user_id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Returns `(chat_id, user_id, spam_score, message_count, last_updated)` for every
+    /// sender record with a non-zero score.
+    #[allow(clippy::type_complexity)]
+    fn scored_senders(&self) -> Result<Vec<(i64, String, i32, i64, i64)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT chat_id, user_id, spam_score, message_count, last_updated FROM senders WHERE spam_score > 0",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Writes the decay accumulated by every sender's score back to the database.
+    ///
+    /// Reads already decay scores, so this does not change what they return. It keeps
+    /// stored scores and tiers in step with the decayed values, so fully decayed
+    /// senders become eligible for `delete_stale_senders`. A row is only rewritten once
+    /// its rounded score drops, and `last_updated` moves to the moment the score
+    /// reached that value, so decay continues along the same curve.
+    ///
+    /// The rows are read under one lock and updated one lock at a time, so message
+    /// handling is never blocked for long. A row that changed in between is skipped.
+    ///
+    /// # Arguments
+    /// * `now` - The current time in seconds since the Unix epoch.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - The number of scores rewritten, or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn decay_sender_scores(&self, now: i64) -> Result<usize> {
+        let mut decayed_rows = 0;
+        for (chat_id, user_id, spam_score, message_count, last_updated) in self.scored_senders()? {
+            let half_life_days = self
+                .decay_rates
+                .half_life_days(ReputationTier::classify(spam_score, message_count));
+            let decayed = decayed_score(spam_score as f64, now - last_updated, half_life_days).round() as i32;
+            if decayed >= spam_score {
+                continue;
+            }
+            // A score of 0 stays 0, so its timestamp keeps marking the last spam hit.
+            let reached_at = if decayed > 0 {
+                last_updated + (half_life_days * 86_400.0 * (spam_score as f64 / decayed as f64).log2()) as i64
+            } else {
+                last_updated
+            };
+            let conn = self.conn()?;
+            decayed_rows += conn.execute(
+                "UPDATE senders SET spam_score = ?1, last_updated = ?2
+                     WHERE chat_id = ?3 AND user_id = ?4 AND spam_score = ?5 AND last_updated = ?6",
+                rusqlite::params![decayed, reached_at, chat_id, user_id, spam_score, last_updated],
+            )?;
+        }
+        Ok(decayed_rows)
+    }
+
+    /// Deletes senders with little history who have not been flagged for a long time.
+    ///
+    /// Keeps the `senders` table from growing with every one-off visitor. Senders
+    /// whose score has not decayed to 0 are kept regardless of age.
+    ///
+    /// # Arguments
+    /// * `max_messages` - Only senders with fewer messages than this are deleted.
+    /// * `cutoff` - Only senders whose `last_updated` is before this Unix timestamp are deleted.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - The number of senders deleted, or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn delete_stale_senders(&self, max_messages: i64, cutoff: i64) -> Result<usize> {
+        let conn = self.conn()?;
+        Ok(conn.execute(
+            "DELETE FROM senders WHERE spam_score = 0 AND message_count < ?1 AND last_updated < ?2",
+            rusqlite::params![max_messages, cutoff],
+        )?)
+    }
+
+    /// Writes a consistent snapshot of the whole database to a new file.
+    ///
+    /// Uses `VACUUM INTO`, so the snapshot is compacted and can be taken while the
+    /// bot keeps running.
+    ///
+    /// # Arguments
+    /// * `path` - The snapshot file to create; it must not exist yet.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the snapshot fails.
+    pub fn backup_into(&self, path: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute("VACUUM INTO ?1", [path])?;
+        Ok(())
+    }
+
+    /// Replaces the whole database with the contents of a snapshot and reloads
+    /// the in-memory rule cache. Snapshots taken by older releases are migrated
+    /// to the current schema.
+    ///
+    /// # Arguments
+    /// * `path` - The snapshot file to restore.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the restore fails.
+    pub fn restore_from(&self, path: &str) -> Result<()> {
+        let mut rules = self.rules.lock_or_recover();
+        let mut conn = self.conn()?;
+        conn.restore(rusqlite::DatabaseName::Main, path, None::<fn(rusqlite::backup::Progress)>)?;
+        migrate(&mut conn)?;
+        *rules = load_rules(&conn)?;
+        *self.bayes.lock_or_recover() = load_model(&conn)?;
+        *self.bayes_pending.lock_or_recover() = BayesModel::default();
+        self.allowed_domains.lock_or_recover().clear();
+        self.safe_patterns.lock_or_recover().clear();
+        self.blocked_media.lock_or_recover().clear();
+        Ok(())
+    }
+
+    /// Retrieves a bot-wide setting stored in the `settings` table.
+    ///
+    /// # Arguments
+    /// * `key` - The setting name.
+    ///
+    /// # Returns
+    /// * `Option<String>` - The stored value, or `None` if unset or on error.
+    pub fn get_setting(&self, key: &str) -> Option<String> {
+        let conn = self.conn_or_log()?;
+        conn.query_row("SELECT value FROM settings WHERE key = ?1", [key], |row| row.get(0))
+            .ok()
+    }
+
+    /// Stores a bot-wide setting in the `settings` table, replacing any previous value.
+    ///
+    /// # Arguments
+    /// * `key` - The setting name.
+    /// * `value` - The value to store.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET value = ?2",
+            [key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the threshold used for the `/report` verdict in a chat.
+    ///
+    /// This is independent of the detection threshold, so human reports can notify
+    /// admins at a lower bar than automatic detection.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to look up.
+    ///
+    /// # Returns
+    /// * `f32` - The chat's report threshold, or else the bot-wide `report_threshold`
+    ///   setting, or else the bot-wide detection `threshold`.
+    pub fn get_report_threshold(&self, chat_id: i64) -> f32 {
+        let global = || self.get_setting("report_threshold").and_then(|v| v.parse().ok()).unwrap_or(self.threshold);
+        let Some(conn) = self.conn_or_log() else {
+            return global();
+        };
+        conn.query_row(
+            "SELECT report_threshold FROM chat_settings WHERE chat_id = ?1 AND report_threshold IS NOT NULL",
+            [chat_id],
+            |row| row.get(0),
+        )
+        .unwrap_or_else(|_| global())
+    }
+
+    /// Sets the threshold used for the `/report` verdict in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to configure.
+    /// * `threshold` - The new report threshold.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn set_report_threshold(&self, chat_id: i64, threshold: f32) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO chat_settings (chat_id, report_threshold) VALUES (?1, ?2)
+                 ON CONFLICT(chat_id) DO UPDATE SET report_threshold = ?2",
+            rusqlite::params![chat_id, threshold],
+        )?;
+        Ok(())
+    }
+
+    /// Returns `true` if a message reported in a chat with the given score counts as spam.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was reported in.
+    /// * `score` - The score of the reported message.
+    pub fn is_reported_spam(&self, chat_id: i64, score: f32) -> bool {
+        score >= self.get_report_threshold(chat_id)
+    }
+
+    /// Appends a moderator note about a user in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the note applies to.
+    /// * `user_id` - The unique identifier of the user.
+    /// * `note` - The note text.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn add_note(&self, chat_id: i64, user_id: &str, note: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO notes (chat_id, user_id, note, created_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![chat_id, user_id, note, now_unix()],
+        )?;
+        Ok(())
+    }
+
+    /// Retrieves the moderator notes about a user in a chat, oldest first.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the notes apply to.
+    /// * `user_id` - The unique identifier of the user.
+    ///
+    /// # Returns
+    /// * `Result<Vec<String>>` - The notes, or a `RuleManagerError` if the query fails.
+    pub fn get_notes(&self, chat_id: i64, user_id: &str) -> Result<Vec<String>> {
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare("SELECT note FROM notes WHERE chat_id = ?1 AND user_id = ?2 ORDER BY id")?;
+        let rows = stmt.query_map(rusqlite::params![chat_id, user_id], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Adds a domain to a chat's trusted-domain allowlist.
+    ///
+    /// Links to allowlisted domains (and their subdomains) are ignored by every
+    /// link-based detector in that chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the allowlist belongs to.
+    /// * `domain` - The normalized (lowercase) domain.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the domain was added, `false` if it was already
+    ///   allowlisted, or a `RuleManagerError` if the database operation fails.
+    pub fn add_allowed_domain(&self, chat_id: i64, domain: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let added = conn.execute(
+            "INSERT OR IGNORE INTO allowed_domains (chat_id, domain) VALUES (?1, ?2)",
+            rusqlite::params![chat_id, domain],
+        )?;
+        self.allowed_domains.lock_or_recover().remove(&chat_id);
+        Ok(added > 0)
+    }
+
+    /// Removes a domain from a chat's trusted-domain allowlist.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the allowlist belongs to.
+    /// * `domain` - The normalized (lowercase) domain.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the domain was removed, `false` if it was not
+    ///   allowlisted, or a `RuleManagerError` if the database operation fails.
+    pub fn remove_allowed_domain(&self, chat_id: i64, domain: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let removed = conn.execute(
+            "DELETE FROM allowed_domains WHERE chat_id = ?1 AND domain = ?2",
+            rusqlite::params![chat_id, domain],
+        )?;
+        self.allowed_domains.lock_or_recover().remove(&chat_id);
+        Ok(removed > 0)
+    }
+
+    /// Adds a safe pattern to a chat.
+    ///
+    /// A message containing a safe pattern (case-insensitively) is never flagged for
+    /// its content in that chat; see `net_score`.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the pattern applies to.
+    /// * `pattern` - The text a legitimate message contains.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the pattern was added, `false` if the chat already
+    ///   had it, or a `RuleManagerError` if the database operation fails.
+    pub fn add_safe_pattern(&self, chat_id: i64, pattern: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let added = conn.execute(
+            "INSERT OR IGNORE INTO safe_patterns (chat_id, pattern) VALUES (?1, ?2)",
+            rusqlite::params![chat_id, pattern.to_lowercase()],
+        )?;
+        self.safe_patterns.lock_or_recover().remove(&chat_id);
+        Ok(added > 0)
+    }
+
+    /// Removes a safe pattern from a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the pattern applies to.
+    /// * `pattern` - The pattern to remove.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the pattern was removed, `false` if the chat did not
+    ///   have it, or a `RuleManagerError` if the database operation fails.
+    pub fn remove_safe_pattern(&self, chat_id: i64, pattern: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let removed = conn.execute(
+            "DELETE FROM safe_patterns WHERE chat_id = ?1 AND pattern = ?2",
+            rusqlite::params![chat_id, pattern.to_lowercase()],
+        )?;
+        self.safe_patterns.lock_or_recover().remove(&chat_id);
+        Ok(removed > 0)
+    }
+
+    /// Retrieves a chat's safe patterns, served from memory after the first query.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the patterns apply to.
+    ///
+    /// # Returns
+    /// * `Result<Vec<String>>` - The lowercase patterns in alphabetical order, or a
+    ///   `RuleManagerError` if the query fails.
+    pub fn get_safe_patterns(&self, chat_id: i64) -> Result<Vec<String>> {
+        if let Some(patterns) = self.safe_patterns.lock_or_recover().get(&chat_id) {
+            return Ok(patterns.clone());
+        }
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT pattern FROM safe_patterns WHERE chat_id = ?1 ORDER BY pattern")?;
+        let rows = stmt.query_map([chat_id], |row| row.get(0))?;
+        let patterns: Vec<String> = rows.collect::<rusqlite::Result<_>>()?;
+        self.safe_patterns.lock_or_recover().insert(chat_id, patterns.clone());
+        Ok(patterns)
+    }
+
+    /// Finds the first of a chat's safe patterns a message contains.
+    ///
+    /// The message and the patterns are normalized as for the stored rules, so homoglyphs
+    /// do not hide a safe pattern any more than a spam keyword, and patterns written in
+    /// Cyrillic or Greek still match.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `message` - The message text.
+    ///
+    /// # Returns
+    /// * `Result<Option<String>>` - The matching pattern, if any, or a `RuleManagerError`
+    ///   if the patterns could not be loaded.
+    pub fn matched_safe_pattern(&self, chat_id: i64, message: &str) -> Result<Option<String>> {
+        let message = Self::normalize(message).to_lowercase();
+        Ok(self
+            .get_safe_patterns(chat_id)?
+            .into_iter()
+            .find(|pattern| message.contains(&Self::normalize(pattern).to_lowercase())))
+    }
+
+    /// Blocks a sticker set or file in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the block applies to.
+    /// * `item` - A sticker set name or a file's `file_unique_id`.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the item was blocked, `false` if it already was,
+    ///   or a `RuleManagerError` if the database operation fails.
+    pub fn block_media(&self, chat_id: i64, item: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let added = conn.execute(
+            "INSERT OR IGNORE INTO blocked_media (chat_id, item) VALUES (?1, ?2)",
+            rusqlite::params![chat_id, item],
+        )?;
+        self.blocked_media.lock_or_recover().remove(&chat_id);
+        Ok(added > 0)
+    }
+
+    /// Unblocks a sticker set or file in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the block applies to.
+    /// * `item` - The sticker set name or file ID to unblock.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the item was unblocked, `false` if it was not
+    ///   blocked, or a `RuleManagerError` if the database operation fails.
+    pub fn unblock_media(&self, chat_id: i64, item: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let removed = conn.execute(
+            "DELETE FROM blocked_media WHERE chat_id = ?1 AND item = ?2",
+            rusqlite::params![chat_id, item],
+        )?;
+        self.blocked_media.lock_or_recover().remove(&chat_id);
+        Ok(removed > 0)
+    }
+
+    /// Retrieves a chat's blocked sticker sets and file IDs, served from memory after
+    /// the first query.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the blocks apply to.
+    ///
+    /// # Returns
+    /// * `Result<Vec<String>>` - The blocked items in alphabetical order, or a
+    ///   `RuleManagerError` if the query fails.
+    pub fn get_blocked_media(&self, chat_id: i64) -> Result<Vec<String>> {
+        if let Some(items) = self.blocked_media.lock_or_recover().get(&chat_id) {
+            return Ok(items.clone());
+        }
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT item FROM blocked_media WHERE chat_id = ?1 ORDER BY item")?;
+        let rows = stmt.query_map([chat_id], |row| row.get(0))?;
+        let items: Vec<String> = rows.collect::<rusqlite::Result<_>>()?;
+        self.blocked_media.lock_or_recover().insert(chat_id, items.clone());
+        Ok(items)
+    }
+
+    /// Finds the blocklist entry a sticker, GIF or file matches in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the media was posted in.
+    /// * `media` - The media of the message.
+    ///
+    /// # Returns
+    /// * `Result<Option<String>>` - The blocked sticker set or file ID, if any, or a
+    ///   `RuleManagerError` if the blocklist could not be loaded.
+    pub fn matched_blocked_media(&self, chat_id: i64, media: &MessageMedia) -> Result<Option<String>> {
+        let keys = media.block_keys();
+        Ok(self.get_blocked_media(chat_id)?.into_iter().find(|item| keys.contains(&item.as_str())))
+    }
+
+    /// Adds a sender to a chat's whitelist, exempting them from spam checks there.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the exemption applies to.
+    /// * `user_id` - The unique identifier of the sender.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the sender was added, `false` if they were already
+    ///   whitelisted, or a `RuleManagerError` if the database operation fails.
+    pub fn add_to_whitelist(&self, chat_id: i64, user_id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let added = conn.execute(
+            "INSERT OR IGNORE INTO whitelist (chat_id, user_id) VALUES (?1, ?2)",
+            rusqlite::params![chat_id, user_id],
+        )?;
+        Ok(added > 0)
+    }
+
+    /// Removes a sender from a chat's whitelist.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the exemption applies to.
+    /// * `user_id` - The unique identifier of the sender.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if the sender was removed, `false` if they were not
+    ///   whitelisted, or a `RuleManagerError` if the database operation fails.
+    pub fn remove_from_whitelist(&self, chat_id: i64, user_id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let removed = conn.execute(
+            "DELETE FROM whitelist WHERE chat_id = ?1 AND user_id = ?2",
+            rusqlite::params![chat_id, user_id],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Returns `true` if the sender is whitelisted in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message was posted in.
+    /// * `user_id` - The unique identifier of the sender.
+    pub fn is_whitelisted(&self, chat_id: i64, user_id: &str) -> bool {
+        let Some(conn) = self.conn_or_log() else {
+            return false;
+        };
+        conn.query_row("SELECT 1 FROM whitelist WHERE chat_id = ?1 AND user_id = ?2", rusqlite::params![chat_id, user_id], |_| Ok(()))
+            .is_ok()
+    }
+
+    /// Records that the bot banned a user from a chat, replacing any earlier record.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the user was banned from.
+    /// * `user_id` - The unique identifier of the banned user.
+    /// * `reason` - Why the user was banned, e.g. which action or admin caused it.
+    ///
+    /// # Returns
+    /// * `Result<()>` - Ok on success, or a `RuleManagerError` if the database operation fails.
+    pub fn record_ban(&self, chat_id: i64, user_id: &str, reason: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO banned (chat_id, user_id, reason, banned_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![chat_id, user_id, reason, now_unix()],
+        )?;
+        Ok(())
+    }
+
+    /// Looks up why a user was banned from a chat.
+    ///
+    /// # Returns
+    /// * `Option<(String, i64)>` - The reason and Unix timestamp of the ban, or `None`
+    ///   if the bot has no record of banning the user there.
+    pub fn get_ban(&self, chat_id: i64, user_id: &str) -> Option<(String, i64)> {
+        let conn = self.conn_or_log()?;
+        conn.query_row(
+            "SELECT reason, banned_at FROM banned WHERE chat_id = ?1 AND user_id = ?2",
+            rusqlite::params![chat_id, user_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .ok()
+    }
+
+    /// Removes the record of a user's ban from a chat, e.g. after they were unbanned.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` if a record was removed, `false` if there was none,
+    ///   or a `RuleManagerError` if the database operation fails.
+    pub fn remove_ban(&self, chat_id: i64, user_id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let removed = conn.execute(
+            "DELETE FROM banned WHERE chat_id = ?1 AND user_id = ?2",
+            rusqlite::params![chat_id, user_id],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Retrieves a chat's trusted-domain allowlist.
+    ///
+    /// The allowlist is served from memory; the database is only queried the first
+    /// time a chat's allowlist is needed, or after it changes.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the allowlist belongs to.
+    ///
+    /// # Returns
+    /// * `Result<Vec<String>>` - The allowlisted domains in alphabetical order, or a
+    ///   `RuleManagerError` if the query fails.
+    pub fn get_allowed_domains(&self, chat_id: i64) -> Result<Vec<String>> {
+        if let Some(domains) = self.allowed_domains.lock_or_recover().get(&chat_id) {
+            return Ok(domains.clone());
+        }
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT domain FROM allowed_domains WHERE chat_id = ?1 ORDER BY domain")?;
+        let rows = stmt.query_map([chat_id], |row| row.get(0))?;
+        let domains: Vec<String> = rows.collect::<rusqlite::Result<_>>()?;
+        self.allowed_domains.lock_or_recover().insert(chat_id, domains.clone());
+        Ok(domains)
+    }
+
+    /// Records a spam event together with each detector's contribution to its score.
+    ///
+    /// Only non-zero contributions are stored. The event and its contributions are
+    /// written in a single transaction.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the spam was posted in.
+    /// * `user_id` - The unique identifier of the sender.
+    /// * `score` - The total score of the message.
+    /// * `contributions` - Pairs of `(detector, contribution)`.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn record_spam_event(
+        &self,
+        chat_id: i64,
+        user_id: &str,
+        score: f32,
+        contributions: &[(&str, f32)],
+    ) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO spam_events (chat_id, user_id, score, created_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![chat_id, user_id, score, now_unix()],
+        )?;
+        let event_id = tx.last_insert_rowid();
+        for (detector, contribution) in contributions.iter().filter(|(_, c)| *c != 0.0) {
+            tx.execute(
+                "INSERT INTO spam_event_contributions (event_id, detector, contribution) VALUES (?1, ?2, ?3)",
+                rusqlite::params![event_id, detector, contribution],
+            )?;
+        }
+        Ok(tx.commit()?)
+    }
+
+    /// Stores a flagged message for `/recent_spam`.
+    ///
+    /// The text is cut to `FLAGGED_TEXT_LIMIT` characters, and only the chat's newest
+    /// `FLAGGED_MESSAGES_KEPT` messages are kept.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the spam was posted in.
+    /// * `user_id` - The sender, or the chat a post was made on behalf of.
+    /// * `text` - The message text.
+    /// * `score` - The score the message was flagged with.
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn record_flagged_message(&self, chat_id: i64, user_id: &str, text: &str, score: f32) -> Result<()> {
+        let text: String = text.chars().take(FLAGGED_TEXT_LIMIT).collect();
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO flagged_messages (chat_id, user_id, text, score, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![chat_id, user_id, text, score, now_unix()],
+        )?;
+        tx.execute(
+            "DELETE FROM flagged_messages WHERE chat_id = ?1 AND id NOT IN
+                 (SELECT id FROM flagged_messages WHERE chat_id = ?1 ORDER BY id DESC LIMIT ?2)",
+            rusqlite::params![chat_id, FLAGGED_MESSAGES_KEPT],
+        )?;
+        Ok(tx.commit()?)
+    }
+
+    /// Lists a chat's most recently flagged messages, newest first.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to list messages for.
+    /// * `limit` - The maximum number of messages to return.
+    ///
+    /// # Returns
+    /// * `Result<Vec<FlaggedMessage>>` - The messages, or a `RuleManagerError` if the query fails.
+    pub fn recent_flagged_messages(&self, chat_id: i64, limit: usize) -> Result<Vec<FlaggedMessage>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT user_id, text, score, created_at FROM flagged_messages
+                 WHERE chat_id = ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(rusqlite::params![chat_id, limit as i64], |row| {
+            Ok(FlaggedMessage {
+                user_id: row.get(0)?,
+                text: row.get(1)?,
+                score: row.get(2)?,
+                created_at: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Counts the spam events in a chat since a point in time.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to count events for.
+    /// * `since` - Unix timestamp; older events are ignored.
+    ///
+    /// # Returns
+    /// * `Result<i64>` - The number of events, or a `RuleManagerError` if the query fails.
+    pub fn count_spam_events(&self, chat_id: i64, since: i64) -> Result<i64> {
+        let conn = self.conn()?;
+        Ok(conn.query_row(
+            "SELECT COUNT(*) FROM spam_events WHERE chat_id = ?1 AND created_at >= ?2",
+            rusqlite::params![chat_id, since],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Aggregates detector contributions over the spam events in a chat since a point in time.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to aggregate events for.
+    /// * `since` - Unix timestamp; older events are ignored.
+    ///
+    /// # Returns
+    /// * `Result<Vec<DetectorStat>>` - One entry per detector that fired, ordered by
+    ///   total contribution (largest first), or a `RuleManagerError` if the query fails.
+    pub fn detector_stats(&self, chat_id: i64, since: i64) -> Result<Vec<DetectorStat>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT c.detector, COUNT(*), SUM(c.contribution)
+                 FROM spam_event_contributions c
+                 JOIN spam_events e ON e.id = c.event_id
+                 WHERE e.chat_id = ?1 AND e.created_at >= ?2
+                 GROUP BY c.detector
+                 ORDER BY SUM(c.contribution) DESC",
+        )?;
+        let rows = stmt.query_map(rusqlite::params![chat_id, since], |row| {
+            Ok(DetectorStat {
+                detector: row.get(0)?,
+                fired: row.get(1)?,
+                total: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Stores a labeled message for threshold calibration.
+    ///
+    /// # Arguments
+    /// * `text` - The message text.
+    /// * `is_spam` - Whether the message is known to be spam (`true`) or ham (`false`).
+    ///
+    /// # Returns
+    /// * `Result<()>` - A `Result` indicating success or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn add_labeled_message(&self, text: &str, is_spam: bool) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO labeled_messages (text, is_spam) VALUES (?1, ?2)",
+            rusqlite::params![text, is_spam],
+        )?;
+        Ok(())
+    }
+
+    /// Retrieves all labeled messages.
+    ///
+    /// # Returns
+    /// * `Result<Vec<(String, bool)>>` - Pairs of `(text, is_spam)`, or a
+    ///   `RuleManagerError` if the query fails.
+    pub fn get_labeled_messages(&self) -> Result<Vec<(String, bool)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT text, is_spam FROM labeled_messages ORDER BY id")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns the name of the active rule profile.
+    pub fn active_profile(&self) -> String {
+        self.active_profile.lock_or_recover().clone()
+    }
+
+    /// Switches the active rule profile.
+    ///
+    /// # Arguments
+    /// * `profile` - The profile name; must consist of ASCII letters, digits, `_` or `-`.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the profile name is valid and was applied.
+    pub fn set_active_profile(&self, profile: &str) -> bool {
+        if !is_valid_profile_name(profile) {
+            return false;
+        }
+        *self.active_profile.lock_or_recover() = profile.to_string();
+        true
+    }
+
+    /// Returns the Lua script path for the active rule profile.
+    ///
+    /// The default profile uses `rules_path`; a profile `name` uses the sibling
+    /// file with `_<name>` appended to its stem, e.g. `/etc/bot/rules_strict.lua`
+    /// for `/etc/bot/rules.lua`.
+    pub fn rules_script_path(&self) -> String {
+        let profile = self.active_profile();
+        if profile == DEFAULT_PROFILE {
+            return self.rules_path.clone();
+        }
+        let path = Path::new(&self.rules_path);
+        let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("rules");
+        let file_name = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => format!("{}_{}.{}", stem, profile, ext),
+            None => format!("{}_{}", stem, profile),
+        };
+        path.with_file_name(file_name).to_string_lossy().into_owned()
+    }
+
+    /// Stores a recurring profile switch.
+    ///
+    /// # Arguments
+    /// * `profile` - The profile to activate.
+    /// * `days` - Bitmask of weekdays the switch fires on (bit 0 = Sunday).
+    /// * `minute_of_day` - Minute after midnight (UTC) the switch fires at.
+    ///
+    /// # Returns
+    /// * `Result<i64>` - The id of the new schedule, or a `RuleManagerError`
+    ///   if the database operation fails.
+    pub fn add_profile_schedule(&self, profile: &str, days: u8, minute_of_day: u32) -> Result<i64> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO profile_schedules (profile, days, minute_of_day) VALUES (?1, ?2, ?3)",
+            rusqlite::params![profile, days, minute_of_day],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Retrieves all recurring profile switches.
+    ///
+    /// # Returns
+    /// * `Result<Vec<ProfileSchedule>>` - The stored schedules, or a `RuleManagerError`
+    ///   if the query fails.
+    pub fn get_profile_schedules(&self) -> Result<Vec<ProfileSchedule>> {
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare("SELECT id, profile, days, minute_of_day FROM profile_schedules ORDER BY id")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(ProfileSchedule {
+                id: row.get(0)?,
+                profile: row.get(1)?,
+                days: row.get(2)?,
+                minute_of_day: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Evaluates a message against custom rules defined in a Lua script.
+    ///
+    /// Loads the active profile's script (`rules.lua` by default) and executes the `check_spam` function
+    /// with the provided message after `normalize`. The stored rules are scored
+    /// separately by `matched_rules`.
+    ///
+    /// # Arguments
+    /// * `message` - The text message to evaluate for spam.
+    ///
+    /// Both loading the script and running `check_spam` count towards
+    /// `lua_max_instructions` and `lua_timeout`, so a script that loops forever is
+    /// aborted instead of hanging the handler.
+    ///
+    /// # Returns
+    /// * `Result<f32>` - The Lua score for the message, or a `RuleManagerError::Lua`
+    ///   if the script cannot be read, fails to run, lacks `check_spam`, or exceeds
+    ///   its limits.
+    pub fn check_custom_rules(&self, message: &str) -> Result<f32> {
+        let path = self.rules_script_path();
+        let script = std::fs::read_to_string(&path).map_err(|source| LuaRulesError::Read { path, source })?;
+        let message = Self::normalize(message);
+        let lua = Lua::new();
+        let started = Instant::now();
+        let instructions = Arc::new(AtomicU64::new(0));
+        let aborted = Arc::new(AtomicBool::new(false));
+        if self.lua_max_instructions > 0 || !self.lua_timeout.is_zero() {
+            let (max_instructions, timeout) = (self.lua_max_instructions, self.lua_timeout);
+            let (counted, abort) = (instructions.clone(), aborted.clone());
+            lua.set_hook(
+                HookTriggers {
+                    every_nth_instruction: Some(LUA_HOOK_INTERVAL),
+                    ..Default::default()
+                },
+                move |_, _| {
+                    let count = counted.fetch_add(LUA_HOOK_INTERVAL as u64, Ordering::Relaxed) + LUA_HOOK_INTERVAL as u64;
+                    let over_instructions = max_instructions > 0 && count > max_instructions;
+                    let over_time = !timeout.is_zero() && started.elapsed() > timeout;
+                    if over_instructions || over_time {
+                        abort.store(true, Ordering::Relaxed);
+                        return Err(rlua::Error::RuntimeError("Lua rules exceeded their limits".to_string()));
+                    }
+                    Ok(())
+                },
+            );
+        }
+        let score: rlua::Result<f32> = lua.context(|lua_ctx| {
+            lua_ctx.load(&script).exec()?;
+            let globals = lua_ctx.globals();
+            globals.set("message", message)?;
+            lua_ctx.load("return check_spam(message)").eval()
+        });
+        if aborted.load(Ordering::Relaxed) {
+            return Err(LuaRulesError::Timeout {
+                instructions: instructions.load(Ordering::Relaxed),
+                elapsed: started.elapsed(),
+            }
+            .into());
+        }
+        Ok(score.map_err(LuaRulesError::from)?)
+    }
+
+    /// Checks that the Lua script and the database work, so misconfigurations surface
+    /// at startup rather than when spam slips through.
+    ///
+    /// The script is loaded and run once on `SELF_CHECK_MESSAGE`, and a setting is
+    /// written and read back in a transaction that is rolled back, which fails on a
+    /// read-only database.
+    ///
+    /// # Returns
+    /// * `SelfCheck` - The outcome of each check.
+    pub fn self_check(&self) -> SelfCheck {
+        SelfCheck {
+            lua: self.check_custom_rules(SELF_CHECK_MESSAGE),
+            database: self.database_round_trip(),
+        }
+    }
+
+    /// Writes a setting and reads it back without committing, for `self_check`.
+    fn database_round_trip(&self) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let written = now_unix().to_string();
+        tx.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            rusqlite::params![SELF_CHECK_SETTING, written],
+        )?;
+        let read: String = tx.query_row("SELECT value FROM settings WHERE key = ?1", [SELF_CHECK_SETTING], |row| row.get(0))?;
+        debug_assert_eq!(read, written);
+        // Dropping the transaction rolls the write back
+        Ok(())
+    }
+
+    /// Scores a message with the Lua script and the built-in stored rules combined.
+    ///
+    /// If the Lua script fails, the error is logged and only the built-in rules are
+    /// scored, which keeps the previous behavior for callers that cannot act on a
+    /// broken script.
+    ///
+    /// # Arguments
+    /// * `message` - The text message to evaluate for spam.
+    ///
+    /// # Returns
+    /// * `f32` - The Lua score (0 on failure) plus the built-in rules' score.
+    pub fn check_custom_rules_or_fallback(&self, message: &str) -> f32 {
+        self.evaluate(message).total
+    }
+
+    /// Scores a message with the Lua script and the stored rules, keeping which
+    /// stored rules matched.
+    ///
+    /// A failing Lua script is logged and scores 0, as in `check_custom_rules_or_fallback`.
+    ///
+    /// # Arguments
+    /// * `message` - The text message to evaluate for spam.
+    ///
+    /// # Returns
+    /// * `RuleEvaluation` - The total score, the Lua score and the matching stored rules.
+    pub fn evaluate(&self, message: &str) -> RuleEvaluation {
+        let lua_score = self.check_custom_rules(message).unwrap_or_else(|e| {
+            if matches!(e, RuleManagerError::Lua(LuaRulesError::Timeout { .. })) {
+                log::warn!("Lua rules timed out, scoring built-in rules only: {}", e);
+            } else {
+                log::error!("Lua rules failed, scoring built-in rules only: {}", e);
+            }
+            0.0
+        });
+        let matches = self.matched_rules(message);
+        let total = lua_score + matches.iter().map(|(_, score)| score).sum::<f32>();
+        RuleEvaluation { total, lua_score, matches }
+    }
+
+    /// Records a Lua failure noticed in a chat and decides whether to alert its admins.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the failure was noticed in.
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the chat's admins were not alerted within `LUA_ALERT_INTERVAL`.
+    pub fn should_alert_lua_failure(&self, chat_id: i64, now: Instant) -> bool {
+        let mut alerts = self.lua_failure_alerts.lock_or_recover();
+        match alerts.get(&chat_id) {
+            Some(alerted_at) if now.duration_since(*alerted_at) < LUA_ALERT_INTERVAL => false,
+            _ => {
+                alerts.insert(chat_id, now);
+                true
+            }
+        }
+    }
+}
+
+/// Spawns a background task that writes the rule hits counted in memory to the
+/// database every `RULE_HITS_FLUSH_SECS` seconds (default `DEFAULT_RULE_HITS_FLUSH_SECS`).
+///
+/// # Arguments
+/// * `rule_manager` - The manager counting the hits.
+pub fn spawn_rule_hit_flush(rule_manager: Arc<RuleManager>) -> tokio::task::JoinHandle<()> {
+    let period = Duration::from_secs(env_parse("RULE_HITS_FLUSH_SECS", DEFAULT_RULE_HITS_FLUSH_SECS).max(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let result = rule_manager.run_blocking(|manager: &RuleManager| manager.flush_rule_hits()).await;
+            if let Err(e) = result {
+                log::error!("Failed to write rule hit counts: {}", e);
+            }
+        }
+    })
+}
+
+/// Spawns a background task that writes the Bayes classifier's training to the
+/// database every `BAYES_FLUSH_SECS` seconds (default `DEFAULT_BAYES_FLUSH_SECS`).
+///
+/// # Arguments
+/// * `rule_manager` - The manager holding the classifier.
+pub fn spawn_bayes_flush(rule_manager: Arc<RuleManager>) -> tokio::task::JoinHandle<()> {
+    let period = Duration::from_secs(env_parse("BAYES_FLUSH_SECS", DEFAULT_BAYES_FLUSH_SECS).max(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let result = rule_manager.run_blocking(|manager: &RuleManager| manager.flush_bayes()).await;
+            if let Err(e) = result {
+                log::error!("Failed to write Bayes classifier counts: {}", e);
+            }
+        }
+    })
+}
 
 /// Unit tests for the `rules` module.
 ///
@@ -206,64 +3153,1099 @@ mod tests {
     use tempfile::NamedTempFile;
     use RuleManager;
 
-    /// Sets up a temporary database and RuleManager for testing.
-    fn setup_test_manager() -> (NamedTempFile, RuleManager) {
-        let temp_file = NamedTempFile::new().unwrap();
-        let manager = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
-        (temp_file, manager)
+    /// The chat sender records are kept for in tests.
+    const CHAT: i64 = -100;
+
+    /// Sets up a temporary database and RuleManager for testing.
+    fn setup_test_manager() -> (NamedTempFile, RuleManager) {
+        let temp_file = NamedTempFile::new().unwrap();
+        let manager = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        (temp_file, manager)
+    }
+
+    /// Sums the scores of the stored rules matching a message, as `check_message` does.
+    fn builtin_score(manager: &RuleManager, message: &str) -> f32 {
+        manager.matched_rules(message).iter().map(|(_, score)| score).sum()
+    }
+
+    #[tokio::test]
+    async fn test_async_variants_match_the_blocking_methods() {
+        let (_temp_file, manager) = setup_test_manager();
+        let manager = Arc::new(manager);
+        assert!(manager.add_rule_async("casino".to_string(), 4.0).await.unwrap());
+        assert!(manager.rules.lock_or_recover().iter().any(|rule| rule.keyword == "casino"));
+        manager.increment_sender_score_async(CHAT, "user1", true).await.unwrap();
+        manager.increment_sender_score_async(CHAT, "user1", false).await.unwrap();
+        assert_eq!(manager.get_sender_score_async(CHAT, "user1").await, manager.get_sender_score(CHAT, "user1"));
+        assert_eq!(manager.get_sender_score_async(CHAT, "user1").await, 1);
+    }
+
+    #[test]
+    fn test_new_initializes_database() {
+        let (_temp_file, manager) = setup_test_manager();
+        // Verify tables are created by attempting to insert and query
+        let conn = manager.conn().unwrap();
+        conn.execute(
+            "INSERT INTO rules (keyword, score) VALUES (?1, ?2)",
+            ["test", "5.0"],
+        )
+        .unwrap();
+        let count: i32 = conn
+            .query_row("SELECT COUNT(*) FROM rules", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_new_upgrades_v1_database() {
+        let temp_file = NamedTempFile::new().unwrap();
+        {
+            let conn = Connection::open(temp_file.path()).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE rules (id INTEGER PRIMARY KEY, keyword TEXT NOT NULL, score REAL NOT NULL);
+                 CREATE TABLE senders (user_id TEXT PRIMARY KEY, spam_score INTEGER DEFAULT 0, message_count INTEGER DEFAULT 0);
+                 CREATE TABLE schema_version (version INTEGER NOT NULL);
+                 INSERT INTO schema_version (version) VALUES (1);
+                 INSERT INTO rules (keyword, score) VALUES ('spam', 4.0);
+                 INSERT INTO senders (user_id, spam_score, message_count) VALUES ('user1', 0, 10);",
+            )
+            .unwrap();
+        }
+        let manager = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(crate::migrations::schema_version(&manager.conn().unwrap()).unwrap(), crate::migrations::LATEST_VERSION);
+        assert_eq!(builtin_score(&manager, "spam"), 4.0);
+        assert_eq!(manager.get_sender_stats(crate::migrations::LEGACY_SENDERS_CHAT_ID, "user1"), Some((0, 10)));
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), None);
+        manager.add_to_whitelist(CHAT, "user1").unwrap();
+        assert!(manager.is_whitelisted(CHAT, "user1"));
+    }
+
+    #[test]
+    fn test_add_rule_succeeds() {
+        let (_temp_file, manager) = setup_test_manager();
+        let result = manager.add_rule("spam".to_string(), 10.0);
+        assert!(result.is_ok());
+        let rules = manager.rules.lock_or_recover();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].keyword, "spam");
+        assert_eq!(rules[0].score, 10.0);
+    }
+
+    #[test]
+    fn test_builtin_rules_match_substrings_ignoring_case() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.add_rule("crypto".to_string(), 4.0).unwrap();
+        manager.add_rule("Free Money".to_string(), 6.0).unwrap();
+        assert_eq!(builtin_score(&manager, "Best CRYPTOcurrency deals"), 4.0);
+        assert_eq!(builtin_score(&manager, "get free money and crypto"), 10.0);
+        assert_eq!(builtin_score(&manager, "FREE MONEY"), 6.0);
+        assert_eq!(builtin_score(&manager, "free of charge"), 0.0);
+    }
+
+    #[test]
+    fn test_whole_word_rules_do_not_match_inside_words() {
+        let (temp_file, manager) = setup_test_manager();
+        manager.add_rule("cialis".to_string(), 5.0).unwrap();
+        manager.add_rule("crypto".to_string(), 2.0).unwrap();
+        // Substring matching is the default, which flags the specialist
+        assert_eq!(builtin_score(&manager, "Ask a specialist"), 5.0);
+
+        assert!(manager.set_match_mode("cialis", MatchMode::WholeWord).unwrap());
+        assert!(manager.set_match_mode("crypto", MatchMode::Prefix).unwrap());
+        assert!(!manager.set_match_mode("missing", MatchMode::Prefix).unwrap());
+        assert_eq!(builtin_score(&manager, "Ask a specialist"), 0.0);
+        assert_eq!(builtin_score(&manager, "Cheap CIALIS!"), 5.0);
+        assert_eq!(builtin_score(&manager, "cialis_online"), 0.0);
+        assert_eq!(builtin_score(&manager, "Cryptocurrency giveaway"), 2.0);
+        assert_eq!(builtin_score(&manager, "anticrypto"), 0.0);
+
+        // Updating the score keeps the mode, and it survives a reload
+        manager.add_rule("cialis".to_string(), 6.0).unwrap();
+        let reloaded = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(builtin_score(&reloaded, "Ask a specialist"), 0.0);
+        assert_eq!(builtin_score(&reloaded, "specialist cialis"), 6.0);
+    }
+
+    #[test]
+    fn test_fuzzy_match_sees_through_spacing_and_leetspeak() {
+        assert!(RuleManager::fuzzy_match("Buy v i a g r a now", "viagra"));
+        assert!(RuleManager::fuzzy_match("V.I.A.G.R.A!!!", "viagra"));
+        assert!(RuleManager::fuzzy_match("cl1ck here", "click here"));
+        assert!(RuleManager::fuzzy_match("fr33 m0n3y", "free money"));
+        assert!(RuleManager::fuzzy_match("viiiaaagra", "viagra"));
+        assert!(RuleManager::fuzzy_match("cr-y-pt0", "crypto"));
+        assert!(!RuleManager::fuzzy_match("via the grand hall", "viagra"));
+        assert!(!RuleManager::fuzzy_match("anything", "!!!"));
+
+        let (_temp_file, mut manager) = setup_test_manager();
+        manager.add_rule("viagra".to_string(), 5.0).unwrap();
+        assert_eq!(builtin_score(&manager, "v i a g r a"), 0.0);
+        assert!(manager.set_match_mode("viagra", MatchMode::Fuzzy).unwrap());
+        assert_eq!(builtin_score(&manager, "v i a g r a"), 5.0);
+        assert_eq!(builtin_score(&manager, "Vi@gr@"), 5.0);
+
+        manager.weight_by_occurrences = true;
+        manager.occurrence_cap = 0;
+        assert_eq!(builtin_score(&manager, "v1agra... V I A G R A"), 10.0);
+    }
+
+    #[test]
+    fn test_occurrence_weighting_multiplies_scores_up_to_the_cap() {
+        let (_temp_file, mut manager) = setup_test_manager();
+        manager.add_rule("buy".to_string(), 1.0).unwrap();
+        manager.add_rule("crypto".to_string(), 2.0).unwrap();
+        let stuffed = "buy buy buy crypto CRYPTO";
+        assert_eq!(builtin_score(&manager, stuffed), 3.0);
+
+        manager.weight_by_occurrences = true;
+        manager.occurrence_cap = 0;
+        assert_eq!(builtin_score(&manager, stuffed), 7.0);
+        assert_eq!(builtin_score(&manager, "buy crypto"), 3.0);
+        manager.occurrence_cap = 2;
+        assert_eq!(builtin_score(&manager, stuffed), 6.0);
+    }
+
+    #[test]
+    fn test_confusable_characters_still_match_keyword_rules() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.add_rule("paypal".to_string(), 5.0).unwrap();
+        // "раypal" and "рауpаl" with Cyrillic а, р and у, and fullwidth "ｐａｙｐａｌ"
+        assert_eq!(RuleManager::normalize("Verify your \u{440}\u{430}ypal"), "Verify your paypal");
+        assert_eq!(builtin_score(&manager, "Verify your \u{440}\u{430}\u{443}p\u{430}l account"), 5.0);
+        assert_eq!(builtin_score(&manager, "\u{ff50}\u{ff41}\u{ff59}\u{ff50}\u{ff41}\u{ff4c} login"), 5.0);
+        assert_eq!(builtin_score(&manager, "pay pal"), 0.0);
+    }
+
+    #[test]
+    fn test_cyrillic_keywords_and_safe_patterns_still_match() {
+        let manager = RuleManager::new(IN_MEMORY_PATH).unwrap();
+        manager.add_rule("спам".to_string(), 5.0).unwrap();
+        manager.add_rule("скидка".to_string(), 2.0).unwrap();
+        manager.set_match_mode("скидка", MatchMode::Fuzzy).unwrap();
+        // "спам" normalizes to "cпaм", which the keyword must be normalized to as well
+        assert_eq!(manager.matched_rules("Это СПАМ"), vec![("спам".to_string(), 5.0)]);
+        assert_eq!(manager.matched_rules("с к и д к а"), vec![("скидка".to_string(), 2.0)]);
+        assert!(manager.add_safe_pattern(-100, "не спам").unwrap());
+        assert_eq!(manager.matched_safe_pattern(-100, "Это не спам").unwrap(), Some("не спам".to_string()));
+    }
+
+    #[test]
+    fn test_suggest_keywords_skips_short_and_known_words() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.add_rule("crypto".to_string(), 4.0).unwrap();
+        let suggestions = manager.suggest_keywords("Get FREE crypto at airdrop-giveaway now, free!", 3);
+        assert_eq!(suggestions, vec!["giveaway", "airdrop", "free"]);
+        assert_eq!(manager.suggest_keywords("Get FREE crypto", 3), vec!["free"]);
+        assert!(manager.suggest_keywords("hi all", 3).is_empty());
+    }
+
+    #[test]
+    fn test_regex_rules_match_obfuscated_text() {
+        let (temp_file, manager) = setup_test_manager();
+        manager
+            .add_regex_rule(Regex::new(r"(?i)f\s*r\s*[e€3]\s*[e€3]").unwrap(), 6.0)
+            .unwrap();
+        manager.add_rule("promo".to_string(), 2.0).unwrap();
+        assert_eq!(builtin_score(&manager, "Get it f r € e today"), 6.0);
+        assert_eq!(builtin_score(&manager, "FREE PROMO"), 8.0);
+        assert_eq!(builtin_score(&manager, "hello"), 0.0);
+
+        // Rules are reloaded, and regexes recompiled, from the database.
+        let reloaded = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(builtin_score(&reloaded, "fr3e"), 6.0);
+    }
+
+    #[test]
+    fn test_disabled_category_is_not_scored() {
+        let (temp_file, manager) = setup_test_manager();
+        manager.add_rule_in_category("airdrop".to_string(), 5.0, "crypto").unwrap();
+        manager.add_rule_in_category("wallet".to_string(), 3.0, "crypto").unwrap();
+        manager.add_rule("promo".to_string(), 2.0).unwrap();
+        assert_eq!(builtin_score(&manager, "airdrop promo"), 7.0);
+
+        assert_eq!(manager.set_category_enabled("crypto", false).unwrap(), 2);
+        assert_eq!(builtin_score(&manager, "airdrop wallet promo"), 2.0);
+        // New rules join a disabled category disabled.
+        manager.add_rule_in_category("token".to_string(), 4.0, "crypto").unwrap();
+        assert_eq!(builtin_score(&manager, "token"), 0.0);
+
+        // The toggle survives a restart.
+        let reloaded = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(builtin_score(&reloaded, "airdrop promo"), 2.0);
+        reloaded.set_category_enabled("crypto", true).unwrap();
+        assert_eq!(builtin_score(&reloaded, "airdrop token promo"), 11.0);
+    }
+
+    #[test]
+    fn test_add_rule_updates_existing_keyword() {
+        let (temp_file, manager) = setup_test_manager();
+        assert!(manager.add_rule("spam".to_string(), 10.0).unwrap());
+        assert!(!manager.add_rule("spam".to_string(), 4.0).unwrap());
+        assert_eq!(builtin_score(&manager, "spam"), 4.0);
+        assert_eq!(manager.rules.lock_or_recover().len(), 1);
+
+        let reloaded = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(builtin_score(&reloaded, "spam"), 4.0);
+    }
+
+    #[test]
+    fn test_add_rules_stores_the_batch_atomically() {
+        let (temp_file, manager) = setup_test_manager();
+        manager.add_rule("spam".to_string(), 10.0).unwrap();
+        assert_eq!(manager.add_rules(vec![("spam".to_string(), 4.0), ("casino".to_string(), 3.0)]).unwrap(), 2);
+        assert_eq!(builtin_score(&manager, "spam casino"), 7.0);
+
+        // A failing row rolls back the rows stored before it
+        manager
+            .conn()
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER reject_bad BEFORE INSERT ON rules WHEN NEW.keyword = 'bad'
+                 BEGIN SELECT RAISE(ABORT, 'rejected'); END;",
+            )
+            .unwrap();
+        assert!(manager.add_rules(vec![("airdrop".to_string(), 5.0), ("bad".to_string(), 1.0)]).is_err());
+        assert_eq!(builtin_score(&manager, "airdrop"), 0.0);
+        let reloaded = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(builtin_score(&reloaded, "airdrop spam casino"), 7.0);
     }
 
     #[test]
-    fn test_new_initializes_database() {
+    fn test_rule_hits_are_batched_and_listed_by_count() {
         let (temp_file, manager) = setup_test_manager();
-        // Verify tables are created by attempting to insert and query
-        let conn = manager.conn.lock().unwrap();
-        conn.execute(
-            "INSERT INTO rules (keyword, score) VALUES (?1, ?2)",
-            &["test", &"5.0"],
-        )
-        .unwrap();
-        let count: i32 = conn
-            .query_row("SELECT COUNT(*) FROM rules", [], |row| row.get(0))
+        manager.add_rule("crypto".to_string(), 5.0).unwrap();
+        manager.add_rule("casino".to_string(), 3.0).unwrap();
+        manager.add_rule("airdrop".to_string(), 4.0).unwrap();
+        for text in ["crypto casino", "crypto", "cheap crypto"] {
+            manager.record_rule_hits(&manager.matched_rules(text));
+        }
+        // Nothing is written until the hits are flushed
+        let stored: i64 = manager
+            .conn().unwrap()
+            .query_row("SELECT hit_count FROM rules WHERE keyword = 'crypto'", [], |row| row.get(0))
             .unwrap();
-        assert_eq!(count, 1);
+        assert_eq!(stored, 0);
+
+        let stats = manager.rule_stats().unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ("crypto".to_string(), 5.0, 3),
+                ("casino".to_string(), 3.0, 1),
+                ("airdrop".to_string(), 4.0, 0),
+            ]
+        );
+        assert_eq!(manager.flush_rule_hits().unwrap(), 0);
+        let reloaded = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(reloaded.rule_stats().unwrap()[0], ("crypto".to_string(), 5.0, 3));
     }
 
     #[test]
-    fn test_add_rule_succeeds() {
+    fn test_bayes_training_scores_at_once_and_persists_on_flush() {
         let (temp_file, manager) = setup_test_manager();
-        let result = manager.add_rule("spam".to_string(), 10.0);
-        assert!(result.is_ok());
-        let rules = manager.rules.lock().unwrap();
+        assert_eq!(manager.bayes_score("free crypto airdrop"), 0.5);
+        for i in 0..crate::bayes::MIN_TRAINING_MESSAGES {
+            manager.train_bayes(&format!("free crypto airdrop {}", i), true);
+            manager.train_bayes(&format!("see you at lunch {}", i), false);
+        }
+        assert!(manager.bayes_score("claim the airdrop") > 0.9);
+        assert!(manager.bayes_score("lunch later?") < 0.1);
+
+        // Training is only written to the database by a flush
+        let path = temp_file.path().to_str().unwrap();
+        assert_eq!(RuleManager::new(path).unwrap().bayes_score("claim the airdrop"), 0.5);
+        assert_eq!(manager.flush_bayes().unwrap(), 20);
+        assert_eq!(manager.flush_bayes().unwrap(), 0);
+        assert!(RuleManager::new(path).unwrap().bayes_score("claim the airdrop") > 0.9);
+    }
+
+    #[test]
+    fn test_in_memory_database_is_shared_by_the_pool_and_private_to_the_manager() {
+        let manager = RuleManager::new(IN_MEMORY_PATH).unwrap();
+        manager.add_rule("spam".to_string(), 4.0).unwrap();
+        // Two connections checked out at once see the same database
+        let (first, second) = (manager.conn().unwrap(), manager.conn().unwrap());
+        first.execute("INSERT INTO whitelist (chat_id, user_id) VALUES (-100, '7')", []).unwrap();
+        let count: i64 = second.query_row("SELECT COUNT(*) FROM whitelist", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+        drop((first, second));
+        assert_eq!(manager.rule_stats().unwrap().len(), 1);
+
+        let other = RuleManager::new(IN_MEMORY_PATH).unwrap();
+        assert!(other.rule_stats().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_a_panic_holding_the_rules_lock_does_not_break_later_operations() {
+        let manager = Arc::new(RuleManager::new(IN_MEMORY_PATH).unwrap());
+        manager.add_rule("crypto".to_string(), 5.0).unwrap();
+        let shared = manager.clone();
+        let panicked = std::thread::spawn(move || {
+            let _rules = shared.rules.lock_or_recover();
+            panic!("rule update failed halfway");
+        })
+        .join();
+        assert!(panicked.is_err());
+
+        manager.add_rule("casino".to_string(), 3.0).unwrap();
+        assert_eq!(builtin_score(&manager, "crypto casino"), 8.0);
+        assert_eq!(manager.rule_count(), 2);
+        assert_eq!(manager.rule_stats().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_blocked_media_matches_sticker_sets_and_files_per_chat() {
+        let (_temp_file, manager) = setup_test_manager();
+        let sticker = MessageMedia {
+            kind: crate::media::MediaKind::Sticker,
+            file_unique_id: "AgADBQADwDZPEw".to_string(),
+            set_name: Some("NsfwPack".to_string()),
+        };
+        assert_eq!(manager.matched_blocked_media(-100, &sticker).unwrap(), None);
+        assert!(manager.block_media(-100, "NsfwPack").unwrap());
+        assert!(!manager.block_media(-100, "NsfwPack").unwrap());
+        assert_eq!(manager.matched_blocked_media(-100, &sticker).unwrap().as_deref(), Some("NsfwPack"));
+        assert_eq!(manager.matched_blocked_media(-200, &sticker).unwrap(), None);
+        assert!(manager.block_media(-100, "AgADBQADwDZPEw").unwrap());
+        assert!(manager.unblock_media(-100, "NsfwPack").unwrap());
+        assert!(!manager.unblock_media(-100, "NsfwPack").unwrap());
+        assert_eq!(manager.get_blocked_media(-100).unwrap(), ["AgADBQADwDZPEw"]);
+        assert_eq!(manager.matched_blocked_media(-100, &sticker).unwrap().as_deref(), Some("AgADBQADwDZPEw"));
+    }
+
+    #[test]
+    fn test_negative_rules_and_safe_patterns_net_out_the_score() {
+        let manager = RuleManager::new(IN_MEMORY_PATH).unwrap();
+        manager.add_rule("crypto".to_string(), 5.0).unwrap();
+        manager.add_rule("airdrop".to_string(), 4.0).unwrap();
+        manager.add_rule("meetup".to_string(), -3.0).unwrap();
+
+        let text = "Crypto airdrop talk at the meetup";
+        let score: f32 = manager.matched_rules(text).iter().map(|(_, score)| score).sum();
+        assert_eq!(score, 6.0);
+        assert_eq!(net_score(score, false), 6.0);
+        assert_eq!(net_score(-2.0, false), -2.0);
+
+        assert_eq!(manager.matched_safe_pattern(-100, text).unwrap(), None);
+        assert!(manager.add_safe_pattern(-100, "Crypto Talk").unwrap());
+        assert!(!manager.add_safe_pattern(-100, "crypto talk").unwrap());
+        assert!(manager.add_safe_pattern(-100, "airdrop talk").unwrap());
+        let safe = manager.matched_safe_pattern(-100, text).unwrap();
+        assert_eq!(safe.as_deref(), Some("airdrop talk"));
+        assert_eq!(net_score(score, safe.is_some()), 0.0);
+        assert_eq!(net_score(-2.0, true), -2.0);
+        // Safe patterns only apply to the chat they were added to
+        assert_eq!(manager.matched_safe_pattern(-200, text).unwrap(), None);
+
+        assert!(manager.remove_safe_pattern(-100, "AIRDROP TALK").unwrap());
+        assert_eq!(manager.get_safe_patterns(-100).unwrap(), ["crypto talk"]);
+        assert_eq!(manager.matched_safe_pattern(-100, text).unwrap(), None);
+    }
+
+    #[test]
+    fn test_flagged_messages_are_listed_newest_first_and_capped() {
+        let manager = RuleManager::new(IN_MEMORY_PATH).unwrap();
+        for i in 0..FLAGGED_MESSAGES_KEPT + 5 {
+            manager.record_flagged_message(-100, "7", &format!("spam {}", i), 6.0).unwrap();
+        }
+        manager.record_flagged_message(-200, "8", &"x".repeat(FLAGGED_TEXT_LIMIT + 10), 9.5).unwrap();
+
+        let recent = manager.recent_flagged_messages(-100, 2).unwrap();
+        let texts: Vec<&str> = recent.iter().map(|message| message.text.as_str()).collect();
+        assert_eq!(texts, ["spam 104", "spam 103"]);
+        assert_eq!((recent[0].user_id.as_str(), recent[0].score), ("7", 6.0));
+        assert_eq!(manager.recent_flagged_messages(-100, 1000).unwrap().len(), FLAGGED_MESSAGES_KEPT as usize);
+        let other = manager.recent_flagged_messages(-200, 10).unwrap();
+        assert_eq!(other[0].text.chars().count(), FLAGGED_TEXT_LIMIT);
+    }
+
+    #[test]
+    fn test_failures_are_reported_as_rule_manager_errors() {
+        let manager = RuleManager::new(IN_MEMORY_PATH).unwrap();
+        manager.conn().unwrap().execute("DROP TABLE notes", []).unwrap();
+        let error = manager.get_notes(-100, "7").unwrap_err();
+        assert!(matches!(error, RuleManagerError::Database(_)));
+        assert!(error.to_string().starts_with("database error: no such table: notes"));
+    }
+
+    #[test]
+    fn test_migration_removes_duplicate_keywords() {
+        let temp_file = NamedTempFile::new().unwrap();
+        {
+            let conn = Connection::open(temp_file.path()).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE rules (id INTEGER PRIMARY KEY, keyword TEXT NOT NULL, score REAL NOT NULL);
+                 INSERT INTO rules (keyword, score) VALUES ('spam', 10.0), ('spam', 3.0), ('http', 5.0);",
+            )
+            .unwrap();
+        }
+        let manager = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(manager.rules.lock_or_recover().len(), 2);
+        assert_eq!(builtin_score(&manager, "spam"), 3.0);
+        assert!(!manager.add_rule("spam".to_string(), 1.0).unwrap());
+    }
+
+    #[test]
+    fn test_remove_rule() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.add_rule("spam".to_string(), 10.0).unwrap();
+        manager.add_rule("http".to_string(), 5.0).unwrap();
+        assert_eq!(manager.remove_rule("spam").unwrap(), 1);
+        assert_eq!(manager.remove_rule("missing").unwrap(), 0);
+        let rules = manager.rules.lock_or_recover();
         assert_eq!(rules.len(), 1);
-        assert_eq!(rules[0].keyword, "spam");
-        assert_eq!(rules[0].score, 10.0);
+        assert_eq!(rules[0].keyword, "http");
     }
 
     #[test]
     fn test_increment_sender_score() {
-        let (temp_file, manager) = setup_test_manager();
-        let result = manager.increment_sender_score("user1", true);
+        let (_temp_file, manager) = setup_test_manager();
+        let result = manager.increment_sender_score(CHAT, "user1", true);
         assert!(result.is_ok());
-        let score = manager.get_sender_score("user1");
+        let score = manager.get_sender_score(CHAT, "user1");
         assert_eq!(score, 1);
-        let result = manager.increment_sender_score("user1", false);
+        let result = manager.increment_sender_score(CHAT, "user1", false);
         assert!(result.is_ok());
-        let score = manager.get_sender_score("user1");
+        let score = manager.get_sender_score(CHAT, "user1");
         assert_eq!(score, 1); // No decrement
     }
 
+    #[test]
+    fn test_score_history_records_every_score_change() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.increment_sender_score(CHAT, "7", false).unwrap();
+        manager.increment_sender_score_by(CHAT, "7", 3).unwrap();
+        manager.add_spam_points(CHAT, "7", 1).unwrap();
+        manager.increment_sender_score(-200, "7", true).unwrap();
+        assert!(manager.reset_sender_score(CHAT, "7").unwrap());
+        let history: Vec<(i32, String)> = manager
+            .score_history(CHAT, "7", 0)
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.delta, event.reason))
+            .collect();
+        assert_eq!(
+            history,
+            [(3, "spam".to_string()), (1, "edited into spam".to_string()), (-4, "reset".to_string())]
+        );
+        assert!(manager.score_history(CHAT, "7", now_unix() + 1).unwrap().is_empty());
+        assert_eq!(manager.pardon_senders_below(-200, 5).unwrap(), 1);
+        assert_eq!(manager.score_history(-200, "7", 0).unwrap().last().unwrap().delta, -1);
+        assert_eq!(manager.delete_score_events_before(now_unix() + 1).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_sender_records_are_kept_per_chat() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.increment_sender_score_by(CHAT, "user1", 5).unwrap();
+        manager.add_warning(CHAT, "user1").unwrap();
+        manager.increment_sender_score(-200, "user1", false).unwrap();
+        assert_eq!(manager.get_sender_score(CHAT, "user1"), 5);
+        assert_eq!(manager.get_sender_score(-200, "user1"), 0);
+        assert_eq!(manager.get_warnings(-200, "user1"), 0);
+        assert_eq!(manager.get_message_count(-200, "user1"), 1);
+        assert_eq!(manager.top_senders(-200, 10).unwrap(), []);
+        assert_eq!(manager.pardon_senders_below(-200, 10).unwrap(), 0);
+        assert!(manager.reset_sender_score(CHAT, "user1").unwrap());
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), Some((0, 1)));
+    }
+
+    #[test]
+    fn test_get_sender_stats() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), None);
+        manager.increment_sender_score(CHAT, "user1", true).unwrap();
+        manager.increment_sender_score(CHAT, "user1", false).unwrap();
+        manager.increment_sender_score(CHAT, "user1", false).unwrap();
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), Some((1, 3)));
+    }
+
+    #[test]
+    fn test_spam_ratio() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert_eq!(manager.spam_ratio(CHAT, "user1"), 0.0);
+        manager.increment_sender_score(CHAT, "user1", true).unwrap();
+        for _ in 0..3 {
+            manager.increment_sender_score(CHAT, "user1", false).unwrap();
+        }
+        assert_eq!(manager.spam_ratio(CHAT, "user1"), 0.25);
+    }
+
+    #[test]
+    fn test_weighted_hits_count_as_one_spam_message_each() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.increment_sender_score_by(CHAT, "user1", 5).unwrap();
+        manager.increment_sender_score_by(CHAT, "user1", 3).unwrap();
+        manager.increment_sender_score_by(CHAT, "user1", 0).unwrap();
+        manager.increment_sender_score_by(CHAT, "user1", 0).unwrap();
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), Some((8, 4)));
+        assert_eq!(manager.get_spam_message_count(CHAT, "user1"), 2);
+        assert_eq!(manager.spam_ratio(CHAT, "user1"), 0.5);
+        // A clean message edited into spam is already counted, so the ratio cannot pass 1.0
+        manager.add_spam_points(CHAT, "user1", 4).unwrap();
+        manager.add_spam_points(CHAT, "user1", 4).unwrap();
+        manager.add_spam_points(CHAT, "user1", 4).unwrap();
+        assert_eq!(manager.get_spam_message_count(CHAT, "user1"), 4);
+        assert_eq!(manager.spam_ratio(CHAT, "user1"), 1.0);
+        manager.reset_sender_score(CHAT, "user1").unwrap();
+        assert_eq!(manager.spam_ratio(CHAT, "user1"), 0.0);
+    }
+
+    #[test]
+    fn test_sender_stats_and_spam_ratio_use_the_decayed_score() {
+        let (_temp_file, manager) = setup_test_manager();
+        {
+            let conn = manager.conn().unwrap();
+            conn.execute(
+                "INSERT INTO senders (chat_id, user_id, spam_score, message_count, spam_messages, last_updated)
+                     VALUES (-100, 'halved', 8, 10, 4, ?1)",
+                [now_unix() - 30 * 86_400],
+            )
+            .unwrap();
+        }
+        assert_eq!(manager.get_sender_score(CHAT, "halved"), 4);
+        assert_eq!(manager.get_sender_stats(CHAT, "halved"), Some((4, 10)));
+        assert_eq!(manager.spam_ratio(CHAT, "halved"), 0.2);
+    }
+
+    #[test]
+    fn test_trusted_sender_score_decays_faster_than_spammer() {
+        let (_temp_file, manager) = setup_test_manager();
+        {
+            let conn = manager.conn().unwrap();
+            conn.execute(
+                "INSERT INTO senders (chat_id, user_id, spam_score, message_count, last_updated)
+                     VALUES (-100, 'trusted', 1, 500, 0), (-100, 'spammer', 5, 20, 0)",
+                [],
+            )
+            .unwrap();
+        }
+        let rates = DecayRates::default();
+        let week = 7 * 86_400;
+        let (tier, trusted) = manager.effective_sender_score(CHAT, "trusted", &rates, week);
+        assert_eq!(tier, ReputationTier::Trusted);
+        let (tier, spammer) = manager.effective_sender_score(CHAT, "spammer", &rates, week);
+        assert_eq!(tier, ReputationTier::Suspicious);
+        // Compare the share of the original score that remains.
+        assert!(trusted / 1.0 < spammer / 5.0);
+    }
+
+    #[test]
+    fn test_old_sender_scores_decay_and_stay_expired() {
+        let (_temp_file, manager) = setup_test_manager();
+        let long_ago = now_unix() - 180 * 86_400;
+        {
+            let conn = manager.conn().unwrap();
+            conn.execute(
+                "INSERT INTO senders (chat_id, user_id, spam_score, message_count, last_updated)
+                     VALUES (-100, 'old', 6, 10, ?1), (-100, 'recent', 6, 10, ?2)",
+                [long_ago, now_unix()],
+            )
+            .unwrap();
+        }
+        assert!(manager.get_sender_score(CHAT, "old") < 6);
+        assert_eq!(manager.get_sender_score(CHAT, "recent"), 6);
+        // A new hit counts from the decayed score, not the stale stored one.
+        manager.increment_sender_score(CHAT, "old", true).unwrap();
+        assert_eq!(manager.get_sender_score(CHAT, "old"), 1);
+    }
+
+    #[test]
+    fn test_decay_sender_scores_keeps_read_scores_unchanged() {
+        let (_temp_file, manager) = setup_test_manager();
+        let now = now_unix();
+        {
+            let conn = manager.conn().unwrap();
+            conn.execute(
+                "INSERT INTO senders (chat_id, user_id, spam_score, message_count, last_updated)
+                     VALUES (-100, 'gone', 2, 3, ?1), (-100, 'halved', 8, 30, ?2), (-100, 'fresh', 4, 30, ?3)",
+                [now - 365 * 86_400, now - 30 * 86_400, now],
+            )
+            .unwrap();
+        }
+        let before: Vec<i32> = ["gone", "halved", "fresh"].iter().map(|u| manager.get_sender_score(CHAT, u)).collect();
+        assert_eq!(manager.decay_sender_scores(now).unwrap(), 2);
+        let after: Vec<i32> = ["gone", "halved", "fresh"].iter().map(|u| manager.get_sender_score(CHAT, u)).collect();
+        assert_eq!(before, after);
+        assert_eq!(manager.get_sender_stats(CHAT, "gone"), Some((0, 3)));
+        assert_eq!(manager.get_sender_stats(CHAT, "halved"), Some((4, 30)));
+        // Nothing left to fold.
+        assert_eq!(manager.decay_sender_scores(now).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_delete_stale_senders_keeps_active_and_flagged_senders() {
+        let (_temp_file, manager) = setup_test_manager();
+        let now = now_unix();
+        let old = now - 100 * 86_400;
+        {
+            let conn = manager.conn().unwrap();
+            conn.execute(
+                "INSERT INTO senders (chat_id, user_id, spam_score, message_count, last_updated)
+                     VALUES (-100, 'stale', 0, 2, ?1), (-100, 'regular', 0, 500, ?1), (-100, 'recent', 0, 2, ?2), (-100, 'flagged', 3, 2, ?1)",
+                [old, now],
+            )
+            .unwrap();
+        }
+        assert_eq!(manager.delete_stale_senders(10, now - 90 * 86_400).unwrap(), 1);
+        assert_eq!(manager.get_sender_stats(CHAT, "stale"), None);
+        assert!(manager.get_sender_stats(CHAT, "regular").is_some());
+        assert!(manager.get_sender_stats(CHAT, "recent").is_some());
+        assert!(manager.get_sender_stats(CHAT, "flagged").is_some());
+    }
+
+    #[test]
+    fn test_concurrent_score_reads_and_updates_do_not_block() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.increment_sender_score(CHAT, "user1", true).unwrap();
+        let manager = Arc::new(manager);
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let manager = manager.clone();
+                std::thread::spawn(move || (0..50).map(|_| manager.get_sender_score(CHAT, "user1")).min())
+            })
+            .collect();
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let manager = manager.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        manager.increment_sender_score(CHAT, "user2", true).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), Some(1));
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        // No hit was lost to interleaved read-modify-writes.
+        assert_eq!(manager.get_sender_stats(CHAT, "user2"), Some((40, 40)));
+    }
+
     #[test]
     fn test_get_sender_score_returns_zero_for_new_user() {
-        let (temp_file, manager) = setup_test_manager();
-        let score = manager.get_sender_score("nonexistent");
+        let (_temp_file, manager) = setup_test_manager();
+        let score = manager.get_sender_score(CHAT, "nonexistent");
         assert_eq!(score, 0);
     }
 
+    #[test]
+    fn test_detector_stats_aggregates_contributions() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager
+            .record_spam_event(1, "u1", 12.0, &[("lua_rules", 10.0), ("links", 2.0), ("gibberish", 0.0)])
+            .unwrap();
+        manager
+            .record_spam_event(1, "u2", 9.0, &[("lua_rules", 5.0), ("links", 4.0)])
+            .unwrap();
+        manager.record_spam_event(1, "u3", 5.0, &[("gibberish", 5.0)]).unwrap();
+        manager.record_spam_event(2, "u4", 50.0, &[("links", 50.0)]).unwrap();
+
+        assert_eq!(manager.count_spam_events(1, 0).unwrap(), 3);
+        let stats = manager.detector_stats(1, 0).unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                DetectorStat { detector: "lua_rules".to_string(), fired: 2, total: 15.0 },
+                DetectorStat { detector: "links".to_string(), fired: 2, total: 6.0 },
+                DetectorStat { detector: "gibberish".to_string(), fired: 1, total: 5.0 },
+            ]
+        );
+        assert_eq!(stats[0].average(), 7.5);
+        // Events outside the window are ignored.
+        assert!(manager.detector_stats(1, now_unix() + 60).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_paused_chats_resume_manually_or_when_the_pause_runs_out() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.set_chat_threshold(-100, 7.0).unwrap();
+        assert!(!manager.is_paused(-100, 1_000));
+        assert!(!manager.resume_chat(-100).unwrap());
+
+        manager.pause_chat(-100, None).unwrap();
+        manager.pause_chat(-200, Some(1_500)).unwrap();
+        assert!(manager.is_paused(-100, 1_000) && manager.is_paused(-200, 1_000));
+        // Pausing keeps the chat's other settings
+        assert_eq!(manager.get_chat_threshold(-100), 7.0);
+
+        assert!(manager.take_expired_pauses(1_200).unwrap().is_empty());
+        assert!(!manager.is_paused(-200, 1_500));
+        assert_eq!(manager.take_expired_pauses(1_500).unwrap(), [-200]);
+        assert!(manager.take_expired_pauses(1_600).unwrap().is_empty());
+        assert!(manager.is_paused(-100, i64::MAX));
+
+        assert!(manager.resume_chat(-100).unwrap());
+        assert!(!manager.is_paused(-100, 1_000));
+    }
+
+    #[test]
+    fn test_bans_are_recorded_per_chat_and_removed() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.record_ban(-100, "user1", "auto-ban at spam score 5").unwrap();
+        let (reason, banned_at) = manager.get_ban(-100, "user1").unwrap();
+        assert_eq!(reason, "auto-ban at spam score 5");
+        assert!(banned_at > 0);
+        assert_eq!(manager.get_ban(-200, "user1"), None);
+        manager.record_ban(-100, "user1", "banned by admin 7").unwrap();
+        assert_eq!(manager.get_ban(-100, "user1").unwrap().0, "banned by admin 7");
+        assert!(manager.remove_ban(-100, "user1").unwrap());
+        assert!(!manager.remove_ban(-100, "user1").unwrap());
+        assert_eq!(manager.get_ban(-100, "user1"), None);
+    }
+
+    #[test]
+    fn test_sender_record_gathers_every_table() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert_eq!(
+            manager.get_sender_record(CHAT, "user1"),
+            SenderRecord { user_id: "user1".to_string(), ..Default::default() }
+        );
+
+        manager.increment_sender_score(CHAT, "user1", true).unwrap();
+        manager.increment_sender_score(CHAT, "user1", false).unwrap();
+        manager.add_warning(CHAT, "user1").unwrap();
+        manager.add_to_whitelist(CHAT, "user1").unwrap();
+        manager.record_ban(-200, "user1", "banned by admin 7").unwrap();
+        manager.record_ban(-100, "user1", "auto-ban at spam score 5").unwrap();
+        manager.record_ban(-100, "user2", "auto-ban at spam score 6").unwrap();
+
+        let record = manager.get_sender_record(CHAT, "user1");
+        assert!(record.known && record.whitelisted);
+        assert_eq!((record.spam_score, record.message_count, record.warnings), (1, 2, 1));
+        let bans: Vec<(i64, &str)> = record.bans.iter().map(|(chat, reason, _)| (*chat, reason.as_str())).collect();
+        // Bans in other chats are not shown
+        assert_eq!(bans, [(-100, "auto-ban at spam score 5")]);
+    }
+
+    #[test]
+    fn test_exported_rules_import_into_another_database() {
+        let (_source_file, source) = setup_test_manager();
+        source.add_rule_in_category("crypto".to_string(), 4.0, "finance").unwrap();
+        source.add_regex_rule(Regex::new(r"(?i)f\s*r\s*e\s*e").unwrap(), 5.0).unwrap();
+        source.set_category_enabled("finance", false).unwrap();
+        let json = source.export_rules();
+
+        let (_temp_file, manager) = setup_test_manager();
+        manager.add_rule("crypto".to_string(), 1.0).unwrap();
+        assert_eq!(manager.import_rules(&json).unwrap(), 2);
+        assert_eq!(manager.rule_count(), 2);
+        // The imported rule replaced the existing one, keeping its disabled category.
+        assert_eq!(builtin_score(&manager, "crypto"), 0.0);
+        assert_eq!(builtin_score(&manager, "F R E E"), 5.0);
+        assert_eq!(manager.export_rules(), json);
+
+        // Hand-written sets only need a keyword and score.
+        assert_eq!(manager.import_rules(r#"[{"keyword": "airdrop", "score": 3}]"#).unwrap(), 1);
+        assert_eq!(builtin_score(&manager, "airdrop"), 3.0);
+    }
+
+    #[test]
+    fn test_invalid_rule_sets_import_nothing() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert!(matches!(manager.import_rules("not json"), Err(RuleImportError::Json(_))));
+        let invalid = r#"[{"keyword": "ok", "score": 1}, {"keyword": "(", "score": 1, "is_regex": true}]"#;
+        assert!(matches!(manager.import_rules(invalid), Err(RuleImportError::InvalidRegex { .. })));
+        assert!(matches!(
+            manager.import_rules(r#"[{"keyword": "", "score": 1}]"#),
+            Err(RuleImportError::EmptyKeyword)
+        ));
+        assert_eq!(manager.rule_count(), 0);
+    }
+
+    #[test]
+    fn test_spam_point_does_not_count_another_message() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.increment_sender_score(CHAT, "user1", false).unwrap();
+        manager.add_spam_points(CHAT, "user1", 1).unwrap();
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), Some((1, 1)));
+        manager.increment_sender_score(CHAT, "user1", true).unwrap();
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), Some((2, 2)));
+    }
+
+    #[test]
+    fn test_top_senders_are_ranked_by_spam_score() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.increment_sender_score_by(CHAT, "user1", 2).unwrap();
+        manager.increment_sender_score_by(CHAT, "user2", 7).unwrap();
+        manager.increment_sender_score_by(CHAT, "user3", 4).unwrap();
+        manager.increment_sender_score(CHAT, "clean", false).unwrap();
+        assert_eq!(
+            manager.top_senders(CHAT, 10).unwrap(),
+            [("user2".to_string(), 7), ("user3".to_string(), 4), ("user1".to_string(), 2)]
+        );
+        assert_eq!(manager.top_senders(CHAT, 1).unwrap(), [("user2".to_string(), 7)]);
+    }
+
+    #[test]
+    fn test_top_senders_are_ranked_by_decayed_score() {
+        let (_temp_file, manager) = setup_test_manager();
+        let now = now_unix();
+        {
+            let conn = manager.conn().unwrap();
+            conn.execute(
+                "INSERT INTO senders (chat_id, user_id, spam_score, message_count, last_updated)
+                     VALUES (-100, 'old', 8, 10, ?1), (-100, 'faded', 2, 10, ?1), (-100, 'recent', 6, 10, ?2)",
+                [now - 30 * 86_400, now],
+            )
+            .unwrap();
+        }
+        // "old" is Suspicious and halves in 30 days; "faded" is Neutral and has decayed to 0
+        assert_eq!(manager.top_senders(CHAT, 10).unwrap(), [("recent".to_string(), 6), ("old".to_string(), 4)]);
+        assert_eq!(manager.get_sender_score(CHAT, "old"), 4);
+        assert_eq!(manager.get_sender_score(CHAT, "faded"), 0);
+    }
+
+    #[test]
+    fn test_sender_score_grows_by_the_given_points() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.increment_sender_score_by(CHAT, "user1", 10).unwrap();
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), Some((10, 1)));
+        manager.increment_sender_score_by(CHAT, "user1", 0).unwrap();
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), Some((10, 2)));
+        manager.add_spam_points(CHAT, "user1", 3).unwrap();
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), Some((13, 2)));
+    }
+
+    #[test]
+    fn test_warnings_accumulate_and_clear() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert_eq!(manager.get_warnings(CHAT, "user1"), 0);
+        assert_eq!(manager.add_warning(CHAT, "user1").unwrap(), 1);
+        assert_eq!(manager.add_warning(CHAT, "user1").unwrap(), 2);
+        assert_eq!(manager.get_warnings(CHAT, "user1"), 2);
+        // Warnings do not touch the spam score.
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), Some((0, 0)));
+        assert!(manager.clear_warnings(CHAT, "user1").unwrap());
+        assert!(!manager.clear_warnings(CHAT, "user1").unwrap());
+        assert_eq!(manager.get_warnings(CHAT, "user1"), 0);
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert!(!manager.is_whitelisted(CHAT, "user1"));
+        assert!(manager.add_to_whitelist(CHAT, "user1").unwrap());
+        assert!(!manager.add_to_whitelist(CHAT, "user1").unwrap());
+        assert!(manager.is_whitelisted(CHAT, "user1"));
+        assert!(!manager.is_whitelisted(CHAT, "user2"));
+        // Whitelisting is per chat
+        assert!(!manager.is_whitelisted(-200, "user1"));
+        assert!(!manager.remove_from_whitelist(-200, "user1").unwrap());
+        assert!(manager.remove_from_whitelist(CHAT, "user1").unwrap());
+        assert!(!manager.remove_from_whitelist(CHAT, "user1").unwrap());
+        assert!(!manager.is_whitelisted(CHAT, "user1"));
+    }
+
+    #[test]
+    fn test_reset_sender_score() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert!(!manager.reset_sender_score(CHAT, "user1").unwrap());
+        manager.increment_sender_score(CHAT, "user1", true).unwrap();
+        manager.increment_sender_score(CHAT, "user1", true).unwrap();
+        assert!(manager.reset_sender_score(CHAT, "user1").unwrap());
+        assert_eq!(manager.get_sender_stats(CHAT, "user1"), Some((0, 2)));
+    }
+
+    #[test]
+    fn test_pardon_senders_below_only_resets_low_scores() {
+        let (_temp_file, manager) = setup_test_manager();
+        {
+            let conn = manager.conn().unwrap();
+            for (user_id, score) in [("low1", 1), ("low2", 2), ("edge", 3), ("high", 10), ("clean", 0)] {
+                conn.execute(
+                    "INSERT INTO senders (chat_id, user_id, spam_score, message_count, last_updated) VALUES (?1, ?2, ?3, 1, ?4)",
+                    rusqlite::params![CHAT, user_id, score, now_unix()],
+                )
+                .unwrap();
+            }
+        }
+        assert_eq!(manager.count_senders_below(CHAT, 3).unwrap(), 2);
+        assert_eq!(manager.pardon_senders_below(CHAT, 3).unwrap(), 2);
+        assert_eq!(manager.get_sender_score(CHAT, "low1"), 0);
+        assert_eq!(manager.get_sender_score(CHAT, "low2"), 0);
+        assert_eq!(manager.get_sender_score(CHAT, "edge"), 3);
+        assert_eq!(manager.get_sender_score(CHAT, "high"), 10);
+    }
+
+    #[test]
+    fn test_pardon_senders_below_compares_decayed_scores() {
+        let (_temp_file, manager) = setup_test_manager();
+        {
+            let conn = manager.conn().unwrap();
+            conn.execute(
+                "INSERT INTO senders (chat_id, user_id, spam_score, message_count, last_updated)
+                     VALUES (-100, 'old', 8, 10, ?1), (-100, 'recent', 8, 10, ?2)",
+                [now_unix() - 30 * 86_400, now_unix()],
+            )
+            .unwrap();
+        }
+        // "old" is Suspicious and has halved to 4 in 30 days
+        assert_eq!(manager.count_senders_below(CHAT, 5).unwrap(), 1);
+        assert_eq!(manager.pardon_senders_below(CHAT, 5).unwrap(), 1);
+        assert_eq!(manager.get_sender_score(CHAT, "old"), 0);
+        assert_eq!(manager.get_sender_score(CHAT, "recent"), 8);
+        assert_eq!(manager.score_history(CHAT, "old", 0).unwrap().last().unwrap().delta, -4);
+    }
+
+    #[test]
+    fn test_chat_threshold_falls_back_to_default() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert_eq!(manager.get_chat_threshold(1), manager.threshold);
+        manager.set_chat_threshold(1, 8.0).unwrap();
+        manager.set_chat_threshold(1, 9.0).unwrap();
+        assert_eq!(manager.get_chat_threshold(1), 9.0);
+        assert_eq!(manager.get_chat_threshold(2), manager.threshold);
+    }
+
+    #[test]
+    fn test_chat_language_is_kept_alongside_the_threshold() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert_eq!(manager.get_chat_language(1), None);
+        manager.set_chat_threshold(1, 8.0).unwrap();
+        assert_eq!(manager.get_chat_language(1), None);
+        manager.set_chat_language(1, "ru").unwrap();
+        assert_eq!(manager.get_chat_language(1).as_deref(), Some("ru"));
+        assert_eq!(manager.get_chat_threshold(1), 8.0);
+    }
+
+    #[test]
+    fn test_welcome_message_is_off_until_set() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert_eq!(manager.get_welcome_message(1), None);
+        manager.set_chat_language(1, "ru").unwrap();
+        assert_eq!(manager.get_welcome_message(1), None);
+        manager.set_welcome_message(1, Some("Welcome, {name}!")).unwrap();
+        assert_eq!(manager.get_welcome_message(1).as_deref(), Some("Welcome, {name}!"));
+        assert_eq!(manager.get_welcome_message(2), None);
+        assert_eq!(manager.get_chat_language(1).as_deref(), Some("ru"));
+        manager.set_welcome_message(1, None).unwrap();
+        assert_eq!(manager.get_welcome_message(1), None);
+    }
+
+    #[test]
+    fn test_action_policy_is_set_per_chat_and_can_be_cleared() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert_eq!(manager.get_action_policy(1), None);
+        manager.set_chat_threshold(1, 8.0).unwrap();
+        manager.set_action_policy(1, Some(ActionPolicy::Ban)).unwrap();
+        assert_eq!(manager.get_action_policy(1), Some(ActionPolicy::Ban));
+        assert_eq!(manager.get_action_policy(2), None);
+        assert_eq!(manager.get_chat_threshold(1), 8.0);
+        manager.set_action_policy(1, None).unwrap();
+        assert_eq!(manager.get_action_policy(1), None);
+        assert_eq!(ActionPolicy::parse("Mute"), Some(ActionPolicy::Mute));
+        assert_eq!(ActionPolicy::parse("kick"), None);
+    }
+
+    #[test]
+    fn test_report_threshold_is_independent_of_detection_threshold() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert_eq!(manager.get_report_threshold(CHAT), manager.threshold);
+        manager.set_report_threshold(CHAT, 2.0).unwrap();
+        assert!(manager.is_reported_spam(CHAT, 3.0));
+        assert!(3.0 < manager.get_chat_threshold(CHAT));
+        manager.set_report_threshold(CHAT, 8.0).unwrap();
+        assert!(!manager.is_reported_spam(CHAT, 6.0));
+        assert_eq!(manager.get_report_threshold(CHAT), 8.0);
+    }
+
+    #[test]
+    fn test_report_threshold_is_scoped_by_chat() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.set_chat_threshold(-200, 7.0).unwrap();
+        manager.set_report_threshold(CHAT, 2.0).unwrap();
+        assert_eq!(manager.get_report_threshold(CHAT), 2.0);
+        assert_eq!(manager.get_report_threshold(-200), manager.threshold);
+        assert_eq!(manager.get_chat_threshold(-200), 7.0);
+        // A report threshold set before they were kept per chat still applies to the other chats
+        manager.set_setting("report_threshold", "4").unwrap();
+        assert_eq!(manager.get_report_threshold(-200), 4.0);
+        assert_eq!(manager.get_report_threshold(CHAT), 2.0);
+    }
+
+    #[test]
+    fn test_allowed_domains_are_scoped_by_chat() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert!(manager.add_allowed_domain(1, "rust-lang.org").unwrap());
+        assert!(!manager.add_allowed_domain(1, "rust-lang.org").unwrap());
+        assert!(manager.add_allowed_domain(1, "github.com").unwrap());
+        assert_eq!(manager.get_allowed_domains(1).unwrap(), vec!["github.com", "rust-lang.org"]);
+        assert!(manager.get_allowed_domains(2).unwrap().is_empty());
+        assert!(manager.remove_allowed_domain(1, "github.com").unwrap());
+        assert!(!manager.remove_allowed_domain(1, "github.com").unwrap());
+        assert_eq!(manager.get_allowed_domains(1).unwrap(), vec!["rust-lang.org"]);
+    }
+
+    #[test]
+    fn test_notes_are_scoped_by_chat_and_user() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.add_note(1, "user1", "warned for self-promo").unwrap();
+        manager.add_note(1, "user1", "known alt of user3").unwrap();
+        manager.add_note(2, "user1", "other chat").unwrap();
+        assert_eq!(
+            manager.get_notes(1, "user1").unwrap(),
+            vec!["warned for self-promo".to_string(), "known alt of user3".to_string()]
+        );
+        assert!(manager.get_notes(1, "user2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_restore_from_backup_reloads_rules() {
+        let (_temp_file, manager) = setup_test_manager();
+        let backup_dir = tempfile::tempdir().unwrap();
+        let backup = backup_dir.path().join("snapshot.db");
+        let backup = backup.to_str().unwrap();
+        manager.add_rule("spam".to_string(), 10.0).unwrap();
+        manager.backup_into(backup).unwrap();
+        manager.add_rule("promo".to_string(), 4.0).unwrap();
+
+        manager.restore_from(backup).unwrap();
+        let rules = manager.rules.lock_or_recover();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].keyword, "spam");
+    }
+
+    #[test]
+    fn test_labeled_messages_round_trip() {
+        let (_temp_file, manager) = setup_test_manager();
+        manager.add_labeled_message("buy now", true).unwrap();
+        manager.add_labeled_message("hello", false).unwrap();
+        let labeled = manager.get_labeled_messages().unwrap();
+        assert_eq!(
+            labeled,
+            vec![("buy now".to_string(), true), ("hello".to_string(), false)]
+        );
+    }
+
+    #[test]
+    fn test_set_active_profile_rejects_invalid_names() {
+        let (_temp_file, manager) = setup_test_manager();
+        assert_eq!(manager.rules_script_path(), "rules.lua");
+        assert!(manager.set_active_profile("weekend"));
+        assert_eq!(manager.rules_script_path(), "rules_weekend.lua");
+        assert!(!manager.set_active_profile("../etc"));
+        assert_eq!(manager.active_profile(), "weekend");
+    }
+
+    #[test]
+    fn test_profile_scripts_live_next_to_configured_rules_path() {
+        let (_temp_file, mut manager) = setup_test_manager();
+        manager.rules_path = "/etc/spam-bot/rules.lua".to_string();
+        assert_eq!(manager.rules_script_path(), "/etc/spam-bot/rules.lua");
+        manager.set_active_profile("strict");
+        assert_eq!(manager.rules_script_path(), "/etc/spam-bot/rules_strict.lua");
+    }
+
     #[test]
     fn test_check_custom_rules() {
-        let (temp_file, manager) = setup_test_manager();
-        // Create a temporary rules.lua for testing
+        let (_temp_file, mut manager) = setup_test_manager();
+        // Point the manager at a temporary rules script for testing
+        let script = NamedTempFile::new().unwrap();
+        manager.rules_path = script.path().to_str().unwrap().to_string();
         let lua_content = r#"
             function check_spam(message)
                 if string.lower(message):find("spam") then
@@ -272,10 +4254,102 @@ mod tests {
                 return 0
             end
         "#;
-        fs::write("rules.lua", lua_content).unwrap();
-        let score = manager.check_custom_rules("This is spam");
+        fs::write(script.path(), lua_content).unwrap();
+        let score = manager.check_custom_rules("This is spam").unwrap();
         assert_eq!(score, 10.0);
-        let score = manager.check_custom_rules("hello");
+        let score = manager.check_custom_rules("hello").unwrap();
         assert_eq!(score, 0.0);
     }
+
+    #[test]
+    fn test_broken_lua_rules_return_an_error() {
+        let (_temp_file, mut manager) = setup_test_manager();
+        manager.add_rule("spam".to_string(), 3.0).unwrap();
+        let script = NamedTempFile::new().unwrap();
+        manager.rules_path = script.path().to_str().unwrap().to_string();
+
+        fs::write(script.path(), "function check_spam(message) return 1").unwrap();
+        assert!(matches!(manager.check_custom_rules("spam"), Err(RuleManagerError::Lua(LuaRulesError::Lua(_)))));
+        fs::write(script.path(), "function other(message) return 1 end").unwrap();
+        assert!(matches!(manager.check_custom_rules("spam"), Err(RuleManagerError::Lua(LuaRulesError::Lua(_)))));
+        // The fallback scores the stored rules alone.
+        assert_eq!(manager.check_custom_rules_or_fallback("spam"), 3.0);
+
+        manager.rules_path = "/nonexistent/rules.lua".to_string();
+        assert!(matches!(manager.check_custom_rules("spam"), Err(RuleManagerError::Lua(LuaRulesError::Read { .. }))));
+    }
+
+    #[test]
+    fn test_self_check_runs_the_script_and_leaves_no_trace() {
+        let (_temp_file, mut manager) = setup_test_manager();
+        let script = NamedTempFile::new().unwrap();
+        manager.rules_path = script.path().to_str().unwrap().to_string();
+        fs::write(script.path(), "function check_spam(message) return #message end").unwrap();
+
+        let check = manager.self_check();
+        assert!(check.passed());
+        assert_eq!(check.lua.unwrap(), SELF_CHECK_MESSAGE.len() as f32);
+        assert_eq!(manager.get_setting(SELF_CHECK_SETTING), None);
+
+        manager.rules_path = "/nonexistent/rules.lua".to_string();
+        let check = manager.self_check();
+        assert!(!check.passed());
+        assert!(matches!(check.lua, Err(RuleManagerError::Lua(LuaRulesError::Read { .. }))));
+        assert!(check.database.is_ok());
+    }
+
+    #[test]
+    fn test_evaluate_lists_the_matching_rules() {
+        let (_temp_file, mut manager) = setup_test_manager();
+        manager.add_rule("crypto".to_string(), 5.0).unwrap();
+        manager.add_rule("http".to_string(), 3.0).unwrap();
+        manager.add_rule("casino".to_string(), 4.0).unwrap();
+        let script = NamedTempFile::new().unwrap();
+        manager.rules_path = script.path().to_str().unwrap().to_string();
+        fs::write(script.path(), "function check_spam(message) return 1 end").unwrap();
+
+        let evaluation = manager.evaluate("Crypto gains at http://x.example, crypto!");
+        assert_eq!(evaluation.matches, vec![("crypto".to_string(), 5.0), ("http".to_string(), 3.0)]);
+        assert_eq!(evaluation.lua_score, 1.0);
+        assert_eq!(evaluation.total, 9.0);
+        assert_eq!(manager.check_custom_rules_or_fallback("Crypto gains at http://x.example, crypto!"), 9.0);
+
+        manager.weight_by_occurrences = true;
+        assert_eq!(manager.matched_rules("crypto crypto")[0], ("crypto".to_string(), 10.0));
+    }
+
+    #[test]
+    fn test_runaway_lua_rules_are_aborted() {
+        let (_temp_file, mut manager) = setup_test_manager();
+        manager.add_rule("spam".to_string(), 3.0).unwrap();
+        let script = NamedTempFile::new().unwrap();
+        manager.rules_path = script.path().to_str().unwrap().to_string();
+        manager.lua_max_instructions = 100_000;
+        manager.lua_timeout = Duration::from_secs(5);
+
+        fs::write(script.path(), "function check_spam(message) while true do end end").unwrap();
+        let started = Instant::now();
+        assert!(matches!(manager.check_custom_rules("spam"), Err(RuleManagerError::Lua(LuaRulesError::Timeout { .. }))));
+        assert_eq!(manager.check_custom_rules_or_fallback("spam"), 3.0);
+        // Loops at load time are caught too, and the time limit applies on its own
+        fs::write(script.path(), "while true do end").unwrap();
+        manager.lua_max_instructions = 0;
+        manager.lua_timeout = Duration::from_millis(50);
+        assert!(matches!(manager.check_custom_rules("spam"), Err(RuleManagerError::Lua(LuaRulesError::Timeout { .. }))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Well-behaved scripts are unaffected
+        fs::write(script.path(), "function check_spam(message) return 2 end").unwrap();
+        assert_eq!(manager.check_custom_rules("spam").unwrap(), 2.0);
+    }
+
+    #[test]
+    fn test_lua_failure_alerts_are_throttled_per_chat() {
+        let (_temp_file, manager) = setup_test_manager();
+        let now = Instant::now();
+        assert!(manager.should_alert_lua_failure(1, now));
+        assert!(!manager.should_alert_lua_failure(1, now + Duration::from_secs(60)));
+        assert!(manager.should_alert_lua_failure(2, now));
+        assert!(manager.should_alert_lua_failure(1, now + LUA_ALERT_INTERVAL));
+    }
 }
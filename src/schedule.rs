@@ -0,0 +1,240 @@
+//! A module for switching rule profiles on a recurring schedule.
+//!
+//! Schedules are stored by `RuleManager` in the `profile_schedules` table and describe
+//! "activate profile X on these weekdays at HH:MM (UTC)". A background task periodically
+//! determines which schedule fired most recently and activates its profile. When several
+//! schedules fire at the same minute, the most recently added one wins, so overlapping
+//! schedules always resolve deterministically.
+
+use crate::rules::{is_valid_profile_name, ProfileSchedule, RuleManager};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Minutes in a day.
+const MINUTES_PER_DAY: i64 = 24 * 60;
+/// Minutes in a week.
+const MINUTES_PER_WEEK: i64 = 7 * MINUTES_PER_DAY;
+/// Three-letter weekday names, indexed from Sunday.
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A source of the current time, injectable so the scheduler can be tested.
+pub trait Clock: Send + Sync {
+    /// Returns the current time as seconds since the Unix epoch.
+    fn now_unix(&self) -> u64;
+}
+
+/// The system wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_unix(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// Parses a schedule specification of the form `<profile> <days> <HH:MM>`.
+///
+/// `<days>` is `daily`, `weekdays`, `weekends`, or a comma-separated list of
+/// three-letter weekday names (e.g. `sat,sun`). Times are in UTC.
+///
+/// # Arguments
+/// * `spec` - The command argument, e.g. `strict weekends 18:00`.
+///
+/// # Returns
+/// * `Result<(String, u8, u32), String>` - The profile, weekday bitmask and minute of day,
+///   or a human-readable error message.
+pub fn parse_schedule(spec: &str) -> Result<(String, u8, u32), String> {
+    let parts: Vec<&str> = spec.split_whitespace().collect();
+    if parts.len() != 3 {
+        return Err("Usage: /scheduleprofile <profile> <daily|weekdays|weekends|mon,tue,...> <HH:MM>".to_string());
+    }
+    let profile = parts[0];
+    if !is_valid_profile_name(profile) {
+        return Err(format!("Invalid profile name '{}'.", profile));
+    }
+    let days = parse_days(parts[1]).ok_or_else(|| format!("Invalid days '{}'.", parts[1]))?;
+    let minute_of_day = parse_time(parts[2]).ok_or_else(|| format!("Invalid time '{}'.", parts[2]))?;
+    Ok((profile.to_string(), days, minute_of_day))
+}
+
+/// Parses a days specification into a weekday bitmask (bit 0 = Sunday).
+fn parse_days(days: &str) -> Option<u8> {
+    match days.to_lowercase().as_str() {
+        "daily" => Some(0b111_1111),
+        "weekdays" => Some(0b011_1110),
+        "weekends" => Some(0b100_0001),
+        list => {
+            let mut mask = 0u8;
+            for day in list.split(',') {
+                let index = WEEKDAYS.iter().position(|d| *d == day.trim())?;
+                mask |= 1 << index;
+            }
+            Some(mask)
+        }
+    }
+}
+
+/// Parses `HH:MM` into minutes after midnight.
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    if hours < 24 && minutes < 60 {
+        Some(hours * 60 + minutes)
+    } else {
+        None
+    }
+}
+
+/// Formats a weekday bitmask back into a comma-separated list of weekday names.
+pub fn format_days(days: u8) -> String {
+    WEEKDAYS
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| days & (1u8 << i) != 0)
+        .map(|(_, d)| *d)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Determines which schedule fired most recently at the given time.
+///
+/// # Arguments
+/// * `schedules` - The stored schedules.
+/// * `now_unix` - The current time in seconds since the Unix epoch.
+///
+/// # Returns
+/// * `Option<&ProfileSchedule>` - The schedule that fired most recently; on ties the
+///   one with the highest id. `None` if no schedule has any weekday set.
+pub fn active_schedule_at(schedules: &[ProfileSchedule], now_unix: u64) -> Option<&ProfileSchedule> {
+    let days_since_epoch = (now_unix / 86_400) as i64;
+    // 1970-01-01 was a Thursday (index 4 when counting from Sunday).
+    let weekday = (days_since_epoch + 4) % 7;
+    let minute_of_week = weekday * MINUTES_PER_DAY + (now_unix % 86_400) as i64 / 60;
+
+    schedules
+        .iter()
+        .filter_map(|schedule| {
+            (0..7)
+                .filter(|&day| schedule.days & (1u8 << day) != 0)
+                .map(|day| {
+                    let fired_at = day * MINUTES_PER_DAY + schedule.minute_of_day as i64;
+                    (minute_of_week - fired_at).rem_euclid(MINUTES_PER_WEEK)
+                })
+                .min()
+                .map(|minutes_ago| (minutes_ago, schedule))
+        })
+        .min_by(|(a_ago, a), (b_ago, b)| a_ago.cmp(b_ago).then(b.id.cmp(&a.id)))
+        .map(|(_, schedule)| schedule)
+}
+
+/// Applies the schedule that fired most recently, switching the active profile if needed.
+///
+/// # Arguments
+/// * `rule_manager` - The `RuleManager` holding the schedules and the active profile.
+/// * `clock` - The time source.
+///
+/// # Returns
+/// * `Option<String>` - The newly activated profile, or `None` if nothing changed.
+pub fn apply_schedules(rule_manager: &RuleManager, clock: &dyn Clock) -> Option<String> {
+    let schedules = match rule_manager.get_profile_schedules() {
+        Ok(schedules) => schedules,
+        Err(e) => {
+            log::error!("Failed to load profile schedules: {}", e);
+            return None;
+        }
+    };
+    let schedule = active_schedule_at(&schedules, clock.now_unix())?;
+    if rule_manager.active_profile() == schedule.profile {
+        return None;
+    }
+    if rule_manager.set_active_profile(&schedule.profile) {
+        log::info!("Scheduled switch to rule profile '{}'", schedule.profile);
+        Some(schedule.profile.clone())
+    } else {
+        None
+    }
+}
+
+/// Spawns a background task that applies profile schedules once a minute.
+///
+/// # Arguments
+/// * `rule_manager` - The shared `RuleManager`.
+/// * `clock` - The time source.
+///
+/// # Returns
+/// * `JoinHandle<()>` - The handle of the spawned task.
+pub fn spawn_profile_scheduler(
+    rule_manager: Arc<RuleManager>,
+    clock: Arc<dyn Clock>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            apply_schedules(&rule_manager, clock.as_ref());
+        }
+    })
+}
+
+/// Unit tests for the `schedule` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    /// A clock frozen at a fixed Unix timestamp.
+    struct FixedClock(u64);
+
+    impl Clock for FixedClock {
+        fn now_unix(&self) -> u64 {
+            self.0
+        }
+    }
+
+    /// 2024-06-01 is a Saturday; returns its timestamp at the given UTC time.
+    fn saturday_at(hours: u64, minutes: u64) -> u64 {
+        1_717_200_000 + hours * 3600 + minutes * 60
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        assert_eq!(
+            parse_schedule("strict weekends 18:30"),
+            Ok(("strict".to_string(), 0b100_0001, 18 * 60 + 30))
+        );
+        assert_eq!(parse_schedule("relaxed mon,tue 09:00").unwrap().1, 0b000_0110);
+        assert!(parse_schedule("strict someday 18:00").is_err());
+        assert!(parse_schedule("strict daily 25:00").is_err());
+        assert!(parse_schedule("../x daily 10:00").is_err());
+    }
+
+    #[test]
+    fn test_scheduled_switch_activates_right_profile() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let manager = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        manager.add_profile_schedule("normal", 0b011_1110, 8 * 60).unwrap();
+        manager.add_profile_schedule("weekend", 0b100_0001, 10 * 60).unwrap();
+
+        // Saturday 09:00: the most recent switch was Friday 08:00.
+        assert_eq!(apply_schedules(&manager, &FixedClock(saturday_at(9, 0))), Some("normal".to_string()));
+        // Saturday 10:00: the weekend switch has just fired.
+        assert_eq!(apply_schedules(&manager, &FixedClock(saturday_at(10, 0))), Some("weekend".to_string()));
+        assert_eq!(manager.active_profile(), "weekend");
+        // Nothing changes on a later tick.
+        assert_eq!(apply_schedules(&manager, &FixedClock(saturday_at(11, 0))), None);
+    }
+
+    #[test]
+    fn test_overlapping_schedules_latest_added_wins() {
+        let schedules = vec![
+            ProfileSchedule { id: 1, profile: "a".to_string(), days: 0b111_1111, minute_of_day: 600 },
+            ProfileSchedule { id: 2, profile: "b".to_string(), days: 0b100_0000, minute_of_day: 600 },
+        ];
+        let active = active_schedule_at(&schedules, saturday_at(10, 5)).unwrap();
+        assert_eq!(active.profile, "b");
+    }
+}
@@ -0,0 +1,53 @@
+//! A module for locking mutexes without cascading panics.
+//!
+//! A `std::sync::Mutex` is poisoned when a thread panics while holding it, after
+//! which every `lock().unwrap()` panics as well, so one failing handler would take
+//! down every task sharing the lock. The bot's locks guard caches, counters and
+//! rule lists that stay usable after such a panic, so `LockExt::lock_or_recover`
+//! takes the guard anyway, logs the poisoning once and clears it.
+
+use std::sync::{Mutex, MutexGuard};
+
+/// Locks a mutex, recovering it if a thread panicked while holding it.
+pub trait LockExt<T> {
+    /// Acquires the lock, blocking until it is available.
+    ///
+    /// # Returns
+    /// * `MutexGuard<T>` - The guard, even if the mutex was poisoned.
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| {
+            log::warn!("Recovered a lock poisoned by a panicking thread");
+            self.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+}
+
+/// Unit tests for the `sync` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_poisoned_lock_is_recovered_and_cleared() {
+        let counter = Arc::new(Mutex::new(1));
+        let shared = counter.clone();
+        let panicked = std::thread::spawn(move || {
+            let mut guard = shared.lock_or_recover();
+            *guard += 1;
+            panic!("handler failed while holding the lock");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(counter.is_poisoned());
+
+        *counter.lock_or_recover() += 1;
+        assert_eq!(*counter.lock_or_recover(), 3);
+        assert!(!counter.is_poisoned());
+    }
+}
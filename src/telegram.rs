@@ -0,0 +1,210 @@
+//! A module abstracting the Telegram calls made when acting on spam.
+//!
+//! `ChatApi` covers the few `Requester` methods the notification and moderation
+//! helpers need. `Bot` implements it by calling Telegram, and tests use a recording
+//! mock instead, so the spam pipeline can be exercised without a live bot.
+//!
+//! Bursts of spam are when the bot is most likely to be throttled, so the calls that
+//! notify admins and act on spam go through `with_retry`, which waits out Telegram's
+//! `RetryAfter` (HTTP 429) responses instead of dropping the request.
+
+use crate::utils::CachedAdmin;
+use std::future::{Future, IntoFuture};
+use std::time::Duration;
+use teloxide::errors::RequestError;
+use teloxide::payloads::SendMessageSetters;
+use teloxide::prelude::Requester;
+use teloxide::types::{ChatId, InlineKeyboardMarkup, UserId};
+use teloxide::Bot;
+
+/// How many times a request throttled by Telegram is retried before giving up.
+pub const RATE_LIMIT_RETRIES: usize = 2;
+
+/// Longest `RetryAfter` wait honoured; a request throttled for longer fails at once
+/// rather than stalling the handler.
+pub const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// Sends a request, retrying it when Telegram answers with `RetryAfter`.
+///
+/// On `RetryAfter` the request is rebuilt and sent again after the indicated wait,
+/// up to `RATE_LIMIT_RETRIES` times. Other errors, and waits above `MAX_RETRY_WAIT`,
+/// are returned unchanged.
+///
+/// # Arguments
+/// * `request` - Builds the request; called once per attempt.
+///
+/// # Returns
+/// * `Result<T, RequestError>` - The response, or the last error.
+pub async fn with_retry<T, F, R>(mut request: F) -> Result<T, RequestError>
+where
+    F: FnMut() -> R,
+    R: IntoFuture<Output = Result<T, RequestError>>,
+{
+    let mut retries = 0;
+    loop {
+        match request().await {
+            Err(RequestError::RetryAfter(wait)) if retries < RATE_LIMIT_RETRIES && wait <= MAX_RETRY_WAIT => {
+                retries += 1;
+                log::warn!("Rate limited by Telegram; retrying in {:?} (retry {}/{})", wait, retries, RATE_LIMIT_RETRIES);
+                tokio::time::sleep(wait).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The Telegram calls made by the notification and moderation helpers.
+pub trait ChatApi: Sync {
+    /// Sends a text message, with optional inline buttons, to a chat.
+    fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> impl Future<Output = Result<(), RequestError>> + Send;
+
+    /// Fetches the administrators of a chat.
+    fn chat_admins(&self, chat_id: ChatId) -> impl Future<Output = Result<Vec<CachedAdmin>, RequestError>> + Send;
+
+    /// Bans a user from a chat.
+    fn ban_member(&self, chat_id: ChatId, user_id: UserId) -> impl Future<Output = Result<(), RequestError>> + Send;
+}
+
+impl ChatApi for Bot {
+    async fn send_text(&self, chat_id: ChatId, text: &str, keyboard: Option<InlineKeyboardMarkup>) -> Result<(), RequestError> {
+        with_retry(|| {
+            let request = self.send_message(chat_id, text);
+            match keyboard.clone() {
+                Some(keyboard) => request.reply_markup(keyboard),
+                None => request,
+            }
+        })
+        .await
+        .map(|_| ())
+    }
+
+    async fn chat_admins(&self, chat_id: ChatId) -> Result<Vec<CachedAdmin>, RequestError> {
+        let admins = with_retry(|| self.get_chat_administrators(chat_id)).await?;
+        Ok(admins
+            .iter()
+            .map(|admin| CachedAdmin {
+                id: admin.user.id,
+                is_bot: admin.user.is_bot,
+            })
+            .collect())
+    }
+
+    async fn ban_member(&self, chat_id: ChatId, user_id: UserId) -> Result<(), RequestError> {
+        with_retry(|| self.ban_chat_member(chat_id, user_id)).await.map(|_| ())
+    }
+}
+
+/// A recording `ChatApi` for tests.
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use crate::sync::LockExt;
+    use std::sync::Mutex;
+    use teloxide::errors::ApiError;
+
+    /// A call made through `MockApi`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ApiCall {
+        /// A message sent to `chat_id`, and whether it carried buttons.
+        Send { chat_id: ChatId, text: String, keyboard: bool },
+        /// A ban of `user_id` from `chat_id`.
+        Ban { chat_id: ChatId, user_id: UserId },
+    }
+
+    /// Answers admin lookups with a fixed list and records every send and ban.
+    #[derive(Debug, Default)]
+    pub struct MockApi {
+        /// The admins of every chat, or `None` to fail admin lookups.
+        pub admins: Option<Vec<CachedAdmin>>,
+        /// The sends and bans made so far, in order.
+        pub calls: Mutex<Vec<ApiCall>>,
+    }
+
+    impl MockApi {
+        /// Creates a mock whose chats are administered by `admins`.
+        pub fn with_admins(admins: Vec<CachedAdmin>) -> Self {
+            Self {
+                admins: Some(admins),
+                calls: Mutex::new(Vec::new()),
+            }
+        }
+
+        /// Returns the calls made so far.
+        pub fn calls(&self) -> Vec<ApiCall> {
+            self.calls.lock_or_recover().clone()
+        }
+    }
+
+    impl ChatApi for MockApi {
+        async fn send_text(&self, chat_id: ChatId, text: &str, keyboard: Option<InlineKeyboardMarkup>) -> Result<(), RequestError> {
+            self.calls.lock_or_recover().push(ApiCall::Send {
+                chat_id,
+                text: text.to_string(),
+                keyboard: keyboard.is_some(),
+            });
+            Ok(())
+        }
+
+        async fn chat_admins(&self, _chat_id: ChatId) -> Result<Vec<CachedAdmin>, RequestError> {
+            self.admins
+                .clone()
+                .ok_or_else(|| RequestError::Api(ApiError::Unknown("admins unavailable".to_string())))
+        }
+
+        async fn ban_member(&self, chat_id: ChatId, user_id: UserId) -> Result<(), RequestError> {
+            self.calls.lock_or_recover().push(ApiCall::Ban { chat_id, user_id });
+            Ok(())
+        }
+    }
+}
+
+/// Unit tests for the `telegram` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use teloxide::errors::ApiError;
+
+    /// Answers the first `throttled` attempts with `RetryAfter(wait)`, then succeeds.
+    async fn attempts_until_success(throttled: usize, wait: Duration) -> (Result<(), RequestError>, usize) {
+        let attempts = AtomicUsize::new(0);
+        let result = with_retry(|| async {
+            if attempts.fetch_add(1, Ordering::SeqCst) < throttled {
+                Err(RequestError::RetryAfter(wait))
+            } else {
+                Ok(())
+            }
+        })
+        .await;
+        (result, attempts.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_requests_are_retried_a_bounded_number_of_times() {
+        let (result, attempts) = attempts_until_success(2, Duration::ZERO).await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+
+        let (result, attempts) = attempts_until_success(10, Duration::ZERO).await;
+        assert!(matches!(result, Err(RequestError::RetryAfter(_))));
+        assert_eq!(attempts, RATE_LIMIT_RETRIES + 1);
+
+        let (result, attempts) = attempts_until_success(1, MAX_RETRY_WAIT + Duration::from_secs(1)).await;
+        assert!(matches!(result, Err(RequestError::RetryAfter(_))));
+        assert_eq!(attempts, 1);
+
+        let attempts = AtomicUsize::new(0);
+        let result: Result<(), _> = with_retry(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(RequestError::Api(ApiError::BotBlocked))
+        })
+        .await;
+        assert!(matches!(result, Err(RequestError::Api(ApiError::BotBlocked))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,5 +1,16 @@
-use crate::rules::RuleManager;
-use teloxide::errors::RequestError;
+use crate::config::{env_parse, Config};
+use crate::flood::ChatLockdown;
+use crate::metrics::Metrics;
+use crate::lang::{Key, Lang};
+use crate::media::message_media;
+use crate::rules::{net_score, now_unix, FlaggedMessage, RuleEvaluation, RuleManager, ScoreEvent, SenderRecord};
+use crate::sync::LockExt;
+use crate::telegram::{with_retry, ChatApi};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use teloxide::errors::{ApiError, RequestError};
 use teloxide::prelude::Requester;
 /// A module providing utility functions for Telegram bot administration and notifications.
 ///
@@ -7,59 +18,1304 @@ use teloxide::prelude::Requester;
 /// about spam detection events. It leverages the `teloxide` library for Telegram interactions
 /// and integrates with the `rules` module for spam score management.
 use teloxide::{
-    types::{ChatId, Message},
+    types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup, Message, MessageId, UserId},
+    utils::html,
     Bot,
 };
 
+/// An administrator as remembered by `AdminCache`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachedAdmin {
+    /// The admin's user ID.
+    pub id: UserId,
+    /// Whether the admin is a bot account.
+    pub is_bot: bool,
+}
+
+/// A time-bounded cache of each chat's administrators.
+///
+/// Admin lookups happen for almost every command and spam notification, so the list
+/// fetched from Telegram is reused until it is older than the TTL instead of calling
+/// `get_chat_administrators` every time.
+pub struct AdminCache {
+    /// Cached admins per chat, with the time they were fetched.
+    entries: Mutex<HashMap<ChatId, (Vec<CachedAdmin>, Instant)>>,
+    /// How long a fetched admin list is reused.
+    ttl: Duration,
+}
+
+impl AdminCache {
+    /// Creates an empty cache whose entries expire after `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Creates a cache with the TTL from `ADMIN_CACHE_TTL_SECS` (default 300; 0 disables caching).
+    pub fn from_env() -> Self {
+        Self::new(Duration::from_secs(env_parse("ADMIN_CACHE_TTL_SECS", 300)))
+    }
+
+    /// Returns a chat's admins, fetching them from Telegram on a miss or expiry.
+    ///
+    /// # Arguments
+    /// * `bot` - The Telegram API to fetch the admins from.
+    /// * `chat_id` - The chat whose admins are needed.
+    ///
+    /// # Returns
+    /// * `Result<Vec<CachedAdmin>>` - The admins, or a `RequestError` if the fetch fails.
+    pub async fn admins<A: ChatApi>(&self, bot: &A, chat_id: ChatId) -> Result<Vec<CachedAdmin>, RequestError> {
+        self.get_or_fetch(chat_id, Instant::now(), || bot.chat_admins(chat_id)).await
+    }
+
+    /// Returns the cached admins of a chat, or fetches and stores them with `fetch`.
+    ///
+    /// The lock is not held while fetching, so a slow request never blocks other chats.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat whose admins are needed.
+    /// * `now` - The current time.
+    /// * `fetch` - Fetches the admin list on a miss or expiry.
+    ///
+    /// # Returns
+    /// * `Result<Vec<CachedAdmin>, E>` - The admins, or the error returned by `fetch`.
+    pub async fn get_or_fetch<F, Fut, E>(&self, chat_id: ChatId, now: Instant, fetch: F) -> Result<Vec<CachedAdmin>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<CachedAdmin>, E>>,
+    {
+        if let Some((admins, fetched_at)) = self.entries.lock_or_recover().get(&chat_id) {
+            if now.duration_since(*fetched_at) < self.ttl {
+                return Ok(admins.clone());
+            }
+        }
+        let admins = fetch().await?;
+        self.entries.lock_or_recover().insert(chat_id, (admins.clone(), now));
+        Ok(admins)
+    }
+}
+
+/// Limits spam notifications to one per chat per window during spam bursts.
+///
+/// During a raid every spam message would otherwise DM every admin, flooding them
+/// and risking Telegram's rate limits. The first notification in a window is sent;
+/// later ones are only counted, and the count is reported in a single summary.
+pub struct NotificationThrottle {
+    /// Per chat, when the last notification was sent and how many were suppressed since.
+    chats: Mutex<HashMap<ChatId, (Instant, usize)>>,
+    /// Minimum time between notifications for a chat.
+    window: Duration,
+}
+
+impl NotificationThrottle {
+    /// Creates a throttle allowing one notification per chat every `window`.
+    ///
+    /// A zero window disables throttling.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            chats: Mutex::new(HashMap::new()),
+            window,
+        }
+    }
+
+    /// Creates a throttle with the window from `NOTIFY_THROTTLE_SECS` (default 60; 0 disables throttling).
+    pub fn from_env() -> Self {
+        Self::new(Duration::from_secs(env_parse("NOTIFY_THROTTLE_SECS", 60)))
+    }
+
+    /// Returns the minimum time between notifications for a chat.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Decides whether a spam notification for a chat may be sent now.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the spam was posted in.
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    /// * `Option<usize>` - `Some` with the number of notifications suppressed since the
+    ///   last one, which the caller should report, or `None` if this one is suppressed.
+    pub fn admit(&self, chat_id: ChatId, now: Instant) -> Option<usize> {
+        if self.window.is_zero() {
+            return Some(0);
+        }
+        let mut chats = self.chats.lock_or_recover();
+        if let Some((sent_at, suppressed)) = chats.get_mut(&chat_id) {
+            if now.duration_since(*sent_at) < self.window {
+                *suppressed += 1;
+                return None;
+            }
+        }
+        Some(chats.insert(chat_id, (now, 0)).map_or(0, |(_, suppressed)| suppressed))
+    }
+
+    /// Takes the suppressed counts of chats whose window has ended.
+    ///
+    /// Each returned summary counts as that chat's notification for a new window.
+    /// Chats without suppressed notifications are forgotten.
+    ///
+    /// # Arguments
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    /// * `Vec<(ChatId, usize)>` - The chats to summarize and their suppressed counts.
+    pub fn take_due_summaries(&self, now: Instant) -> Vec<(ChatId, usize)> {
+        let mut chats = self.chats.lock_or_recover();
+        let mut due = Vec::new();
+        chats.retain(|chat_id, (sent_at, suppressed)| {
+            if now.duration_since(*sent_at) < self.window {
+                return true;
+            }
+            if *suppressed == 0 {
+                return false;
+            }
+            due.push((*chat_id, *suppressed));
+            *sent_at = now;
+            *suppressed = 0;
+            true
+        });
+        due
+    }
+}
+
+/// Formats the summary of spam notifications suppressed by `NotificationThrottle`.
+pub fn suppressed_summary(suppressed: usize) -> String {
+    format!(
+        "{} more spam messages were detected; notifications about them were suppressed to avoid flooding.",
+        suppressed
+    )
+}
+
+/// Spawns a background task that sends the admins of each throttled chat a summary
+/// of the spam notifications suppressed once its window ends.
+///
+/// Does nothing if throttling is disabled.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance.
+/// * `throttle` - The shared notification throttle.
+/// * `admin_cache` - The shared cache of chat administrators.
+pub fn spawn_notification_summaries(bot: Bot, throttle: Arc<NotificationThrottle>, admin_cache: Arc<AdminCache>) {
+    if throttle.window().is_zero() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(throttle.window());
+        loop {
+            interval.tick().await;
+            for (chat_id, suppressed) in throttle.take_due_summaries(Instant::now()) {
+                if let Err(e) = message_admins(&bot, chat_id, &suppressed_summary(suppressed), &admin_cache).await {
+                    log::error!("Failed to send suppressed notification summary for chat {}: {}", chat_id, e);
+                }
+            }
+        }
+    });
+}
+
+/// A run of spam messages from one sender in one chat.
+struct SpamBurst {
+    /// When the first spam message of the burst was flagged.
+    started_at: Instant,
+    /// How many spam messages the burst has so far.
+    messages: usize,
+    /// How the sender is named in notifications.
+    author: String,
+}
+
+/// Collapses the admin notifications about one sender's spam burst into one summary.
+///
+/// A user pasting several spam lines in quick succession would otherwise trigger a
+/// notification, and a spam point, for every line. The first spam message of a burst
+/// is reported as usual; later ones within the window are still deleted, but only
+/// counted, and the count is reported in a single summary once the window ends.
+pub struct SenderCooldown {
+    /// Per chat and sender, the current burst.
+    bursts: Mutex<HashMap<(ChatId, UserId), SpamBurst>>,
+    /// How long a burst lasts after its first spam message.
+    window: Duration,
+    /// How many spam messages of a burst add to the sender's score; 0 means all of them.
+    score_cap: usize,
+}
+
+impl SenderCooldown {
+    /// Creates a cooldown whose bursts last `window`, scoring at most `score_cap`
+    /// messages of each (0 for no cap).
+    ///
+    /// A zero window disables the cooldown.
+    pub fn new(window: Duration, score_cap: usize) -> Self {
+        Self {
+            bursts: Mutex::new(HashMap::new()),
+            window,
+            score_cap,
+        }
+    }
+
+    /// Creates a cooldown from `SENDER_COOLDOWN_SECS` (default 60; 0 disables the cooldown)
+    /// and `SENDER_COOLDOWN_SCORE_CAP` (default 3; 0 scores every message).
+    pub fn from_env() -> Self {
+        Self::new(
+            Duration::from_secs(env_parse("SENDER_COOLDOWN_SECS", 60)),
+            env_parse("SENDER_COOLDOWN_SCORE_CAP", 3),
+        )
+    }
+
+    /// Returns how long a burst lasts after its first spam message.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Records a spam message from a sender.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the spam was posted in.
+    /// * `user_id` - The sender.
+    /// * `author` - How the sender is named in notifications.
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    /// * `(usize, Option<usize>)` - The position of the message in the sender's burst,
+    ///   starting at 1 (only the first is reported to admins), and the size of the
+    ///   sender's previous burst if it ended without being summarized yet.
+    pub fn record(&self, chat_id: ChatId, user_id: UserId, author: &str, now: Instant) -> (usize, Option<usize>) {
+        if self.window.is_zero() {
+            return (1, None);
+        }
+        let mut bursts = self.bursts.lock_or_recover();
+        if let Some(burst) = bursts.get_mut(&(chat_id, user_id)) {
+            if now.duration_since(burst.started_at) < self.window {
+                burst.messages += 1;
+                return (burst.messages, None);
+            }
+        }
+        let burst = SpamBurst {
+            started_at: now,
+            messages: 1,
+            author: author.to_string(),
+        };
+        let finished = bursts.insert((chat_id, user_id), burst).map(|burst| burst.messages);
+        (1, finished.filter(|&messages| messages > 1))
+    }
+
+    /// Returns whether the spam message at `position` in a burst adds to the sender's score.
+    pub fn counts_score(&self, position: usize) -> bool {
+        self.score_cap == 0 || position <= self.score_cap
+    }
+
+    /// Takes the bursts whose window has ended.
+    ///
+    /// Bursts of a single message were already reported and are only forgotten.
+    ///
+    /// # Arguments
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    /// * `Vec<(ChatId, String, usize)>` - The chat, sender and size of each burst to summarize.
+    pub fn take_due_summaries(&self, now: Instant) -> Vec<(ChatId, String, usize)> {
+        let mut bursts = self.bursts.lock_or_recover();
+        let mut due = Vec::new();
+        bursts.retain(|(chat_id, _), burst| {
+            if now.duration_since(burst.started_at) < self.window {
+                return true;
+            }
+            if burst.messages > 1 {
+                due.push((*chat_id, std::mem::take(&mut burst.author), burst.messages));
+            }
+            false
+        });
+        due
+    }
+}
+
+/// Formats the summary of a spam burst collapsed by `SenderCooldown`.
+pub fn sender_burst_summary(author: &str, messages: usize) -> String {
+    format!(
+        "User {} sent {} spam messages in a row; only the first was reported, but all were handled.",
+        author, messages
+    )
+}
+
+/// Spawns a background task that sends the admins of each chat a summary of the
+/// spam bursts collapsed by the cooldown once their window ends.
+///
+/// Does nothing if the cooldown is disabled.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance.
+/// * `cooldown` - The shared sender cooldown.
+/// * `admin_cache` - The shared cache of chat administrators.
+pub fn spawn_sender_burst_summaries(bot: Bot, cooldown: Arc<SenderCooldown>, admin_cache: Arc<AdminCache>) {
+    if cooldown.window().is_zero() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(cooldown.window());
+        loop {
+            interval.tick().await;
+            for (chat_id, author, messages) in cooldown.take_due_summaries(Instant::now()) {
+                if let Err(e) = message_admins(&bot, chat_id, &sender_burst_summary(&author, messages), &admin_cache).await {
+                    log::error!("Failed to send spam burst summary for chat {}: {}", chat_id, e);
+                }
+            }
+        }
+    });
+}
+
+/// How often chats whose moderation pause has run out are looked for.
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often lifted chat lockdowns are looked for.
+const LOCKDOWN_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Formats the notice sent to admins when their chat is locked down.
+pub fn lockdown_engaged_notice(lockdown: &ChatLockdown) -> String {
+    format!(
+        "Lockdown engaged: more than {} messages in {} seconds. Messages from new members score {} extra until the chat has been calm for {} seconds.",
+        lockdown.max_messages,
+        lockdown.window.as_secs(),
+        lockdown.score,
+        lockdown.duration.as_secs()
+    )
+}
+
+/// Formats the notice sent to admins when their chat's lockdown ends.
+pub fn lockdown_lifted_notice() -> String {
+    "Lockdown lifted: message traffic is back to normal.".to_string()
+}
+
+/// Spawns a background task that lifts expired chat lockdowns and tells each chat's
+/// admins when theirs is lifted.
+///
+/// Does nothing if lockdowns are disabled.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance.
+/// * `lockdown` - The shared chat lockdown detector.
+/// * `admin_cache` - The shared cache of chat administrators.
+/// * `notify` - Whether admins are messaged; lifted lockdowns are always logged.
+pub fn spawn_lockdown_monitor(bot: Bot, lockdown: Arc<ChatLockdown>, admin_cache: Arc<AdminCache>, notify: bool) {
+    if !lockdown.is_enabled() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LOCKDOWN_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for chat_id in lockdown.take_lifted(Instant::now()) {
+                log::info!("Lockdown lifted in chat {}", chat_id);
+                if !notify {
+                    continue;
+                }
+                if let Err(e) = message_admins(&bot, ChatId(chat_id), &lockdown_lifted_notice(), &admin_cache).await {
+                    log::error!("Failed to notify admins of chat {} about the lifted lockdown: {}", chat_id, e);
+                }
+            }
+        }
+    });
+}
+
+/// Spawns a background task that resumes spam moderation in chats whose `/pause`
+/// has run out, and tells each chat that moderation is back on.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance.
+/// * `rule_manager` - The manager the pauses are stored with.
+pub fn spawn_pause_expiry(bot: Bot, rule_manager: Arc<RuleManager>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PAUSE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let resumed = rule_manager.run_blocking(|manager: &RuleManager| manager.take_expired_pauses(now_unix())).await;
+            let chats = resumed.unwrap_or_else(|e| {
+                log::error!("Failed to resume paused chats: {}", e);
+                Vec::new()
+            });
+            for chat_id in chats {
+                log::info!("Spam moderation resumed in chat {} after its pause ran out", chat_id);
+                let lang = Lang::resolve(rule_manager.get_chat_language(chat_id).as_deref(), None);
+                if let Err(e) = bot.send_message(ChatId(chat_id), lang.text(Key::ModerationResumed)).await {
+                    log::error!("Failed to tell chat {} that moderation resumed: {}", chat_id, e);
+                }
+            }
+        }
+    });
+}
+
 /// Checks if a user is an administrator in the given chat.
 ///
 /// Determines whether the sender of a message is an admin. In private chats,
-/// all users are considered admins by default. In group chats, it queries the
-/// Telegram API to fetch the list of administrators and checks if the user's
-/// ID is included.
+/// all users are considered admins by default. In group chats, it looks up the
+/// chat's administrators through the `AdminCache`, which only queries the
+/// Telegram API when the cached list is missing or expired.
 ///
 /// # Arguments
 /// * `bot` - A reference to the Telegram bot instance.
 /// * `msg` - A reference to the message containing the user and chat context.
+/// * `admin_cache` - The shared cache of chat administrators.
 ///
 /// # Returns
 /// * `Result<bool>` - A `Result` containing `true` if the user is an admin,
-///   `false` otherwise, or a `RequestError` if the API call fails.
-///
-/// # Panics
-/// * Panics if `msg.from()` is `None` (i.e., no sender information).
-pub async fn is_admin(bot: &Bot, msg: &Message) -> Result<bool, RequestError> {
-    if msg.chat.is_private() {
+///   `false` otherwise (including messages without a sender, such as channel posts),
+///   or a `RequestError` if the API call fails. Anonymous admins are admins.
+pub async fn is_admin(bot: &Bot, msg: &Message, admin_cache: &AdminCache) -> Result<bool, RequestError> {
+    if msg.chat.is_private() || message_sender(msg) == MessageSender::AnonymousAdmin {
         Ok(true)
     } else {
-        let admins = bot.get_chat_administrators(msg.chat.id).await?;
-        let user_id = msg.from().unwrap().id;
+        let user_id = match msg.from() {
+            Some(user) => user.id,
+            None => return Ok(false),
+        };
+        let admins = admin_cache.admins(bot, msg.chat.id).await?;
         log::info!(
             "Checking admin status for user {} in chat {}",
             user_id,
             msg.chat.id
         );
-        let is_admin = admins.iter().any(|admin| {
-            log::info!("Admin found: {}", admin.user.id);
-            admin.user.id == user_id
-        });
-        Ok(is_admin)
+        Ok(admins.iter().any(|admin| admin.id == user_id))
+    }
+}
+
+/// Checks if the sender of a message is the owner (creator) of the chat.
+///
+/// In private chats the user is considered the owner.
+///
+/// # Arguments
+/// * `bot` - A reference to the Telegram bot instance.
+/// * `msg` - A reference to the message containing the user and chat context.
+///
+/// # Returns
+/// * `Result<bool>` - `true` if the sender owns the chat, or a `RequestError` if the API call fails.
+pub async fn is_owner(bot: &Bot, msg: &Message) -> Result<bool, RequestError> {
+    if msg.chat.is_private() {
+        return Ok(true);
+    }
+    match msg.from() {
+        Some(user) => Ok(bot.get_chat_member(msg.chat.id, user.id).await?.is_owner()),
+        None => Ok(false),
+    }
+}
+
+/// An action the bot can take against a spammer, ordered from mildest to harshest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SpamAction {
+    /// Only notify the admins.
+    Notify,
+    /// Temporarily restrict the user from posting.
+    Mute,
+    /// Remove the user from the chat.
+    Ban,
+}
+
+impl SpamAction {
+    /// Returns `true` for actions that restrict or remove the user.
+    pub fn is_destructive(self) -> bool {
+        matches!(self, SpamAction::Mute | SpamAction::Ban)
+    }
+}
+
+/// Downgrades destructive actions to notifications in chats with too few human admins.
+///
+/// If the bot is the only admin, a mistaken ban or mute could lock the community out
+/// with nobody able to undo it, so destructive actions require at least
+/// `min_human_admins` human (non-bot) admins.
+///
+/// # Arguments
+/// * `requested` - The action the bot would normally take.
+/// * `human_admins` - The number of non-bot admins in the chat.
+/// * `min_human_admins` - The minimum required for destructive actions.
+///
+/// # Returns
+/// * `SpamAction` - `requested`, or `SpamAction::Notify` if it was destructive and suppressed.
+pub fn effective_action(requested: SpamAction, human_admins: usize, min_human_admins: usize) -> SpamAction {
+    if requested.is_destructive() && human_admins < min_human_admins {
+        SpamAction::Notify
+    } else {
+        requested
+    }
+}
+
+/// Bans a sender whose spam score reached `AUTO_BAN_SCORE` and tells the admins.
+///
+/// Like every destructive action, the ban falls back to a notification if the chat
+/// has fewer human admins than configured. Telegram API failures are logged rather
+/// than returned, so a failed ban never stops the message handler.
+///
+/// # Arguments
+/// * `bot` - The Telegram API the ban and notification go through.
+/// * `chat_id` - The group the sender is banned from.
+/// * `user_id` - The sender to ban.
+/// * `spam_score` - The sender's current spam score.
+/// * `config` - Bot-wide handler settings.
+/// * `admin_cache` - The shared cache of chat administrators.
+/// * `metrics` - The shared metrics aggregator counting automatic bans.
+/// * `rule_manager` - The manager the ban is recorded with.
+#[allow(clippy::too_many_arguments)]
+pub async fn auto_ban<A: ChatApi>(
+    bot: &A,
+    chat_id: ChatId,
+    user_id: UserId,
+    spam_score: i32,
+    config: &Config,
+    admin_cache: &AdminCache,
+    metrics: &Metrics,
+    rule_manager: &RuleManager,
+) {
+    let human_admins = count_human_admins(bot, chat_id, admin_cache).await.unwrap_or_else(|e| {
+        log::error!("Failed to fetch admins for chat {}: {}", chat_id, e);
+        0
+    });
+    let notice = match effective_action(SpamAction::Ban, human_admins, config.min_human_admins) {
+        SpamAction::Ban => match bot.ban_member(chat_id, user_id).await {
+            Ok(()) => {
+                metrics.incr_auto_bans();
+                let reason = format!("auto-ban at spam score {}", spam_score);
+                if let Err(e) = rule_manager.record_ban(chat_id.0, &user_id.to_string(), &reason) {
+                    log::error!("Failed to record ban: {}", e);
+                }
+                log::info!("Auto-banned user {} in chat {} (spam score {})", user_id, chat_id, spam_score);
+                format!(
+                    "User {} was auto-banned after reaching a spam score of {}. Use /reset_score {} to undo.",
+                    user_id, spam_score, user_id
+                )
+            }
+            Err(e) => {
+                log::error!("Failed to auto-ban user {} in chat {}: {}", user_id, chat_id, e);
+                return;
+            }
+        },
+        _ => format!(
+            "User {} reached a spam score of {} but was not banned automatically because this chat has too few human admins.",
+            user_id, spam_score
+        ),
+    };
+    if let Err(e) = message_admins(bot, chat_id, &notice, admin_cache).await {
+        log::error!("Failed to notify admins about auto-ban of user {}: {}", user_id, e);
+    }
+}
+
+/// Counts the human (non-bot) administrators of a chat.
+///
+/// # Arguments
+/// * `bot` - The Telegram API to fetch the admins from.
+/// * `chat_id` - The ID of the chat.
+/// * `admin_cache` - The shared cache of chat administrators.
+///
+/// # Returns
+/// * `Result<usize>` - The number of human admins, or a `RequestError` if the API call fails.
+pub async fn count_human_admins<A: ChatApi>(bot: &A, chat_id: ChatId, admin_cache: &AdminCache) -> Result<usize, RequestError> {
+    let admins = admin_cache.admins(bot, chat_id).await?;
+    Ok(admins.iter().filter(|admin| !admin.is_bot).count())
+}
+
+/// Tracks destructive commands awaiting confirmation.
+///
+/// A destructive command is first registered as pending for the issuing user in
+/// the chat; repeating the exact same command within the time-to-live confirms it.
+pub struct PendingConfirmations {
+    /// Pending actions keyed by `(chat_id, user_id)`, with the time they were requested.
+    pending: Mutex<HashMap<(i64, u64), (String, Instant)>>,
+    /// How long a pending action stays confirmable.
+    ttl: Duration,
+}
+
+impl PendingConfirmations {
+    /// Creates an empty store whose pending actions expire after `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Registers or confirms an action.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the command was issued in.
+    /// * `user_id` - The user issuing the command.
+    /// * `action` - A description of the exact action, e.g. `batchpardon 3`.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the same action was pending and is now confirmed;
+    ///   `false` if the action was newly registered and needs confirmation.
+    pub fn confirm(&self, chat_id: i64, user_id: u64, action: &str) -> bool {
+        let mut pending = self.pending.lock_or_recover();
+        let now = Instant::now();
+        pending.retain(|_, (_, requested)| now.duration_since(*requested) < self.ttl);
+        match pending.get(&(chat_id, user_id)) {
+            Some((pending_action, _)) if pending_action == action => {
+                pending.remove(&(chat_id, user_id));
+                true
+            }
+            _ => {
+                pending.insert((chat_id, user_id), (action.to_string(), now));
+                false
+            }
+        }
+    }
+}
+
+/// Who sent a message, as far as scoring and moderation are concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageSender {
+    /// A user posting as themselves.
+    User(UserId),
+    /// An anonymous admin, posting as the group itself.
+    AnonymousAdmin,
+    /// A chat, such as a channel's own post or a user posting in a group as their channel.
+    Chat(ChatId),
+    /// No sender at all, as in some service messages.
+    Unknown,
+}
+
+/// Classifies the sender of a message.
+///
+/// Messages sent on behalf of a chat carry a placeholder `from` user shared by every
+/// such message (e.g. `@GroupAnonymousBot`), so `sender_chat` is checked first and the
+/// placeholder is never treated as a real user.
+///
+/// # Arguments
+/// * `from` - The message's `from` user, if any.
+/// * `sender_chat` - The chat the message was sent on behalf of, if any.
+/// * `chat_id` - The chat the message was posted in.
+/// * `is_channel` - Whether that chat is a channel.
+pub fn classify_sender(
+    from: Option<UserId>,
+    sender_chat: Option<ChatId>,
+    chat_id: ChatId,
+    is_channel: bool,
+) -> MessageSender {
+    match sender_chat {
+        Some(sender_chat) if sender_chat == chat_id && !is_channel => MessageSender::AnonymousAdmin,
+        Some(sender_chat) => MessageSender::Chat(sender_chat),
+        None => from.map_or(MessageSender::Unknown, MessageSender::User),
+    }
+}
+
+/// Classifies the sender of a message, see `classify_sender`.
+pub fn message_sender(msg: &Message) -> MessageSender {
+    classify_sender(
+        msg.from().map(|user| user.id),
+        msg.sender_chat().map(|chat| chat.id),
+        msg.chat.id,
+        msg.chat.is_channel(),
+    )
+}
+
+/// Determines whether a message is an automatic forward from the chat's linked channel.
+///
+/// In a discussion group, every post in the linked channel is reflected into the group
+/// as an automatic forward whose `sender_chat` is the channel. Such posts are official
+/// content and should not be scored like user messages.
+///
+/// # Arguments
+/// * `sender_chat_id` - The chat the message was sent on behalf of, if any.
+/// * `is_automatic_forward` - Whether Telegram marked the message as an automatic forward.
+/// * `linked_chat_id` - The channel linked to the group, if any.
+///
+/// # Returns
+/// * `bool` - `true` if the message is an automatic forward from the linked channel.
+pub fn is_linked_channel_post(
+    sender_chat_id: Option<ChatId>,
+    is_automatic_forward: bool,
+    linked_chat_id: Option<ChatId>,
+) -> bool {
+    is_automatic_forward && sender_chat_id.is_some() && sender_chat_id == linked_chat_id
+}
+
+/// Mute duration used when `/mute` is given no duration.
+pub const DEFAULT_MUTE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Longest mute Telegram applies as given; longer restrictions count as permanent.
+const MAX_MUTE_DURATION: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+/// Parses a duration such as `90s`, `30m`, `2h` or `7d`.
+///
+/// # Arguments
+/// * `spec` - A positive whole number followed by `s`, `m`, `h` or `d`.
+///
+/// # Returns
+/// * `Option<Duration>` - The duration, or `None` if `spec` is not one.
+pub fn parse_duration(spec: &str) -> Option<Duration> {
+    let spec = spec.trim();
+    let multiplier = match spec.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    match spec[..spec.len() - 1].parse::<u64>() {
+        Ok(amount) if amount > 0 => Some(Duration::from_secs(amount.saturating_mul(multiplier))),
+        _ => None,
+    }
+}
+
+/// Parses a mute duration such as `90s`, `30m`, `2h` or `7d`.
+///
+/// # Arguments
+/// * `spec` - The command argument; empty selects `DEFAULT_MUTE_DURATION`.
+///
+/// # Returns
+/// * `Result<Duration, String>` - The duration, or a human-readable error message.
+pub fn parse_mute_duration(spec: &str) -> Result<Duration, String> {
+    if spec.trim().is_empty() {
+        return Ok(DEFAULT_MUTE_DURATION);
+    }
+    let usage = "Usage: /mute [duration], e.g. /mute 30m, /mute 2h or /mute 7d (default 1h).";
+    let duration = parse_duration(spec).ok_or_else(|| usage.to_string())?;
+    if duration > MAX_MUTE_DURATION {
+        return Err("Mutes can last at most 366 days.".to_string());
+    }
+    Ok(duration)
+}
+
+/// The usage of `/add_rule`, shown when its arguments cannot be parsed.
+pub const ADD_RULE_USAGE: &str =
+    "Usage: /add_rule <keyword> <score> [category]. Quote keywords with spaces, e.g. /add_rule \"free money\" 10";
+
+/// Parses the arguments of `/add_rule` into a keyword, a score and an optional category.
+///
+/// A keyword in double quotes (straight or curly, as some Telegram clients type them)
+/// may contain spaces. Without quotes, everything before the score is the keyword, so
+/// `free money 10` also adds `free money`; the score is the last word, or the one
+/// before it if the last word is a category.
+///
+/// # Arguments
+/// * `input` - The command argument.
+///
+/// # Returns
+/// * `Result<(String, f32, Option<String>), String>` - The keyword, score and category,
+///   or a human-readable error message.
+pub fn parse_add_rule(input: &str) -> Result<(String, f32, Option<String>), String> {
+    let input = input.trim();
+    let (keyword, rest) = match input.strip_prefix(['"', '“']) {
+        Some(quoted) => {
+            let (keyword, rest) =
+                quoted.split_once(['"', '”']).ok_or_else(|| format!("The keyword is missing its closing quote. {}", ADD_RULE_USAGE))?;
+            (keyword.trim().to_string(), rest.split_whitespace().collect::<Vec<_>>())
+        }
+        None => {
+            let words: Vec<&str> = input.split_whitespace().collect();
+            let last_is_score = words.last().is_some_and(|word| word.parse::<f32>().is_ok());
+            let score_at = if words.len() > 2 && !last_is_score { words.len() - 2 } else { words.len().saturating_sub(1) };
+            (words[..score_at].join(" "), words[score_at..].to_vec())
+        }
+    };
+    if keyword.is_empty() {
+        return Err(ADD_RULE_USAGE.to_string());
+    }
+    let (score, category) = match rest.as_slice() {
+        [score] => (*score, None),
+        [score, category] => (*score, Some(category.to_string())),
+        _ => return Err(ADD_RULE_USAGE.to_string()),
+    };
+    match score.parse::<f32>() {
+        Ok(score) if score.is_finite() => Ok((keyword, score, category)),
+        _ => Err(format!("Invalid score '{}'; it must be a number such as 10 or -2.5. {}", score, ADD_RULE_USAGE)),
+    }
+}
+
+/// Parses the `keyword:score` pairs of a `/add_rules` batch.
+///
+/// Pairs are separated by newlines or commas; blank entries are skipped. The score
+/// follows the last colon, so keywords such as `http://` may contain colons.
+///
+/// # Arguments
+/// * `input` - The command argument.
+///
+/// # Returns
+/// * `(Vec<(String, f32)>, Vec<String>)` - The parsed pairs in order, and the
+///   entries that could not be parsed.
+pub fn parse_rule_batch(input: &str) -> (Vec<(String, f32)>, Vec<String>) {
+    let mut rules = Vec::new();
+    let mut invalid = Vec::new();
+    for entry in input.split(['\n', ',']).map(str::trim).filter(|entry| !entry.is_empty()) {
+        match entry.rsplit_once(':') {
+            Some((keyword, score)) if !keyword.trim().is_empty() => match score.trim().parse::<f32>() {
+                Ok(score) if score.is_finite() => rules.push((keyword.trim().to_string(), score)),
+                _ => invalid.push(entry.to_string()),
+            },
+            _ => invalid.push(entry.to_string()),
+        }
+    }
+    (rules, invalid)
+}
+
+/// An action an admin can pick from the buttons of a spam notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationAction {
+    /// Ban the sender from the chat.
+    Ban,
+    /// Mute the sender for `DEFAULT_MUTE_DURATION`.
+    Mute,
+    /// Leave the sender alone.
+    Dismiss,
+}
+
+/// The callback data of a spam notification button.
+///
+/// Notifications are usually sent to admins privately, so the button has to carry
+/// the chat the spam was posted in as well as the sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpamCallback {
+    /// What the admin chose.
+    pub action: NotificationAction,
+    /// The chat the spam was posted in.
+    pub chat_id: ChatId,
+    /// The sender of the spam.
+    pub user_id: UserId,
+}
+
+impl SpamCallback {
+    /// Encodes the callback as `spam:<action>:<chat_id>:<user_id>`, well within
+    /// Telegram's 64-byte limit for callback data.
+    pub fn encode(&self) -> String {
+        let action = match self.action {
+            NotificationAction::Ban => "ban",
+            NotificationAction::Mute => "mute",
+            NotificationAction::Dismiss => "dismiss",
+        };
+        format!("spam:{}:{}:{}", action, self.chat_id.0, self.user_id.0)
+    }
+
+    /// Parses callback data produced by `encode`.
+    ///
+    /// # Returns
+    /// * `Option<SpamCallback>` - The callback, or `None` if the data is not a spam notification button.
+    pub fn parse(data: &str) -> Option<Self> {
+        let parts: Vec<&str> = data.split(':').collect();
+        if parts.len() != 4 || parts[0] != "spam" {
+            return None;
+        }
+        let action = match parts[1] {
+            "ban" => NotificationAction::Ban,
+            "mute" => NotificationAction::Mute,
+            "dismiss" => NotificationAction::Dismiss,
+            _ => return None,
+        };
+        Some(Self {
+            action,
+            chat_id: ChatId(parts[2].parse().ok()?),
+            user_id: UserId(parts[3].parse().ok()?),
+        })
+    }
+}
+
+/// Builds the Ban / Mute / Dismiss buttons attached to a spam notification.
+///
+/// # Arguments
+/// * `chat_id` - The chat the spam was posted in.
+/// * `user_id` - The sender of the spam.
+pub fn spam_action_keyboard(chat_id: ChatId, user_id: UserId) -> InlineKeyboardMarkup {
+    let button = |label: &str, action: NotificationAction| {
+        InlineKeyboardButton::callback(label.to_string(), SpamCallback { action, chat_id, user_id }.encode())
+    };
+    InlineKeyboardMarkup::new([[
+        button("Ban", NotificationAction::Ban),
+        button("Mute", NotificationAction::Mute),
+        button("Dismiss", NotificationAction::Dismiss),
+    ]])
+}
+
+/// Prefix of the callback data of the keyword buttons offered by `/report <text>`.
+pub const ADD_RULE_PREFIX: &str = "addrule:";
+
+/// Builds one button per suggested keyword, each adding it as a rule when pressed.
+///
+/// # Arguments
+/// * `keywords` - The suggested keywords, short enough for Telegram's 64-byte callback data.
+pub fn add_rule_keyboard(keywords: &[String]) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([keywords
+        .iter()
+        .map(|keyword| InlineKeyboardButton::callback(format!("Add '{}'", keyword), format!("{}{}", ADD_RULE_PREFIX, keyword)))
+        .collect::<Vec<_>>()])
+}
+
+/// Maximum length of a Telegram message, in characters.
+pub const TELEGRAM_MESSAGE_LIMIT: usize = 4096;
+
+/// Joins lines into as few messages as possible without exceeding `max_len` characters each.
+///
+/// A single line longer than `max_len` is split on character boundaries.
+///
+/// # Arguments
+/// * `lines` - The lines to send, in order.
+/// * `max_len` - The maximum number of characters per message.
+///
+/// # Returns
+/// * `Vec<String>` - The message chunks, each at most `max_len` characters long.
+pub fn split_message(lines: &[String], max_len: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+    for line in lines {
+        let chars: Vec<char> = line.chars().collect();
+        for piece in chars.chunks(max_len.max(1)) {
+            let separator = if current.is_empty() { 0 } else { 1 };
+            if current_len + separator + piece.len() > max_len {
+                chunks.push(std::mem::take(&mut current));
+                current_len = 0;
+            } else if separator == 1 {
+                current.push('\n');
+                current_len += 1;
+            }
+            current.extend(piece);
+            current_len += piece.len();
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Formats a venue for scoring by the text pipeline.
+///
+/// # Arguments
+/// * `title` - The venue name.
+/// * `address` - The venue address.
+///
+/// # Returns
+/// * `String` - The title and address on separate lines.
+pub fn venue_text(title: &str, address: &str) -> String {
+    format!("{}\n{}", title, address)
+}
+
+/// Extracts the text of a message that the spam pipeline should score.
+///
+/// Plain text and the captions of photos, videos and other media are scored as-is,
+/// and venues are scored by their title and address. A bare location has no text and
+/// yields an empty string, so it still reaches the location flood check, as do
+/// stickers, GIFs and files without a caption, which are checked by `crate::media`.
+/// Other media without a caption yields `None` and is skipped.
+///
+/// # Arguments
+/// * `msg` - The incoming message.
+///
+/// # Returns
+/// * `Option<String>` - The text to score, or `None` for messages that are not checked.
+pub fn message_text(msg: &Message) -> Option<String> {
+    if let Some(text) = msg.text().or_else(|| msg.caption()) {
+        Some(text.to_string())
+    } else if let Some(venue) = msg.venue() {
+        Some(venue_text(&venue.title, &venue.address))
+    } else if msg.location().is_some() || message_media(msg).is_some() {
+        Some(String::new())
+    } else {
+        None
+    }
+}
+
+/// Treats Telegram's "message to delete not found" error as success.
+///
+/// A message that a user already deleted themselves is gone either way, so this
+/// case is logged at debug level instead of being reported as a failure. All other
+/// errors are passed through unchanged.
+///
+/// # Arguments
+/// * `result` - The result of a `delete_message` request.
+///
+/// # Returns
+/// * `Result<()>` - `Ok(())` if the message was deleted or was already gone,
+///   otherwise the original `RequestError`.
+pub fn ignore_message_not_found<T>(result: Result<T, RequestError>) -> Result<(), RequestError> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(RequestError::Api(ApiError::MessageToDeleteNotFound)) => {
+            log::debug!("Message to delete was already gone");
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Deletes a message, treating an already-deleted message as success and retrying
+/// it if Telegram rate-limits the request.
+///
+/// # Arguments
+/// * `bot` - A reference to the Telegram bot instance.
+/// * `chat_id` - The ID of the chat containing the message.
+/// * `message_id` - The ID of the message to delete.
+///
+/// # Returns
+/// * `Result<()>` - A `Result` indicating success or a `RequestError` if
+///   the deletion fails for any reason other than the message being gone.
+pub async fn delete_message(bot: &Bot, chat_id: ChatId, message_id: MessageId) -> Result<(), RequestError> {
+    ignore_message_not_found(with_retry(|| bot.delete_message(chat_id, message_id)).await)
+}
+
+/// Formats what the bot knows about a user for admins.
+///
+/// Used by `/score` and by spam notifications so both views show the same details.
+///
+/// # Arguments
+/// * `user_id` - The ID of the user.
+/// * `spam_score` - The user's current spam score.
+/// * `notes` - Moderator notes attached to the user in the chat.
+///
+/// # Returns
+/// * `String` - The formatted report.
+pub fn format_user_report(user_id: &str, spam_score: i32, notes: &[String]) -> String {
+    let mut report = format!("Sender ID: {}\nSpam Score: {}", user_id, spam_score);
+    if !notes.is_empty() {
+        report.push_str("\nNotes:");
+        for note in notes {
+            report.push_str(&format!("\n- {}", note));
+        }
+    }
+    report
+}
+
+/// Formats the `/whois` reply for a sender.
+///
+/// # Arguments
+/// * `record` - The sender's record, as returned by `RuleManager::get_sender_record`.
+///
+/// # Returns
+/// * `String` - The score, messages, warnings, whitelisting and bans, one per line.
+pub fn format_sender_record(record: &SenderRecord) -> String {
+    if !record.known && !record.whitelisted && record.bans.is_empty() {
+        return format!("The bot has no record of user {}.", record.user_id);
+    }
+    let mut lines = vec![
+        format!("User {}", record.user_id),
+        format!("Spam score: {}", record.spam_score),
+        format!("Messages: {}", record.message_count),
+        format!("Warnings: {}", record.warnings),
+        format!("Whitelisted: {}", if record.whitelisted { "yes" } else { "no" }),
+    ];
+    if record.bans.is_empty() {
+        lines.push("Bans: none".to_string());
+    } else {
+        lines.push("Bans:".to_string());
+        for (chat_id, reason, banned_at) in &record.bans {
+            let banned_at = chrono::DateTime::<chrono::Utc>::from_timestamp(*banned_at, 0)
+                .map_or_else(|| banned_at.to_string(), |time| time.format("%Y-%m-%d %H:%M UTC").to_string());
+            lines.push(format!("- chat {} on {}: {}", chat_id, banned_at, reason));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Describes the stored rules a message matched, for admin notifications.
+///
+/// # Arguments
+/// * `matches` - `(keyword, score)` pairs as returned by `RuleManager::matched_rules`.
+///
+/// # Returns
+/// * `String` - e.g. `Matched: 'crypto' (+5), 'http' (+3)`.
+pub fn format_matches(matches: &[(String, f32)]) -> String {
+    let matches: Vec<String> = matches.iter().map(|(keyword, score)| format!("'{}' (+{})", keyword, score)).collect();
+    format!("Matched: {}", matches.join(", "))
+}
+
+/// Maximum length, in characters, of the matched rules line of a score breakdown.
+const BREAKDOWN_MATCHES_LIMIT: usize = 1000;
+
+/// Explains how a message was scored, for the `/report` reply.
+///
+/// Lists the score against the threshold, the Lua script's share and the stored
+/// rules that matched. Matches beyond `BREAKDOWN_MATCHES_LIMIT` characters are
+/// only counted, so the breakdown always fits in a Telegram message.
+///
+/// # Arguments
+/// * `evaluation` - The message's evaluation, as returned by `RuleManager::evaluate`.
+/// * `threshold` - The score at which a message counts as spam.
+///
+/// # Returns
+/// * `String` - e.g. `Score: 8 (report threshold 5)\nSpam: true\nLua script: +3\nMatched: 'crypto' (+5)`.
+pub fn format_score_breakdown(evaluation: &RuleEvaluation, threshold: f32) -> String {
+    let mut lines = vec![
+        format!("Score: {} (report threshold {})", evaluation.total, threshold),
+        format!("Spam: {}", evaluation.total >= threshold),
+    ];
+    if evaluation.lua_score != 0.0 {
+        lines.push(format!("Lua script: {:+}", evaluation.lua_score));
+    }
+    lines.push(matched_rules_line(&evaluation.matches));
+    lines.join("\n")
+}
+
+/// Lists the stored rules a message matched, shortened to `BREAKDOWN_MATCHES_LIMIT` characters.
+fn matched_rules_line(matches: &[(String, f32)]) -> String {
+    if matches.is_empty() {
+        return "Matched: no stored rules".to_string();
+    }
+    let mut shown = 0;
+    let mut length = 0;
+    for (keyword, score) in matches {
+        length += format!(", '{}' (+{})", keyword, score).chars().count();
+        if shown > 0 && length > BREAKDOWN_MATCHES_LIMIT {
+            break;
+        }
+        shown += 1;
+    }
+    let mut matched = truncate_chars(&format_matches(&matches[..shown]), BREAKDOWN_MATCHES_LIMIT);
+    if shown < matches.len() {
+        matched.push_str(&format!(" and {} more", matches.len() - shown));
+    }
+    matched
+}
+
+/// Explains how `/testmessage` scored a sample, as a message check would score it
+/// from an established sender.
+///
+/// # Arguments
+/// * `evaluation` - The sample's evaluation, as returned by `RuleManager::evaluate`.
+/// * `detectors` - The built-in detectors' contributions, as `(detector name, score)` pairs.
+/// * `threshold` - The chat's spam threshold.
+/// * `safe_pattern` - The chat's safe pattern the sample contains, if any.
+///
+/// # Returns
+/// * `String` - The total score against the threshold, the verdict, the Lua script's
+///   share, the detectors that fired, the matched stored rules and the safe pattern.
+pub fn format_test_result(
+    evaluation: &RuleEvaluation,
+    detectors: &[(&str, f32)],
+    threshold: f32,
+    safe_pattern: Option<&str>,
+) -> String {
+    let raw = evaluation.total + detectors.iter().map(|(_, score)| score).sum::<f32>();
+    let total = net_score(raw, safe_pattern.is_some());
+    let mut lines = vec![
+        format!("Score: {} (spam threshold {})", total, threshold),
+        format!("Would be flagged: {}", if total >= threshold { "yes" } else { "no" }),
+    ];
+    if evaluation.lua_score != 0.0 {
+        lines.push(format!("Lua script: {:+}", evaluation.lua_score));
+    }
+    let fired: Vec<String> = detectors
+        .iter()
+        .filter(|(_, score)| *score != 0.0)
+        .map(|(name, score)| format!("{} ({:+})", name, score))
+        .collect();
+    if !fired.is_empty() {
+        lines.push(format!("Detectors: {}", fired.join(", ")));
+    }
+    lines.push(matched_rules_line(&evaluation.matches));
+    if let Some(pattern) = safe_pattern {
+        lines.push(format!("Safe pattern: '{}' (score {} capped at 0)", pattern, raw));
+    }
+    lines.join("\n")
+}
+
+/// Formats the `/report` reply for a reported message, shortening the quoted text
+/// so the reply fits in a single Telegram message.
+///
+/// # Arguments
+/// * `text` - The reported text.
+/// * `breakdown` - The score breakdown from `format_score_breakdown`.
+///
+/// # Returns
+/// * `String` - The reply, at most `TELEGRAM_MESSAGE_LIMIT` characters long.
+pub fn format_report_reply(text: &str, breakdown: &str) -> String {
+    let prefix = "Reported: ";
+    let budget = TELEGRAM_MESSAGE_LIMIT.saturating_sub(prefix.len() + 1 + breakdown.chars().count());
+    format!("{}{}\n{}", prefix, truncate_chars(text, budget), breakdown)
+}
+
+/// Characters of a flagged message's text shown by `/recent_spam`.
+const RECENT_SPAM_TEXT_LIMIT: usize = 200;
+
+/// Formats a flagged message as one `/recent_spam` line, with its text shortened.
+///
+/// # Arguments
+/// * `message` - The flagged message.
+///
+/// # Returns
+/// * `String` - e.g. `2024-05-01 12:00 UTC, user 7, score 6: buy crypto now`.
+pub fn format_flagged_message(message: &FlaggedMessage) -> String {
+    let flagged_at = chrono::DateTime::<chrono::Utc>::from_timestamp(message.created_at, 0)
+        .map_or_else(|| message.created_at.to_string(), |time| time.format("%Y-%m-%d %H:%M UTC").to_string());
+    let text = truncate_chars(&message.text.replace('\n', " "), RECENT_SPAM_TEXT_LIMIT);
+    format!("{}, user {}, score {}: {}", flagged_at, message.user_id, message.score, text)
+}
+
+/// Formats a chat's welcome message for members who just joined, as Telegram HTML.
+///
+/// The text is escaped and each `{name}` is replaced by mentions of the new members, so
+/// one message greets everyone who joined together.
+///
+/// # Arguments
+/// * `template` - The chat's welcome message, as set with `/set_welcome`.
+/// * `members` - `(user_id, name)` pairs of the members to greet.
+///
+/// # Returns
+/// * `String` - The message, to be sent with `ParseMode::Html`.
+pub fn format_welcome(template: &str, members: &[(UserId, String)]) -> String {
+    let mentions = members
+        .iter()
+        .map(|(user_id, name)| html::user_mention(user_id.0 as i64, name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    html::escape(template).replace("{name}", &mentions)
+}
+
+/// Formats a sender's score events for `/history`, one line per event.
+///
+/// # Arguments
+/// * `events` - The events, oldest first.
+///
+/// # Returns
+/// * `Vec<String>` - e.g. `2024-05-01 12:00 UTC: +3 (spam)`.
+pub fn format_score_history(events: &[ScoreEvent]) -> Vec<String> {
+    events
+        .iter()
+        .map(|event| {
+            let changed_at = chrono::DateTime::<chrono::Utc>::from_timestamp(event.created_at, 0)
+                .map_or_else(|| event.created_at.to_string(), |time| time.format("%Y-%m-%d %H:%M UTC").to_string());
+            format!("{}: {:+} ({})", changed_at, event.delta, event.reason)
+        })
+        .collect()
+}
+
+/// Formats the `/top_spammers` leaderboard, one numbered line per sender.
+///
+/// # Arguments
+/// * `senders` - `(user_id, spam_score)` pairs, highest score first.
+///
+/// # Returns
+/// * `Vec<String>` - e.g. `1. user 7: spam score 12`.
+pub fn format_top_senders(senders: &[(String, i32)]) -> Vec<String> {
+    senders
+        .iter()
+        .enumerate()
+        .map(|(rank, (user_id, spam_score))| format!("{}. user {}: spam score {}", rank + 1, user_id, spam_score))
+        .collect()
+}
+
+/// Shortens `text` to at most `max_chars` characters, marking a cut with an ellipsis.
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
     }
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
 }
 
 /// Notifies administrators about a detected spam message.
 ///
 /// Attempts to send a notification to all admins in a group chat with details
-/// of the spam message, including the text, sender ID, and spam score. In private
+/// of the spam message, including the text, sender ID, spam score, the stored
+/// rules it matched, and any moderator notes about the sender. In private
 /// chats, the notification is sent to the same chat. If fetching admins fails
 /// or no admins are found, a fallback notification is sent in the group chat.
+/// The notification carries Ban, Mute and Dismiss buttons for the sender, see
+/// `spam_action_keyboard`.
 ///
 /// # Arguments
-/// * `bot` - A reference to the Telegram bot instance.
+/// * `bot` - The Telegram API the notification is sent through.
 /// * `chat_id` - The ID of the chat where the spam was detected.
 /// * `text` - The text of the spam message.
 /// * `rule_manager` - A reference to the `RuleManager` for retrieving sender scores.
 /// * `user_id` - The ID of the sender of the spam message.
+/// * `deleted` - Whether the bot deleted the message, in which case the text in the
+///   notification is the only remaining copy.
+/// * `admin_cache` - The shared cache of chat administrators.
 ///
 /// # Returns
 /// * `Result<()>` - A `Result` indicating success or a `RequestError` if
@@ -68,39 +1324,120 @@ pub async fn is_admin(bot: &Bot, msg: &Message) -> Result<bool, RequestError> {
 /// # Notes
 /// * Logs the notification process and any errors for debugging.
 /// * Uses a fallback mechanism to ensure visibility if private notifications fail.
-pub async fn notify_admins(
-    bot: &Bot,
+pub async fn notify_admins<A: ChatApi>(
+    bot: &A,
     chat_id: ChatId,
     text: &str,
     rule_manager: &RuleManager,
     user_id: &str,
+    deleted: bool,
+    admin_cache: &AdminCache,
 ) -> Result<(), RequestError> {
-    let spam_score = rule_manager.get_sender_score(user_id);
-    let message = format!(
-        "Spam detected: {}\nSender ID: {}\nSpam Score: {}",
-        text, user_id, spam_score
+    let spam_score = rule_manager.get_sender_score(chat_id.0, user_id);
+    let notes = rule_manager.get_notes(chat_id.0, user_id).unwrap_or_else(|e| {
+        log::error!("Failed to load notes for user {}: {}", user_id, e);
+        Vec::new()
+    });
+    let heading = if deleted { "Spam detected and deleted" } else { "Spam detected" };
+    let mut message = format!(
+        "{}: {}\n{}",
+        heading,
+        text,
+        format_user_report(user_id, spam_score, &notes)
     );
+    let matches = rule_manager.matched_rules(text);
+    if !matches.is_empty() {
+        message = format!("{}\n{}", message, format_matches(&matches));
+    }
+    let keyboard = user_id.parse().ok().map(|id| spam_action_keyboard(chat_id, UserId(id)));
+    send_to_admins(bot, chat_id, &message, keyboard, admin_cache).await
+}
+
+/// Where admin notifications about a chat are delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationRoute {
+    /// Sent in the chat itself.
+    SameChat,
+    /// Sent privately to each admin, falling back to the chat itself.
+    AdminsPrivately,
+}
+
+/// Decides where admin notifications about a chat are delivered.
+///
+/// | Chat       | ID                  | Route             |
+/// |------------|---------------------|-------------------|
+/// | Private    | positive            | `SameChat`        |
+/// | Group      | negative            | `AdminsPrivately` |
+/// | Supergroup | `-100` prefix       | `AdminsPrivately` |
+/// | Channel    | `-100` prefix       | `AdminsPrivately` |
+///
+/// `ChatId::is_group` only covers basic groups, so supergroups, where most spam
+/// is, must not be told apart from private chats with it. Supergroups and
+/// channels share an ID range and are routed alike.
+pub fn notification_route(chat_id: ChatId) -> NotificationRoute {
+    if chat_id.is_user() {
+        NotificationRoute::SameChat
+    } else {
+        NotificationRoute::AdminsPrivately
+    }
+}
+
+/// Sends a message to every administrator of a chat.
+///
+/// In groups, supergroups and channels the message is sent privately to each admin;
+/// if fetching the admins fails or none are found, it is sent in the chat instead.
+/// In private chats the message is sent to the same chat. See `notification_route`.
+///
+/// # Arguments
+/// * `bot` - The Telegram API the message is sent through.
+/// * `chat_id` - The ID of the chat whose admins are notified.
+/// * `message` - The notification text.
+/// * `admin_cache` - The shared cache of chat administrators.
+///
+/// # Returns
+/// * `Result<()>` - A `Result` indicating success or a `RequestError` if
+///   sending the fallback notification fails.
+pub async fn message_admins<A: ChatApi>(
+    bot: &A,
+    chat_id: ChatId,
+    message: &str,
+    admin_cache: &AdminCache,
+) -> Result<(), RequestError> {
+    send_to_admins(bot, chat_id, message, None, admin_cache).await
+}
+
+/// Sends a message, with optional inline buttons, to every administrator of a chat.
+///
+/// See `message_admins` for where the message is delivered.
+async fn send_to_admins<A: ChatApi>(
+    bot: &A,
+    chat_id: ChatId,
+    message: &str,
+    keyboard: Option<InlineKeyboardMarkup>,
+    admin_cache: &AdminCache,
+) -> Result<(), RequestError> {
+    let send = |target: ChatId| bot.send_text(target, message, keyboard.clone());
     log::info!("Attempting to notify admins in chat {}", chat_id);
-    if chat_id.is_group() {
-        let admins_result = bot.get_chat_administrators(chat_id).await;
+    if notification_route(chat_id) == NotificationRoute::AdminsPrivately {
+        let admins_result = admin_cache.admins(bot, chat_id).await;
         match admins_result {
             Ok(admins) => {
                 log::info!(
                     "Found {} admins: {:?}",
                     admins.len(),
-                    admins.iter().map(|a| a.user.id).collect::<Vec<_>>()
+                    admins.iter().map(|a| a.id).collect::<Vec<_>>()
                 );
                 if admins.is_empty() {
                     log::warn!(
                         "No admins found in chat {}. Sending fallback notification in group.",
                         chat_id
                     );
-                    bot.send_message(chat_id, &message).await?;
+                    send(chat_id).await?;
                 } else {
                     for admin in admins {
-                        let admin_user_id = admin.user.id;
+                        let admin_user_id = admin.id;
                         log::info!("Attempting to notify admin {}", admin_user_id);
-                        match bot.send_message(admin_user_id, &message).await {
+                        match send(admin_user_id.into()).await {
                             Ok(_) => log::info!("Notification sent to admin {}", admin_user_id),
                             Err(e) => log::error!(
                                 "Failed to send notification to admin {}: {}",
@@ -113,11 +1450,494 @@ pub async fn notify_admins(
             }
             Err(e) => {
                 log::error!("Failed to fetch admins for chat {}: {}. Sending fallback notification in group.", chat_id, e);
-                bot.send_message(chat_id, &message).await?;
+                send(chat_id).await?;
             }
         }
     } else {
-        bot.send_message(chat_id, message).await?;
+        send(chat_id).await?;
     }
     Ok(())
 }
+
+/// Unit tests for the `utils` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detectors::Detectors;
+    use crate::flood::LocationFlood;
+    use crate::metrics::format_prometheus;
+    use crate::telegram::mock::{ApiCall, MockApi};
+    use tempfile::NamedTempFile;
+
+    /// Creates a rule manager backed by a temporary database.
+    fn test_rule_manager() -> (NamedTempFile, RuleManager) {
+        let temp_file = NamedTempFile::new().unwrap();
+        let manager = RuleManager::new(temp_file.path().to_str().unwrap()).unwrap();
+        (temp_file, manager)
+    }
+
+    #[tokio::test]
+    async fn test_spam_notification_is_sent_privately_to_each_admin() {
+        let (_temp_file, manager) = test_rule_manager();
+        manager.add_rule("crypto".to_string(), 5.0).unwrap();
+        let api = MockApi::with_admins(vec![
+            CachedAdmin { id: UserId(1), is_bot: false },
+            CachedAdmin { id: UserId(2), is_bot: false },
+        ]);
+        let cache = AdminCache::new(Duration::from_secs(60));
+        notify_admins(&api, ChatId(-100123), "free crypto", &manager, "42", true, &cache).await.unwrap();
+
+        let calls = api.calls();
+        assert_eq!(calls.len(), 2);
+        for (call, admin) in calls.iter().zip([1, 2]) {
+            match call {
+                ApiCall::Send { chat_id, text, keyboard } => {
+                    assert_eq!(*chat_id, ChatId(admin));
+                    assert!(text.starts_with("Spam detected and deleted: free crypto"));
+                    assert!(text.contains("Matched: 'crypto' (+5)"));
+                    assert!(keyboard);
+                }
+                other => panic!("unexpected call {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spam_notification_falls_back_to_the_chat() {
+        let (_temp_file, manager) = test_rule_manager();
+        let api = MockApi::default();
+        let cache = AdminCache::new(Duration::from_secs(60));
+        notify_admins(&api, ChatId(-100123), "spam", &manager, "42", false, &cache).await.unwrap();
+        assert!(matches!(api.calls().as_slice(), [ApiCall::Send { chat_id: ChatId(-100123), .. }]));
+    }
+
+    #[tokio::test]
+    async fn test_auto_ban_bans_and_tells_the_admins() {
+        let (_temp_file, manager) = test_rule_manager();
+        let metrics = Metrics::new();
+        let config = Config::default();
+        let api = MockApi::with_admins(vec![CachedAdmin { id: UserId(1), is_bot: false }]);
+        let cache = AdminCache::new(Duration::from_secs(60));
+        auto_ban(&api, ChatId(-100123), UserId(42), 10, &config, &cache, &metrics, &manager).await;
+
+        let calls = api.calls();
+        assert_eq!(calls[0], ApiCall::Ban { chat_id: ChatId(-100123), user_id: UserId(42) });
+        assert!(matches!(&calls[1], ApiCall::Send { chat_id: ChatId(1), text, .. } if text.contains("auto-banned")));
+        assert_eq!(calls.len(), 2);
+        assert!(manager.get_ban(-100123, "42").is_some());
+        assert!(format_prometheus(&metrics, 0).contains("auto_bans_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_auto_ban_needs_a_human_admin() {
+        let (_temp_file, manager) = test_rule_manager();
+        let api = MockApi::with_admins(vec![CachedAdmin { id: UserId(99), is_bot: true }]);
+        let cache = AdminCache::new(Duration::from_secs(60));
+        auto_ban(&api, ChatId(-100123), UserId(42), 10, &Config::default(), &cache, &Metrics::new(), &manager).await;
+
+        let calls = api.calls();
+        assert!(!calls.iter().any(|call| matches!(call, ApiCall::Ban { .. })));
+        assert!(matches!(&calls[..], [ApiCall::Send { text, .. }] if text.contains("not banned automatically")));
+        assert!(manager.get_ban(-100123, "42").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_admin_cache_reuses_admins_within_ttl() {
+        let cache = AdminCache::new(Duration::from_secs(60));
+        let fetches = std::cell::Cell::new(0);
+        let counter = &fetches;
+        let admin = CachedAdmin { id: UserId(7), is_bot: false };
+        let fetch = move || async move {
+            counter.set(counter.get() + 1);
+            Ok::<_, ()>(vec![admin])
+        };
+        let start = Instant::now();
+        assert_eq!(cache.get_or_fetch(ChatId(-1), start, fetch).await, Ok(vec![admin]));
+        assert_eq!(cache.get_or_fetch(ChatId(-1), start + Duration::from_secs(30), fetch).await, Ok(vec![admin]));
+        assert_eq!(fetches.get(), 1);
+        // Other chats and expired entries are fetched again.
+        cache.get_or_fetch(ChatId(-2), start, fetch).await.unwrap();
+        cache.get_or_fetch(ChatId(-1), start + Duration::from_secs(61), fetch).await.unwrap();
+        assert_eq!(fetches.get(), 3);
+    }
+
+    #[test]
+    fn test_spam_callback_round_trips_and_rejects_other_data() {
+        let callback = SpamCallback {
+            action: NotificationAction::Mute,
+            chat_id: ChatId(-1001234567890),
+            user_id: UserId(42),
+        };
+        assert_eq!(callback.encode(), "spam:mute:-1001234567890:42");
+        assert_eq!(SpamCallback::parse(&callback.encode()), Some(callback));
+        assert!(callback.encode().len() <= 64);
+        assert_eq!(SpamCallback::parse("spam:kick:-100:42"), None);
+        assert_eq!(SpamCallback::parse("spam:ban:-100"), None);
+        assert_eq!(SpamCallback::parse("spam:ban:group:42"), None);
+        assert_eq!(SpamCallback::parse("other:ban:-100:42"), None);
+    }
+
+    #[test]
+    fn test_spam_action_keyboard_has_one_button_per_action() {
+        let keyboard = spam_action_keyboard(ChatId(-100), UserId(7));
+        let labels: Vec<&str> = keyboard.inline_keyboard[0].iter().map(|button| button.text.as_str()).collect();
+        assert_eq!(labels, ["Ban", "Mute", "Dismiss"]);
+    }
+
+    #[test]
+    fn test_notification_throttle_suppresses_within_window_and_summarizes() {
+        let throttle = NotificationThrottle::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let chat = ChatId(-100);
+        assert_eq!(throttle.admit(chat, start), Some(0));
+        for i in 1..=15 {
+            assert_eq!(throttle.admit(chat, start + Duration::from_secs(i)), None);
+        }
+        // Other chats have their own window.
+        assert_eq!(throttle.admit(ChatId(-200), start), Some(0));
+        assert!(throttle.take_due_summaries(start + Duration::from_secs(30)).is_empty());
+
+        let summaries = throttle.take_due_summaries(start + Duration::from_secs(60));
+        assert_eq!(summaries, vec![(chat, 15)]);
+        // The summary starts a new window.
+        assert_eq!(throttle.admit(chat, start + Duration::from_secs(61)), None);
+        // Once it ends, the next notification reports what was suppressed since the summary.
+        assert_eq!(throttle.admit(chat, start + Duration::from_secs(120)), Some(1));
+    }
+
+    #[test]
+    fn test_notification_throttle_disabled_with_zero_window() {
+        let throttle = NotificationThrottle::new(Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(throttle.admit(ChatId(-100), now), Some(0));
+        assert_eq!(throttle.admit(ChatId(-100), now), Some(0));
+    }
+
+    #[test]
+    fn test_sender_cooldown_collapses_a_burst_into_one_summary() {
+        let cooldown = SenderCooldown::new(Duration::from_secs(60), 3);
+        let start = Instant::now();
+        let (chat, user) = (ChatId(-100), UserId(7));
+        let positions: Vec<usize> = (0..5)
+            .map(|i| cooldown.record(chat, user, "@spammer", start + Duration::from_secs(i)).0)
+            .collect();
+        assert_eq!(positions, [1, 2, 3, 4, 5]);
+        let scored: Vec<bool> = positions.iter().map(|&position| cooldown.counts_score(position)).collect();
+        assert_eq!(scored, [true, true, true, false, false]);
+        // Other senders, and the same sender elsewhere, have their own bursts.
+        assert_eq!(cooldown.record(chat, UserId(8), "@other", start), (1, None));
+        assert_eq!(cooldown.record(ChatId(-200), user, "@spammer", start), (1, None));
+        assert!(cooldown.take_due_summaries(start + Duration::from_secs(30)).is_empty());
+
+        // Single-message bursts were already reported in full.
+        let summaries = cooldown.take_due_summaries(start + Duration::from_secs(60));
+        assert_eq!(summaries, vec![(chat, "@spammer".to_string(), 5)]);
+        assert!(cooldown.take_due_summaries(start + Duration::from_secs(120)).is_empty());
+        assert_eq!(cooldown.record(chat, user, "@spammer", start + Duration::from_secs(121)), (1, None));
+    }
+
+    #[test]
+    fn test_sender_cooldown_reports_an_unsummarized_burst_and_can_be_disabled() {
+        let cooldown = SenderCooldown::new(Duration::from_secs(60), 0);
+        let start = Instant::now();
+        let (chat, user) = (ChatId(-100), UserId(7));
+        cooldown.record(chat, user, "@spammer", start);
+        cooldown.record(chat, user, "@spammer", start + Duration::from_secs(1));
+        // A new burst before the summary task ran hands back the previous one.
+        assert_eq!(cooldown.record(chat, user, "@spammer", start + Duration::from_secs(90)), (1, Some(2)));
+        assert!(cooldown.counts_score(100));
+
+        let disabled = SenderCooldown::new(Duration::ZERO, 3);
+        assert_eq!(disabled.record(chat, user, "@spammer", start), (1, None));
+        assert_eq!(disabled.record(chat, user, "@spammer", start), (1, None));
+    }
+
+    #[test]
+    fn test_notifications_reach_admins_privately_in_every_group_kind() {
+        // Private chat
+        assert_eq!(notification_route(ChatId(123456789)), NotificationRoute::SameChat);
+        // Basic group
+        assert_eq!(notification_route(ChatId(-123456789)), NotificationRoute::AdminsPrivately);
+        // Supergroup or channel
+        assert_eq!(notification_route(ChatId(-1001234567890)), NotificationRoute::AdminsPrivately);
+    }
+
+    #[test]
+    fn test_format_matches_lists_triggers_with_their_scores() {
+        let matches = vec![("crypto".to_string(), 5.0), ("http".to_string(), 2.5)];
+        assert_eq!(format_matches(&matches), "Matched: 'crypto' (+5), 'http' (+2.5)");
+    }
+
+    #[test]
+    fn test_score_breakdown_explains_the_verdict_and_fits_in_a_message() {
+        let evaluation = RuleEvaluation {
+            total: 8.0,
+            lua_score: 3.0,
+            matches: vec![("crypto".to_string(), 5.0)],
+        };
+        assert_eq!(
+            format_score_breakdown(&evaluation, 5.0),
+            "Score: 8 (report threshold 5)\nSpam: true\nLua script: +3\nMatched: 'crypto' (+5)"
+        );
+        let clean = RuleEvaluation::default();
+        assert_eq!(format_score_breakdown(&clean, 5.0), "Score: 0 (report threshold 5)\nSpam: false\nMatched: no stored rules");
+
+        let many = RuleEvaluation {
+            total: 500.0,
+            lua_score: 0.0,
+            matches: (0..500).map(|i| (format!("keyword{}", i), 1.0)).collect(),
+        };
+        let breakdown = format_score_breakdown(&many, 5.0);
+        assert!(breakdown.ends_with("more"));
+        assert!(breakdown.chars().count() < 1100);
+        let reply = format_report_reply(&"spam ".repeat(2000), &breakdown);
+        assert_eq!(reply.chars().count(), TELEGRAM_MESSAGE_LIMIT);
+        assert!(reply.starts_with("Reported: spam") && reply.ends_with("more"));
+        assert_eq!(format_report_reply("hi", "Spam: false"), "Reported: hi\nSpam: false");
+    }
+
+    #[test]
+    fn test_sender_record_lists_every_detail() {
+        let mut record = SenderRecord { user_id: "7".to_string(), ..Default::default() };
+        assert_eq!(format_sender_record(&record), "The bot has no record of user 7.");
+
+        record.known = true;
+        record.spam_score = 3;
+        record.message_count = 12;
+        record.warnings = 1;
+        assert!(format_sender_record(&record).ends_with("Warnings: 1\nWhitelisted: no\nBans: none"));
+        record.bans.push((-100, "auto-ban at spam score 5".to_string(), 1_714_564_800));
+        assert_eq!(
+            format_sender_record(&record),
+            "User 7\nSpam score: 3\nMessages: 12\nWarnings: 1\nWhitelisted: no\nBans:\n- chat -100 on 2024-05-01 12:00 UTC: auto-ban at spam score 5"
+        );
+    }
+
+    #[test]
+    fn test_flagged_message_is_one_short_line() {
+        let message = FlaggedMessage {
+            user_id: "7".to_string(),
+            text: format!("buy\ncrypto {}", "x".repeat(300)),
+            score: 6.5,
+            created_at: 1_714_564_800,
+        };
+        let line = format_flagged_message(&message);
+        assert!(line.starts_with("2024-05-01 12:00 UTC, user 7, score 6.5: buy crypto xxx"));
+        assert!(line.ends_with('…') && !line.contains('\n'));
+        assert_eq!(line.chars().count(), "2024-05-01 12:00 UTC, user 7, score 6.5: ".len() + 200);
+    }
+
+    #[test]
+    fn test_welcome_mentions_every_new_member() {
+        let members = [(UserId(7), "Ann".to_string()), (UserId(42), "<Bob>".to_string())];
+        assert_eq!(
+            format_welcome("Welcome, {name}! Read the rules & behave.", &members),
+            "Welcome, <a href=\"tg://user?id=7\">Ann</a>, <a href=\"tg://user?id=42\">&lt;Bob&gt;</a>! Read the rules &amp; behave."
+        );
+        assert_eq!(format_welcome("No <b>names</b> here", &members), "No &lt;b&gt;names&lt;/b&gt; here");
+    }
+
+    #[test]
+    fn test_score_history_shows_signed_deltas() {
+        let event = |delta, reason: &str| ScoreEvent {
+            user_id: "7".to_string(),
+            chat_id: -100,
+            delta,
+            reason: reason.to_string(),
+            created_at: 1_714_564_800,
+        };
+        assert_eq!(
+            format_score_history(&[event(3, "spam"), event(-3, "reset")]),
+            ["2024-05-01 12:00 UTC: +3 (spam)", "2024-05-01 12:00 UTC: -3 (reset)"]
+        );
+    }
+
+    #[test]
+    fn test_top_senders_are_numbered_from_one() {
+        let senders = [("7".to_string(), 12), ("42".to_string(), 3)];
+        assert_eq!(format_top_senders(&senders), ["1. user 7: spam score 12", "2. user 42: spam score 3"]);
+    }
+
+    #[test]
+    fn test_test_result_adds_the_detectors_to_the_rule_score() {
+        let evaluation = RuleEvaluation {
+            total: 3.0,
+            lua_score: 0.0,
+            matches: vec![("crypto".to_string(), 3.0)],
+        };
+        let detectors = [("links", 1.0), ("gibberish", 0.0), ("link_count", 3.0)];
+        assert_eq!(
+            format_test_result(&evaluation, &detectors, 5.0, None),
+            "Score: 7 (spam threshold 5)\nWould be flagged: yes\nDetectors: links (+1), link_count (+3)\nMatched: 'crypto' (+3)"
+        );
+        assert!(format_test_result(&RuleEvaluation::default(), &[], 5.0, None).contains("Would be flagged: no"));
+        let safe = format_test_result(&evaluation, &detectors, 5.0, Some("crypto talk"));
+        assert!(safe.starts_with("Score: 0 (spam threshold 5)\nWould be flagged: no"));
+        assert!(safe.ends_with("Safe pattern: 'crypto talk' (score 7 capped at 0)"));
+    }
+
+    #[test]
+    fn test_senders_posting_as_a_chat_are_not_users() {
+        let group = ChatId(-1001234567890);
+        let channel = ChatId(-1009876543210);
+        assert_eq!(classify_sender(Some(UserId(42)), None, group, false), MessageSender::User(UserId(42)));
+        // Anonymous admins and channels come with a shared placeholder user
+        assert_eq!(classify_sender(Some(UserId(1087968824)), Some(group), group, false), MessageSender::AnonymousAdmin);
+        assert_eq!(classify_sender(Some(UserId(136817688)), Some(channel), group, false), MessageSender::Chat(channel));
+        // A channel's own post
+        assert_eq!(classify_sender(None, Some(channel), channel, true), MessageSender::Chat(channel));
+        assert_eq!(classify_sender(None, None, group, false), MessageSender::Unknown);
+    }
+
+    #[test]
+    fn test_parse_mute_duration() {
+        assert_eq!(parse_mute_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_mute_duration(" 2h "), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_mute_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_mute_duration(""), Ok(DEFAULT_MUTE_DURATION));
+        assert!(parse_mute_duration("30").is_err());
+        assert!(parse_mute_duration("0m").is_err());
+        assert!(parse_mute_duration("-5m").is_err());
+        assert!(parse_mute_duration("abch").is_err());
+        assert!(parse_mute_duration("400d").is_err());
+        assert_eq!(parse_duration("400d"), Some(Duration::from_secs(400 * 24 * 60 * 60)));
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn test_add_rule_accepts_quoted_and_multi_word_keywords() {
+        let rule = |keyword: &str, score, category: Option<&str>| Ok((keyword.to_string(), score, category.map(String::from)));
+        assert_eq!(parse_add_rule("spam 10.0"), rule("spam", 10.0, None));
+        assert_eq!(parse_add_rule("airdrop 8 crypto"), rule("airdrop", 8.0, Some("crypto")));
+        assert_eq!(parse_add_rule("meetup -3"), rule("meetup", -3.0, None));
+        assert_eq!(parse_add_rule("\"free money\" 10"), rule("free money", 10.0, None));
+        assert_eq!(parse_add_rule(" “free  money” 10 crypto "), rule("free  money", 10.0, Some("crypto")));
+        assert_eq!(parse_add_rule("free money 10"), rule("free money", 10.0, None));
+        assert_eq!(parse_add_rule("free money 10 crypto"), rule("free money", 10.0, Some("crypto")));
+    }
+
+    #[test]
+    fn test_add_rule_rejects_malformed_arguments() {
+        for input in ["", "spam", "10", "\"\" 10", "\"free money\"", "\"free money\" 10 crypto extra"] {
+            assert_eq!(parse_add_rule(input), Err(ADD_RULE_USAGE.to_string()), "{}", input);
+        }
+        assert!(parse_add_rule("\"free money 10").unwrap_err().starts_with("The keyword is missing its closing quote."));
+        assert!(parse_add_rule("spam ten").unwrap_err().starts_with("Invalid score 'ten'"));
+        assert!(parse_add_rule("\"free money\" lots").unwrap_err().starts_with("Invalid score 'lots'"));
+        assert!(parse_add_rule("spam NaN").unwrap_err().starts_with("Invalid score 'NaN'"));
+    }
+
+    #[test]
+    fn test_parse_rule_batch() {
+        let (rules, invalid) = parse_rule_batch("airdrop:10\n casino : 4.5, http://:2,,\nfree money:oops\n:3\nnoscore");
+        assert_eq!(
+            rules,
+            vec![("airdrop".to_string(), 10.0), ("casino".to_string(), 4.5), ("http://".to_string(), 2.0)]
+        );
+        assert_eq!(invalid, vec!["free money:oops", ":3", "noscore"]);
+    }
+
+    #[test]
+    fn test_split_message_respects_limit() {
+        let lines: Vec<String> = (0..100).map(|i| format!("keyword{} => 5", i)).collect();
+        let chunks = split_message(&lines, 200);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 200));
+        assert_eq!(chunks.join("\n"), lines.join("\n"));
+        assert_eq!(split_message(&["abcdef".to_string()], 4), vec!["abcd", "ef"]);
+        assert!(split_message(&[], 10).is_empty());
+    }
+
+    #[test]
+    fn test_venue_with_spammy_title_is_scored() {
+        let detectors = Detectors::default();
+        assert!(detectors.score(&venue_text("FREE PRIZES at bit.ly/claim", "Main St 1")) > 0.0);
+        assert_eq!(detectors.score(&venue_text("Central Station", "Main St 1")), 0.0);
+    }
+
+    #[test]
+    fn test_normal_location_is_not_scored() {
+        let detectors = Detectors::default();
+        let flood = LocationFlood::default();
+        // A bare location has no text to score and a single one is no flood.
+        assert_eq!(detectors.score(""), 0.0);
+        assert_eq!(flood.check(1, 7, 0, Instant::now()), 0.0);
+    }
+
+    #[test]
+    fn test_destructive_actions_suppressed_without_human_admins() {
+        assert_eq!(effective_action(SpamAction::Ban, 0, 1), SpamAction::Notify);
+        assert_eq!(effective_action(SpamAction::Mute, 0, 1), SpamAction::Notify);
+        assert_eq!(effective_action(SpamAction::Notify, 0, 1), SpamAction::Notify);
+    }
+
+    #[test]
+    fn test_destructive_actions_allowed_with_enough_admins() {
+        assert_eq!(effective_action(SpamAction::Ban, 1, 1), SpamAction::Ban);
+        assert_eq!(effective_action(SpamAction::Mute, 2, 2), SpamAction::Mute);
+        assert_eq!(effective_action(SpamAction::Ban, 1, 2), SpamAction::Notify);
+    }
+
+    #[test]
+    fn test_linked_channel_post_is_exempted() {
+        let channel = Some(ChatId(-1001));
+        assert!(is_linked_channel_post(channel, true, channel));
+    }
+
+    #[test]
+    fn test_other_senders_are_not_exempted() {
+        let channel = Some(ChatId(-1001));
+        // A normal user posting the same text has no sender chat.
+        assert!(!is_linked_channel_post(None, false, channel));
+        // Another channel posting on its own behalf is still scored.
+        assert!(!is_linked_channel_post(Some(ChatId(-1002)), false, channel));
+        // Manual forwards from the linked channel are not automatic forwards.
+        assert!(!is_linked_channel_post(channel, false, channel));
+        // Groups without a linked channel never exempt anything.
+        assert!(!is_linked_channel_post(None, true, None));
+    }
+
+    #[test]
+    fn test_pending_confirmation_requires_repeat() {
+        let confirmations = PendingConfirmations::new(Duration::from_secs(60));
+        assert!(!confirmations.confirm(1, 7, "batchpardon 3"));
+        assert!(!confirmations.confirm(1, 8, "batchpardon 3"));
+        assert!(confirmations.confirm(1, 7, "batchpardon 3"));
+        // A confirmed action must be requested again.
+        assert!(!confirmations.confirm(1, 7, "batchpardon 3"));
+        // A different action replaces the pending one.
+        assert!(!confirmations.confirm(1, 7, "batchpardon 5"));
+        assert!(confirmations.confirm(1, 7, "batchpardon 5"));
+    }
+
+    #[test]
+    fn test_delete_of_missing_message_is_success() {
+        let not_found: Result<(), RequestError> =
+            Err(RequestError::Api(ApiError::MessageToDeleteNotFound));
+        assert!(ignore_message_not_found(not_found).is_ok());
+    }
+
+    #[test]
+    fn test_other_delete_errors_are_propagated() {
+        let forbidden: Result<(), RequestError> =
+            Err(RequestError::Api(ApiError::MessageCantBeDeleted));
+        assert!(matches!(
+            ignore_message_not_found(forbidden),
+            Err(RequestError::Api(ApiError::MessageCantBeDeleted))
+        ));
+    }
+
+    #[test]
+    fn test_format_user_report_without_notes() {
+        assert_eq!(format_user_report("42", 3, &[]), "Sender ID: 42\nSpam Score: 3");
+    }
+
+    #[test]
+    fn test_format_user_report_includes_notes() {
+        let notes = vec!["warned for self-promo on 5/1".to_string(), "known alt of 7".to_string()];
+        assert_eq!(
+            format_user_report("42", 3, &notes),
+            "Sender ID: 42\nSpam Score: 3\nNotes:\n- warned for self-promo on 5/1\n- known alt of 7"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! A module for receiving updates through a webhook instead of long polling.
+//!
+//! When `WEBHOOK_URL` is set, the bot registers it with Telegram and serves updates on
+//! `WEBHOOK_PORT` with teloxide's axum listener; otherwise it keeps long polling. Both
+//! modes run the same handler tree.
+//!
+//! The listener speaks plain HTTP on all interfaces. Telegram only delivers webhooks
+//! over HTTPS (on ports 443, 80, 88 or 8443), so the bot is expected to sit behind a
+//! reverse proxy that terminates TLS for `WEBHOOK_URL` and forwards the request, with
+//! the same path, to `WEBHOOK_PORT`. Telegram sends `WEBHOOK_SECRET` (a random token
+//! if unset) with every update, and requests without it are rejected.
+
+use crate::config::env_parse;
+use reqwest::Url;
+use std::net::SocketAddr;
+use teloxide::update_listeners::webhooks::Options;
+use thiserror::Error;
+
+/// Port the webhook listener binds to when `WEBHOOK_PORT` is unset.
+pub const DEFAULT_WEBHOOK_PORT: u16 = 8443;
+
+/// Why the webhook settings cannot be used.
+#[derive(Debug, Error)]
+pub enum WebhookConfigError {
+    /// `WEBHOOK_URL` is not a valid URL.
+    #[error("invalid WEBHOOK_URL: {0}")]
+    InvalidUrl(String),
+    /// `WEBHOOK_URL` is not an HTTPS URL, which Telegram requires.
+    #[error("WEBHOOK_URL must be an https:// URL, got {0}")]
+    NotHttps(String),
+    /// `WEBHOOK_PORT` is 0.
+    #[error("WEBHOOK_PORT must not be 0")]
+    InvalidPort,
+    /// `WEBHOOK_SECRET` is not 1-256 characters of `A-Z`, `a-z`, `0-9`, `_` and `-`.
+    #[error("WEBHOOK_SECRET must be 1-256 characters of A-Z, a-z, 0-9, _ and -")]
+    InvalidSecret,
+}
+
+/// Configuration for webhook mode, read from the environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookConfig {
+    /// Public HTTPS URL Telegram sends updates to; its path is the route served locally.
+    pub url: Url,
+    /// TCP port the listener binds to, on all interfaces.
+    pub port: u16,
+    /// Token Telegram sends with every update; teloxide generates one when `None`.
+    pub secret_token: Option<String>,
+}
+
+impl WebhookConfig {
+    /// Builds the webhook configuration from environment variables.
+    ///
+    /// * `WEBHOOK_URL` - Public HTTPS URL for updates; the bot long-polls when it is unset.
+    /// * `WEBHOOK_PORT` - Local port the listener binds to (default 8443).
+    /// * `WEBHOOK_SECRET` - Token Telegram sends with updates (default: random per start).
+    ///
+    /// # Returns
+    /// * `Option<Result<Self, WebhookConfigError>>` - `None` if `WEBHOOK_URL` is unset,
+    ///   otherwise the configuration or why it is invalid.
+    pub fn from_env() -> Option<Result<Self, WebhookConfigError>> {
+        let url = std::env::var("WEBHOOK_URL").ok().filter(|url| !url.trim().is_empty())?;
+        let secret_token = std::env::var("WEBHOOK_SECRET").ok().filter(|token| !token.is_empty());
+        Some(Self::new(&url, env_parse("WEBHOOK_PORT", DEFAULT_WEBHOOK_PORT), secret_token))
+    }
+
+    /// Validates webhook settings.
+    ///
+    /// # Arguments
+    /// * `url` - The public URL Telegram sends updates to.
+    /// * `port` - The local port to listen on.
+    /// * `secret_token` - The token Telegram sends with updates, if fixed.
+    ///
+    /// # Returns
+    /// * `Result<Self, WebhookConfigError>` - The configuration, or why it is invalid.
+    pub fn new(url: &str, port: u16, secret_token: Option<String>) -> Result<Self, WebhookConfigError> {
+        let url = Url::parse(url.trim()).map_err(|e| WebhookConfigError::InvalidUrl(e.to_string()))?;
+        if url.scheme() != "https" {
+            return Err(WebhookConfigError::NotHttps(url.to_string()));
+        }
+        if port == 0 {
+            return Err(WebhookConfigError::InvalidPort);
+        }
+        let valid_secret = |token: &String| {
+            (1..=256).contains(&token.len()) && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if secret_token.as_ref().is_some_and(|token| !valid_secret(token)) {
+            return Err(WebhookConfigError::InvalidSecret);
+        }
+        Ok(Self { url, port, secret_token })
+    }
+
+    /// Returns the address the listener binds to.
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::from(([0, 0, 0, 0], self.port))
+    }
+
+    /// Builds the options for teloxide's webhook listener.
+    pub fn options(&self) -> Options {
+        let options = Options::new(self.address(), self.url.clone());
+        match &self.secret_token {
+            Some(token) => options.secret_token(token.clone()),
+            None => options,
+        }
+    }
+}
+
+/// Unit tests for the `webhook` module.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webhook_settings_are_validated() {
+        let config = WebhookConfig::new(" https://bot.example.com/telegram ", 8443, None).unwrap();
+        assert_eq!(config.url.path(), "/telegram");
+        assert_eq!(config.address(), "0.0.0.0:8443".parse().unwrap());
+
+        assert!(matches!(WebhookConfig::new("bot.example.com", 8443, None), Err(WebhookConfigError::InvalidUrl(_))));
+        assert!(matches!(
+            WebhookConfig::new("http://bot.example.com/telegram", 8443, None),
+            Err(WebhookConfigError::NotHttps(_))
+        ));
+        assert!(matches!(WebhookConfig::new("https://bot.example.com", 0, None), Err(WebhookConfigError::InvalidPort)));
+        assert!(WebhookConfig::new("https://bot.example.com", 8443, Some("s3cret_token-1".to_string())).is_ok());
+        assert!(matches!(
+            WebhookConfig::new("https://bot.example.com", 8443, Some("not secret!".to_string())),
+            Err(WebhookConfigError::InvalidSecret)
+        ));
+    }
+}